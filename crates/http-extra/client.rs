@@ -1,17 +1,23 @@
 use crate::{
-    download::{Download, DownloadParam, DownloadStatus, DownloadSummary}, FetchHeadSnafu, FetchResourcesSnafu, GetChunkSnafu, IoOperationSnafu, Result,
-    SetTimeoutSnafu,
+    FetchHeadSnafu, FetchResourcesSnafu, GetChunkSnafu, IoOperationSnafu, Result, SetTimeoutSnafu,
+    UnauthorizedSnafu,
+    download::{Download, DownloadParam, DownloadStatus, DownloadSummary},
+    segment,
+    sha256::StreamingDigest,
 };
 use reqwest::{
-    header::{HeaderMap, ACCEPT_RANGES, CONTENT_LENGTH, RANGE}, Client,
-    Url,
+    Client, StatusCode, Url,
+    header::{ACCEPT_RANGES, CONTENT_LENGTH, HeaderMap, RANGE},
+};
+use snafu::{ResultExt, ensure};
+use std::{
+    io::SeekFrom,
+    path::{Path, PathBuf},
 };
-use snafu::ResultExt;
-use std::path::{Path, PathBuf};
 use tokio::{
     fs::File,
-    io::AsyncWriteExt,
-    time::{timeout, Duration},
+    io::{AsyncSeekExt, AsyncWriteExt},
+    time::{Duration, timeout},
 };
 use tracing::{debug, error};
 
@@ -19,25 +25,41 @@ impl Download for Client {
     async fn get_content_length_and_accept_ranges(
         &self,
         url: Url,
+        headers: HeaderMap,
     ) -> Result<(Option<u64>, Option<String>)> {
         let response = self
             .head(url.clone())
+            .headers(headers.clone())
             .send()
             .await
             .context(FetchHeadSnafu)?;
-        let headers = response.headers();
-        if let Some(0) = content_length_value(headers) {
+        ensure!(
+            response.status() != StatusCode::UNAUTHORIZED,
+            UnauthorizedSnafu {
+                url: url.to_string()
+            }
+        );
+        let head = response.headers();
+        if let Some(0) = content_length_value(head) {
             // 使用 get 请求再尝试一次
-            let response = self.get(url.clone()).send().await.context(FetchHeadSnafu)?;
-            let headers = response.headers();
-            Ok((content_length_value(headers), accept_ranges_value(headers)))
+            let response = self
+                .get(url.clone())
+                .headers(headers)
+                .send()
+                .await
+                .context(FetchHeadSnafu)?;
+            let head = response.headers();
+            Ok((content_length_value(head), accept_ranges_value(head)))
         } else {
-            Ok((content_length_value(headers), accept_ranges_value(headers)))
+            Ok((content_length_value(head), accept_ranges_value(head)))
         }
     }
 
     async fn fetch_file(&self, download: DownloadParam) -> Result<DownloadSummary> {
         let chunk_timeout = download.chunk_timeout;
+        let concurrency = download.concurrency;
+        let segment_size = download.segment_size;
+        let progress = download.progress.clone();
         let url = download.fetch_from.clone();
         // 对下载目录和文件做预处理
         let dir = download.save_to.as_path();
@@ -48,11 +70,12 @@ impl Download for Client {
             temp_path,
         } = download_dir_precondition(dir, file_name).await?;
 
-        let mut summary = DownloadSummary::new(download);
+        let mut summary = DownloadSummary::new(download.clone());
 
-        let mut request = self.get(url.clone());
-        let content_length_and_accept_ranges =
-            self.get_content_length_and_accept_ranges(url).await?;
+        let mut request = self.get(url.clone()).headers(download.headers.clone());
+        let content_length_and_accept_ranges = self
+            .get_content_length_and_accept_ranges(url.clone(), download.headers.clone())
+            .await?;
         let temp_len = temp
             .metadata()
             .await
@@ -60,33 +83,98 @@ impl Download for Client {
                 message: "Failed to read metadata from temp file".to_owned(),
             })?
             .len();
+        // 断点续传时已经存在的大小
+        let mut resumed = 0;
+        let mut total = None;
         if let (Some(content_length), Some(accept_ranges)) = content_length_and_accept_ranges {
+            total = Some(content_length);
             let resumable = accept_ranges == "bytes";
             summary = summary
                 .with_resumable(resumable)
                 .with_connet_length(content_length);
+            let progress_path = segment::progress_path(&temp_path);
             if !resumable {
                 // 服务器不支持断点续传
                 temp.set_len(0).await.context(IoOperationSnafu {
                     message: "Failed to clear the temp file".to_owned(),
                 })?;
+                if progress_path.exists() {
+                    tokio::fs::remove_file(&progress_path)
+                        .await
+                        .context(IoOperationSnafu {
+                            message: format!(
+                                "Failed to remove the progress file({})",
+                                progress_path.display()
+                            ),
+                        })?;
+                }
+            } else if progress_path.exists() || (concurrency > 1 && content_length > segment_size) {
+                // 存在分片的进度时，暂存文件已经预先分配了大小，需要按照分片续传
+                drop(temp);
+                let digest =
+                    segment::fetch_segments(self, &download, &temp_path, content_length).await?;
+                download_dir_after_treatment(path, temp_path).await?;
+                return Ok(summary
+                    .with_digest(digest)
+                    .with_status(DownloadStatus::Success));
             }
             if content_length == temp_len {
                 debug!(
                     "The size of the temporary file is the same as the size of the remote file. Just only need to do some post-processing related to the file."
                 );
+                let mut digest = StreamingDigest::new();
+                digest.update_from_file(&temp_path, temp_len).await?;
                 download_dir_after_treatment(path, temp_path).await?;
-                return Ok(summary.with_status(DownloadStatus::Success));
+                if let Some(progress) = &progress {
+                    progress.start(total, temp_len);
+                    progress.finish();
+                }
+                return Ok(summary
+                    .with_digest(digest.finalize())
+                    .with_status(DownloadStatus::Success));
             }
             if resumable && temp_len > 0 {
-                request = request.header(RANGE, format!("bytes={temp_len}-{content_length}"));
+                request = request.header(RANGE, format!("bytes={temp_len}-{}", content_length - 1));
+                // 从暂存文件的末尾继续写入
+                temp.seek(SeekFrom::End(0))
+                    .await
+                    .context(IoOperationSnafu {
+                        message: "Failed to seek to the end of the temp file".to_owned(),
+                    })?;
+                resumed = temp_len;
             }
         }
         let mut response = request.send().await.context(FetchResourcesSnafu)?;
+        // 认证失败时返回错误，调用方可以更新认证信息之后重试
+        ensure!(
+            response.status() != StatusCode::UNAUTHORIZED,
+            UnauthorizedSnafu {
+                url: url.to_string()
+            }
+        );
         if !response.status().is_success() {
             error!("The response was abnormal during byte transmission.");
             return Ok(summary.with_status(DownloadStatus::Failed("Response exception".to_owned())));
         }
+        // 服务器忽略 Range 时返回完整的内容，需要清空暂存文件从头写入
+        if resumed > 0 && response.status() != StatusCode::PARTIAL_CONTENT {
+            debug!("The server ignored the range request, download the whole file again");
+            temp.set_len(0).await.context(IoOperationSnafu {
+                message: "Failed to truncate the temp file".to_owned(),
+            })?;
+            temp.seek(SeekFrom::Start(0))
+                .await
+                .context(IoOperationSnafu {
+                    message: "Failed to seek to the start of the temp file".to_owned(),
+                })?;
+            resumed = 0;
+        }
+        // 断点续传时，已经存在的部分需要先计算摘要
+        let mut digest = StreamingDigest::new();
+        digest.update_from_file(&temp_path, resumed).await?;
+        if let Some(progress) = &progress {
+            progress.start(total, resumed);
+        }
         if let Some(chunk_timeout) = chunk_timeout {
             let chunk_timeout = Duration::from_secs(chunk_timeout);
             while let Some(chunk) = timeout(chunk_timeout, response.chunk())
@@ -100,6 +188,10 @@ impl Download for Client {
                 temp.flush().await.context(IoOperationSnafu {
                     message: "Failed to flush the temp file with chunk timeout".to_owned(),
                 })?;
+                digest.update(&chunk);
+                if let Some(progress) = &progress {
+                    progress.advance(chunk.len() as u64);
+                }
             }
         } else {
             while let Some(chunk) = response.chunk().await.context(GetChunkSnafu)? {
@@ -109,12 +201,21 @@ impl Download for Client {
                 temp.flush().await.context(IoOperationSnafu {
                     message: "Failed to flush the temp file".to_owned(),
                 })?;
+                digest.update(&chunk);
+                if let Some(progress) = &progress {
+                    progress.advance(chunk.len() as u64);
+                }
             }
         }
 
         // 对保存的文件做后处理
         download_dir_after_treatment(path, temp_path).await?;
-        Ok(summary.with_status(DownloadStatus::Success))
+        if let Some(progress) = &progress {
+            progress.finish();
+        }
+        Ok(summary
+            .with_digest(digest.finalize())
+            .with_status(DownloadStatus::Success))
     }
 }
 
@@ -291,4 +392,29 @@ mod test {
         let file_len = file.metadata().await.unwrap().len();
         assert_eq!(summary.connet_length(), file_len);
     }
+
+    #[tokio::test]
+    async fn test_download_segments() {
+        let dir = tempfile::tempdir().unwrap();
+        let url = Url::from_str("https://www.7-zip.org/a/7z2409-linux-x64.tar.xz").unwrap();
+        let filename = "7z2409-linux-x64.tar.xz";
+        let file_path = dir.path().join(filename);
+        let download_param = DownloadParam::try_new(url, filename, dir.path())
+            .unwrap()
+            .with_concurrency(4)
+            .with_segment_size(256 * 1024);
+        let summary = CLIENT.fetch_file(download_param).await.unwrap();
+        let file_len = tokio::fs::metadata(&file_path).await.unwrap().len();
+        assert_eq!(summary.connet_length(), file_len);
+        // 下载时计算的摘要和完整读取文件计算的摘要相同
+        let digest = summary.digest().unwrap();
+        assert!(crate::sha256::checksum(&file_path, digest.trim_start_matches("sha256:")).unwrap());
+        // 下载完成后不会残留暂存文件和进度文件
+        assert!(!dir.path().join(format!("{filename}.part")).exists());
+        assert!(
+            !dir.path()
+                .join(format!("{filename}.part.segments"))
+                .exists()
+        );
+    }
 }
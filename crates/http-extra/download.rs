@@ -1,14 +1,21 @@
-use crate::{GetDefaultHomeDirectorySnafu, IoOperationSnafu, Result};
-use reqwest::Url;
+use crate::{GetDefaultHomeDirectorySnafu, IoOperationSnafu, Result, progress::Progress};
+use reqwest::{
+    Url,
+    header::{HeaderMap, HeaderName, HeaderValue},
+};
 use snafu::{OptionExt, ResultExt, ensure_whatever};
 use std::path::{Path, PathBuf};
 use sys_extra::dir::UserDirs;
 
+// 默认的分片大小，64 MiB
+const DEFAULT_SEGMENT_SIZE: u64 = 64 * 1024 * 1024;
+
 pub trait Download {
     /// 获取 content-length 和 accept-ranges
     async fn get_content_length_and_accept_ranges(
         &self,
         url: Url,
+        headers: HeaderMap,
     ) -> Result<(Option<u64>, Option<String>)>;
 
     /// 获取文件
@@ -26,12 +33,20 @@ where
 pub struct DownloadParam {
     // 下载路径
     pub(crate) fetch_from: Url,
+    // 请求时附加的请求头，例如认证信息
+    pub(crate) headers: HeaderMap,
     // 保存的文件名
     pub(crate) file_name: String,
     // 保存的路径
     pub(crate) save_to: PathBuf,
     // 读写文件片允许超时时间
     pub(crate) chunk_timeout: Option<u64>,
+    // 并行下载时同时建立的连接数
+    pub(crate) concurrency: usize,
+    // 并行下载时每个分片的大小，单位为字节
+    pub(crate) segment_size: u64,
+    // 下载进度的订阅
+    pub(crate) progress: Option<Progress>,
 }
 
 impl PartialEq<Self> for DownloadParam {
@@ -42,7 +57,10 @@ impl PartialEq<Self> for DownloadParam {
             _ => false,
         };
         eq_chunk_timeout
+            && self.concurrency == other.concurrency
+            && self.segment_size == other.segment_size
             && self.fetch_from == other.fetch_from
+            && self.headers == other.headers
             && self.file_name == other.file_name
             && self.save_to == other.save_to
     }
@@ -70,9 +88,13 @@ impl DownloadParam {
         );
         Ok(Self {
             fetch_from: url,
+            headers: HeaderMap::new(),
             file_name: file_name.as_ref().to_owned(),
             save_to: save_to.to_owned(),
             chunk_timeout: None,
+            concurrency: 1,
+            segment_size: DEFAULT_SEGMENT_SIZE,
+            progress: None,
         })
     }
 
@@ -86,10 +108,33 @@ impl DownloadParam {
         Self::try_new(url, file_name, save_to)
     }
 
+    /// 每个请求都会附加这个请求头，相同名字的请求头会被替换
+    pub fn with_header(mut self, name: HeaderName, value: HeaderValue) -> Self {
+        self.headers.insert(name, value);
+        self
+    }
+
     pub fn with_chunk_timeout(mut self, chunk_timeout: Option<u64>) -> Self {
         self.chunk_timeout = chunk_timeout;
         self
     }
+
+    /// 连接数大于 1 并且服务器支持断点续传时，会将文件拆分成多个分片并行下载
+    pub fn with_concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+
+    pub fn with_segment_size(mut self, segment_size: u64) -> Self {
+        self.segment_size = segment_size.max(1);
+        self
+    }
+
+    /// 订阅下载进度，比较两个下载参数是否相同时不考虑进度的订阅
+    pub fn with_progress(mut self, progress: Progress) -> Self {
+        self.progress = Some(progress);
+        self
+    }
 }
 
 impl TryFrom<Url> for DownloadParam {
@@ -158,6 +203,8 @@ pub struct DownloadSummary {
     status: DownloadStatus,
     connet_length: u64,
     resumable: bool,
+    // 下载过程中计算的摘要，格式为 sha256:<hex>
+    digest: Option<String>,
 }
 
 impl DownloadSummary {
@@ -167,6 +214,7 @@ impl DownloadSummary {
             status: DownloadStatus::NotStarted,
             connet_length: 0_u64,
             resumable: false,
+            digest: None,
         }
     }
 
@@ -185,6 +233,11 @@ impl DownloadSummary {
         self
     }
 
+    pub fn with_digest(mut self, digest: String) -> Self {
+        self.digest = Some(digest);
+        self
+    }
+
     pub fn status(&self) -> DownloadStatus {
         self.status.clone()
     }
@@ -196,6 +249,11 @@ impl DownloadSummary {
     pub fn resumable(&self) -> bool {
         self.resumable
     }
+
+    /// 下载成功时文件的摘要，格式为 `sha256:<hex>`
+    pub fn digest(&self) -> Option<String> {
+        self.digest.clone()
+    }
 }
 
 #[cfg(test)]
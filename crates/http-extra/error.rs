@@ -13,6 +13,34 @@ pub enum Error {
     SetTimeout { source: tokio::time::error::Elapsed },
     #[snafu(display("Failed to get chunk"))]
     GetChunk { source: reqwest::Error },
+    #[snafu(display(
+        "The server responded with {status} when fetching the segment({start}-{end})"
+    ))]
+    UnexpectedSegmentStatus { status: u16, start: u64, end: u64 },
+    #[snafu(display(
+        "The segment({start}-{end}) is incomplete, only {downloaded} bytes have been downloaded"
+    ))]
+    IncompleteSegment {
+        start: u64,
+        end: u64,
+        downloaded: u64,
+    },
+    #[snafu(display("Failed to join the task of downloading segment"))]
+    JoinSegment { source: tokio::task::JoinError },
+    #[snafu(display("The registry rejected the authorization when fetching {url}"))]
+    Unauthorized { url: String },
+    #[snafu(display("Failed to fetch the token from {realm}"))]
+    FetchToken {
+        realm: String,
+        source: reqwest::Error,
+    },
+    #[snafu(display("The token server({realm}) responded with {status}"))]
+    UnexpectedTokenStatus { realm: String, status: u16 },
+    #[snafu(display("Failed to deserialize the token from {realm}"))]
+    DeserializeToken {
+        realm: String,
+        source: serde_json::Error,
+    },
     #[snafu(display("{message}"))]
     IoOperation {
         message: String,
@@ -21,7 +49,7 @@ pub enum Error {
     #[snafu(whatever, display("{message}"))]
     GenericError {
         message: String,
-        #[snafu(source(from(Box<dyn std::error::Error>, Some)))]
-        source: Option<Box<dyn std::error::Error>>,
+        #[snafu(source(from(Box<dyn std::error::Error + Send + Sync>, Some)))]
+        source: Option<Box<dyn std::error::Error + Send + Sync>>,
     },
 }
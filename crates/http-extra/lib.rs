@@ -3,7 +3,10 @@
 pub mod client;
 pub mod download;
 mod error;
+pub mod progress;
+pub mod registry;
 pub mod retry;
+mod segment;
 pub mod sha256;
 
 pub use error::*;
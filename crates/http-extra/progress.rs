@@ -0,0 +1,215 @@
+//! 传输进度
+//!
+//! 下载过程中会产生 [`ProgressEvent`]，调用方通过 [`Progress::new`] 提供回调订阅这些事件
+
+use std::{
+    fmt::{Debug, Display, Formatter},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+// 两次传输中的事件之间的最小间隔，避免过于频繁地回调
+const EMIT_INTERVAL: Duration = Duration::from_millis(100);
+
+/// 进度事件
+#[derive(Clone, Debug, PartialEq)]
+pub enum ProgressEvent {
+    /// 开始传输，断点续传时 resumed 为已经存在的大小
+    Started(ProgressState),
+    /// 收到了新的数据
+    Advanced(ProgressState),
+    /// 传输失败，等待 delay 之后开始第 attempt 次重试
+    Retrying {
+        attempt: usize,
+        delay: Duration,
+        error: String,
+    },
+    /// 传输完成
+    Finished(ProgressState),
+}
+
+/// 进度的快照
+#[derive(Clone, Debug, PartialEq)]
+pub struct ProgressState {
+    /// 已经接收的大小，包括断点续传之前已经存在的部分
+    pub received: u64,
+    /// 总大小，来自 content-length
+    pub total: Option<u64>,
+    /// 断点续传时已经存在的大小
+    pub resumed: u64,
+    /// 本次传输的平均速率，单位为每秒
+    pub rate: f64,
+    /// 预计剩余的时间
+    pub eta: Option<Duration>,
+}
+
+/// 进度的订阅，克隆之后共享同一个进度，可以在多个分片之间使用
+#[derive(Clone)]
+pub struct Progress {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    subscriber: Box<dyn Fn(ProgressEvent) + Send + Sync>,
+    tracker: Mutex<Tracker>,
+}
+
+struct Tracker {
+    received: u64,
+    total: Option<u64>,
+    resumed: u64,
+    started_at: Instant,
+    emitted_at: Instant,
+}
+
+impl Tracker {
+    fn state(&self) -> ProgressState {
+        let elapsed = self.started_at.elapsed().as_secs_f64();
+        let transferred = self.received.saturating_sub(self.resumed);
+        let rate = if elapsed > 0.0 {
+            transferred as f64 / elapsed
+        } else {
+            0.0
+        };
+        let eta = self.total.and_then(|total| {
+            let remaining = total.saturating_sub(self.received);
+            if remaining == 0 {
+                Some(Duration::ZERO)
+            } else if rate > 0.0 {
+                Some(Duration::from_secs_f64(remaining as f64 / rate))
+            } else {
+                None
+            }
+        });
+        ProgressState {
+            received: self.received,
+            total: self.total,
+            resumed: self.resumed,
+            rate,
+            eta,
+        }
+    }
+}
+
+impl Progress {
+    pub fn new(subscriber: impl Fn(ProgressEvent) + Send + Sync + 'static) -> Self {
+        let now = Instant::now();
+        Self {
+            inner: Arc::new(Inner {
+                subscriber: Box::new(subscriber),
+                tracker: Mutex::new(Tracker {
+                    received: 0,
+                    total: None,
+                    resumed: 0,
+                    started_at: now,
+                    emitted_at: now,
+                }),
+            }),
+        }
+    }
+
+    /// 开始一次传输，重试时会重新开始计算速率
+    pub fn start(&self, total: Option<u64>, resumed: u64) {
+        let state = {
+            let mut tracker = self.lock();
+            let now = Instant::now();
+            tracker.received = resumed;
+            tracker.total = total;
+            tracker.resumed = resumed;
+            tracker.started_at = now;
+            tracker.emitted_at = now;
+            tracker.state()
+        };
+        (self.inner.subscriber)(ProgressEvent::Started(state));
+    }
+
+    /// 收到了 len 大小的数据
+    pub fn advance(&self, len: u64) {
+        let state = {
+            let mut tracker = self.lock();
+            tracker.received += len;
+            if tracker.emitted_at.elapsed() < EMIT_INTERVAL {
+                return;
+            }
+            tracker.emitted_at = Instant::now();
+            tracker.state()
+        };
+        (self.inner.subscriber)(ProgressEvent::Advanced(state));
+    }
+
+    /// 传输失败，准备重试
+    pub fn retry(&self, attempt: usize, delay: Duration, error: impl Display) {
+        (self.inner.subscriber)(ProgressEvent::Retrying {
+            attempt,
+            delay,
+            error: error.to_string(),
+        });
+    }
+
+    /// 传输完成
+    pub fn finish(&self) {
+        let state = {
+            let mut tracker = self.lock();
+            if let Some(total) = tracker.total {
+                tracker.received = tracker.received.max(total);
+            }
+            tracker.state()
+        };
+        (self.inner.subscriber)(ProgressEvent::Finished(state));
+    }
+
+    /// 当前进度的快照
+    pub fn state(&self) -> ProgressState {
+        self.lock().state()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Tracker> {
+        self.inner
+            .tracker
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl Debug for Progress {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Progress")
+            .field("state", &self.state())
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_progress_events() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let progress = {
+            let events = events.clone();
+            Progress::new(move |event| events.lock().unwrap().push(event))
+        };
+        progress.start(Some(100), 40);
+        // 间隔太短的进度不会产生事件
+        progress.advance(10);
+        progress.retry(1, Duration::from_secs(1), "timeout");
+        progress.finish();
+        let events = events.lock().unwrap();
+        assert_eq!(events.len(), 3);
+        let ProgressEvent::Started(started) = &events[0] else {
+            panic!("The first event should be started");
+        };
+        assert_eq!((started.received, started.resumed), (40, 40));
+        assert!(matches!(
+            &events[1],
+            ProgressEvent::Retrying { attempt: 1, error, .. } if error == "timeout"
+        ));
+        let ProgressEvent::Finished(finished) = &events[2] else {
+            panic!("The last event should be finished");
+        };
+        assert_eq!(finished.received, 100);
+        assert_eq!(finished.total, Some(100));
+        assert_eq!(finished.eta, Some(Duration::ZERO));
+    }
+}
@@ -0,0 +1,457 @@
+//! 注册表的令牌认证
+//!
+//! 按照 Docker Registry v2 的约定，注册表返回 401 并且提供
+//! `WWW-Authenticate: Bearer realm=...,service=...,scope=...` 时，从 realm 获取令牌，
+//! 之后的请求附加 `Authorization: Bearer <token>`，令牌按照 scope 缓存到过期为止
+
+use crate::{
+    DeserializeTokenSnafu, FetchResourcesSnafu, FetchTokenSnafu, IoOperationSnafu, Result,
+    UnauthorizedSnafu, UnexpectedTokenStatusSnafu,
+};
+use base64ct::{Base64, Encoding};
+use reqwest::{
+    Client, Response, StatusCode, Url,
+    header::{AUTHORIZATION, HeaderValue, WWW_AUTHENTICATE},
+};
+use serde::Deserialize;
+use snafu::{OptionExt, ResultExt, ensure};
+use std::{
+    collections::HashMap,
+    fmt::{Debug, Formatter},
+    path::Path,
+    sync::{Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+use tracing::debug;
+
+// 令牌的响应中没有 expires_in 时，按照约定有效期为 60 秒
+const DEFAULT_TOKEN_EXPIRES_IN: u64 = 60;
+// 令牌提前一段时间过期，避免请求途中令牌失效
+const TOKEN_EXPIRY_MARGIN: Duration = Duration::from_secs(10);
+
+/// 访问注册表的凭据
+#[derive(Clone, Eq, PartialEq)]
+pub struct Credential {
+    username: String,
+    password: String,
+}
+
+impl Credential {
+    pub fn new(username: impl Into<String>, password: impl Into<String>) -> Self {
+        Self {
+            username: username.into(),
+            password: password.into(),
+        }
+    }
+
+    /// 从 Docker 的凭据文件（`~/.docker/config.json`）中读取注册表对应的凭据
+    ///
+    /// auths 中的键可以是 `host[:port]`，也可以是带有协议和路径的完整地址，文件中没有对应的凭据时返回 None
+    pub fn from_docker_config(path: impl AsRef<Path>, registry: &Url) -> Result<Option<Self>> {
+        let path = path.as_ref();
+        let config = std::fs::read(path).context(IoOperationSnafu {
+            message: format!("Failed to read the credential file({})", path.display()),
+        })?;
+        let config: DockerConfig = serde_json::from_slice(&config).with_whatever_context(|_| {
+            format!(
+                "Failed to deserialize the credential file({})",
+                path.display()
+            )
+        })?;
+        let authority = authority(registry);
+        let Some(auth) = config
+            .auths
+            .into_iter()
+            .find_map(|(key, auth)| (authority_of_key(&key) == authority).then_some(auth))
+        else {
+            return Ok(None);
+        };
+        match auth {
+            DockerAuth {
+                username: Some(username),
+                password: Some(password),
+                ..
+            } => Ok(Some(Self::new(username, password))),
+            DockerAuth {
+                auth: Some(auth), ..
+            } => {
+                // auth 是 base64 编码的 username:password
+                let decoded = Base64::decode_vec(auth.trim())
+                    .ok()
+                    .and_then(|decoded| String::from_utf8(decoded).ok())
+                    .whatever_context(format!(
+                        "The auth of {authority} in the credential file isn't valid base64"
+                    ))?;
+                let (username, password) = decoded.split_once(':').whatever_context(format!(
+                    "The auth of {authority} in the credential file isn't username:password"
+                ))?;
+                Ok(Some(Self::new(username, password)))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    fn basic(&self) -> HeaderValue {
+        let encoded =
+            Base64::encode_string(format!("{}:{}", self.username, self.password).as_bytes());
+        let mut value = HeaderValue::from_str(&format!("Basic {encoded}"))
+            .expect("The base64 string is always a valid header value");
+        value.set_sensitive(true);
+        value
+    }
+}
+
+impl Debug for Credential {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        // 不输出密码
+        f.debug_struct("Credential")
+            .field("username", &self.username)
+            .finish_non_exhaustive()
+    }
+}
+
+#[derive(Deserialize)]
+struct DockerConfig {
+    #[serde(default)]
+    auths: HashMap<String, DockerAuth>,
+}
+
+#[derive(Deserialize)]
+struct DockerAuth {
+    auth: Option<String>,
+    username: Option<String>,
+    password: Option<String>,
+}
+
+/// 注册表在 WWW-Authenticate 中提供的认证方式
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Challenge {
+    /// 直接使用用户名和密码认证
+    Basic,
+    /// 从 realm 获取令牌
+    Bearer {
+        realm: String,
+        service: Option<String>,
+        scope: Option<String>,
+    },
+}
+
+impl Challenge {
+    /// 解析 WWW-Authenticate，例如 `Bearer realm="https://auth.docker.io/token",service="registry.docker.io"`
+    pub fn parse(str: &str) -> Option<Self> {
+        let str = str.trim();
+        let (scheme, params) = str.split_once(' ').unwrap_or((str, ""));
+        if scheme.eq_ignore_ascii_case("basic") {
+            return Some(Challenge::Basic);
+        }
+        if !scheme.eq_ignore_ascii_case("bearer") {
+            return None;
+        }
+        let mut params = parse_params(params);
+        Some(Challenge::Bearer {
+            realm: params.remove("realm")?,
+            service: params.remove("service"),
+            scope: params.remove("scope"),
+        })
+    }
+}
+
+// 解析 key="value",key=value 形式的参数，引号中的值可以包含逗号
+fn parse_params(str: &str) -> HashMap<String, String> {
+    let mut params = HashMap::new();
+    let mut rest = str.trim();
+    while let Some((key, value)) = rest.split_once('=') {
+        let key = key
+            .trim()
+            .trim_start_matches(',')
+            .trim()
+            .to_ascii_lowercase();
+        let value = value.trim_start();
+        let (value, remain) = if let Some(quoted) = value.strip_prefix('"') {
+            match quoted.split_once('"') {
+                Some((value, remain)) => (value, remain),
+                None => (quoted, ""),
+            }
+        } else {
+            value.split_once(',').unwrap_or((value, ""))
+        };
+        params.insert(key, value.trim().to_owned());
+        rest = remain.trim_start().trim_start_matches(',');
+    }
+    params
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    token: Option<String>,
+    access_token: Option<String>,
+    expires_in: Option<u64>,
+}
+
+struct Token {
+    value: HeaderValue,
+    expires_at: Instant,
+}
+
+impl Token {
+    fn parse(realm: &str, bytes: &[u8]) -> Result<Self> {
+        let TokenResponse {
+            token,
+            access_token,
+            expires_in,
+        } = serde_json::from_slice(bytes).context(DeserializeTokenSnafu { realm })?;
+        let token = token
+            .or(access_token)
+            .filter(|token| !token.is_empty())
+            .whatever_context(format!("The response of {realm} doesn't contain any token"))?;
+        let mut value = HeaderValue::from_str(&format!("Bearer {token}"))
+            .whatever_context(format!("The token from {realm} isn't a valid header value"))?;
+        value.set_sensitive(true);
+        let expires_in = Duration::from_secs(expires_in.unwrap_or(DEFAULT_TOKEN_EXPIRES_IN));
+        Ok(Self {
+            value,
+            expires_at: Instant::now() + expires_in.saturating_sub(TOKEN_EXPIRY_MARGIN),
+        })
+    }
+}
+
+/// 访问注册表的客户端，注册表要求认证时自动获取令牌
+pub struct RegistryClient {
+    client: Client,
+    credential: Option<Credential>,
+    // 注册表最近一次返回的认证方式，没有要求认证时为 None
+    challenge: Mutex<Option<Challenge>>,
+    // 按照 scope 缓存的令牌
+    tokens: Mutex<HashMap<String, Token>>,
+}
+
+impl RegistryClient {
+    pub fn new(client: Client) -> Self {
+        Self {
+            client,
+            credential: None,
+            challenge: Mutex::new(None),
+            tokens: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// 没有凭据时匿名获取令牌
+    pub fn with_credential(mut self, credential: Option<Credential>) -> Self {
+        self.credential = credential;
+        self
+    }
+
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+
+    /// 拉取仓库时需要的 scope，例如 repository:library/qwen3:pull
+    pub fn pull_scope(repository: &str) -> String {
+        format!("repository:{repository}:pull")
+    }
+
+    /// 发送 GET 请求，注册表返回 401 时，按照质询获取新的认证信息之后重新发送一次
+    pub async fn get(&self, url: Url, scope: &str) -> Result<Response> {
+        let authorization = self.authorization(scope).await?;
+        let response = self.send(url.clone(), authorization).await?;
+        if response.status() != StatusCode::UNAUTHORIZED {
+            return Ok(response);
+        }
+        let challenge = response
+            .headers()
+            .get(WWW_AUTHENTICATE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(Challenge::parse)
+            .context(UnauthorizedSnafu {
+                url: url.to_string(),
+            })?;
+        debug!("{url} requires authorization, {challenge:?}");
+        *lock(&self.challenge) = Some(challenge);
+        self.invalidate(scope);
+        let authorization = self.authorization(scope).await?;
+        ensure!(
+            authorization.is_some(),
+            UnauthorizedSnafu {
+                url: url.to_string()
+            }
+        );
+        let response = self.send(url.clone(), authorization).await?;
+        ensure!(
+            response.status() != StatusCode::UNAUTHORIZED,
+            UnauthorizedSnafu {
+                url: url.to_string()
+            }
+        );
+        Ok(response)
+    }
+
+    /// 访问 scope 时需要附加的 Authorization，令牌过期时重新获取，注册表没有要求认证时返回 None
+    pub async fn authorization(&self, scope: &str) -> Result<Option<HeaderValue>> {
+        if let Some(token) = lock(&self.tokens).get(scope)
+            && token.expires_at > Instant::now()
+        {
+            return Ok(Some(token.value.clone()));
+        }
+        let challenge = lock(&self.challenge).clone();
+        match challenge {
+            None => Ok(None),
+            Some(Challenge::Basic) => Ok(self.credential.as_ref().map(Credential::basic)),
+            Some(Challenge::Bearer { realm, service, .. }) => {
+                let token = self.fetch_token(&realm, service.as_deref(), scope).await?;
+                let value = token.value.clone();
+                lock(&self.tokens).insert(scope.to_owned(), token);
+                Ok(Some(value))
+            }
+        }
+    }
+
+    /// 注册表拒绝了缓存的令牌时，删除这个令牌，下次使用时重新获取
+    pub fn invalidate(&self, scope: &str) {
+        lock(&self.tokens).remove(scope);
+    }
+
+    async fn send(&self, url: Url, authorization: Option<HeaderValue>) -> Result<Response> {
+        let request = self.client.get(url.clone());
+        let request = match authorization {
+            Some(authorization) => request.header(AUTHORIZATION, authorization),
+            None => request,
+        };
+        request.send().await.context(FetchResourcesSnafu)
+    }
+
+    async fn fetch_token(&self, realm: &str, service: Option<&str>, scope: &str) -> Result<Token> {
+        let mut url = Url::parse(realm)
+            .with_whatever_context(|_| format!("The realm({realm}) isn't a valid url"))?;
+        {
+            let mut query = url.query_pairs_mut();
+            if let Some(service) = service {
+                query.append_pair("service", service);
+            }
+            query.append_pair("scope", scope);
+        }
+        let request = self.client.get(url);
+        // 没有凭据时匿名获取令牌
+        let request = match &self.credential {
+            Some(credential) => request.header(AUTHORIZATION, credential.basic()),
+            None => request,
+        };
+        let response = request.send().await.context(FetchTokenSnafu { realm })?;
+        let status = response.status();
+        ensure!(
+            status.is_success(),
+            UnexpectedTokenStatusSnafu {
+                realm,
+                status: status.as_u16(),
+            }
+        );
+        let bytes = response.bytes().await.context(FetchTokenSnafu { realm })?;
+        Token::parse(realm, &bytes)
+    }
+}
+
+impl Debug for RegistryClient {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RegistryClient")
+            .field("credential", &self.credential)
+            .field("challenge", &*lock(&self.challenge))
+            .finish_non_exhaustive()
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+// 注册表地址中的 host[:port]
+fn authority(url: &Url) -> String {
+    let host = url.host_str().unwrap_or_default();
+    match url.port() {
+        Some(port) => format!("{host}:{port}"),
+        None => host.to_owned(),
+    }
+}
+
+// 凭据文件中的键可能是 https://index.docker.io/v1/ 这样的地址
+fn authority_of_key(key: &str) -> &str {
+    let key = key
+        .strip_prefix("https://")
+        .or_else(|| key.strip_prefix("http://"))
+        .unwrap_or(key);
+    key.split('/').next().unwrap_or(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_challenge() {
+        let challenge = Challenge::parse(
+            r#"Bearer realm="https://auth.docker.io/token",service="registry.docker.io",scope="repository:library/qwen3:pull,push""#,
+        );
+        assert_eq!(
+            challenge,
+            Some(Challenge::Bearer {
+                realm: "https://auth.docker.io/token".to_owned(),
+                service: Some("registry.docker.io".to_owned()),
+                scope: Some("repository:library/qwen3:pull,push".to_owned()),
+            })
+        );
+        let challenge = Challenge::parse("bearer realm=https://mirror.local/token");
+        assert_eq!(
+            challenge,
+            Some(Challenge::Bearer {
+                realm: "https://mirror.local/token".to_owned(),
+                service: None,
+                scope: None,
+            })
+        );
+        assert_eq!(
+            Challenge::parse(r#"Basic realm="registry""#),
+            Some(Challenge::Basic)
+        );
+        assert_eq!(Challenge::parse("Bearer service=registry"), None);
+        assert_eq!(Challenge::parse("Digest realm=registry"), None);
+    }
+
+    #[test]
+    fn test_parse_token() {
+        let realm = "https://auth.docker.io/token";
+        let token = Token::parse(realm, br#"{"token":"abc","expires_in":300}"#).unwrap();
+        assert_eq!(token.value, "Bearer abc");
+        assert!(token.expires_at > Instant::now() + Duration::from_secs(280));
+        let token = Token::parse(realm, br#"{"access_token":"def"}"#).unwrap();
+        assert_eq!(token.value, "Bearer def");
+        assert!(token.expires_at <= Instant::now() + Duration::from_secs(50));
+        assert!(Token::parse(realm, br#"{"token":""}"#).is_err());
+    }
+
+    #[test]
+    fn test_credential_from_docker_config() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.json");
+        // mirror:secret
+        std::fs::write(
+            &path,
+            r#"{"auths":{"https://mirror.local:5000/v2/":{"auth":"bWlycm9yOnNlY3JldA=="},"registry.local":{"username":"user","password":"pass"}}}"#,
+        )
+        .unwrap();
+        let credential = Credential::from_docker_config(
+            &path,
+            &Url::parse("https://mirror.local:5000").unwrap(),
+        )
+        .unwrap();
+        assert_eq!(credential, Some(Credential::new("mirror", "secret")));
+        assert_eq!(credential.unwrap().basic(), "Basic bWlycm9yOnNlY3JldA==");
+        let credential =
+            Credential::from_docker_config(&path, &Url::parse("https://registry.local/").unwrap())
+                .unwrap();
+        assert_eq!(credential, Some(Credential::new("user", "pass")));
+        let credential =
+            Credential::from_docker_config(&path, &Url::parse("https://mirror.local").unwrap())
+                .unwrap();
+        assert_eq!(credential, None);
+    }
+}
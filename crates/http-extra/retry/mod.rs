@@ -5,13 +5,25 @@ pub mod strategy;
 pub async fn spawn<T, E: Debug>(
     strategy: impl IntoIterator<Item = Duration>,
     action: impl AsyncFn() -> Result<T, E>,
+) -> Result<T, E> {
+    spawn_notify(strategy, action, |_, _, _| {}).await
+}
+
+/// 和 [`spawn`] 相同，每次重试之前会调用 notify，参数为第几次重试、等待的时间以及失败的原因
+pub async fn spawn_notify<T, E: Debug>(
+    strategy: impl IntoIterator<Item = Duration>,
+    action: impl AsyncFn() -> Result<T, E>,
+    notify: impl Fn(usize, Duration, &E),
 ) -> Result<T, E> {
     let mut strategy = strategy.into_iter();
+    let mut attempt = 0;
     loop {
         match action().await {
             Ok(t) => return Ok(t),
             Err(err) => {
                 if let Some(duration) = strategy.next() {
+                    attempt += 1;
+                    notify(attempt, duration, &err);
                     tokio::time::sleep(duration).await;
                     tracing::warn!("Future execution failed, starting retry! Error: {err:?}");
                 } else {
@@ -166,4 +178,21 @@ mod tests {
         assert_eq!(res, Err(3));
         assert_eq!(counter.load(Ordering::SeqCst), 3);
     }
+
+    #[tokio::test]
+    async fn notifies_before_each_retry() {
+        use super::strategy::FixedInterval;
+        let s = FixedInterval::from_millis(10).take(2);
+        let attempts = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let cloned_attempts = attempts.clone();
+        let future = super::spawn_notify(
+            s,
+            async || future::ready(Err::<(), u64>(42)).await,
+            move |attempt, _, err: &u64| cloned_attempts.lock().unwrap().push((attempt, *err)),
+        );
+        let res = future.await;
+
+        assert_eq!(res, Err(42));
+        assert_eq!(*attempts.lock().unwrap(), vec![(1, 42), (2, 42)]);
+    }
 }
@@ -0,0 +1,437 @@
+//! 分片并行下载
+//!
+//! 将支持断点续传的资源拆分成多个分片，每个分片使用单独的连接写入到预先分配好大小的暂存文件中，
+//! 每个分片的进度保存在 `<file>.part.segments` 中，下载中断之后每个分片可以独立续传
+
+use crate::{
+    FetchResourcesSnafu, GetChunkSnafu, IncompleteSegmentSnafu, IoOperationSnafu, JoinSegmentSnafu,
+    Result, SetTimeoutSnafu, UnexpectedSegmentStatusSnafu, download::DownloadParam,
+    progress::Progress, sha256::StreamingDigest,
+};
+use reqwest::{
+    Client, StatusCode, Url,
+    header::{HeaderMap, RANGE},
+};
+use snafu::{ResultExt, ensure};
+use std::{
+    fmt::{Display, Formatter},
+    io::SeekFrom,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tokio::{
+    fs::OpenOptions,
+    io::{AsyncSeekExt, AsyncWriteExt},
+    sync::{Mutex, Semaphore},
+    task::JoinSet,
+    time::{Duration, timeout},
+};
+use tracing::debug;
+
+// 每个分片每写入 8 MiB 保存一次进度
+const SAVE_INTERVAL: u64 = 8 * 1024 * 1024;
+
+/// 进度文件的路径
+pub(crate) fn progress_path(temp_path: &Path) -> PathBuf {
+    let mut path = temp_path.as_os_str().to_owned();
+    path.push(".segments");
+    PathBuf::from(path)
+}
+
+/// 并行下载全部的分片，下载完成后删除进度文件，返回文件的摘要
+///
+/// 分片下载完成之后，从文件开头开始连续下载完成的部分会立即计算摘要，不需要在全部下载完成之后再读取一遍文件
+pub(crate) async fn fetch_segments(
+    client: &Client,
+    download: &DownloadParam,
+    temp_path: &Path,
+    content_length: u64,
+) -> Result<String> {
+    let DownloadParam {
+        fetch_from: url,
+        headers,
+        chunk_timeout,
+        concurrency,
+        segment_size,
+        progress: reporter,
+        ..
+    } = download;
+    let progress_path = progress_path(temp_path);
+    let temp = OpenOptions::new()
+        .write(true)
+        .open(temp_path)
+        .await
+        .context(IoOperationSnafu {
+            message: format!("Failed to open the temp file({})", temp_path.display()),
+        })?;
+    let temp_len = temp
+        .metadata()
+        .await
+        .context(IoOperationSnafu {
+            message: "Failed to read metadata from temp file".to_owned(),
+        })?
+        .len();
+    let progress = if progress_path.exists() {
+        match SegmentProgress::load(&progress_path).await {
+            Some(progress)
+                if progress.content_length == content_length && temp_len == content_length =>
+            {
+                debug!("Resume the segments from {}", progress_path.display());
+                progress
+            }
+            // 进度文件损坏或者远程文件已经变化，只能重新下载
+            _ => {
+                temp.set_len(0).await.context(IoOperationSnafu {
+                    message: "Failed to clear the temp file".to_owned(),
+                })?;
+                SegmentProgress::new(content_length, *segment_size)
+            }
+        }
+    } else {
+        // 之前使用单个连接下载的数据可以继续使用
+        SegmentProgress::new(content_length, *segment_size).with_downloaded(temp_len)
+    };
+    // 预先分配文件大小，每个分片直接写入到对应的位置
+    temp.set_len(content_length)
+        .await
+        .context(IoOperationSnafu {
+            message: "Failed to preallocate the temp file".to_owned(),
+        })?;
+    drop(temp);
+    progress.save(&progress_path).await?;
+    if let Some(reporter) = reporter {
+        reporter.start(Some(content_length), progress.downloaded());
+    }
+
+    let pending = progress.pending();
+    let progress = Arc::new(Mutex::new(progress));
+    let semaphore = Arc::new(Semaphore::new((*concurrency).max(1)));
+    let mut tasks = JoinSet::new();
+    for index in pending {
+        let task = SegmentTask {
+            client: client.clone(),
+            url: url.clone(),
+            headers: headers.clone(),
+            temp_path: temp_path.to_owned(),
+            progress_path: progress_path.clone(),
+            progress: progress.clone(),
+            index,
+            chunk_timeout: *chunk_timeout,
+            reporter: reporter.clone(),
+        };
+        let semaphore = semaphore.clone();
+        tasks.spawn(async move {
+            let _permit = semaphore
+                .acquire_owned()
+                .await
+                .expect("The semaphore of segments has been closed");
+            task.fetch().await
+        });
+    }
+    let mut digest = StreamingDigest::new();
+    let mut result = Ok(());
+    while let Some(joined) = tasks.join_next().await {
+        if result.is_err() {
+            continue;
+        }
+        let joined = match joined.context(JoinSegmentSnafu).and_then(|result| result) {
+            Ok(_) => {
+                let contiguous = progress.lock().await.contiguous();
+                digest.update_from_file(temp_path, contiguous).await
+            }
+            Err(e) => Err(e),
+        };
+        if let Err(e) = joined {
+            // 有一个分片失败时，取消其它的分片，已经下载的进度保存下来，重试时继续
+            tasks.abort_all();
+            result = Err(e);
+        }
+    }
+    let saved = progress.lock().await.save(&progress_path).await;
+    result?;
+    saved?;
+    digest.update_from_file(temp_path, content_length).await?;
+    tokio::fs::remove_file(&progress_path)
+        .await
+        .context(IoOperationSnafu {
+            message: format!(
+                "Failed to remove the progress file({})",
+                progress_path.display()
+            ),
+        })?;
+    if let Some(reporter) = reporter {
+        reporter.finish();
+    }
+    Ok(digest.finalize())
+}
+
+struct SegmentTask {
+    client: Client,
+    url: Url,
+    headers: HeaderMap,
+    temp_path: PathBuf,
+    progress_path: PathBuf,
+    progress: Arc<Mutex<SegmentProgress>>,
+    // 分片的下标
+    index: usize,
+    chunk_timeout: Option<u64>,
+    reporter: Option<Progress>,
+}
+
+impl SegmentTask {
+    async fn fetch(self) -> Result<()> {
+        let segment = self.progress.lock().await.segments[self.index].clone();
+        let Segment { start, end, .. } = segment;
+        let mut position = segment.position();
+        if position >= end {
+            return Ok(());
+        }
+        let mut response = self
+            .client
+            .get(self.url.clone())
+            .headers(self.headers.clone())
+            .header(RANGE, format!("bytes={position}-{}", end - 1))
+            .send()
+            .await
+            .context(FetchResourcesSnafu)?;
+        let status = response.status();
+        ensure!(
+            status == StatusCode::PARTIAL_CONTENT,
+            UnexpectedSegmentStatusSnafu {
+                status: status.as_u16(),
+                start: position,
+                end,
+            }
+        );
+        let mut file = OpenOptions::new()
+            .write(true)
+            .open(&self.temp_path)
+            .await
+            .context(IoOperationSnafu {
+                message: format!("Failed to open the temp file({})", self.temp_path.display()),
+            })?;
+        file.seek(SeekFrom::Start(position))
+            .await
+            .context(IoOperationSnafu {
+                message: format!("Failed to seek the temp file to {position}"),
+            })?;
+        let mut unsaved = 0;
+        while position < end {
+            let chunk = match self.chunk_timeout {
+                Some(chunk_timeout) => {
+                    timeout(Duration::from_secs(chunk_timeout), response.chunk())
+                        .await
+                        .context(SetTimeoutSnafu)?
+                }
+                None => response.chunk().await,
+            }
+            .context(GetChunkSnafu)?;
+            let Some(chunk) = chunk else {
+                break;
+            };
+            // 服务器返回的数据超过分片的范围时，只写入分片范围内的数据
+            let len = chunk.len().min((end - position) as usize);
+            file.write_all(&chunk[..len])
+                .await
+                .context(IoOperationSnafu {
+                    message: "Failed to write the segment to temp file".to_owned(),
+                })?;
+            // 数据写入之后再更新进度，避免进度超过实际写入的数据
+            file.flush().await.context(IoOperationSnafu {
+                message: "Failed to flush the temp file".to_owned(),
+            })?;
+            position += len as u64;
+            unsaved += len as u64;
+            if let Some(reporter) = &self.reporter {
+                reporter.advance(len as u64);
+            }
+            let mut progress = self.progress.lock().await;
+            progress.segments[self.index].downloaded = position - start;
+            if unsaved >= SAVE_INTERVAL || position >= end {
+                progress.save(&self.progress_path).await?;
+                unsaved = 0;
+            }
+        }
+        ensure!(
+            position >= end,
+            IncompleteSegmentSnafu {
+                start,
+                end,
+                downloaded: position - start,
+            }
+        );
+        Ok(())
+    }
+}
+
+/// 单个分片，范围是 [start, end)
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Segment {
+    start: u64,
+    end: u64,
+    // 已经下载的字节数
+    downloaded: u64,
+}
+
+impl Segment {
+    // 下一个需要下载的位置
+    fn position(&self) -> u64 {
+        self.start + self.downloaded
+    }
+}
+
+/// 全部分片的进度
+///
+/// 保存的格式为文本，第一行是文件的大小，之后每一行是一个分片的 `start end downloaded`
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct SegmentProgress {
+    content_length: u64,
+    segments: Vec<Segment>,
+}
+
+impl SegmentProgress {
+    fn new(content_length: u64, segment_size: u64) -> Self {
+        let segment_size = segment_size.max(1);
+        let segments = (0..content_length)
+            .step_by(segment_size as usize)
+            .map(|start| Segment {
+                start,
+                end: (start + segment_size).min(content_length),
+                downloaded: 0,
+            })
+            .collect();
+        Self {
+            content_length,
+            segments,
+        }
+    }
+
+    // 文件开头已经连续下载了 len 字节
+    fn with_downloaded(mut self, len: u64) -> Self {
+        for segment in self.segments.iter_mut() {
+            segment.downloaded = len.clamp(segment.start, segment.end) - segment.start;
+        }
+        self
+    }
+
+    // 全部分片已经下载的大小
+    fn downloaded(&self) -> u64 {
+        self.segments.iter().map(|segment| segment.downloaded).sum()
+    }
+
+    // 从文件开头开始连续下载完成的大小
+    fn contiguous(&self) -> u64 {
+        self.segments
+            .iter()
+            .find(|segment| segment.position() < segment.end)
+            .map_or(self.content_length, Segment::position)
+    }
+
+    // 还没有下载完成的分片的下标
+    fn pending(&self) -> Vec<usize> {
+        self.segments
+            .iter()
+            .enumerate()
+            .filter(|(_, segment)| segment.position() < segment.end)
+            .map(|(index, _)| index)
+            .collect()
+    }
+
+    fn parse(str: &str) -> Option<Self> {
+        let mut lines = str.lines();
+        let content_length = lines.next()?.trim().parse().ok()?;
+        let mut segments = Vec::new();
+        let mut expected_start = 0;
+        for line in lines {
+            let mut values = line.split_whitespace().map(|value| value.parse::<u64>());
+            let (Some(Ok(start)), Some(Ok(end)), Some(Ok(downloaded)), None) =
+                (values.next(), values.next(), values.next(), values.next())
+            else {
+                return None;
+            };
+            // 分片必须首尾相连，并且进度不能超过分片的范围
+            if start != expected_start || end <= start || downloaded > end - start {
+                return None;
+            }
+            expected_start = end;
+            segments.push(Segment {
+                start,
+                end,
+                downloaded,
+            });
+        }
+        (expected_start == content_length).then_some(Self {
+            content_length,
+            segments,
+        })
+    }
+
+    async fn load(path: &Path) -> Option<Self> {
+        let str = tokio::fs::read_to_string(path).await.ok()?;
+        Self::parse(&str)
+    }
+
+    // 先写入临时文件再重命名，避免中断时留下不完整的进度文件
+    async fn save(&self, path: &Path) -> Result<()> {
+        let mut temp_path = path.as_os_str().to_owned();
+        temp_path.push(".tmp");
+        let temp_path = PathBuf::from(temp_path);
+        tokio::fs::write(&temp_path, self.to_string())
+            .await
+            .context(IoOperationSnafu {
+                message: format!("Failed to write the progress file({})", temp_path.display()),
+            })?;
+        tokio::fs::rename(&temp_path, path)
+            .await
+            .context(IoOperationSnafu {
+                message: format!(
+                    "Failed to rename file(\"{}\") to the new(\"{}\")",
+                    temp_path.display(),
+                    path.display(),
+                ),
+            })
+    }
+}
+
+impl Display for SegmentProgress {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{}", self.content_length)?;
+        for Segment {
+            start,
+            end,
+            downloaded,
+        } in &self.segments
+        {
+            writeln!(f, "{start} {end} {downloaded}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_segment_progress() {
+        let progress = SegmentProgress::new(250, 100);
+        assert_eq!(progress.segments.len(), 3);
+        assert_eq!(progress.segments[2].end, 250);
+        assert_eq!(progress.pending(), vec![0, 1, 2]);
+        let progress = progress.with_downloaded(150);
+        assert_eq!(progress.segments[0].downloaded, 100);
+        assert_eq!(progress.segments[1].downloaded, 50);
+        assert_eq!(progress.segments[2].downloaded, 0);
+        assert_eq!(progress.pending(), vec![1, 2]);
+        assert_eq!(progress.downloaded(), 150);
+        assert_eq!(progress.contiguous(), 150);
+        let str = progress.to_string();
+        assert_eq!(str, "250\n0 100 100\n100 200 50\n200 250 0\n");
+        assert_eq!(SegmentProgress::parse(&str), Some(progress));
+        // 不完整或者范围不连续的进度文件
+        assert_eq!(SegmentProgress::parse("250\n0 100 100\n100 200 50\n"), None);
+        assert_eq!(SegmentProgress::parse("250\n0 100 100\n150 250 0\n"), None);
+        assert_eq!(SegmentProgress::parse("100\n0 100 101\n"), None);
+        assert_eq!(SegmentProgress::parse(""), None);
+    }
+}
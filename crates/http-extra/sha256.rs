@@ -1,10 +1,14 @@
 use crate::{IoOperationSnafu, Result};
 use base64ct::{Base64, Encoding};
-use faster_hex::hex_decode;
+use faster_hex::{hex_decode, hex_string};
 use memmap2::Mmap;
 use sha2::{Digest, Sha256};
 use snafu::ResultExt;
-use std::{fs::File, path::Path};
+use std::{fs::File, io::SeekFrom, path::Path};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+// 从文件中读取数据计算摘要时，每次读取的大小
+const READ_BUFFER_SIZE: usize = 1024 * 1024;
 
 pub fn checksum(file: impl AsRef<Path>, digest: impl AsRef<str>) -> Result<bool> {
     let file = File::open(&file).context(IoOperationSnafu {
@@ -22,6 +26,67 @@ pub fn checksum(file: impl AsRef<Path>, digest: impl AsRef<str>) -> Result<bool>
     Ok(hash.as_slice().eq(&digest_byte))
 }
 
+/// 增量计算文件的 SHA-256，下载时每写入一块数据就更新一次，不需要在下载完成之后再读取一遍文件
+#[derive(Clone, Debug, Default)]
+pub struct StreamingDigest {
+    hasher: Sha256,
+    // 已经计算的字节数
+    hashed: u64,
+}
+
+impl StreamingDigest {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.hasher.update(data);
+        self.hashed += data.len() as u64;
+    }
+
+    /// 已经计算的字节数
+    pub fn hashed(&self) -> u64 {
+        self.hashed
+    }
+
+    /// 读取文件中 [hashed, end) 范围的数据更新摘要，断点续传时用来计算已经存在的部分
+    pub async fn update_from_file(&mut self, path: impl AsRef<Path>, end: u64) -> Result<()> {
+        let path = path.as_ref();
+        if end <= self.hashed {
+            return Ok(());
+        }
+        let mut file = tokio::fs::File::open(path)
+            .await
+            .context(IoOperationSnafu {
+                message: format!(
+                    "When computing the digest of {}, the file cannot be opened",
+                    path.display()
+                ),
+            })?;
+        file.seek(SeekFrom::Start(self.hashed))
+            .await
+            .context(IoOperationSnafu {
+                message: format!("Failed to seek {} to {}", path.display(), self.hashed),
+            })?;
+        let mut buffer = vec![0u8; READ_BUFFER_SIZE];
+        while self.hashed < end {
+            let len = READ_BUFFER_SIZE.min((end - self.hashed) as usize);
+            file.read_exact(&mut buffer[..len])
+                .await
+                .context(IoOperationSnafu {
+                    message: format!("Failed to read {} to compute the digest", path.display()),
+                })?;
+            self.update(&buffer[..len]);
+        }
+        Ok(())
+    }
+
+    /// 摘要，格式为 `sha256:<hex>`
+    pub fn finalize(self) -> String {
+        format!("sha256:{}", hex_string(&self.hasher.finalize()))
+    }
+}
+
 pub fn digest(data: &[u8]) -> String {
     let mut hasher = Sha256::new();
     hasher.update(data);
@@ -31,7 +96,7 @@ pub fn digest(data: &[u8]) -> String {
 
 #[cfg(test)]
 mod tests {
-    use super::{checksum, digest};
+    use super::{StreamingDigest, checksum, digest};
     use std::io::Write;
 
     #[test]
@@ -55,4 +120,20 @@ mod tests {
             digest(hello)
         );
     }
+
+    #[tokio::test]
+    async fn test_streaming_digest() {
+        let dir = tempfile::tempdir().unwrap();
+        let text = dir.path().join("1.txt");
+        std::fs::write(&text, b"Hello, World!").unwrap();
+        // 前 7 个字节从文件中读取，剩下的部分增量更新
+        let mut streaming = StreamingDigest::new();
+        streaming.update_from_file(&text, 7).await.unwrap();
+        assert_eq!(streaming.hashed(), 7);
+        streaming.update(b"World!");
+        assert_eq!(
+            streaming.finalize(),
+            "sha256:dffd6021bb2bd5b0af676290809ec3a53191dd81c7f70a4b28688a362182986f"
+        );
+    }
 }
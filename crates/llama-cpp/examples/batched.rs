@@ -0,0 +1,138 @@
+use clap::Parser;
+use llama_cpp::{
+    context::ContextParams,
+    model::ModelParams,
+    runtime::Runtime,
+    sampler::Sampler,
+    scheduler::{Event, Request, Scheduler},
+    utils::ggml_time_us,
+};
+use snafu::{ResultExt, Whatever};
+use std::{collections::HashMap, path::PathBuf};
+
+#[derive(Parser, Debug)]
+#[command(version, long_about = None)]
+#[command(about = "Generate several prompts in parallel with one context")]
+struct Cli {
+    // 本地模型所在路径
+    #[arg(short = 'm', long, help = "The path of the model file")]
+    model: PathBuf,
+    // 同时处理的序列数量
+    #[arg(
+        short = 's',
+        long,
+        default_value = "4",
+        help = "The number of sequences to decode in parallel"
+    )]
+    sequences: u32,
+    // 每个提示词要预测的令牌数量
+    #[arg(
+        short = 'p',
+        long,
+        default_value = "32",
+        help = "The number of tokens to predict for each prompt"
+    )]
+    quantity: u32,
+    #[arg(
+        long = "ngl",
+        default_value = "99",
+        help = "The number of layers to offload to the GPU"
+    )]
+    layer: i32,
+    // 提示词，每个提示词使用一个序列
+    #[arg(default_values = ["Hello my name is", "The capital of France is", "Rust is a language", "Once upon a time"])]
+    prompts: Vec<String>,
+}
+
+fn main() -> Result<(), Whatever> {
+    let Cli {
+        model: mode_path,
+        sequences,
+        quantity: token_quantity,
+        layer: gpu_layer,
+        prompts,
+    } = Cli::parse();
+
+    // 动态加载后端
+    let runtime = Runtime::load_all();
+
+    // 获取默认的模型参数
+    let model_params = ModelParams::default();
+    // 设置卸载到 GPU 的层数
+    model_params.with_n_gpu_layers(gpu_layer);
+
+    // 从文件中加载模型
+    let model = runtime
+        .load_model_from_file(mode_path, &model_params)
+        .with_whatever_context(|_| "Failed to load model from the path")?;
+    let vocab = model.vocab();
+
+    // 每个序列使用 512 个令牌的上下文
+    let context_params = ContextParams::default()
+        .with_n_ctx(512 * sequences)
+        .with_n_batch(512)
+        .with_n_seq_max(sequences);
+    let context = runtime
+        .new_context(&model, context_params)
+        .with_whatever_context(|_| "Failed to create context")?;
+    let mut scheduler = Scheduler::new(context);
+
+    // 每个提示词提交一个请求，超过序列数量的请求会等待空闲的序列
+    let mut outputs = HashMap::new();
+    for (i, prompt) in prompts.iter().enumerate() {
+        let tokens = vocab
+            .tokenize(prompt, true, true)
+            .with_whatever_context(|_| "Failed to tokenize the prompt")?;
+        let sampler = Sampler::from_chain(
+            [
+                Sampler::init_from_top_k(40),
+                Sampler::init_from_temp(0.8),
+                Sampler::init_from_dist(i as u32),
+            ],
+            false,
+        );
+        let request = Request {
+            prompt: tokens,
+            sampler,
+            max_tokens: Some(token_quantity),
+        };
+        let id = scheduler
+            .submit(request)
+            .with_whatever_context(|_| "Failed to submit the request")?;
+        outputs.insert(id, (prompt.clone(), String::new()));
+    }
+
+    let start = ggml_time_us();
+    let mut decode_num = 0;
+    while !scheduler.is_idle() {
+        let events = scheduler
+            .step(&model)
+            .with_whatever_context(|_| "Failed to decode batch")?;
+        for event in events {
+            match event {
+                Event::Token { id, token } => {
+                    decode_num += 1;
+                    let piece = vocab
+                        .token_to_piece(&token, 0, true)
+                        .with_whatever_context(|_| "Failed to get token str")?;
+                    if let Some((_, output)) = outputs.get_mut(&id) {
+                        output.push_str(&piece);
+                    }
+                }
+                Event::Finished { id, reason, .. } => {
+                    if let Some((prompt, output)) = outputs.get(&id) {
+                        println!("{prompt}{output}\n({reason:?})\n");
+                    }
+                }
+            }
+        }
+    }
+    let end = ggml_time_us();
+    println!(
+        "decoded {decode_num} tokens in {} s, speed: {} t/s",
+        (end - start) as f64 / 1_000_000.0,
+        decode_num as f64 / ((end - start) as f64 / 1_000_000.0),
+    );
+
+    Ok(())
+}
@@ -1,8 +1,8 @@
 use clap::Parser;
 use llama_cpp::{
-    batch::Batch,
     context::ContextParams,
-    model::{Message, ModelParams},
+    generator::{Generator, GeneratorError},
+    model::{Message, ModelParams, Special},
     runtime::Runtime,
     sampler::Sampler,
 };
@@ -113,6 +113,8 @@ fn main() -> Result<(), Whatever> {
 
     let mut count = 1;
     let mut messages = Vec::<Message>::new();
+    // 上一轮对话结束之后模板渲染出来的长度
+    let mut prev_len = 0;
     // 获取模板
     let template = model
         .chat_template(None)
@@ -135,45 +137,40 @@ fn main() -> Result<(), Whatever> {
                 let prompt = model
                     .apply_chat_template(&template, messages.as_slice(), true)
                     .with_whatever_context(|_| "Failed to apply chat template to model")?;
-                let n_ctx_used = context.kv_cache_seq_pos_max(0) + 1;
-                let is_first = n_ctx_used == 0;
+                // 之前的对话已经在 KV cache 中，只需要解码新增的部分
+                let is_first = prev_len == 0;
                 let tokens = vocab
-                    .tokenize(prompt, is_first, true)
+                    .tokenize(&prompt[prev_len..], is_first, true)
                     .with_whatever_context(|_| "Failed to get tokens from vocab")?;
-                let mut batch = Batch::get_one(&tokens)
-                    .with_whatever_context(|_| "Failed to create a new batch by tokens")?;
+                let generator = Generator::new(&model, &mut context, &mut sampler, tokens)
+                    .with_whatever_context(|_| "Failed to create a generator")?
+                    .with_special(Special::Tokenize);
                 let mut response = String::new();
-                loop {
-                    let n_ctx = context.n_ctx();
-                    if n_ctx_used + batch.n_tokens() > n_ctx as i32 {
-                        eprintln!("context size exceeded!");
-                        exit(0);
-                    }
-                    context
-                        .decode(&mut batch)
-                        .with_whatever_context(|_| "Failed to decode token")?;
-                    let new_token = sampler.sample(&context, -1);
-                    if vocab.is_eog_token(new_token) {
-                        break;
-                    }
-                    let piece = vocab
-                        .token_to_piece(&new_token, 0, true)
-                        .with_whatever_context(|_| "Failed to get new piece from token")?;
+                for piece in generator {
+                    let piece = match piece {
+                        Err(llama_cpp::Error::Generator {
+                            source: GeneratorError::ContextFull { .. },
+                        }) => {
+                            eprintln!("context size exceeded!");
+                            exit(0);
+                        }
+                        piece => piece.with_whatever_context(|_| "Failed to generate a piece")?,
+                    };
                     response += &piece;
                     print!("{piece}");
                     // print! 不会自动刷新缓冲区，要确保消息立即显示在控制台上，需要手动刷新
                     stdout()
                         .flush()
                         .with_whatever_context(|_| "Failed to flush to stdout")?;
-                    batch = Batch::get_one(&[new_token])
-                        .with_whatever_context(|_| "Failed to create a new batch by new token")?;
                 }
+                println!();
                 let message = Message::try_new("assistant", response)
                     .with_whatever_context(|_| "Failed to create new message")?;
                 messages.push(message);
-                model
+                prev_len = model
                     .apply_chat_template(&template, messages.as_slice(), false)
-                    .with_whatever_context(|_| "Failed to apply chat template")?;
+                    .with_whatever_context(|_| "Failed to apply chat template")?
+                    .len();
                 stdout()
                     .flush()
                     .with_whatever_context(|_| "Failed to flush to stdout")?;
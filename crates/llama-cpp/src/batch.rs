@@ -26,6 +26,9 @@ pub struct Batch {
     allocated_space: i32,
 }
 
+// 数组由 llama_batch_init 分配，只属于这个 Batch，可以和调度器一起交给其它线程
+unsafe impl Send for Batch {}
+
 #[derive(Debug, Snafu)]
 #[snafu(visibility(pub(crate)))]
 pub enum BatchError {
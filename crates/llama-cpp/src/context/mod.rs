@@ -1,8 +1,10 @@
 mod params;
 mod perf;
+mod state;
 
 pub use params::*;
 pub use perf::Perf;
+pub use state::StateError;
 
 use crate::{
     batch::Batch,
@@ -10,6 +12,7 @@ use crate::{
         DecodeAborted, DecodeCouldNotFindKvSlot, DecodeFatal, DecodeInvalidInputBatch,
         DecodeUnknown, EncodeUnknown,
     },
+    model::AdapterLora,
 };
 use snafu::prelude::*;
 use std::{
@@ -60,8 +63,13 @@ pub enum ContextError {
     MemorySeqDivP0TooLarge { source: TryFromIntError },
     #[snafu(display("Provided end position is too large for u32, when memory seq div"))]
     MemorySeqDivP1TooLarge { source: TryFromIntError },
+    #[snafu(display("Failed to set the lora adapter to the context: {code}"))]
+    SetLoraAdapter { code: i32 },
 }
 
+// llama_context 没有绑定在创建它的线程上，只要求同一时间只有一个线程使用，&mut 已经保证了这一点
+unsafe impl Send for Context {}
+
 impl Debug for Context {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("LlamaContext")
@@ -113,6 +121,12 @@ impl Context {
         unsafe { llama_cpp_sys::llama_n_ctx(self.raw.as_ptr()) }
     }
 
+    /// 上下文中可以同时存在的序列数量
+    #[must_use]
+    pub fn n_seq_max(&self) -> u32 {
+        unsafe { llama_cpp_sys::llama_n_seq_max(self.raw.as_ptr()) }
+    }
+
     /// 处理一批令牌，使用解码器处理批处理
     ///
     /// 正返回值并不意味着致命错误，而是一个警告
@@ -244,6 +258,12 @@ impl Context {
         Ok(())
     }
 
+    /// KV cache 是否支持移动位置，不支持时无法进行上下文移动
+    #[must_use]
+    pub fn kv_cache_can_shift(&self) -> bool {
+        unsafe { llama_cpp_sys::llama_memory_can_shift(self.memory_ptr()) }
+    }
+
     #[must_use]
     pub fn kv_cache_seq_pos_max(&self, seq_id: i32) -> i32 {
         unsafe { llama_cpp_sys::llama_memory_seq_pos_max(self.memory_ptr(), seq_id) }
@@ -254,6 +274,31 @@ impl Context {
         unsafe { llama_cpp_sys::llama_memory_seq_pos_min(self.memory_ptr(), seq_id) }
     }
 
+    /// 在上下文中使用 LoRA 适配器，scale 为适配器的权重，已经使用的适配器只更新权重
+    ///
+    /// 适配器需要比上下文活得更久
+    pub fn set_lora_adapter(
+        &mut self,
+        adapter: &AdapterLora,
+        scale: f32,
+    ) -> Result<(), ContextError> {
+        let code = unsafe {
+            llama_cpp_sys::llama_set_adapter_lora(self.raw.as_ptr(), adapter.raw_mut(), scale)
+        };
+        ensure!(code == 0, SetLoraAdapterSnafu { code });
+        Ok(())
+    }
+
+    /// 不再使用 LoRA 适配器，上下文中没有使用这个适配器时返回 false
+    pub fn remove_lora_adapter(&mut self, adapter: &AdapterLora) -> bool {
+        unsafe { llama_cpp_sys::llama_rm_adapter_lora(self.raw.as_ptr(), adapter.raw_mut()) == 0 }
+    }
+
+    /// 不再使用任何 LoRA 适配器
+    pub fn clear_lora_adapters(&mut self) {
+        unsafe { llama_cpp_sys::llama_clear_adapter_lora(self.raw.as_ptr()) }
+    }
+
     /// Reset the timings for the context.
     pub fn reset_timings(&mut self) {
         unsafe { llama_cpp_sys::llama_perf_context_reset(self.raw.as_ptr()) }
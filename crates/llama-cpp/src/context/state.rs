@@ -0,0 +1,215 @@
+//! 保存和恢复上下文的状态，包括 KV cache、logits 和嵌入向量，恢复之后不需要重新解码提示词
+//!
+//! 可以保存整个上下文，也可以只保存一个序列；保存到文件时会同时保存序列中的 token
+
+use crate::{context::Context, token::Token};
+use snafu::prelude::*;
+use std::{ffi::CString, path::Path};
+
+#[derive(Debug, Snafu)]
+#[snafu(visibility(pub(crate)))]
+pub enum StateError {
+    #[snafu(display("There was a null byte in the path({path})"))]
+    StatePathNul {
+        path: String,
+        source: std::ffi::NulError,
+    },
+    #[snafu(display("Only {written} of {size} bytes of the state were copied"))]
+    StateGetData { size: usize, written: usize },
+    #[snafu(display("Failed to restore the state from {size} bytes"))]
+    StateSetData { size: usize },
+    #[snafu(display("Failed to save the state to {path}"))]
+    StateSaveFile { path: String },
+    #[snafu(display("Failed to load the state from {path}"))]
+    StateLoadFile { path: String },
+}
+
+// 将路径转换成 C 字符串
+fn path_to_cstring(path: &Path) -> Result<CString, StateError> {
+    CString::new(path.as_os_str().as_encoded_bytes()).context(StatePathNulSnafu {
+        path: path.display().to_string(),
+    })
+}
+
+impl Context {
+    /// 保存整个上下文的状态需要的字节数
+    #[must_use]
+    pub fn state_size(&self) -> usize {
+        unsafe { llama_cpp_sys::llama_state_get_size(self.raw_mut()) }
+    }
+
+    /// 将整个上下文的状态复制到字节数组中
+    pub fn state_to_bytes(&self) -> Result<Vec<u8>, StateError> {
+        let size = self.state_size();
+        let mut bytes = vec![0_u8; size];
+        let written = unsafe {
+            llama_cpp_sys::llama_state_get_data(self.raw_mut(), bytes.as_mut_ptr(), size)
+        };
+        ensure!(written == size, StateGetDataSnafu { size, written });
+        Ok(bytes)
+    }
+
+    /// 从字节数组中恢复整个上下文的状态
+    pub fn state_from_bytes(&mut self, bytes: &[u8]) -> Result<(), StateError> {
+        let size = bytes.len();
+        let read =
+            unsafe { llama_cpp_sys::llama_state_set_data(self.raw_mut(), bytes.as_ptr(), size) };
+        ensure!(read > 0, StateSetDataSnafu { size });
+        Ok(())
+    }
+
+    /// 将整个上下文的状态和 token 保存到文件中
+    pub fn save_state_file(
+        &self,
+        path: impl AsRef<Path>,
+        tokens: &[Token],
+    ) -> Result<(), StateError> {
+        let path = path.as_ref();
+        let path_str = path_to_cstring(path)?;
+        let saved = unsafe {
+            llama_cpp_sys::llama_state_save_file(
+                self.raw_mut(),
+                path_str.as_ptr(),
+                tokens.as_ptr() as _,
+                tokens.len(),
+            )
+        };
+        ensure!(
+            saved,
+            StateSaveFileSnafu {
+                path: path.display().to_string()
+            }
+        );
+        Ok(())
+    }
+
+    /// 从文件中恢复整个上下文的状态，返回保存时的 token，token 的数量不能超过 max_tokens
+    pub fn load_state_file(
+        &mut self,
+        path: impl AsRef<Path>,
+        max_tokens: usize,
+    ) -> Result<Vec<Token>, StateError> {
+        let path = path.as_ref();
+        let path_str = path_to_cstring(path)?;
+        let mut tokens = Vec::<Token>::with_capacity(max_tokens);
+        let mut n_tokens = 0;
+        let loaded = unsafe {
+            llama_cpp_sys::llama_state_load_file(
+                self.raw_mut(),
+                path_str.as_ptr(),
+                tokens.as_mut_ptr() as _,
+                max_tokens,
+                &mut n_tokens,
+            )
+        };
+        ensure!(
+            loaded,
+            StateLoadFileSnafu {
+                path: path.display().to_string()
+            }
+        );
+        // llama.cpp 已经写入了 n_tokens 个 token
+        unsafe { tokens.set_len(n_tokens.min(max_tokens)) };
+        Ok(tokens)
+    }
+
+    /// 保存一个序列的状态需要的字节数
+    #[must_use]
+    pub fn seq_state_size(&self, seq_id: i32) -> usize {
+        unsafe { llama_cpp_sys::llama_state_seq_get_size(self.raw_mut(), seq_id) }
+    }
+
+    /// 将一个序列的状态复制到字节数组中
+    pub fn seq_state_to_bytes(&self, seq_id: i32) -> Result<Vec<u8>, StateError> {
+        let size = self.seq_state_size(seq_id);
+        let mut bytes = vec![0_u8; size];
+        let written = unsafe {
+            llama_cpp_sys::llama_state_seq_get_data(
+                self.raw_mut(),
+                bytes.as_mut_ptr(),
+                size,
+                seq_id,
+            )
+        };
+        ensure!(written == size, StateGetDataSnafu { size, written });
+        Ok(bytes)
+    }
+
+    /// 从字节数组中恢复一个序列的状态到 dest_seq_id 中
+    pub fn seq_state_from_bytes(
+        &mut self,
+        bytes: &[u8],
+        dest_seq_id: i32,
+    ) -> Result<(), StateError> {
+        let size = bytes.len();
+        let read = unsafe {
+            llama_cpp_sys::llama_state_seq_set_data(
+                self.raw_mut(),
+                bytes.as_ptr(),
+                size,
+                dest_seq_id,
+            )
+        };
+        ensure!(read > 0, StateSetDataSnafu { size });
+        Ok(())
+    }
+
+    /// 将一个序列的状态和 token 保存到文件中，返回写入的字节数
+    pub fn save_seq_state_file(
+        &self,
+        path: impl AsRef<Path>,
+        seq_id: i32,
+        tokens: &[Token],
+    ) -> Result<usize, StateError> {
+        let path = path.as_ref();
+        let path_str = path_to_cstring(path)?;
+        let written = unsafe {
+            llama_cpp_sys::llama_state_seq_save_file(
+                self.raw_mut(),
+                path_str.as_ptr(),
+                seq_id,
+                tokens.as_ptr() as _,
+                tokens.len(),
+            )
+        };
+        ensure!(
+            written > 0,
+            StateSaveFileSnafu {
+                path: path.display().to_string()
+            }
+        );
+        Ok(written)
+    }
+
+    /// 从文件中恢复一个序列的状态到 dest_seq_id 中，返回保存时的 token，token 的数量不能超过 max_tokens
+    pub fn load_seq_state_file(
+        &mut self,
+        path: impl AsRef<Path>,
+        dest_seq_id: i32,
+        max_tokens: usize,
+    ) -> Result<Vec<Token>, StateError> {
+        let path = path.as_ref();
+        let path_str = path_to_cstring(path)?;
+        let mut tokens = Vec::<Token>::with_capacity(max_tokens);
+        let mut n_tokens = 0;
+        let read = unsafe {
+            llama_cpp_sys::llama_state_seq_load_file(
+                self.raw_mut(),
+                path_str.as_ptr(),
+                dest_seq_id,
+                tokens.as_mut_ptr() as _,
+                max_tokens,
+                &mut n_tokens,
+            )
+        };
+        ensure!(
+            read > 0,
+            StateLoadFileSnafu {
+                path: path.display().to_string()
+            }
+        );
+        // llama.cpp 已经写入了 n_tokens 个 token
+        unsafe { tokens.set_len(n_tokens.min(max_tokens)) };
+        Ok(tokens)
+    }
+}
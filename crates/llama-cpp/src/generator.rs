@@ -0,0 +1,525 @@
+//! 流式生成：解码提示词之后逐个采样 token，输出解码之后的文本片段
+//!
+//! 一个字符可能被拆分到多个 token 中，不完整的字节会被保留到字符完整之后再输出；
+//! 可能是停止字符串开头的文本也会被保留，直到确认不是停止字符串为止
+
+use crate::{
+    Result,
+    batch::Batch,
+    context::Context,
+    model::{Model, Special},
+    prefix_cache::PrefixCache,
+    sampler::Sampler,
+    token::Token,
+};
+use snafu::prelude::*;
+use std::{
+    ops::Range,
+    time::{Duration, Instant},
+};
+
+#[derive(Debug, Snafu)]
+#[snafu(visibility(pub(crate)))]
+pub enum GeneratorError {
+    #[snafu(display("The prompt is empty"))]
+    EmptyPrompt,
+    #[snafu(display(
+        "The context is full, {n_past} tokens have been used, {n_tokens} more tokens exceed the context size {n_ctx}"
+    ))]
+    ContextFull {
+        n_past: u32,
+        n_tokens: usize,
+        n_ctx: u32,
+    },
+}
+
+/// 停止生成的原因
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum FinishReason {
+    /// 生成了结束的 token 或者停止 token
+    Eog,
+    /// 遇到了停止字符串，停止字符串本身不会输出
+    Stop(String),
+    /// 达到了最多生成的 token 数量
+    Length,
+}
+
+/// 上下文用完时的处理方式
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum ContextShift {
+    /// 不做处理，返回 [`GeneratorError::ContextFull`]
+    #[default]
+    Disabled,
+    /// 保留开头的 n_keep 个 token（例如系统提示词），丢弃剩余部分中较早的一半，之后的 token 向前移动
+    Discard { n_keep: u32 },
+}
+
+impl ContextShift {
+    // 上下文中有 n_past 个 token 时丢弃的范围，开头的 n_keep 个 token 始终保留，没有可以丢弃的 token 时返回 None
+    fn discard_range(self, n_past: u32) -> Option<Range<u32>> {
+        let ContextShift::Discard { n_keep } = self else {
+            return None;
+        };
+        let n_keep = n_keep.min(n_past);
+        let n_discard = (n_past - n_keep) / 2;
+        (n_discard > 0).then_some(n_keep..n_keep + n_discard)
+    }
+}
+
+/// 流式生成的迭代器，每次返回一段文本，结束之后可以通过 [`Generator::finish_reason`] 获取结束的原因
+///
+/// 上下文用完并且无法移动时返回 [`GeneratorError::ContextFull`]，返回错误之后迭代器结束。
+/// 提示词从序列中已有的 KV cache 之后开始解码，多轮对话只需要提供新的 token；
+/// 使用前缀缓存时需要提供完整的提示词，和缓存的公共前缀不会重复解码
+pub struct Generator<'a> {
+    model: &'a Model,
+    context: &'a mut Context,
+    sampler: &'a mut Sampler,
+    prefix_cache: Option<&'a mut PrefixCache>,
+    batch: Batch,
+    seq_id: i32,
+    // 等待解码的 token，第一次是提示词，之后是上一次采样的 token
+    pending: Vec<Token>,
+    n_prompt: usize,
+    // 从前缀缓存中复用的 token 数量
+    n_cached: usize,
+    // 下一个 token 在序列中的位置
+    n_past: u32,
+    n_generated: u32,
+    max_tokens: Option<u32>,
+    context_shift: ContextShift,
+    // 上下文移动时丢弃的 token 数量
+    n_discarded: u32,
+    stop_tokens: Vec<Token>,
+    special: Special,
+    decoder: Utf8Decoder,
+    stop: StopMatcher,
+    started_at: Instant,
+    prompt_duration: Option<Duration>,
+    finish_reason: Option<FinishReason>,
+    // 已经输出了保留的文本或者发生了错误
+    done: bool,
+}
+
+impl<'a> Generator<'a> {
+    /// 创建生成器，提示词为空时返回错误
+    pub fn new(
+        model: &'a Model,
+        context: &'a mut Context,
+        sampler: &'a mut Sampler,
+        prompt: Vec<Token>,
+    ) -> Result<Self> {
+        ensure!(!prompt.is_empty(), EmptyPromptSnafu);
+        let n_batch = context.n_batch().max(1);
+        let n_past = (context.kv_cache_seq_pos_max(0) + 1).max(0) as u32;
+        Ok(Self {
+            model,
+            context,
+            sampler,
+            prefix_cache: None,
+            batch: Batch::new(n_batch as i32, 1),
+            seq_id: 0,
+            n_prompt: prompt.len(),
+            pending: prompt,
+            n_cached: 0,
+            n_past,
+            n_generated: 0,
+            max_tokens: None,
+            context_shift: ContextShift::Disabled,
+            n_discarded: 0,
+            stop_tokens: Vec::new(),
+            special: Special::Plaintext,
+            decoder: Utf8Decoder::default(),
+            stop: StopMatcher::new(Vec::new()),
+            started_at: Instant::now(),
+            prompt_duration: None,
+            finish_reason: None,
+            done: false,
+        })
+    }
+
+    /// 使用的序列，默认是 0
+    #[must_use]
+    pub fn with_seq_id(mut self, seq_id: i32) -> Self {
+        self.seq_id = seq_id;
+        self.n_past = (self.context.kv_cache_seq_pos_max(seq_id) + 1).max(0) as u32;
+        self
+    }
+
+    /// 使用前缀缓存，只解码提示词中和缓存不一致的部分，解码的 token 会记录到缓存中
+    #[must_use]
+    pub fn with_prefix_cache(mut self, prefix_cache: &'a mut PrefixCache) -> Self {
+        self.prefix_cache = Some(prefix_cache);
+        self
+    }
+
+    /// 最多生成的 token 数量，默认不限制
+    #[must_use]
+    pub fn with_max_tokens(mut self, max_tokens: Option<u32>) -> Self {
+        self.max_tokens = max_tokens;
+        self
+    }
+
+    /// 上下文用完时的处理方式，默认返回错误
+    #[must_use]
+    pub fn with_context_shift(mut self, context_shift: ContextShift) -> Self {
+        self.context_shift = context_shift;
+        self
+    }
+
+    /// 遇到这些字符串时停止生成，空字符串会被忽略
+    #[must_use]
+    pub fn with_stop_strings(mut self, stops: Vec<String>) -> Self {
+        self.stop = StopMatcher::new(stops);
+        self
+    }
+
+    /// 除了词汇表中的结束 token 之外，遇到这些 token 时也停止生成
+    #[must_use]
+    pub fn with_stop_tokens(mut self, stop_tokens: Vec<Token>) -> Self {
+        self.stop_tokens = stop_tokens;
+        self
+    }
+
+    /// 控制 token 是否转换成文本，默认当作普通文本处理
+    #[must_use]
+    pub fn with_special(mut self, special: Special) -> Self {
+        self.special = special;
+        self
+    }
+
+    /// 提示词的 token 数量
+    #[must_use]
+    pub fn n_prompt(&self) -> usize {
+        self.n_prompt
+    }
+
+    /// 从前缀缓存中复用的提示词 token 数量
+    #[must_use]
+    pub fn n_cached(&self) -> usize {
+        self.n_cached
+    }
+
+    /// 已经生成的 token 数量，不包括结束 token
+    #[must_use]
+    pub fn n_generated(&self) -> u32 {
+        self.n_generated
+    }
+
+    /// 序列中已经使用的位置
+    #[must_use]
+    pub fn n_past(&self) -> u32 {
+        self.n_past
+    }
+
+    /// 上下文移动时丢弃的 token 数量，大于 0 时说明较早的内容已经不在上下文中
+    #[must_use]
+    pub fn n_discarded(&self) -> u32 {
+        self.n_discarded
+    }
+
+    /// 处理提示词使用的时间，提示词还没有处理时为 None
+    #[must_use]
+    pub fn prompt_duration(&self) -> Option<Duration> {
+        self.prompt_duration
+    }
+
+    /// 从创建生成器开始经过的时间
+    #[must_use]
+    pub fn elapsed(&self) -> Duration {
+        self.started_at.elapsed()
+    }
+
+    /// 结束的原因，还没有结束或者发生了错误时为 None
+    #[must_use]
+    pub fn finish_reason(&self) -> Option<&FinishReason> {
+        self.finish_reason.as_ref()
+    }
+
+    // 解码等待中的 token，采样一个新的 token，返回可以输出的文本
+    fn advance(&mut self) -> Result<String> {
+        if self.max_tokens.is_some_and(|max| self.n_generated >= max) {
+            self.finish_reason = Some(FinishReason::Length);
+            return Ok(String::new());
+        }
+        // 第一次解码之前，从前缀缓存中复用和提示词相同的部分
+        if self.prompt_duration.is_none()
+            && let Some(prefix_cache) = self.prefix_cache.as_deref_mut()
+        {
+            let n_cached = prefix_cache.reuse(self.context, self.seq_id, &self.pending)?;
+            self.pending.drain(..n_cached);
+            self.n_cached = n_cached;
+            self.n_past = n_cached as u32;
+        }
+        let n_ctx = self.context.n_ctx();
+        while self.n_past as usize + self.pending.len() > n_ctx as usize && self.shift()? {}
+        ensure!(
+            self.n_past as usize + self.pending.len() <= n_ctx as usize,
+            ContextFullSnafu {
+                n_past: self.n_past,
+                n_tokens: self.pending.len(),
+                n_ctx
+            }
+        );
+        // 提示词比批次长时分多次解码，只有最后一个 token 需要输出 logits
+        let n_batch = self.batch.allocated_space() as usize;
+        let n_pending = self.pending.len();
+        for (i, chunk) in self.pending.chunks(n_batch).enumerate() {
+            self.batch.clear();
+            for (j, token) in chunk.iter().enumerate() {
+                let last = i * n_batch + j + 1 == n_pending;
+                self.batch
+                    .add(*token, self.n_past as i32, &[self.seq_id], last)?;
+                self.n_past += 1;
+            }
+            self.context.decode(&mut self.batch)?;
+            if let Some(prefix_cache) = self.prefix_cache.as_deref_mut() {
+                prefix_cache.extend(self.seq_id, chunk);
+            }
+        }
+        self.pending.clear();
+        self.prompt_duration
+            .get_or_insert_with(|| self.started_at.elapsed());
+        let token = self.sampler.sample(self.context, self.batch.n_tokens() - 1);
+        if self.model.vocab().is_eog_token(token) || self.stop_tokens.contains(&token) {
+            self.finish_reason = Some(FinishReason::Eog);
+            return Ok(String::new());
+        }
+        self.n_generated += 1;
+        self.pending.push(token);
+        // 无法转换的 token 不输出任何文本
+        let bytes = self
+            .model
+            .token_to_bytes(token, self.special)
+            .unwrap_or_default();
+        let (text, stop) = self.stop.push(&self.decoder.push(&bytes));
+        if let Some(stop) = stop {
+            self.finish_reason = Some(FinishReason::Stop(stop));
+        }
+        Ok(text)
+    }
+
+    // 按照策略丢弃一部分 KV cache，没有丢弃任何 token 时返回 false
+    fn shift(&mut self) -> Result<bool> {
+        let Some(range) = self.context_shift.discard_range(self.n_past) else {
+            return Ok(false);
+        };
+        if !self.context.kv_cache_can_shift() {
+            return Ok(false);
+        }
+        let n_discard = range.end - range.start;
+        let seq_id = self.seq_id as u32;
+        self.context
+            .clear_kv_cache_seq(Some(seq_id), Some(range.start), Some(range.end))?;
+        self.context.kv_cache_seq_add(
+            self.seq_id,
+            Some(range.end),
+            Some(self.n_past),
+            -(n_discard as i32),
+        )?;
+        if let Some(prefix_cache) = self.prefix_cache.as_deref_mut() {
+            prefix_cache.discard(self.seq_id, range.start as usize..range.end as usize);
+        }
+        self.n_past -= n_discard;
+        self.n_discarded += n_discard;
+        Ok(true)
+    }
+}
+
+impl Iterator for Generator<'_> {
+    type Item = Result<String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        while self.finish_reason.is_none() {
+            match self.advance() {
+                Ok(text) if text.is_empty() => {}
+                Ok(text) => return Some(Ok(text)),
+                Err(error) => {
+                    self.done = true;
+                    return Some(Err(error));
+                }
+            }
+        }
+        // 结束之后输出保留的文本，遇到停止字符串时保留的文本都被丢弃
+        self.done = true;
+        let mut rest = self.decoder.finish();
+        rest.insert_str(0, &self.stop.finish());
+        if matches!(self.finish_reason, Some(FinishReason::Stop(_))) || rest.is_empty() {
+            return None;
+        }
+        Some(Ok(rest))
+    }
+}
+
+/// 一个字符可能被拆分到多个 token 中，保留不完整的字节，等到字符完整之后再输出
+#[derive(Default)]
+pub struct Utf8Decoder {
+    pending: Vec<u8>,
+}
+
+impl Utf8Decoder {
+    /// 返回已经完整的字符，无效的字节会被替换成 U+FFFD
+    pub fn push(&mut self, bytes: &[u8]) -> String {
+        self.pending.extend_from_slice(bytes);
+        match std::str::from_utf8(&self.pending) {
+            Ok(text) => {
+                let text = text.to_owned();
+                self.pending.clear();
+                text
+            }
+            // 结尾的字符不完整，等待后续的字节
+            Err(error) if error.error_len().is_none() => {
+                let valid = error.valid_up_to();
+                let text = String::from_utf8_lossy(&self.pending[..valid]).into_owned();
+                self.pending.drain(..valid);
+                text
+            }
+            Err(_) => {
+                let text = String::from_utf8_lossy(&self.pending).into_owned();
+                self.pending.clear();
+                text
+            }
+        }
+    }
+
+    /// 生成结束时，输出剩余的字节
+    pub fn finish(&mut self) -> String {
+        let text = String::from_utf8_lossy(&self.pending).into_owned();
+        self.pending.clear();
+        text
+    }
+}
+
+/// 匹配停止字符串，可能是停止字符串开头的文本会被保留，直到确认不是停止字符串为止
+pub struct StopMatcher {
+    stops: Vec<String>,
+    buffer: String,
+    stopped: bool,
+}
+
+impl StopMatcher {
+    #[must_use]
+    pub fn new(stops: Vec<String>) -> Self {
+        Self {
+            stops: stops.into_iter().filter(|stop| !stop.is_empty()).collect(),
+            buffer: String::new(),
+            stopped: false,
+        }
+    }
+
+    /// 返回可以输出的文本，以及遇到的停止字符串
+    pub fn push(&mut self, text: &str) -> (String, Option<String>) {
+        if self.stopped {
+            return (String::new(), None);
+        }
+        self.buffer.push_str(text);
+        if let Some((index, stop)) = self
+            .stops
+            .iter()
+            .filter_map(|stop| Some((self.buffer.find(stop.as_str())?, stop)))
+            .min()
+        {
+            self.stopped = true;
+            let stop = stop.clone();
+            let text = self.buffer[..index].to_owned();
+            self.buffer.clear();
+            return (text, Some(stop));
+        }
+        // 保留结尾中可能是某个停止字符串开头的部分
+        let keep = self
+            .stops
+            .iter()
+            .map(|stop| partial_suffix_len(&self.buffer, stop))
+            .max()
+            .unwrap_or_default();
+        let text = self.buffer[..self.buffer.len() - keep].to_owned();
+        self.buffer.drain(..self.buffer.len() - keep);
+        (text, None)
+    }
+
+    #[must_use]
+    pub fn stopped(&self) -> bool {
+        self.stopped
+    }
+
+    /// 生成结束时，输出保留的文本
+    pub fn finish(&mut self) -> String {
+        std::mem::take(&mut self.buffer)
+    }
+}
+
+// text 的结尾和 stop 的开头重合的最大长度
+pub(crate) fn partial_suffix_len(text: &str, stop: &str) -> usize {
+    (1..stop.len().min(text.len() + 1))
+        .rev()
+        .filter(|&len| stop.is_char_boundary(len) && text.is_char_boundary(text.len() - len))
+        .find(|&len| text.ends_with(&stop[..len]))
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stop_matcher() {
+        let mut matcher = StopMatcher::new(vec!["</s>".to_owned(), "\n\n".to_owned()]);
+        assert_eq!(matcher.push("Hello"), ("Hello".to_owned(), None));
+        assert_eq!(matcher.push(" world<"), (" world".to_owned(), None));
+        assert_eq!(matcher.push("b>"), ("<b>".to_owned(), None));
+        assert_eq!(matcher.push("\n"), (String::new(), None));
+        assert_eq!(
+            matcher.push("\nnext"),
+            (String::new(), Some("\n\n".to_owned()))
+        );
+        assert!(matcher.stopped());
+        assert_eq!(matcher.push("more"), (String::new(), None));
+
+        let mut matcher = StopMatcher::new(vec!["。结束".to_owned()]);
+        assert_eq!(matcher.push("你好。"), ("你好".to_owned(), None));
+        assert_eq!(matcher.finish(), "。");
+
+        let mut matcher = StopMatcher::new(Vec::new());
+        assert_eq!(matcher.push("text"), ("text".to_owned(), None));
+    }
+
+    #[test]
+    fn test_context_shift_keeps_prefix() {
+        // 开头的 6 个 token 是系统提示词，多次移动之后仍然保留在前缀缓存的开头
+        let system = (0..6).map(Token::new).collect::<Vec<_>>();
+        let mut prefix_cache = PrefixCache::new();
+        prefix_cache.extend(0, &system);
+        prefix_cache.extend(0, &(100..120).map(Token::new).collect::<Vec<_>>());
+        let shift = ContextShift::Discard { n_keep: 6 };
+        let mut n_past = 26;
+        assert_eq!(shift.discard_range(n_past), Some(6..16));
+        for _ in 0..3 {
+            let range = shift.discard_range(n_past).unwrap();
+            prefix_cache.discard(0, range.start as usize..range.end as usize);
+            n_past -= range.end - range.start;
+            assert_eq!(&prefix_cache.tokens(0)[..6], system.as_slice());
+            assert_eq!(prefix_cache.tokens(0).len(), n_past as usize);
+        }
+        // 最近的 token 没有被丢弃
+        assert_eq!(prefix_cache.tokens(0).last(), Some(&Token::new(119)));
+
+        // 只剩下保留的部分时无法继续移动
+        assert_eq!(shift.discard_range(7), None);
+        assert_eq!(shift.discard_range(3), None);
+        assert_eq!(ContextShift::Disabled.discard_range(26), None);
+    }
+
+    #[test]
+    fn test_utf8_decoder() {
+        let mut decoder = Utf8Decoder::default();
+        let bytes = "你好".as_bytes();
+        assert_eq!(decoder.push(&bytes[..2]), "");
+        assert_eq!(decoder.push(&bytes[2..4]), "你");
+        assert_eq!(decoder.push(&bytes[4..]), "好");
+        assert_eq!(decoder.push(&[0xe4]), "");
+        assert_eq!(decoder.finish(), "\u{fffd}");
+    }
+}
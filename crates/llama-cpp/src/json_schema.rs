@@ -0,0 +1,687 @@
+//! 将 JSON Schema 转换成 GBNF 语法，用来约束模型输出的 JSON
+//!
+//! 支持 type、properties/required、additionalProperties、enum、const、items/prefixItems、minItems/maxItems、
+//! minLength/maxLength、$ref/$defs、format（date、time、date-time、uuid）以及 oneOf/anyOf/allOf，其它关键字会被忽略。
+//! 对象的属性按照 properties 中的顺序输出，没有 additionalProperties 时不允许其它属性
+
+use serde_json::{Map, Value, json};
+use snafu::prelude::*;
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+#[derive(Debug, Snafu)]
+#[snafu(visibility(pub(crate)))]
+pub enum JsonSchemaError {
+    #[snafu(display("Only local references are supported, but got {reference}"))]
+    RemoteRef { reference: String },
+    #[snafu(display("The reference {reference} can't be resolved"))]
+    UnresolvedRef { reference: String },
+    #[snafu(display("Unsupported type {kind} in the JSON schema"))]
+    UnsupportedType { kind: String },
+    #[snafu(display("Invalid JSON schema at {name}, {reason}"))]
+    InvalidSchema { name: String, reason: String },
+}
+
+// 空白，和 llama.cpp 一样限制长度，避免模型一直输出空白
+const SPACE_RULE: &str = r#"| " " | "\n"{1,2} [ \t]{0,20}"#;
+
+// 基础规则的名字、内容和依赖的其它基础规则
+const PRIMITIVE_RULES: &[(&str, &str, &[&str])] = &[
+    ("boolean", r#"("true" | "false") space"#, &[]),
+    ("decimal-part", "[0-9]{1,16}", &[]),
+    ("integral-part", "[0] | [1-9] [0-9]{0,15}", &[]),
+    (
+        "number",
+        r#"("-"? integral-part) ("." decimal-part)? ([eE] [-+]? integral-part)? space"#,
+        &["integral-part", "decimal-part"],
+    ),
+    (
+        "integer",
+        r#"("-"? integral-part) space"#,
+        &["integral-part"],
+    ),
+    (
+        "value",
+        "object | array | string | number | boolean | null",
+        &["object", "array", "string", "number", "boolean", "null"],
+    ),
+    (
+        "object",
+        r#""{" space ( string ":" space value ("," space string ":" space value)* )? "}" space"#,
+        &["string", "value"],
+    ),
+    (
+        "array",
+        r#""[" space ( value ("," space value)* )? "]" space"#,
+        &["value"],
+    ),
+    (
+        "uuid",
+        r#""\"" [0-9a-fA-F]{8} "-" [0-9a-fA-F]{4} "-" [0-9a-fA-F]{4} "-" [0-9a-fA-F]{4} "-" [0-9a-fA-F]{12} "\"" space"#,
+        &[],
+    ),
+    (
+        "char",
+        r#"[^"\\\x7F\x00-\x1F] | [\\] (["\\bfnrt] | "u" [0-9a-fA-F]{4})"#,
+        &[],
+    ),
+    ("string", r#""\"" char* "\"" space"#, &["char"]),
+    ("null", r#""null" space"#, &[]),
+    (
+        "date",
+        r#"[0-9]{4} "-" ( "0" [1-9] | "1" [0-2] ) "-" ( "0" [1-9] | [1-2] [0-9] | "3" [0-1] )"#,
+        &[],
+    ),
+    (
+        "time",
+        r#"([01] [0-9] | "2" [0-3]) ":" [0-5] [0-9] ":" [0-5] [0-9] ( "." [0-9]{3} )? ( "Z" | ( "+" | "-" ) ( [01] [0-9] | "2" [0-3] ) ":" [0-5] [0-9] )"#,
+        &[],
+    ),
+    ("date-time", r#"date "T" time"#, &["date", "time"]),
+    ("date-string", r#""\"" date "\"" space"#, &["date"]),
+    ("time-string", r#""\"" time "\"" space"#, &["time"]),
+    (
+        "date-time-string",
+        r#""\"" date-time "\"" space"#,
+        &["date-time"],
+    ),
+];
+
+/// 任意 JSON 对象的语法，根规则为 root
+pub fn json_grammar() -> String {
+    json_schema_to_grammar(&json!({ "type": "object" }))
+        .expect("The JSON schema of any object is valid")
+}
+
+/// 将 JSON Schema 转换成 GBNF 语法，根规则为 root
+pub fn json_schema_to_grammar(schema: &Value) -> Result<String, JsonSchemaError> {
+    let mut converter = JsonSchemaConverter::new();
+    converter.add_schema("root", schema)?;
+    Ok(converter.grammar())
+}
+
+/// JSON Schema 到 GBNF 的转换器，可以将多个 schema 和手写的规则组合成一个语法
+#[derive(Debug)]
+pub struct JsonSchemaConverter {
+    rules: BTreeMap<String, String>,
+    // 正在转换的 schema，用来解析 $ref
+    root: Value,
+    // $ref 对应的规则名，递归引用时直接使用规则名
+    refs: HashMap<String, String>,
+}
+
+impl Default for JsonSchemaConverter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl JsonSchemaConverter {
+    pub fn new() -> Self {
+        Self {
+            rules: BTreeMap::from([("space".to_owned(), SPACE_RULE.to_owned())]),
+            root: Value::Null,
+            refs: HashMap::new(),
+        }
+    }
+
+    /// 转换一个 schema，返回对应的规则名，name 已经被其它规则使用时会在后面加上数字
+    pub fn add_schema(&mut self, name: &str, schema: &Value) -> Result<String, JsonSchemaError> {
+        self.root = schema.clone();
+        self.refs.clear();
+        self.visit(schema, name)
+    }
+
+    /// 添加一条规则，返回实际使用的规则名
+    pub fn add_rule(&mut self, name: &str, rule: &str) -> String {
+        let name = sanitize_name(name);
+        let mut key = name.clone();
+        let mut index = 0;
+        while self
+            .rules
+            .get(&key)
+            .is_some_and(|existing| existing != rule)
+        {
+            index += 1;
+            key = format!("{name}{index}");
+        }
+        self.rules.insert(key.clone(), rule.to_owned());
+        key
+    }
+
+    /// 添加基础规则及其依赖，例如 string、number、value
+    pub fn add_primitive(&mut self, name: &str) -> String {
+        if let Some((_, rule, deps)) = PRIMITIVE_RULES.iter().find(|(key, ..)| *key == name) {
+            self.rules.insert(name.to_owned(), (*rule).to_owned());
+            for dep in *deps {
+                if !self.rules.contains_key(*dep) {
+                    self.add_primitive(dep);
+                }
+            }
+        }
+        name.to_owned()
+    }
+
+    /// 全部的规则组成的语法
+    pub fn grammar(&self) -> String {
+        self.rules
+            .iter()
+            .map(|(name, rule)| format!("{name} ::= {rule}\n"))
+            .collect()
+    }
+
+    fn visit(&mut self, schema: &Value, name: &str) -> Result<String, JsonSchemaError> {
+        let name = if is_reserved(name) {
+            format!("{name}-")
+        } else {
+            name.to_owned()
+        };
+        let schema = match schema {
+            Value::Bool(true) => return Ok(self.alias(&name, "value")),
+            Value::Object(schema) => schema,
+            _ => {
+                return InvalidSchemaSnafu {
+                    name,
+                    reason: "the schema should be an object or true",
+                }
+                .fail();
+            }
+        };
+        if let Some(reference) = schema.get("$ref").and_then(Value::as_str) {
+            let rule = self.resolve_ref(reference)?;
+            return Ok(self.add_rule(&name, &rule));
+        }
+        if let Some(alternatives) = schema
+            .get("oneOf")
+            .or_else(|| schema.get("anyOf"))
+            .and_then(Value::as_array)
+        {
+            let rules = alternatives
+                .iter()
+                .enumerate()
+                .map(|(index, alternative)| self.visit(alternative, &format!("{name}-{index}")))
+                .collect::<Result<Vec<_>, _>>()?;
+            return Ok(self.add_rule(&name, &rules.join(" | ")));
+        }
+        if let Some(all) = schema.get("allOf").and_then(Value::as_array) {
+            let merged = self.merge_all_of(all)?;
+            return self.visit(&merged, &name);
+        }
+        if let Some(value) = schema.get("const") {
+            return Ok(self.add_rule(&name, &format!("{} space", json_literal(value))));
+        }
+        if let Some(values) = schema.get("enum").and_then(Value::as_array) {
+            let literals = values.iter().map(json_literal).collect::<Vec<_>>();
+            return Ok(self.add_rule(&name, &format!("({}) space", literals.join(" | "))));
+        }
+        match schema.get("type") {
+            Some(Value::Array(kinds)) => {
+                let rules = kinds
+                    .iter()
+                    .map(|kind| {
+                        let mut schema = schema.clone();
+                        schema.insert("type".to_owned(), kind.clone());
+                        let kind = kind.as_str().unwrap_or_default();
+                        self.visit(&Value::Object(schema), &format!("{name}-{kind}"))
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok(self.add_rule(&name, &rules.join(" | ")))
+            }
+            Some(Value::String(kind)) => self.visit_type(schema, kind, &name),
+            Some(kind) => UnsupportedTypeSnafu {
+                kind: kind.to_string(),
+            }
+            .fail(),
+            // 没有 type 时按照其它关键字推断
+            None if schema.contains_key("properties")
+                || schema.contains_key("additionalProperties") =>
+            {
+                self.visit_type(schema, "object", &name)
+            }
+            None if schema.contains_key("items") || schema.contains_key("prefixItems") => {
+                self.visit_type(schema, "array", &name)
+            }
+            None => Ok(self.alias(&name, "value")),
+        }
+    }
+
+    fn visit_type(
+        &mut self,
+        schema: &Map<String, Value>,
+        kind: &str,
+        name: &str,
+    ) -> Result<String, JsonSchemaError> {
+        match kind {
+            "object" => self.visit_object(schema, name),
+            "array" => self.visit_array(schema, name),
+            "string" => self.visit_string(schema, name),
+            "number" | "integer" | "boolean" | "null" => Ok(self.alias(name, kind)),
+            kind => UnsupportedTypeSnafu { kind }.fail(),
+        }
+    }
+
+    fn visit_object(
+        &mut self,
+        schema: &Map<String, Value>,
+        name: &str,
+    ) -> Result<String, JsonSchemaError> {
+        let properties = schema
+            .get("properties")
+            .and_then(Value::as_object)
+            .cloned()
+            .unwrap_or_default();
+        let additional = schema.get("additionalProperties");
+        // 没有定义属性时可以是任意的对象
+        if properties.is_empty() && additional.is_none_or(|additional| additional == true) {
+            return Ok(self.alias(name, "object"));
+        }
+        let required: HashSet<&str> = schema
+            .get("required")
+            .and_then(Value::as_array)
+            .map(|required| required.iter().filter_map(Value::as_str).collect())
+            .unwrap_or_default();
+        let mut required_kvs = Vec::new();
+        let mut optional_kvs = Vec::new();
+        for (key, property) in &properties {
+            let value = self.visit(property, &format!("{name}-{key}"))?;
+            let kv = self.add_rule(
+                &format!("{name}-{key}-kv"),
+                &format!("{} space \":\" space {value}", json_literal(&json!(key))),
+            );
+            if required.contains(key.as_str()) {
+                required_kvs.push(kv);
+            } else {
+                optional_kvs.push((key.clone(), kv));
+            }
+        }
+        // 其它属性放在定义的属性之后，可以出现任意次
+        if let Some(additional) = additional.filter(|additional| *additional != false) {
+            let value = self.visit(additional, &format!("{name}-additional-value"))?;
+            self.add_primitive("string");
+            let kv = self.add_rule(
+                &format!("{name}-additional-kv"),
+                &format!("string \":\" space {value}"),
+            );
+            optional_kvs.push(("*".to_owned(), kv));
+        }
+        let mut rule = String::from("\"{\" space ");
+        rule += &required_kvs.join(" \",\" space ");
+        if !optional_kvs.is_empty() {
+            let alternatives = (0..optional_kvs.len())
+                .map(|index| self.optional_kvs(name, &optional_kvs[index..], false))
+                .collect::<Vec<_>>()
+                .join(" | ");
+            if required_kvs.is_empty() {
+                rule += &format!("( {alternatives} )?");
+            } else {
+                rule += &format!(" ( \",\" space ( {alternatives} ) )?");
+            }
+        }
+        rule += " \"}\" space";
+        Ok(self.add_rule(name, &rule))
+    }
+
+    // 可选的属性按顺序出现，每个属性都可以省略，键为 * 的是其它属性，可以出现任意次
+    fn optional_kvs(
+        &mut self,
+        name: &str,
+        kvs: &[(String, String)],
+        first_is_optional: bool,
+    ) -> String {
+        let (key, kv) = &kvs[0];
+        let comma_kv = format!("( \",\" space {kv} )");
+        let mut rule = match (first_is_optional, key == "*") {
+            (true, true) => format!("{comma_kv}*"),
+            (true, false) => format!("{comma_kv}?"),
+            (false, true) => format!("{kv} {comma_kv}*"),
+            (false, false) => kv.clone(),
+        };
+        if kvs.len() > 1 {
+            let rest = self.optional_kvs(name, &kvs[1..], true);
+            let key = if key == "*" { "additional" } else { key };
+            rule += " ";
+            rule += &self.add_rule(&format!("{name}-{key}-rest"), &rest);
+        }
+        rule
+    }
+
+    fn visit_array(
+        &mut self,
+        schema: &Map<String, Value>,
+        name: &str,
+    ) -> Result<String, JsonSchemaError> {
+        // prefixItems 为元组，每个位置的类型不同
+        if let Some(items) = schema.get("prefixItems").and_then(Value::as_array) {
+            let items = items
+                .iter()
+                .enumerate()
+                .map(|(index, item)| self.visit(item, &format!("{name}-tuple-{index}")))
+                .collect::<Result<Vec<_>, _>>()?;
+            let rule = format!("\"[\" space {} \"]\" space", items.join(" \",\" space "));
+            return Ok(self.add_rule(name, &rule));
+        }
+        let item = match schema.get("items") {
+            Some(items) => self.visit(items, &format!("{name}-item"))?,
+            None => self.add_primitive("value"),
+        };
+        let min_items = schema
+            .get("minItems")
+            .and_then(Value::as_u64)
+            .unwrap_or_default();
+        let max_items = schema.get("maxItems").and_then(Value::as_u64);
+        ensure!(
+            max_items.is_none_or(|max_items| min_items <= max_items),
+            InvalidSchemaSnafu {
+                name,
+                reason: format!("minItems({min_items}) is greater than maxItems")
+            }
+        );
+        let items = repetition(&item, min_items, max_items, "\",\" space");
+        Ok(self.add_rule(name, &format!("\"[\" space {items} \"]\" space")))
+    }
+
+    fn visit_string(
+        &mut self,
+        schema: &Map<String, Value>,
+        name: &str,
+    ) -> Result<String, JsonSchemaError> {
+        match schema.get("format").and_then(Value::as_str) {
+            Some("uuid") => return Ok(self.alias(name, "uuid")),
+            Some(format @ ("date" | "time" | "date-time")) => {
+                return Ok(self.alias(name, &format!("{format}-string")));
+            }
+            _ => {}
+        }
+        let min_length = schema.get("minLength").and_then(Value::as_u64);
+        let max_length = schema.get("maxLength").and_then(Value::as_u64);
+        if min_length.is_none() && max_length.is_none() {
+            return Ok(self.alias(name, "string"));
+        }
+        let min_length = min_length.unwrap_or_default();
+        ensure!(
+            max_length.is_none_or(|max_length| min_length <= max_length),
+            InvalidSchemaSnafu {
+                name,
+                reason: format!("minLength({min_length}) is greater than maxLength")
+            }
+        );
+        let chars = repetition(&self.add_primitive("char"), min_length, max_length, "");
+        Ok(self.add_rule(name, &format!("\"\\\"\" {chars} \"\\\"\" space")))
+    }
+
+    // 规则的内容是一个基础规则
+    fn alias(&mut self, name: &str, primitive: &str) -> String {
+        let primitive = self.add_primitive(primitive);
+        self.add_rule(name, &primitive)
+    }
+
+    // 只支持 schema 内部的引用，例如 #/$defs/Name、#/definitions/Name
+    fn resolve_ref(&mut self, reference: &str) -> Result<String, JsonSchemaError> {
+        if let Some(rule) = self.refs.get(reference) {
+            return Ok(rule.clone());
+        }
+        let pointer = reference
+            .strip_prefix('#')
+            .context(RemoteRefSnafu { reference })?;
+        let target = self
+            .root
+            .pointer(pointer)
+            .cloned()
+            .context(UnresolvedRefSnafu { reference })?;
+        let mut name = sanitize_name(pointer.rsplit('/').next().unwrap_or_default());
+        if name.is_empty() {
+            name = "ref".to_owned();
+        }
+        if is_reserved(&name) {
+            name.push('-');
+        }
+        // 先占用规则名，递归的引用直接使用这个规则名
+        let mut rule = name.clone();
+        let mut index = 0;
+        while self.rules.contains_key(&rule) || self.refs.values().any(|used| *used == rule) {
+            index += 1;
+            rule = format!("{name}{index}");
+        }
+        self.refs.insert(reference.to_owned(), rule.clone());
+        self.visit(&target, &rule)
+    }
+
+    // 合并 allOf 中的对象，属性和必需的属性取并集
+    fn merge_all_of(&self, all: &[Value]) -> Result<Value, JsonSchemaError> {
+        let mut properties = Map::new();
+        let mut required = Vec::new();
+        for schema in all {
+            let schema = match schema.get("$ref").and_then(Value::as_str) {
+                Some(reference) => {
+                    let pointer = reference
+                        .strip_prefix('#')
+                        .context(RemoteRefSnafu { reference })?;
+                    self.root
+                        .pointer(pointer)
+                        .context(UnresolvedRefSnafu { reference })?
+                }
+                None => schema,
+            };
+            if let Some(map) = schema.get("properties").and_then(Value::as_object) {
+                properties.extend(map.clone());
+            }
+            if let Some(names) = schema.get("required").and_then(Value::as_array) {
+                required.extend(names.iter().cloned());
+            }
+        }
+        Ok(json!({ "type": "object", "properties": properties, "required": required }))
+    }
+}
+
+// 重复 min 到 max 次，max 为 None 时不限制次数，每两项之间使用 separator 分隔
+fn repetition(item: &str, min: u64, max: Option<u64>, separator: &str) -> String {
+    if max == Some(0) {
+        return String::new();
+    }
+    if separator.is_empty() {
+        return match (min, max) {
+            (0, Some(1)) => format!("{item}?"),
+            (0, None) => format!("{item}*"),
+            (1, None) => format!("{item}+"),
+            (min, None) => format!("{item}{{{min},}}"),
+            (min, Some(max)) if min == max => format!("{item}{{{min}}}"),
+            (min, Some(max)) => format!("{item}{{{min},{max}}}"),
+        };
+    }
+    let rest = repetition(
+        &format!("( {separator} {item} )"),
+        min.saturating_sub(1),
+        max.map(|max| max - 1),
+        "",
+    );
+    let items = if rest.is_empty() {
+        item.to_owned()
+    } else {
+        format!("{item} {rest}")
+    };
+    if min == 0 {
+        format!("( {items} )?")
+    } else {
+        items
+    }
+}
+
+// 基础规则的名字，schema 中同名的规则需要改名
+fn is_reserved(name: &str) -> bool {
+    name == "space" || PRIMITIVE_RULES.iter().any(|(key, ..)| *key == name)
+}
+
+// 规则名只能包含字母、数字和 -
+fn sanitize_name(name: &str) -> String {
+    name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+        .collect()
+}
+
+// JSON 值序列化之后的字面量
+fn json_literal(value: &Value) -> String {
+    gbnf_literal(&value.to_string())
+}
+
+/// GBNF 中的字符串字面量
+pub(crate) fn gbnf_literal(text: &str) -> String {
+    let mut literal = String::with_capacity(text.len() + 2);
+    literal.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => literal.push_str("\\\""),
+            '\\' => literal.push_str("\\\\"),
+            '\n' => literal.push_str("\\n"),
+            '\r' => literal.push_str("\\r"),
+            '\t' => literal.push_str("\\t"),
+            c => literal.push(c),
+        }
+    }
+    literal.push('"');
+    literal
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 语法中的一条规则
+    fn rule<'a>(grammar: &'a str, name: &str) -> &'a str {
+        grammar
+            .lines()
+            .find_map(|line| line.strip_prefix(&format!("{name} ::= ")))
+            .unwrap_or_else(|| panic!("The rule {name} is missing in\n{grammar}"))
+    }
+
+    #[test]
+    fn test_object_properties() {
+        let grammar = json_schema_to_grammar(&json!({
+            "type": "object",
+            "properties": {
+                "name": { "type": "string" },
+                "age": { "type": "integer" },
+                "email": { "type": "string", "format": "email" },
+            },
+            "required": ["name"],
+        }))
+        .unwrap();
+        assert_eq!(
+            rule(&grammar, "root"),
+            r#""{" space root-name-kv ( "," space ( root-age-kv root-age-rest | root-email-kv ) )? "}" space"#
+        );
+        assert_eq!(
+            rule(&grammar, "root-name-kv"),
+            r#""\"name\"" space ":" space root-name"#
+        );
+        assert_eq!(
+            rule(&grammar, "root-age-rest"),
+            r#"( "," space root-email-kv )?"#
+        );
+        assert_eq!(rule(&grammar, "root-email"), "string");
+        assert_eq!(rule(&grammar, "root-age"), "integer");
+        assert!(grammar.contains("integral-part ::= "));
+    }
+
+    #[test]
+    fn test_enum_const_and_formats() {
+        let grammar = json_schema_to_grammar(&json!({
+            "type": "object",
+            "properties": {
+                "color": { "enum": ["red", "green", 1] },
+                "kind": { "const": "point" },
+                "at": { "type": "string", "format": "date-time" },
+                "id": { "type": "string", "format": "uuid" },
+                "code": { "type": "string", "minLength": 2, "maxLength": 4 },
+            },
+            "required": ["color", "kind", "at", "id", "code"],
+        }))
+        .unwrap();
+        assert_eq!(
+            rule(&grammar, "root-color"),
+            r#"("\"red\"" | "\"green\"" | "1") space"#
+        );
+        assert_eq!(rule(&grammar, "root-kind"), r#""\"point\"" space"#);
+        assert_eq!(rule(&grammar, "root-at"), "date-time-string");
+        assert_eq!(rule(&grammar, "root-id"), "uuid");
+        assert_eq!(rule(&grammar, "root-code"), r#""\"" char{2,4} "\"" space"#);
+        assert!(grammar.contains("date ::= "));
+        assert!(grammar.contains("time ::= "));
+    }
+
+    #[test]
+    fn test_arrays() {
+        let grammar = json_schema_to_grammar(&json!({
+            "type": "array",
+            "items": { "type": "number" },
+            "minItems": 1,
+            "maxItems": 3,
+        }))
+        .unwrap();
+        assert_eq!(
+            rule(&grammar, "root"),
+            r#""[" space root-item ( "," space root-item ){0,2} "]" space"#
+        );
+        let grammar = json_schema_to_grammar(&json!({ "items": { "type": "string" } })).unwrap();
+        assert_eq!(
+            rule(&grammar, "root"),
+            r#""[" space ( root-item ( "," space root-item )* )? "]" space"#
+        );
+        let grammar = json_schema_to_grammar(&json!({
+            "prefixItems": [{ "type": "string" }, { "type": "boolean" }],
+        }))
+        .unwrap();
+        assert_eq!(
+            rule(&grammar, "root"),
+            r#""[" space root-tuple-0 "," space root-tuple-1 "]" space"#
+        );
+        assert!(
+            json_schema_to_grammar(&json!({ "type": "array", "minItems": 2, "maxItems": 1 }))
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_refs_and_alternatives() {
+        let grammar = json_schema_to_grammar(&json!({
+            "$defs": {
+                "node": {
+                    "type": "object",
+                    "properties": {
+                        "value": { "anyOf": [{ "type": "string" }, { "type": "null" }] },
+                        "children": { "type": "array", "items": { "$ref": "#/$defs/node" } },
+                    },
+                    "required": ["value", "children"],
+                },
+            },
+            "$ref": "#/$defs/node",
+        }))
+        .unwrap();
+        assert_eq!(rule(&grammar, "root"), "node");
+        assert_eq!(rule(&grammar, "node-value"), "node-value-0 | node-value-1");
+        assert_eq!(rule(&grammar, "node-value-1"), "null");
+        assert_eq!(rule(&grammar, "node-children-item"), "node");
+        assert!(matches!(
+            json_schema_to_grammar(&json!({ "$ref": "#/$defs/missing" })),
+            Err(JsonSchemaError::UnresolvedRef { .. })
+        ));
+        assert!(matches!(
+            json_schema_to_grammar(&json!({ "$ref": "https://example.com/schema.json" })),
+            Err(JsonSchemaError::RemoteRef { .. })
+        ));
+    }
+
+    #[test]
+    fn test_additional_properties_and_generic_json() {
+        let grammar = json_schema_to_grammar(&json!({
+            "type": "object",
+            "additionalProperties": { "type": "integer" },
+        }))
+        .unwrap();
+        assert_eq!(
+            rule(&grammar, "root"),
+            r#""{" space ( root-additional-kv ( "," space root-additional-kv )* )? "}" space"#
+        );
+        let grammar = json_grammar();
+        assert_eq!(rule(&grammar, "root"), "object");
+        assert!(grammar.contains("value ::= "));
+    }
+}
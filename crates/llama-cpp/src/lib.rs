@@ -1,9 +1,12 @@
 use crate::{
     batch::BatchError,
-    context::ContextError,
+    context::{ContextError, StateError},
+    generator::GeneratorError,
     ggml_numa::StrategyError as GgmlNumaStrategyError,
+    json_schema::JsonSchemaError,
     model::{ModelError, TemplateError},
     runtime::RuntimeError,
+    scheduler::SchedulerError,
     token::TokenError,
     vocabulary::VocabularyTypeError,
 };
@@ -11,10 +14,14 @@ use snafu::Snafu;
 
 pub mod batch;
 pub mod context;
+pub mod generator;
 pub mod ggml_numa;
+pub mod json_schema;
 pub mod model;
+pub mod prefix_cache;
 pub mod runtime;
 pub mod sampler;
+pub mod scheduler;
 pub mod token;
 pub mod utils;
 pub mod vocabulary;
@@ -39,6 +46,14 @@ pub enum Error {
     VocabType { source: VocabularyTypeError },
     #[snafu(transparent)]
     Context { source: ContextError },
+    #[snafu(transparent)]
+    State { source: StateError },
+    #[snafu(transparent)]
+    Generator { source: GeneratorError },
+    #[snafu(transparent)]
+    Scheduler { source: SchedulerError },
+    #[snafu(transparent)]
+    JsonSchema { source: JsonSchemaError },
     #[snafu(whatever, display("{message}"))]
     GenericError {
         message: String,
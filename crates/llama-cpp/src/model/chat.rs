@@ -1,3 +1,4 @@
+use super::ToolCall;
 use snafu::prelude::*;
 use std::{
     ffi::{CStr, CString},
@@ -31,6 +32,12 @@ pub enum TemplateError {
         from: usize,
         source: TryFromIntError,
     },
+    #[snafu(display("Failed to compile the Jinja template"))]
+    JinjaSyntax { source: minijinja::Error },
+    #[snafu(display("Failed to render the Jinja template"))]
+    JinjaRender { source: minijinja::Error },
+    #[snafu(display("The message cannot be passed to llama.cpp"))]
+    TemplateMessage { source: MessageError },
 }
 
 impl Template {
@@ -78,10 +85,16 @@ impl DerefMut for Template {
 }
 
 /// `llama_chat_message` 包装
+///
+/// 工具调用相关的字段只有 Jinja 模板可以使用，llama.cpp 内置的模板会忽略
 #[derive(Debug, Eq, PartialEq, Clone)]
 pub struct Message {
     pub role: CString,
     pub content: CString,
+    // 模型的回答中调用的工具
+    pub tool_calls: Vec<ToolCall>,
+    // 角色为 tool 时，对应的工具调用的 id
+    pub tool_call_id: Option<String>,
 }
 
 #[derive(Debug, Snafu)]
@@ -110,12 +123,24 @@ impl Message {
                 from: content.to_owned(),
                 role: "content".to_owned(),
             })?,
+            tool_calls: Vec::new(),
+            tool_call_id: None,
         })
     }
+
+    pub fn with_tool_calls(mut self, tool_calls: Vec<ToolCall>) -> Self {
+        self.tool_calls = tool_calls;
+        self
+    }
+
+    pub fn with_tool_call_id(mut self, tool_call_id: impl Into<String>) -> Self {
+        self.tool_call_id = Some(tool_call_id.into());
+        self
+    }
 }
 
 impl From<Message> for llama_cpp_sys::llama_chat_message {
-    fn from(Message { role, content }: Message) -> Self {
+    fn from(Message { role, content, .. }: Message) -> Self {
         Self {
             role: role.as_ptr(),
             content: content.as_ptr(),
@@ -124,7 +149,7 @@ impl From<Message> for llama_cpp_sys::llama_chat_message {
 }
 
 impl From<&Message> for llama_cpp_sys::llama_chat_message {
-    fn from(Message { role, content }: &Message) -> Self {
+    fn from(Message { role, content, .. }: &Message) -> Self {
         Self {
             role: role.as_ptr(),
             content: content.as_ptr(),
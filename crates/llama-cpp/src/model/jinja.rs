@@ -0,0 +1,467 @@
+use super::{
+    JinjaRenderSnafu, JinjaSyntaxSnafu, Message, Template, TemplateError, TemplateMessageSnafu,
+    ToolCall,
+};
+use minijinja::{Environment, Error as JinjaError, ErrorKind, Value as JinjaValue, value::Kwargs};
+use serde::Serialize;
+use serde_json::{Map, Value, json, ser::PrettyFormatter};
+use snafu::prelude::*;
+use std::{
+    io,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+// 模板在 Environment 中的名字，没有扩展名，不会自动转义 HTML
+const TEMPLATE_NAME: &str = "chat_template";
+
+/// 使用 Jinja 渲染的对话模板
+///
+/// `llama_chat_apply_template` 只能识别 llama.cpp 内置的几种模板，这里按照 transformers 的方式渲染 GGUF 中的
+/// `tokenizer.chat_template`，可以传入工具定义、`enable_thinking` 等变量
+#[derive(Debug)]
+pub struct JinjaTemplate {
+    // 原始的模板，渲染失败时交给 llama.cpp 处理
+    source: Template,
+    env: Environment<'static>,
+}
+
+impl JinjaTemplate {
+    pub fn new(source: impl AsRef<str>) -> Result<Self, TemplateError> {
+        let source = source.as_ref();
+        let mut env = Environment::new();
+        // 和 transformers 中的设置保持一致
+        env.set_trim_blocks(true);
+        env.set_lstrip_blocks(true);
+        // 支持 Python 字符串、字典和列表的方法，例如 strip、startswith、items
+        env.set_unknown_method_callback(minijinja_contrib::pycompat::unknown_method_callback);
+        env.add_function("raise_exception", raise_exception);
+        env.add_function("strftime_now", strftime_now);
+        env.add_filter("tojson", tojson);
+        env.add_template_owned(TEMPLATE_NAME, source.to_owned())
+            .context(JinjaSyntaxSnafu)?;
+        Ok(Self {
+            source: Template::new(source)?,
+            env,
+        })
+    }
+
+    /// 原始的模板
+    pub fn source(&self) -> &Template {
+        &self.source
+    }
+
+    pub fn render(&self, inputs: &ChatTemplateInputs) -> Result<String, TemplateError> {
+        let template = self
+            .env
+            .get_template(TEMPLATE_NAME)
+            .context(JinjaSyntaxSnafu)?;
+        template
+            .render(JinjaValue::from_serialize(inputs.context()))
+            .context(JinjaRenderSnafu)
+    }
+}
+
+/// 渲染对话模板时传入的变量
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ChatTemplateInputs {
+    // 每一条消息都是一个对象，至少包含 role 和 content
+    messages: Vec<Value>,
+    add_generation_prompt: bool,
+    bos_token: Option<String>,
+    eos_token: Option<String>,
+    // 为 None 时模板中的 tools 未定义
+    tools: Option<Vec<Value>>,
+    // 其它的变量，例如 enable_thinking，和上面的变量同名时会被忽略
+    kwargs: Map<String, Value>,
+}
+
+impl ChatTemplateInputs {
+    pub fn new(messages: Vec<Value>) -> Self {
+        Self {
+            messages,
+            ..Self::default()
+        }
+    }
+
+    pub fn from_messages(messages: &[Message]) -> Self {
+        Self::new(
+            messages
+                .iter()
+                .map(|message| {
+                    let mut value = json!({
+                        "role": message.role.to_string_lossy(),
+                        "content": message.content.to_string_lossy(),
+                    });
+                    if !message.tool_calls.is_empty() {
+                        value["tool_calls"] = message
+                            .tool_calls
+                            .iter()
+                            .map(ToolCall::to_template_value)
+                            .collect();
+                    }
+                    if let Some(id) = &message.tool_call_id {
+                        value["tool_call_id"] = Value::String(id.clone());
+                    }
+                    value
+                })
+                .collect(),
+        )
+    }
+
+    /// 是否在末尾添加回答的开头
+    pub fn with_add_generation_prompt(mut self, add_generation_prompt: bool) -> Self {
+        self.add_generation_prompt = add_generation_prompt;
+        self
+    }
+
+    pub fn with_bos_token(mut self, bos_token: impl Into<String>) -> Self {
+        self.bos_token = Some(bos_token.into());
+        self
+    }
+
+    pub fn with_eos_token(mut self, eos_token: impl Into<String>) -> Self {
+        self.eos_token = Some(eos_token.into());
+        self
+    }
+
+    /// 工具的定义，一般为 OpenAI 格式的 `{"type": "function", "function": {...}}`
+    pub fn with_tools(mut self, tools: Vec<Value>) -> Self {
+        self.tools = Some(tools);
+        self
+    }
+
+    /// 设置一个额外的变量，例如 `enable_thinking`
+    pub fn with_kwarg(mut self, name: impl Into<String>, value: impl Into<Value>) -> Self {
+        self.kwargs.insert(name.into(), value.into());
+        self
+    }
+
+    pub fn messages(&self) -> &[Value] {
+        &self.messages
+    }
+
+    pub fn add_generation_prompt(&self) -> bool {
+        self.add_generation_prompt
+    }
+
+    pub fn bos_token(&self) -> Option<&str> {
+        self.bos_token.as_deref()
+    }
+
+    pub fn eos_token(&self) -> Option<&str> {
+        self.eos_token.as_deref()
+    }
+
+    /// 转换为 llama.cpp 中的消息，内容为数组时只保留其中的文本
+    pub fn to_messages(&self) -> Result<Vec<Message>, TemplateError> {
+        self.messages
+            .iter()
+            .map(|message| {
+                let role = message["role"].as_str().unwrap_or_default();
+                let content = match &message["content"] {
+                    Value::String(content) => content.clone(),
+                    Value::Array(parts) => parts
+                        .iter()
+                        .filter_map(|part| part["text"].as_str())
+                        .collect::<Vec<_>>()
+                        .join("\n"),
+                    _ => String::new(),
+                };
+                Message::try_new(role, content).context(TemplateMessageSnafu)
+            })
+            .collect()
+    }
+
+    fn context(&self) -> Map<String, Value> {
+        let mut context = self.kwargs.clone();
+        context.insert("messages".to_owned(), Value::Array(self.messages.clone()));
+        context.insert(
+            "add_generation_prompt".to_owned(),
+            self.add_generation_prompt.into(),
+        );
+        context.insert(
+            "bos_token".to_owned(),
+            self.bos_token.clone().unwrap_or_default().into(),
+        );
+        context.insert(
+            "eos_token".to_owned(),
+            self.eos_token.clone().unwrap_or_default().into(),
+        );
+        match &self.tools {
+            Some(tools) => context.insert("tools".to_owned(), Value::Array(tools.clone())),
+            None => context.remove("tools"),
+        };
+        context
+    }
+}
+
+// 模板中主动抛出的异常，例如消息的角色不符合要求
+fn raise_exception(message: String) -> Result<JinjaValue, JinjaError> {
+    Err(JinjaError::new(ErrorKind::InvalidOperation, message))
+}
+
+// 当前的 UTC 时间，一些模板会在系统提示词中写入日期
+fn strftime_now(format: String) -> String {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs() as i64)
+        .unwrap_or_default();
+    strftime(&format, secs)
+}
+
+// 和 Python 的 json.dumps 一样输出 JSON，默认在逗号和冒号之后添加空格，并且不转义非 ASCII 字符
+fn tojson(value: JinjaValue, kwargs: Kwargs) -> Result<JinjaValue, JinjaError> {
+    let indent = kwargs.get::<Option<usize>>("indent")?;
+    let ensure_ascii = kwargs.get::<Option<bool>>("ensure_ascii")?.unwrap_or(false);
+    let sort_keys = kwargs.get::<Option<bool>>("sort_keys")?.unwrap_or(false);
+    kwargs.assert_all_used()?;
+    let mut value = serde_json::to_value(&value)
+        .map_err(|e| JinjaError::new(ErrorKind::BadSerialization, e.to_string()))?;
+    if sort_keys {
+        sort_object_keys(&mut value);
+    }
+    let mut json = Vec::new();
+    let written = match indent {
+        Some(indent) => {
+            let indent = " ".repeat(indent);
+            let formatter = PrettyFormatter::with_indent(indent.as_bytes());
+            value.serialize(&mut serde_json::Serializer::with_formatter(
+                &mut json, formatter,
+            ))
+        }
+        None => value.serialize(&mut serde_json::Serializer::with_formatter(
+            &mut json,
+            PythonFormatter,
+        )),
+    };
+    written.map_err(|e| JinjaError::new(ErrorKind::BadSerialization, e.to_string()))?;
+    let json = String::from_utf8(json)
+        .map_err(|e| JinjaError::new(ErrorKind::BadSerialization, e.to_string()))?;
+    let json = if ensure_ascii {
+        escape_non_ascii(&json)
+    } else {
+        json
+    };
+    Ok(JinjaValue::from_safe_string(json))
+}
+
+// 紧凑格式的 JSON，分隔符为 ", " 和 ": "
+struct PythonFormatter;
+
+impl serde_json::ser::Formatter for PythonFormatter {
+    fn begin_array_value<W>(&mut self, writer: &mut W, first: bool) -> io::Result<()>
+    where
+        W: ?Sized + io::Write,
+    {
+        if first {
+            Ok(())
+        } else {
+            writer.write_all(b", ")
+        }
+    }
+
+    fn begin_object_key<W>(&mut self, writer: &mut W, first: bool) -> io::Result<()>
+    where
+        W: ?Sized + io::Write,
+    {
+        if first {
+            Ok(())
+        } else {
+            writer.write_all(b", ")
+        }
+    }
+
+    fn begin_object_value<W>(&mut self, writer: &mut W) -> io::Result<()>
+    where
+        W: ?Sized + io::Write,
+    {
+        writer.write_all(b": ")
+    }
+}
+
+fn sort_object_keys(value: &mut Value) {
+    match value {
+        Value::Object(map) => {
+            let mut entries = std::mem::take(map).into_iter().collect::<Vec<_>>();
+            entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+            for (key, mut value) in entries {
+                sort_object_keys(&mut value);
+                map.insert(key, value);
+            }
+        }
+        Value::Array(values) => values.iter_mut().for_each(sort_object_keys),
+        _ => {}
+    }
+}
+
+// 非 ASCII 字符只会出现在字符串中，直接转义为 \uXXXX，超出 BMP 的字符使用代理对
+fn escape_non_ascii(json: &str) -> String {
+    let mut escaped = String::with_capacity(json.len());
+    for c in json.chars() {
+        if c.is_ascii() {
+            escaped.push(c);
+        } else {
+            let mut units = [0_u16; 2];
+            for unit in c.encode_utf16(&mut units) {
+                escaped.push_str(&format!("\\u{unit:04x}"));
+            }
+        }
+    }
+    escaped
+}
+
+const WEEKDAYS: [&str; 7] = [
+    "Sunday",
+    "Monday",
+    "Tuesday",
+    "Wednesday",
+    "Thursday",
+    "Friday",
+    "Saturday",
+];
+
+const MONTHS: [&str; 12] = [
+    "January",
+    "February",
+    "March",
+    "April",
+    "May",
+    "June",
+    "July",
+    "August",
+    "September",
+    "October",
+    "November",
+    "December",
+];
+
+// 按照 strftime 的格式输出 UTC 时间，只支持对话模板中常用的格式，其它的格式原样输出
+fn strftime(format: &str, secs: i64) -> String {
+    let days = secs.div_euclid(86400);
+    let secs_of_day = secs.rem_euclid(86400);
+    let (year, month, day) = civil_from_days(days);
+    let weekday = WEEKDAYS[(days + 4).rem_euclid(7) as usize];
+    let month_name = MONTHS[month as usize - 1];
+    let mut formatted = String::with_capacity(format.len() * 2);
+    let mut chars = format.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            formatted.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('Y') => formatted.push_str(&year.to_string()),
+            Some('y') => formatted.push_str(&format!("{:02}", year.rem_euclid(100))),
+            Some('m') => formatted.push_str(&format!("{month:02}")),
+            Some('d') => formatted.push_str(&format!("{day:02}")),
+            Some('-') if chars.clone().next() == Some('d') => {
+                chars.next();
+                formatted.push_str(&day.to_string());
+            }
+            Some('B') => formatted.push_str(month_name),
+            Some('b') => formatted.push_str(&month_name[..3]),
+            Some('A') => formatted.push_str(weekday),
+            Some('a') => formatted.push_str(&weekday[..3]),
+            Some('H') => formatted.push_str(&format!("{:02}", secs_of_day / 3600)),
+            Some('M') => formatted.push_str(&format!("{:02}", secs_of_day % 3600 / 60)),
+            Some('S') => formatted.push_str(&format!("{:02}", secs_of_day % 60)),
+            Some('%') => formatted.push('%'),
+            Some(other) => {
+                formatted.push('%');
+                formatted.push(other);
+            }
+            None => formatted.push('%'),
+        }
+    }
+    formatted
+}
+
+// 从 1970-01-01 开始的天数转换为年、月、日
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHATML: &str = "{% for message in messages %}{{'<|im_start|>' + message['role'] + '\n' + message['content'] + '<|im_end|>' + '\n'}}{% endfor %}{% if add_generation_prompt %}{{ '<|im_start|>assistant\n' }}{% endif %}";
+
+    #[test]
+    fn test_render_chatml() {
+        let template = JinjaTemplate::new(CHATML).unwrap();
+        let inputs = ChatTemplateInputs::new(vec![
+            json!({ "role": "system", "content": "You are helpful." }),
+            json!({ "role": "user", "content": "Hi" }),
+        ])
+        .with_add_generation_prompt(true);
+        assert_eq!(
+            template.render(&inputs).unwrap(),
+            "<|im_start|>system\nYou are helpful.<|im_end|>\n<|im_start|>user\nHi<|im_end|>\n<|im_start|>assistant\n"
+        );
+    }
+
+    #[test]
+    fn test_render_tools_and_kwargs() {
+        let template = JinjaTemplate::new(
+            "{%- if tools %}{% for tool in tools %}{{ tool | tojson }}\n{% endfor %}{% endif -%}\
+             {{ bos_token }}{{ messages[0].content.strip() }}\
+             {%- if enable_thinking is defined and not enable_thinking %}<think></think>{% endif %}",
+        )
+        .unwrap();
+        let inputs = ChatTemplateInputs::new(vec![json!({ "role": "user", "content": " 你好 " })])
+            .with_bos_token("<s>")
+            .with_tools(vec![
+                json!({ "name": "echo", "parameters": { "type": "object" } }),
+            ])
+            .with_kwarg("enable_thinking", false);
+        assert_eq!(
+            template.render(&inputs).unwrap(),
+            "{\"name\": \"echo\", \"parameters\": {\"type\": \"object\"}}\n<s>你好<think></think>"
+        );
+    }
+
+    #[test]
+    fn test_raise_exception() {
+        let template = JinjaTemplate::new(
+            "{% if messages[0].role != 'user' %}{{ raise_exception('Conversation must start with user') }}{% endif %}",
+        )
+        .unwrap();
+        let inputs = ChatTemplateInputs::new(vec![json!({ "role": "assistant", "content": "" })]);
+        let error = template.render(&inputs).unwrap_err();
+        assert!(matches!(error, TemplateError::JinjaRender { .. }));
+        assert!(JinjaTemplate::new("{% if %}").is_err());
+    }
+
+    #[test]
+    fn test_strftime() {
+        // 2024-07-26 13:05:09 UTC，星期五
+        let secs = 1_721_999_109;
+        assert_eq!(strftime("%d %b %Y", secs), "26 Jul 2024");
+        assert_eq!(
+            strftime("%A, %B %-d %H:%M:%S %%", secs),
+            "Friday, July 26 13:05:09 %"
+        );
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(civil_from_days(-1), (1969, 12, 31));
+    }
+
+    #[test]
+    fn test_to_messages() {
+        let inputs = ChatTemplateInputs::new(vec![json!({
+            "role": "user",
+            "content": [{ "type": "text", "text": "a" }, { "type": "text", "text": "b" }],
+        })]);
+        let messages = inputs.to_messages().unwrap();
+        assert_eq!(messages, vec![Message::try_new("user", "a\nb").unwrap()]);
+    }
+}
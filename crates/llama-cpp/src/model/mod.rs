@@ -1,12 +1,17 @@
 mod chat;
+mod jinja;
 mod lora;
 mod params;
+mod tool;
 
 pub use chat::*;
+pub use jinja::*;
 pub use lora::*;
 pub use params::*;
+pub use tool::*;
 
 use crate::{
+    context::ContextParams,
     token::{Token, TokenAttr, TokenAttrs},
     vocabulary::Vocabulary,
 };
@@ -225,8 +230,9 @@ impl Model {
         }
 
         let attrs = self.token_attr(token);
+        // 字节 token 是多字节字符的一部分，需要保留，由调用方将字节拼接成完整的字符
         if attrs.is_empty()
-            || attrs.intersects(TokenAttr::Unknown | TokenAttr::Byte | TokenAttr::Unused)
+            || attrs.intersects(TokenAttr::Unknown | TokenAttr::Unused)
             || attrs.contains(TokenAttr::Control)
                 && (token == vocab.token_bos() || token == vocab.token_eos())
         {
@@ -295,6 +301,30 @@ impl Model {
         u32::try_from(unsafe { llama_cpp_sys::llama_model_n_head(self.raw.as_ptr()) }).unwrap()
     }
 
+    pub fn n_head_kv(&self) -> u32 {
+        u32::try_from(unsafe { llama_cpp_sys::llama_model_n_head_kv(self.raw.as_ptr()) }).unwrap()
+    }
+
+    /// 估算使用 params 创建的上下文中 KV cache 占用的内存，单位为字节
+    ///
+    /// 没有设置上下文大小时使用训练时的上下文大小，使用分组查询注意力时 K 和 V 的维度按照 KV 头的数量缩小
+    pub fn kv_cache_size(&self, params: &ContextParams) -> u64 {
+        let n_ctx = params
+            .n_ctx()
+            .map_or(self.n_ctx_train(), |n_ctx| n_ctx.get());
+        let n_embd_kv = i64::from(self.n_embd()) * i64::from(self.n_head_kv())
+            / i64::from(self.n_head().max(1));
+        let raw = params.raw();
+        let row = n_embd_kv * i64::from(n_ctx);
+        let (k, v) = unsafe {
+            (
+                llama_cpp_sys::ggml_row_size(raw.type_k, row),
+                llama_cpp_sys::ggml_row_size(raw.type_v, row),
+            )
+        };
+        (k + v) as u64 * u64::from(self.n_layer())
+    }
+
     /// Returns the rope type of the model.
     pub fn rope_type(&self) -> Option<RopeType> {
         match unsafe { llama_cpp_sys::llama_model_rope_type(self.raw.as_ptr()) } {
@@ -467,6 +497,57 @@ impl Model {
 
         Ok(String::from_utf8(buff).context(TemplateCannotCovertToStringSnafu)?)
     }
+
+    /// 将 GGUF 中的 `tokenizer.chat_template` 编译为 Jinja 模板
+    pub fn jinja_chat_template(&self) -> Result<JinjaTemplate, TemplateError> {
+        let template = self.chat_template_by_meta()?;
+        JinjaTemplate::new(template.to_str()?)
+    }
+
+    /// 使用 Jinja 渲染对话，没有提供 bos_token 和 eos_token 时使用词汇表中的 token
+    ///
+    /// 分词时会自动添加 BOS 的话，去掉模板在开头输出的 BOS，避免重复；渲染失败时回退到 llama.cpp 内置的模板，
+    /// 此时工具和额外的变量不会生效
+    #[tracing::instrument(skip_all)]
+    pub fn apply_jinja_chat_template(
+        &self,
+        template: &JinjaTemplate,
+        mut inputs: ChatTemplateInputs,
+    ) -> Result<String, TemplateError> {
+        let vocab = self.vocab();
+        if inputs.bos_token().is_none() {
+            inputs = inputs.with_bos_token(self.special_token_text(vocab.token_bos()));
+        }
+        if inputs.eos_token().is_none() {
+            inputs = inputs.with_eos_token(self.special_token_text(vocab.token_eos()));
+        }
+        let error = match template.render(&inputs) {
+            Ok(prompt) => {
+                let bos = inputs.bos_token().unwrap_or_default();
+                return Ok(match prompt.strip_prefix(bos) {
+                    Some(stripped) if vocab.add_bos() && !bos.is_empty() => stripped.to_owned(),
+                    _ => prompt,
+                });
+            }
+            Err(error) => error,
+        };
+        tracing::debug!(%error, "Fall back to the built-in chat template of llama.cpp");
+        let Ok(messages) = inputs.to_messages() else {
+            return Err(error);
+        };
+        // 内置的模板也无法处理时，Jinja 的错误更有参考价值
+        self.apply_chat_template(template.source(), &messages, inputs.add_generation_prompt())
+            .or(Err(error))
+    }
+
+    // 特殊 token 的文本，模型中没有这个 token 时为空
+    fn special_token_text(&self, token: Token) -> String {
+        if token.raw() < 0 {
+            return String::new();
+        }
+        self.token_to_str(token, Special::Tokenize)
+            .unwrap_or_default()
+    }
 }
 
 impl From<*mut llama_cpp_sys::llama_model> for Model {
@@ -0,0 +1,493 @@
+use super::{Message, MessageError, Model};
+use crate::{
+    generator::partial_suffix_len, json_schema::gbnf_literal, runtime::Runtime, sampler::Sampler,
+};
+use serde_json::{Map, Value, json};
+
+// Hermes 和 Qwen 使用的工具调用标签
+const TOOL_CALL_OPEN: &str = "<tool_call>";
+const TOOL_CALL_CLOSE: &str = "</tool_call>";
+// Llama 3 调用内置工具时使用的 token
+const PYTHON_TAG: &str = "<|python_tag|>";
+// Mistral 的工具调用标记，新版本的模板中每个调用的参数在 [ARGS] 之后
+const MISTRAL_TOOL_CALLS: &str = "[TOOL_CALLS]";
+const MISTRAL_ARGS: &str = "[ARGS]";
+
+// 任意 JSON 值的 GBNF 语法，和 llama.cpp 中的 json.gbnf 一致
+const JSON_GRAMMAR: &str = r#"value ::= object | array | string | number | ("true" | "false" | "null") space
+object ::= "{" space (string ":" space value ("," space string ":" space value)*)? "}" space
+array ::= "[" space (value ("," space value)*)? "]" space
+string ::= "\"" ([^"\\\x7F\x00-\x1F] | "\\" (["\\/bfnrt] | "u" [0-9a-fA-F]{4}))* "\"" space
+number ::= "-"? ([0-9] | [1-9] [0-9]{0,15}) ("." [0-9]+)? ([eE] [-+]? [0-9]{1,16})? space
+space ::= | " " | "\n" [ \t]{0,20}
+"#;
+
+/// 模型生成的一次工具调用
+#[derive(Debug, Clone, Eq, PartialEq, Default)]
+pub struct ToolCall {
+    // 模型的输出中一般没有 id，由调用方生成
+    pub id: Option<String>,
+    pub name: String,
+    // JSON 格式的参数
+    pub arguments: String,
+}
+
+impl ToolCall {
+    /// 对话模板中使用的格式，和 OpenAI 的格式一致，参数是合法的 JSON 时转换为对象
+    pub fn to_template_value(&self) -> Value {
+        let arguments = serde_json::from_str::<Value>(&self.arguments)
+            .unwrap_or_else(|_| Value::String(self.arguments.clone()));
+        let mut value = json!({
+            "type": "function",
+            "function": { "name": self.name, "arguments": arguments },
+        });
+        if let Some(id) = &self.id {
+            value["id"] = Value::String(id.clone());
+        }
+        value
+    }
+}
+
+/// 从模型生成的文本中解析出来的回答和工具调用
+#[derive(Debug, Clone, Eq, PartialEq, Default)]
+pub struct ParsedResponse {
+    pub content: String,
+    pub tool_calls: Vec<ToolCall>,
+}
+
+/// 不同的模型家族输出工具调用的格式
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum ToolCallFormat {
+    /// `<tool_call>{"name": ..., "arguments": ...}</tool_call>`
+    Hermes,
+    /// 和 Hermes 一样，Qwen3-Coder 的标签中还可以是 `<function=name><parameter=key>value</parameter></function>`
+    Qwen,
+    /// `{"name": ..., "parameters": ...}`，前面可能有 `<|python_tag|>`
+    Llama3,
+    /// `[TOOL_CALLS][{"name": ..., "arguments": ...}]` 或者 `[TOOL_CALLS]name[ARGS]{...}`
+    Mistral,
+}
+
+impl ToolCallFormat {
+    /// 根据对话模板的内容判断模型使用的格式
+    pub fn detect(template: &str) -> Option<Self> {
+        if template.contains(MISTRAL_TOOL_CALLS) {
+            Some(Self::Mistral)
+        } else if template.contains(TOOL_CALL_OPEN) {
+            if template.contains("<function=") {
+                Some(Self::Qwen)
+            } else {
+                Some(Self::Hermes)
+            }
+        } else if template.contains(PYTHON_TAG)
+            || (template.contains("<|start_header_id|>") && template.contains("ipython"))
+        {
+            Some(Self::Llama3)
+        } else {
+            None
+        }
+    }
+
+    /// 工具调用开始的文本，生成了这些文本之后才开始使用语法约束
+    pub fn trigger_words(&self) -> &'static [&'static str] {
+        match self {
+            Self::Hermes | Self::Qwen => &[TOOL_CALL_OPEN],
+            Self::Llama3 => &[PYTHON_TAG, "{\"name\""],
+            Self::Mistral => &[MISTRAL_TOOL_CALLS],
+        }
+    }
+
+    /// 将生成的文本解析为回答和工具调用，无法解析的工具调用保留在回答中
+    pub fn parse(&self, text: &str) -> ParsedResponse {
+        let parsed = match self {
+            Self::Hermes => parse_tagged(text, false),
+            Self::Qwen => parse_tagged(text, true),
+            Self::Llama3 => parse_llama3(text),
+            Self::Mistral => parse_mistral(text),
+        };
+        parsed.unwrap_or_else(|| ParsedResponse {
+            content: text.trim().to_owned(),
+            tool_calls: Vec::new(),
+        })
+    }
+
+    /// 流式输出时可以确定是回答的长度，之后的文本可能是工具调用，需要等生成结束之后再解析
+    pub fn streamable_len(&self, text: &str) -> usize {
+        let opener = match self {
+            Self::Hermes | Self::Qwen => TOOL_CALL_OPEN,
+            Self::Mistral => MISTRAL_TOOL_CALLS,
+            // Llama 3 的工具调用只会出现在回答的开头
+            Self::Llama3 => {
+                let trimmed = text.trim_start();
+                let maybe_call = trimmed.starts_with('{')
+                    || trimmed.starts_with(PYTHON_TAG)
+                    || PYTHON_TAG.starts_with(trimmed);
+                return if maybe_call { 0 } else { text.len() };
+            }
+        };
+        match text.find(opener) {
+            Some(index) => index,
+            None => text.len() - partial_suffix_len(text, opener),
+        }
+    }
+
+    /// 约束工具调用的 GBNF 语法，根规则为 root，工具的名字只能是 tools 中定义的名字
+    pub fn grammar(&self, tools: &[Value]) -> String {
+        let names = tools
+            .iter()
+            .filter_map(tool_name)
+            .map(|name| gbnf_literal(&format!("\"{name}\"")))
+            .collect::<Vec<_>>();
+        let tool_name = if names.is_empty() {
+            "string".to_owned()
+        } else {
+            names.join(" | ")
+        };
+        let root = match self {
+            Self::Hermes | Self::Qwen => format!(
+                "root ::= tool-call+\n\
+                 tool-call ::= {} space call {} space\n",
+                gbnf_literal(TOOL_CALL_OPEN),
+                gbnf_literal(TOOL_CALL_CLOSE)
+            ),
+            Self::Llama3 => format!("root ::= ({} space)? call\n", gbnf_literal(PYTHON_TAG)),
+            Self::Mistral => format!(
+                "root ::= {} space \"[\" space call (\",\" space call)* \"]\" space\n",
+                gbnf_literal(MISTRAL_TOOL_CALLS)
+            ),
+        };
+        let arguments = match self {
+            Self::Llama3 => "parameters",
+            _ => "arguments",
+        };
+        format!(
+            "{root}call ::= \"{{\" space \"\\\"name\\\"\" space \":\" space tool-name \",\" space \"\\\"{arguments}\\\"\" space \":\" space object \"}}\" space\n\
+             tool-name ::= {tool_name}\n\
+             {JSON_GRAMMAR}"
+        )
+    }
+
+    /// 只在生成了工具调用的开头之后才生效的语法约束采样器，需要放在采样器链的最前面
+    pub fn lazy_grammar_sampler(&self, model: &Model, tools: &[Value]) -> Sampler {
+        let vocab = model.vocab();
+        // 特殊 token 形式的开头同时作为触发的 token
+        let trigger_tokens = self
+            .trigger_words()
+            .iter()
+            .filter_map(|word| vocab.tokenize(word, false, true).ok())
+            .filter(|tokens| tokens.len() == 1)
+            .flatten()
+            .collect::<Vec<_>>();
+        Runtime::sampler_from_grammar_lazy(
+            model,
+            &self.grammar(tools),
+            "root",
+            self.trigger_words(),
+            &trigger_tokens,
+        )
+    }
+}
+
+/// 对话模板不支持工具时，按照 Hermes 的格式在系统提示词中描述工具，没有系统提示词时添加一条
+pub fn describe_tools_in_system(
+    messages: &mut Vec<Message>,
+    tools: &[Value],
+) -> Result<(), MessageError> {
+    let definitions = tools
+        .iter()
+        .map(Value::to_string)
+        .collect::<Vec<_>>()
+        .join("\n");
+    let names = tools.iter().filter_map(tool_name).collect::<Vec<_>>();
+    let description = format!(
+        "You may call one or more of the following tools ({}):\n<tools>\n{definitions}\n</tools>\n\n\
+         For each call, return a JSON object with the tool name and arguments within <tool_call></tool_call> tags:\n\
+         <tool_call>\n{{\"name\": <tool-name>, \"arguments\": <args-json-object>}}\n</tool_call>",
+        names.join(", ")
+    );
+    match messages.first_mut() {
+        Some(message) if message.role.as_bytes() == b"system" => {
+            let system = format!("{}\n\n{description}", message.content.to_string_lossy());
+            *message = Message::try_new("system", system)?;
+        }
+        _ => messages.insert(0, Message::try_new("system", description)?),
+    }
+    Ok(())
+}
+
+/// 工具定义中的名字，支持 OpenAI 的格式和直接定义函数的格式
+pub fn tool_name(tool: &Value) -> Option<&str> {
+    tool["function"]["name"]
+        .as_str()
+        .or_else(|| tool["name"].as_str())
+}
+
+// 解析 JSON 对象形式的工具调用，参数的字段可能是 arguments 或者 parameters
+fn call_from_value(value: &Value) -> Option<ToolCall> {
+    let name = value["name"].as_str()?;
+    let arguments = match value.get("arguments").or_else(|| value.get("parameters")) {
+        Some(Value::String(arguments)) => arguments.clone(),
+        Some(arguments) => arguments.to_string(),
+        None => "{}".to_owned(),
+    };
+    Some(ToolCall {
+        id: value["id"].as_str().map(str::to_owned),
+        name: name.to_owned(),
+        arguments,
+    })
+}
+
+// 读取文本开头的一个 JSON 值，返回值和剩余的文本
+fn next_value(text: &str) -> Option<(Value, &str)> {
+    let mut values = serde_json::Deserializer::from_str(text).into_iter::<Value>();
+    let value = values.next()?.ok()?;
+    Some((value, &text[values.byte_offset()..]))
+}
+
+// <tool_call> 标签中的工具调用，最后一个标签可能因为生成结束而没有闭合
+fn parse_tagged(text: &str, xml: bool) -> Option<ParsedResponse> {
+    let mut content = String::new();
+    let mut tool_calls = Vec::new();
+    let mut rest = text;
+    while let Some(start) = rest.find(TOOL_CALL_OPEN) {
+        let after = &rest[start + TOOL_CALL_OPEN.len()..];
+        let (body, next) = match after.find(TOOL_CALL_CLOSE) {
+            Some(end) => (&after[..end], &after[end + TOOL_CALL_CLOSE.len()..]),
+            None => (after, ""),
+        };
+        let call = serde_json::from_str::<Value>(body.trim())
+            .ok()
+            .and_then(|value| call_from_value(&value))
+            .or_else(|| xml.then(|| parse_xml_call(body)).flatten());
+        match call {
+            Some(call) => {
+                content.push_str(&rest[..start]);
+                tool_calls.push(call);
+            }
+            None => content.push_str(&rest[..rest.len() - next.len()]),
+        }
+        rest = next;
+    }
+    content.push_str(rest);
+    Some(ParsedResponse {
+        content: content.trim().to_owned(),
+        tool_calls,
+    })
+}
+
+// Qwen3-Coder 的格式，参数的值是合法的 JSON 时使用 JSON 值，否则作为字符串
+fn parse_xml_call(body: &str) -> Option<ToolCall> {
+    let body = body.trim().strip_prefix("<function=")?;
+    let (name, mut rest) = body.split_once('>')?;
+    let mut arguments = Map::new();
+    while let Some(start) = rest.find("<parameter=") {
+        let (key, after) = rest[start + "<parameter=".len()..].split_once('>')?;
+        let (value, next) = after.split_once("</parameter>")?;
+        let value = value.strip_prefix('\n').unwrap_or(value);
+        let value = value.strip_suffix('\n').unwrap_or(value);
+        let value = serde_json::from_str::<Value>(value.trim())
+            .unwrap_or_else(|_| Value::String(value.to_owned()));
+        arguments.insert(key.trim().to_owned(), value);
+        rest = next;
+    }
+    Some(ToolCall {
+        id: None,
+        name: name.trim().to_owned(),
+        arguments: Value::Object(arguments).to_string(),
+    })
+}
+
+// 整个回答都是工具调用，多个调用之间使用分号分隔
+fn parse_llama3(text: &str) -> Option<ParsedResponse> {
+    let trimmed = text.trim_start();
+    let mut rest = trimmed
+        .strip_prefix(PYTHON_TAG)
+        .unwrap_or(trimmed)
+        .trim_start();
+    if !rest.starts_with('{') {
+        return None;
+    }
+    let mut tool_calls = Vec::new();
+    while !rest.is_empty() {
+        let (value, next) = next_value(rest)?;
+        tool_calls.push(call_from_value(&value)?);
+        rest = next.trim_start();
+        rest = rest.strip_prefix(';').unwrap_or(rest).trim_start();
+    }
+    Some(ParsedResponse {
+        content: String::new(),
+        tool_calls,
+    })
+}
+
+// [TOOL_CALLS] 之前的文本是回答，之后是 JSON 数组或者多个 name[ARGS]{...}
+fn parse_mistral(text: &str) -> Option<ParsedResponse> {
+    let start = text.find(MISTRAL_TOOL_CALLS)?;
+    let content = text[..start].trim().to_owned();
+    let mut rest = text[start + MISTRAL_TOOL_CALLS.len()..].trim_start();
+    let mut tool_calls = Vec::new();
+    if rest.starts_with('[') {
+        let (value, _) = next_value(rest)?;
+        for call in value.as_array()? {
+            tool_calls.push(call_from_value(call)?);
+        }
+    } else {
+        while !rest.is_empty() {
+            let (name, after) = rest.split_once(MISTRAL_ARGS)?;
+            let (arguments, next) = next_value(after)?;
+            tool_calls.push(ToolCall {
+                id: None,
+                name: name.trim().to_owned(),
+                arguments: arguments.to_string(),
+            });
+            rest = next.trim_start();
+            rest = rest
+                .strip_prefix(MISTRAL_TOOL_CALLS)
+                .unwrap_or(rest)
+                .trim_start();
+        }
+    }
+    Some(ParsedResponse {
+        content,
+        tool_calls,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn call(name: &str, arguments: &str) -> ToolCall {
+        ToolCall {
+            id: None,
+            name: name.to_owned(),
+            arguments: arguments.to_owned(),
+        }
+    }
+
+    #[test]
+    fn test_detect() {
+        assert_eq!(
+            ToolCallFormat::detect("{{- '<tool_call>' }}"),
+            Some(ToolCallFormat::Hermes)
+        );
+        assert_eq!(
+            ToolCallFormat::detect("<tool_call>\n<function={{ name }}>"),
+            Some(ToolCallFormat::Qwen)
+        );
+        assert_eq!(
+            ToolCallFormat::detect("{{- '<|python_tag|>' }}"),
+            Some(ToolCallFormat::Llama3)
+        );
+        assert_eq!(
+            ToolCallFormat::detect("[TOOL_CALLS]"),
+            Some(ToolCallFormat::Mistral)
+        );
+        assert_eq!(ToolCallFormat::detect("{{ messages }}"), None);
+    }
+
+    #[test]
+    fn test_parse_hermes() {
+        let parsed = ToolCallFormat::Hermes.parse(
+            "Let me check.\n<tool_call>\n{\"name\": \"echo\", \"arguments\": {\"text\": \"hi\"}}\n</tool_call>\n<tool_call>{\"name\": \"now\"",
+        );
+        assert_eq!(
+            parsed.content,
+            "Let me check.\n\n<tool_call>{\"name\": \"now\""
+        );
+        assert_eq!(parsed.tool_calls, vec![call("echo", "{\"text\":\"hi\"}")]);
+
+        let parsed = ToolCallFormat::Hermes.parse("No tools needed.");
+        assert_eq!(parsed.content, "No tools needed.");
+        assert!(parsed.tool_calls.is_empty());
+    }
+
+    #[test]
+    fn test_parse_qwen_xml() {
+        let parsed = ToolCallFormat::Qwen.parse(
+            "<tool_call>\n<function=echo>\n<parameter=text>\nhello world\n</parameter>\n<parameter=times>\n2\n</parameter>\n</function>\n</tool_call>",
+        );
+        assert_eq!(parsed.content, "");
+        assert_eq!(
+            parsed.tool_calls,
+            vec![call("echo", "{\"text\":\"hello world\",\"times\":2}")]
+        );
+    }
+
+    #[test]
+    fn test_parse_llama3() {
+        let parsed = ToolCallFormat::Llama3.parse(
+            "<|python_tag|>{\"name\": \"echo\", \"parameters\": {\"text\": \"a\"}}; {\"name\": \"now\", \"parameters\": {}}",
+        );
+        assert_eq!(
+            parsed.tool_calls,
+            vec![call("echo", "{\"text\":\"a\"}"), call("now", "{}")]
+        );
+        let parsed = ToolCallFormat::Llama3.parse("{ is a brace");
+        assert_eq!(parsed.content, "{ is a brace");
+        assert!(parsed.tool_calls.is_empty());
+    }
+
+    #[test]
+    fn test_parse_mistral() {
+        let parsed = ToolCallFormat::Mistral.parse(
+            "Sure.[TOOL_CALLS][{\"name\": \"echo\", \"arguments\": {\"text\": \"a\"}, \"id\": \"abcdefghi\"}]",
+        );
+        assert_eq!(parsed.content, "Sure.");
+        assert_eq!(
+            parsed.tool_calls,
+            vec![ToolCall {
+                id: Some("abcdefghi".to_owned()),
+                ..call("echo", "{\"text\":\"a\"}")
+            }]
+        );
+        let parsed = ToolCallFormat::Mistral
+            .parse("[TOOL_CALLS]echo[ARGS]{\"text\": \"a\"}[TOOL_CALLS]now[ARGS]{}");
+        assert_eq!(
+            parsed.tool_calls,
+            vec![call("echo", "{\"text\":\"a\"}"), call("now", "{}")]
+        );
+    }
+
+    #[test]
+    fn test_streamable_len() {
+        assert_eq!(ToolCallFormat::Hermes.streamable_len("Hello <tool"), 6);
+        assert_eq!(ToolCallFormat::Hermes.streamable_len("Hi <tool_call>{"), 3);
+        assert_eq!(ToolCallFormat::Hermes.streamable_len("Hello"), 5);
+        assert_eq!(ToolCallFormat::Llama3.streamable_len("  {\"na"), 0);
+        assert_eq!(ToolCallFormat::Llama3.streamable_len("<|py"), 0);
+        assert_eq!(ToolCallFormat::Llama3.streamable_len("Hello"), 5);
+    }
+
+    #[test]
+    fn test_describe_tools_in_system() {
+        let tools = vec![json!({ "type": "function", "function": { "name": "echo" } })];
+        let mut messages = vec![
+            Message::try_new("system", "Be brief.").unwrap(),
+            Message::try_new("user", "hi").unwrap(),
+        ];
+        describe_tools_in_system(&mut messages, &tools).unwrap();
+        assert_eq!(messages.len(), 2);
+        let system = messages[0].content.to_str().unwrap();
+        assert!(system.starts_with("Be brief.\n\nYou may call"));
+        assert!(system.contains("(echo)"));
+
+        let mut messages = vec![Message::try_new("user", "hi").unwrap()];
+        describe_tools_in_system(&mut messages, &tools).unwrap();
+        assert_eq!(messages[0].role.to_str().unwrap(), "system");
+    }
+
+    #[test]
+    fn test_grammar() {
+        let tools = vec![json!({ "type": "function", "function": { "name": "echo" } })];
+        let grammar = ToolCallFormat::Hermes.grammar(&tools);
+        assert!(grammar.starts_with("root ::= tool-call+\n"));
+        assert!(grammar.contains("tool-name ::= \"\\\"echo\\\"\"\n"));
+        assert!(grammar.contains("\"\\\"arguments\\\"\""));
+        assert!(
+            ToolCallFormat::Llama3
+                .grammar(&tools)
+                .contains("\"\\\"parameters\\\"\"")
+        );
+    }
+}
@@ -0,0 +1,94 @@
+//! 提示词的前缀缓存
+//!
+//! 记录每个序列在 KV cache 中的 token，新的提示词和已有的 token 有公共前缀时，只保留公共前缀，
+//! 丢弃不一致的部分，之后只需要解码剩余的 token。多轮对话和相同的系统提示词都可以复用已经解码的部分
+
+use crate::{Result, context::Context, token::Token};
+use std::ops::Range;
+
+/// 每个序列在 KV cache 中的 token
+#[derive(Clone, Debug, Default)]
+pub struct PrefixCache {
+    // 下标为序列的 id
+    sequences: Vec<Vec<Token>>,
+}
+
+impl PrefixCache {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 序列中已经解码的 token
+    #[must_use]
+    pub fn tokens(&self, seq_id: i32) -> &[Token] {
+        usize::try_from(seq_id)
+            .ok()
+            .and_then(|seq_id| self.sequences.get(seq_id))
+            .map_or(&[], Vec::as_slice)
+    }
+
+    /// 序列中的 token 和提示词的公共前缀长度
+    #[must_use]
+    pub fn common_prefix_len(&self, seq_id: i32, prompt: &[Token]) -> usize {
+        self.tokens(seq_id)
+            .iter()
+            .zip(prompt)
+            .take_while(|(cached, token)| cached == token)
+            .count()
+    }
+
+    /// 和提示词公共前缀最长的序列，以及公共前缀的长度
+    #[must_use]
+    pub fn best_match(&self, prompt: &[Token]) -> Option<(i32, usize)> {
+        (0..self.sequences.len() as i32)
+            .map(|seq_id| (seq_id, self.common_prefix_len(seq_id, prompt)))
+            .max_by_key(|(_, len)| *len)
+    }
+
+    /// 准备解码提示词：丢弃序列中和提示词不一致的部分，返回可以复用的 token 数量
+    ///
+    /// 至少保留提示词的最后一个 token 需要解码，这样才能得到采样需要的 logits
+    pub fn reuse(&mut self, context: &mut Context, seq_id: i32, prompt: &[Token]) -> Result<usize> {
+        let mut n_reuse = self.common_prefix_len(seq_id, prompt);
+        if n_reuse == prompt.len() {
+            n_reuse = n_reuse.saturating_sub(1);
+        }
+        let seq = u32::try_from(seq_id).ok();
+        // 有的模型（例如循环模型）不支持只删除序列的一部分，只能从头开始
+        if !context.clear_kv_cache_seq(seq, Some(n_reuse as u32), None)? {
+            context.clear_kv_cache_seq(seq, None, None)?;
+            n_reuse = 0;
+        }
+        self.sequence(seq_id).truncate(n_reuse);
+        Ok(n_reuse)
+    }
+
+    /// 记录已经解码的 token
+    pub fn extend(&mut self, seq_id: i32, tokens: &[Token]) {
+        self.sequence(seq_id).extend_from_slice(tokens);
+    }
+
+    /// 丢弃序列中的一段 token，和 KV cache 的移动保持一致
+    pub fn discard(&mut self, seq_id: i32, range: Range<usize>) {
+        let tokens = self.sequence(seq_id);
+        let range = range.start.min(tokens.len())..range.end.min(tokens.len());
+        tokens.drain(range);
+    }
+
+    /// 清空序列，seq_id 为 None 时清空全部的序列
+    pub fn clear(&mut self, seq_id: Option<i32>) {
+        match seq_id {
+            Some(seq_id) => self.sequence(seq_id).clear(),
+            None => self.sequences.clear(),
+        }
+    }
+
+    fn sequence(&mut self, seq_id: i32) -> &mut Vec<Token> {
+        let index = usize::try_from(seq_id).unwrap_or_default();
+        if self.sequences.len() <= index {
+            self.sequences.resize_with(index + 1, Vec::new);
+        }
+        &mut self.sequences[index]
+    }
+}
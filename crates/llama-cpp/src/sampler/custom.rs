@@ -0,0 +1,290 @@
+//! 使用 Rust 实现的采样器
+//!
+//! 通过 llama_sampler_i 的回调把 [`CustomSampler`] 接入 llama.cpp 的采样器链。回调由 C 代码调用，panic 不能穿过 FFI 的边界，
+//! 回调中发生的 panic 会被捕获并记录日志，这一次调用不会修改候选 token
+
+use super::Sampler;
+use crate::token::{Token, TokenDataVec};
+use std::{
+    any::Any,
+    ffi::{CStr, CString, c_char},
+    mem,
+    panic::{self, AssertUnwindSafe},
+    ptr,
+};
+use tracing::error;
+
+/// 使用 Rust 实现的采样器，通过 [`Sampler::from_custom`] 转换成 llama.cpp 的采样器之后，可以和内置的采样器一起放进采样器链
+///
+/// 复制采样器链时会通过 [`Clone`] 复制采样器的状态。回调中发生的 panic 会被捕获并记录日志，
+/// 复制时发生 panic 的采样器会被替换成不修改候选 token 的采样器
+pub trait CustomSampler: CloneCustomSampler + Send + 'static {
+    /// 采样器的名字，只在创建采样器时读取一次
+    fn name(&self) -> &str;
+
+    /// 接受一个选中的 token，例如记录已经生成的内容
+    fn accept(&mut self, _token: Token) {}
+
+    /// 修改候选 token 的 logit、概率、顺序或者选中的 token，候选 token 的数量只能减少
+    fn apply(&mut self, candidates: &mut TokenDataVec);
+
+    /// 重置采样器的状态，开始新的生成之前调用
+    fn reset(&mut self) {}
+}
+
+/// 复制装箱的采样器，实现了 [`Clone`] 的采样器自动实现
+pub trait CloneCustomSampler {
+    fn clone_boxed(&self) -> Box<dyn CustomSampler>;
+}
+
+impl<T: CustomSampler + Clone> CloneCustomSampler for T {
+    fn clone_boxed(&self) -> Box<dyn CustomSampler> {
+        Box::new(self.clone())
+    }
+}
+
+// llama_sampler 中的 ctx，名字需要在采样器的生命周期内保持有效
+struct CustomContext {
+    name: CString,
+    sampler: Box<dyn CustomSampler>,
+}
+
+static CUSTOM_SAMPLER_I: llama_cpp_sys::llama_sampler_i = llama_cpp_sys::llama_sampler_i {
+    name: Some(custom_name),
+    accept: Some(custom_accept),
+    apply: Some(custom_apply),
+    reset: Some(custom_reset),
+    clone: Some(custom_clone),
+    free: Some(custom_free),
+};
+
+impl Sampler {
+    #[must_use]
+    pub fn from_custom(sampler: impl CustomSampler) -> Self {
+        Self::from_custom_boxed(Box::new(sampler))
+    }
+
+    #[must_use]
+    pub fn from_custom_boxed(sampler: Box<dyn CustomSampler>) -> Self {
+        // 名字中有 \0 时只保留之前的部分
+        let name = sampler.name();
+        let name = CString::new(name.split('\0').next().unwrap_or_default()).unwrap_or_default();
+        let context = Box::new(CustomContext { name, sampler });
+        unsafe {
+            llama_cpp_sys::llama_sampler_init(&CUSTOM_SAMPLER_I, Box::into_raw(context).cast())
+        }
+        .into()
+    }
+}
+
+// 复制时发生 panic 的采样器的替代，保留原来的名字，不修改候选 token
+#[derive(Clone)]
+struct Passthrough(String);
+
+impl CustomSampler for Passthrough {
+    fn name(&self) -> &str {
+        &self.0
+    }
+
+    fn apply(&mut self, _candidates: &mut TokenDataVec) {}
+}
+
+// 调用采样器的回调，发生 panic 时记录日志并返回 None
+fn catch_panic<T>(name: &CStr, callback: &str, f: impl FnOnce() -> T) -> Option<T> {
+    panic::catch_unwind(AssertUnwindSafe(f))
+        .map_err(|payload| {
+            error!(
+                sampler = %name.to_string_lossy(),
+                "The custom sampler panicked in {callback}: {}",
+                panic_message(payload.as_ref())
+            );
+        })
+        .ok()
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("unknown panic")
+}
+
+// 采样器的 ctx 只在 from_custom_boxed 中创建，在 custom_free 中释放
+unsafe fn custom_context<'a>(smpl: *const llama_cpp_sys::llama_sampler) -> &'a CustomContext {
+    unsafe { &*(*smpl).ctx.cast::<CustomContext>() }
+}
+
+unsafe fn custom_context_mut<'a>(smpl: *mut llama_cpp_sys::llama_sampler) -> &'a mut CustomContext {
+    unsafe { &mut *(*smpl).ctx.cast::<CustomContext>() }
+}
+
+unsafe extern "C" fn custom_name(smpl: *const llama_cpp_sys::llama_sampler) -> *const c_char {
+    unsafe { custom_context(smpl) }.name.as_ptr()
+}
+
+unsafe extern "C" fn custom_accept(
+    smpl: *mut llama_cpp_sys::llama_sampler,
+    token: llama_cpp_sys::llama_token,
+) {
+    let CustomContext { name, sampler } = unsafe { custom_context_mut(smpl) };
+    catch_panic(name, "accept", || sampler.accept(token.into()));
+}
+
+unsafe extern "C" fn custom_apply(
+    smpl: *mut llama_cpp_sys::llama_sampler,
+    cur_p: *mut llama_cpp_sys::llama_token_data_array,
+) {
+    let CustomContext { name, sampler } = unsafe { custom_context_mut(smpl) };
+    let data_array = unsafe { &mut *cur_p };
+    let mut candidates = unsafe { TokenDataVec::from_llama_token_data_array(data_array) };
+    // 候选 token 是复制出来的，发生 panic 时不写回，cur_p 保持不变
+    if catch_panic(name, "apply", || sampler.apply(&mut candidates)).is_some() {
+        unsafe { candidates.write_to_llama_token_data_array(data_array) };
+    }
+}
+
+unsafe extern "C" fn custom_reset(smpl: *mut llama_cpp_sys::llama_sampler) {
+    let CustomContext { name, sampler } = unsafe { custom_context_mut(smpl) };
+    catch_panic(name, "reset", || sampler.reset());
+}
+
+unsafe extern "C" fn custom_clone(
+    smpl: *const llama_cpp_sys::llama_sampler,
+) -> *mut llama_cpp_sys::llama_sampler {
+    let CustomContext { name, sampler } = unsafe { custom_context(smpl) };
+    let sampler = catch_panic(name, "clone", || {
+        Sampler::from_custom_boxed(sampler.clone_boxed())
+    })
+    .unwrap_or_else(|| Sampler::from_custom(Passthrough(name.to_string_lossy().into_owned())));
+    let raw = sampler.raw_mut();
+    // 复制出来的采样器由 llama.cpp 释放
+    mem::forget(sampler);
+    raw
+}
+
+// llama_sampler_free 在调用 free 之后会释放 llama_sampler 本身，这里只释放 ctx
+unsafe extern "C" fn custom_free(smpl: *mut llama_cpp_sys::llama_sampler) {
+    unsafe {
+        drop(Box::from_raw((*smpl).ctx.cast::<CustomContext>()));
+        (*smpl).ctx = ptr::null_mut();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::token::TokenData;
+
+    // 禁止生成指定的 token，记录接受过的 token
+    #[derive(Clone)]
+    struct BanSampler {
+        banned: Token,
+        accepted: Vec<Token>,
+    }
+
+    impl CustomSampler for BanSampler {
+        fn name(&self) -> &str {
+            "ban"
+        }
+
+        fn accept(&mut self, token: Token) {
+            self.accepted.push(token);
+        }
+
+        fn apply(&mut self, candidates: &mut TokenDataVec) {
+            for data in candidates.data_mut() {
+                if data.id() == self.banned {
+                    data.set_logit(f32::NEG_INFINITY);
+                }
+            }
+        }
+
+        fn reset(&mut self) {
+            self.accepted.clear();
+        }
+    }
+
+    fn sample_candidates() -> TokenDataVec {
+        TokenDataVec::from_iter(
+            [(0, 1.0), (1, 3.0), (2, 2.0)]
+                .map(|(id, logit)| TokenData::new(Token::new(id), logit, 0.0)),
+            false,
+        )
+    }
+
+    #[test]
+    fn test_custom_sampler_in_chain() {
+        let ban = Sampler::from_custom(BanSampler {
+            banned: Token::new(1),
+            accepted: Vec::new(),
+        });
+        let mut chain = Sampler::from_chain([ban, Sampler::init_from_greedy()], true);
+        let mut candidates = sample_candidates();
+        chain.apply_to(&mut candidates);
+        assert_eq!(candidates.selected_token(), Some(Token::new(2)));
+        chain.accept(Token::new(2));
+        chain.reset();
+
+        // 复制的采样器链中的自定义采样器同样生效
+        let cloned: Sampler = unsafe { llama_cpp_sys::llama_sampler_clone(chain.raw_mut()) }.into();
+        let mut candidates = sample_candidates();
+        cloned.apply_to(&mut candidates);
+        assert_eq!(candidates.selected_token(), Some(Token::new(2)));
+    }
+
+    #[test]
+    fn test_custom_sampler_panic() {
+        #[derive(Clone)]
+        struct Panicking;
+
+        impl CustomSampler for Panicking {
+            fn name(&self) -> &str {
+                "panicking"
+            }
+
+            fn accept(&mut self, _token: Token) {
+                panic!("accept");
+            }
+
+            fn apply(&mut self, candidates: &mut TokenDataVec) {
+                candidates.truncate(1);
+                panic!("apply");
+            }
+        }
+
+        // 发生 panic 时候选 token 不变，之后的采样器照常选择
+        let mut chain = Sampler::from_chain(
+            [Sampler::from_custom(Panicking), Sampler::init_from_greedy()],
+            true,
+        );
+        let mut candidates = sample_candidates();
+        chain.apply_to(&mut candidates);
+        assert_eq!(candidates.data().len(), 3);
+        assert_eq!(candidates.selected_token(), Some(Token::new(1)));
+        chain.accept(Token::new(1));
+    }
+
+    #[test]
+    fn test_custom_sampler_truncate() {
+        #[derive(Clone)]
+        struct FirstTwo;
+
+        impl CustomSampler for FirstTwo {
+            fn name(&self) -> &str {
+                "first-two"
+            }
+
+            fn apply(&mut self, candidates: &mut TokenDataVec) {
+                candidates.truncate(2);
+                candidates.set_selected(Some(0));
+            }
+        }
+
+        let sampler = Sampler::from_custom(FirstTwo);
+        let mut candidates = sample_candidates();
+        sampler.apply_to(&mut candidates);
+        assert_eq!(candidates.data().len(), 2);
+        assert_eq!(candidates.selected_token(), Some(Token::new(0)));
+    }
+}
@@ -1,3 +1,7 @@
+mod custom;
+
+pub use custom::*;
+
 use crate::{
     context::Context,
     token::{LogitBias, Token, TokenDataVec},
@@ -12,6 +16,9 @@ pub struct Sampler {
     raw: NonNull<llama_cpp_sys::llama_sampler>,
 }
 
+// 采样器只保存自己的状态，没有绑定在创建它的线程上，可以交给其它线程中的调度器使用
+unsafe impl Send for Sampler {}
+
 impl Sampler {
     pub fn raw_mut(&self) -> *mut llama_cpp_sys::llama_sampler {
         self.raw.as_ptr()
@@ -0,0 +1,624 @@
+//! 连续批处理：多个生成请求共享同一个上下文
+//!
+//! 每个请求占用上下文中的一个序列，所有序列的提示词和生成的 token 打包到同一个批次中解码，
+//! 每个序列使用自己的采样器。一个请求结束之后立即释放它占用的序列，等待中的请求可以在下一步开始处理，
+//! 不需要等待其它序列结束
+//!
+//! 请求结束之后序列中的 KV cache 会被保留，新的请求优先分配到和提示词公共前缀最长的空闲序列，
+//! 相同的系统提示词和多轮对话中之前的内容不需要重新解码
+
+use crate::{
+    Result, batch::Batch, context::Context, model::Model, prefix_cache::PrefixCache,
+    sampler::Sampler, token::Token,
+};
+use snafu::prelude::*;
+use std::{cmp::Reverse, collections::VecDeque};
+
+#[derive(Debug, Snafu)]
+#[snafu(visibility(pub(crate)))]
+pub enum SchedulerError {
+    #[snafu(display("The prompt is empty"))]
+    EmptyPrompt,
+    #[snafu(display(
+        "The prompt has {n_prompt} tokens, but each sequence can only hold {n_ctx_seq} tokens"
+    ))]
+    PromptTooLong { n_prompt: usize, n_ctx_seq: u32 },
+}
+
+/// 请求的编号，由调度器在提交时分配
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct RequestId(u64);
+
+/// 一个生成请求
+pub struct Request {
+    /// 提示词的 token
+    pub prompt: Vec<Token>,
+    /// 这个请求使用的采样器
+    pub sampler: Sampler,
+    /// 最多生成的 token 数量，为 None 时生成到序列的上下文用完为止
+    pub max_tokens: Option<u32>,
+}
+
+/// 请求结束的原因
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum FinishReason {
+    /// 生成了结束的 token
+    Eog,
+    /// 达到了 max_tokens 或者序列的上下文用完了
+    Length,
+    /// 请求被取消
+    Cancelled,
+}
+
+/// 每一步产生的事件
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Event {
+    /// 请求生成了一个 token
+    Token { id: RequestId, token: Token },
+    /// 请求结束，占用的序列已经释放
+    Finished {
+        id: RequestId,
+        reason: FinishReason,
+        n_prompt: usize,
+        /// 提示词中复用序列中已有的 KV cache，没有重新解码的 token 数量
+        n_cached: usize,
+        n_generated: u32,
+    },
+}
+
+// 一个正在处理的请求
+struct Slot {
+    id: RequestId,
+    prompt: Vec<Token>,
+    sampler: Sampler,
+    max_tokens: Option<u32>,
+    n_cached: usize,
+    // 已经放入批次的提示词 token 数量，提示词比批次长时分多步处理
+    n_prompt_queued: usize,
+    // 下一个 token 在序列中的位置
+    n_past: u32,
+    n_generated: u32,
+    // 上一步采样的 token，下一步需要解码
+    next: Option<Token>,
+    // 需要采样的 logits 在当前批次中的下标
+    i_batch: Option<i32>,
+}
+
+impl Slot {
+    fn finished(&self, reason: FinishReason) -> Event {
+        Event::Finished {
+            id: self.id,
+            reason,
+            n_prompt: self.prompt.len(),
+            n_cached: self.n_cached,
+            n_generated: self.n_generated,
+        }
+    }
+}
+
+/// 连续批处理的调度器
+///
+/// 上下文的 n_seq_max 决定了同时处理的请求数量，每个序列可以使用 n_ctx / n_seq_max 个 token，
+/// 超出的请求在队列中等待。调用 [`Scheduler::step`] 解码一个批次，直到 [`Scheduler::is_idle`] 为 true
+pub struct Scheduler {
+    context: Context,
+    batch: Batch,
+    slots: Slots,
+}
+
+impl Scheduler {
+    /// 创建调度器，上下文中原有的 KV cache 会被清空
+    #[must_use]
+    pub fn new(mut context: Context) -> Self {
+        context.clear_kv_cache(true);
+        let n_seq_max = context.n_seq_max().max(1);
+        let n_batch = context.n_batch().max(1);
+        let n_ctx_seq = context.n_ctx() / n_seq_max;
+        Self {
+            context,
+            batch: Batch::new(n_batch as i32, 1),
+            slots: Slots::new(n_seq_max as usize, n_batch as usize, n_ctx_seq),
+        }
+    }
+
+    pub fn context(&self) -> &Context {
+        &self.context
+    }
+
+    /// 取回上下文，未完成的请求会被丢弃
+    #[must_use]
+    pub fn into_context(self) -> Context {
+        self.context
+    }
+
+    /// 每个序列可以使用的 token 数量
+    #[must_use]
+    pub fn n_ctx_seq(&self) -> u32 {
+        self.slots.n_ctx_seq
+    }
+
+    /// 正在处理的请求数量
+    #[must_use]
+    pub fn active(&self) -> usize {
+        self.slots.active()
+    }
+
+    /// 等待处理的请求数量
+    #[must_use]
+    pub fn pending(&self) -> usize {
+        self.slots.pending.len()
+    }
+
+    /// 没有正在处理和等待处理的请求，也没有未返回的事件
+    #[must_use]
+    pub fn is_idle(&self) -> bool {
+        self.slots.is_idle()
+    }
+
+    /// 提交一个请求，提示词为空或者超过了一个序列的上下文时返回错误
+    pub fn submit(&mut self, request: Request) -> Result<RequestId> {
+        self.slots.submit(request)
+    }
+
+    /// 取消请求，请求不存在或者已经结束时返回 false，结束的事件在下一步返回
+    pub fn cancel(&mut self, id: RequestId) -> bool {
+        self.slots.cancel(id)
+    }
+
+    /// 取消全部的请求并清空 KV cache，例如解码失败之后
+    pub fn cancel_all(&mut self) {
+        self.slots.cancel_all();
+        self.context.clear_kv_cache(true);
+    }
+
+    /// 解码一个批次，返回这一步产生的事件
+    ///
+    /// 生成阶段的序列每个放入一个 token，剩余的空间按照顺序放入提示词，
+    /// 只有提示词的最后一个 token 需要输出 logits
+    pub fn step(&mut self, model: &Model) -> Result<Vec<Event>> {
+        self.assign_pending()?;
+        self.slots.fill(&mut self.batch)?;
+        if self.batch.n_tokens() > 0 {
+            self.context.decode(&mut self.batch)?;
+        }
+        let vocab = model.vocab();
+        let context = &self.context;
+        self.slots.sample(
+            |sampler, i_batch| sampler.sample(context, i_batch),
+            |token| vocab.is_eog_token(token),
+        );
+        Ok(self.slots.take_events())
+    }
+
+    // 将等待中的请求放入空闲的序列，丢弃序列中和提示词不一致的 KV cache
+    fn assign_pending(&mut self) -> Result<()> {
+        for (seq_id, n_reuse) in self.slots.assign() {
+            let seq = Some(seq_id as u32);
+            // 有的模型（例如循环模型）不支持只删除序列的一部分，只能从头开始
+            if !self
+                .context
+                .clear_kv_cache_seq(seq, Some(n_reuse as u32), None)?
+            {
+                self.context.clear_kv_cache_seq(seq, None, None)?;
+                self.slots.restart(seq_id);
+            }
+        }
+        Ok(())
+    }
+}
+
+// 调度的状态：请求分配到序列、打包批次和结束请求，不依赖上下文，KV cache 由 Scheduler 处理
+struct Slots {
+    n_batch: usize,
+    n_ctx_seq: u32,
+    slots: Vec<Option<Slot>>,
+    pending: VecDeque<(RequestId, Request)>,
+    // 两步之间产生的事件，例如取消请求，在下一步返回
+    events: Vec<Event>,
+    next_id: u64,
+    // 每个序列在 KV cache 中的 token，请求结束之后保留，用于复用相同的前缀
+    prefix_cache: PrefixCache,
+}
+
+impl Slots {
+    fn new(n_seq_max: usize, n_batch: usize, n_ctx_seq: u32) -> Self {
+        Self {
+            n_batch,
+            n_ctx_seq,
+            slots: (0..n_seq_max).map(|_| None).collect(),
+            pending: VecDeque::new(),
+            events: Vec::new(),
+            next_id: 0,
+            prefix_cache: PrefixCache::new(),
+        }
+    }
+
+    fn active(&self) -> usize {
+        self.slots.iter().flatten().count()
+    }
+
+    fn is_idle(&self) -> bool {
+        self.pending.is_empty() && self.events.is_empty() && self.active() == 0
+    }
+
+    fn submit(&mut self, request: Request) -> Result<RequestId> {
+        let n_prompt = request.prompt.len();
+        ensure!(n_prompt > 0, EmptyPromptSnafu);
+        ensure!(
+            n_prompt < self.n_ctx_seq as usize,
+            PromptTooLongSnafu {
+                n_prompt,
+                n_ctx_seq: self.n_ctx_seq
+            }
+        );
+        let id = RequestId(self.next_id);
+        self.next_id += 1;
+        self.pending.push_back((id, request));
+        Ok(id)
+    }
+
+    fn cancel(&mut self, id: RequestId) -> bool {
+        if let Some(index) = self.pending.iter().position(|(pending, _)| *pending == id) {
+            self.pending.remove(index);
+            self.events.push(cancelled(id));
+            return true;
+        }
+        let Some(seq_id) = self
+            .slots
+            .iter()
+            .position(|slot| slot.as_ref().is_some_and(|slot| slot.id == id))
+        else {
+            return false;
+        };
+        self.free(seq_id, FinishReason::Cancelled);
+        true
+    }
+
+    // 取消全部的请求，KV cache 被清空之后缓存的 token 不再可信
+    fn cancel_all(&mut self) {
+        let pending = self.pending.drain(..).map(|(id, _)| cancelled(id));
+        self.events.extend(pending);
+        for seq_id in 0..self.slots.len() {
+            self.free(seq_id, FinishReason::Cancelled);
+        }
+        self.prefix_cache.clear(None);
+    }
+
+    // 将等待中的请求分配到空闲的序列，优先使用缓存的 token 和提示词公共前缀最长的序列，
+    // 返回分配的序列以及可以复用的 token 数量
+    fn assign(&mut self) -> Vec<(usize, usize)> {
+        let mut assigned = Vec::new();
+        while let Some((_, request)) = self.pending.front() {
+            let Some((seq_id, n_common)) = self
+                .slots
+                .iter()
+                .enumerate()
+                .filter(|(_, slot)| slot.is_none())
+                .map(|(seq_id, _)| {
+                    let n_common = self
+                        .prefix_cache
+                        .common_prefix_len(seq_id as i32, &request.prompt);
+                    (seq_id, n_common)
+                })
+                .max_by_key(|&(seq_id, n_common)| (n_common, Reverse(seq_id)))
+            else {
+                break;
+            };
+            let Some((id, request)) = self.pending.pop_front() else {
+                break;
+            };
+            // 至少保留提示词的最后一个 token 需要解码，这样才能得到采样需要的 logits
+            let n_reuse = n_common.min(request.prompt.len() - 1);
+            self.prefix_cache
+                .discard(seq_id as i32, n_reuse..usize::MAX);
+            self.slots[seq_id] = Some(Slot {
+                id,
+                prompt: request.prompt,
+                sampler: request.sampler,
+                max_tokens: request.max_tokens,
+                n_cached: n_reuse,
+                n_prompt_queued: n_reuse,
+                n_past: n_reuse as u32,
+                n_generated: 0,
+                next: None,
+                i_batch: None,
+            });
+            assigned.push((seq_id, n_reuse));
+        }
+        assigned
+    }
+
+    // 序列中的 KV cache 已经全部删除，从头开始解码提示词
+    fn restart(&mut self, seq_id: usize) {
+        self.prefix_cache.clear(Some(seq_id as i32));
+        if let Some(slot) = &mut self.slots[seq_id] {
+            slot.n_cached = 0;
+            slot.n_prompt_queued = 0;
+            slot.n_past = 0;
+        }
+    }
+
+    // 生成阶段的序列每个放入一个 token，剩余的空间按照顺序放入提示词
+    fn fill(&mut self, batch: &mut Batch) -> Result<()> {
+        batch.clear();
+        for (seq_id, slot) in self.slots.iter_mut().enumerate() {
+            let Some(slot) = slot else {
+                continue;
+            };
+            slot.i_batch = None;
+            if batch.n_tokens() as usize >= self.n_batch {
+                continue;
+            }
+            let Some(token) = slot.next.take() else {
+                continue;
+            };
+            batch.add(token, slot.n_past as i32, &[seq_id as i32], true)?;
+            self.prefix_cache.extend(seq_id as i32, &[token]);
+            slot.i_batch = Some(batch.n_tokens() - 1);
+            slot.n_past += 1;
+        }
+        for (seq_id, slot) in self.slots.iter_mut().enumerate() {
+            let Some(slot) = slot else {
+                continue;
+            };
+            let space = self.n_batch - batch.n_tokens() as usize;
+            if space == 0 {
+                break;
+            }
+            let rest = &slot.prompt[slot.n_prompt_queued..];
+            let n = rest.len().min(space);
+            for (i, token) in rest[..n].iter().enumerate() {
+                let last = slot.n_prompt_queued + i + 1 == slot.prompt.len();
+                batch.add(*token, slot.n_past as i32, &[seq_id as i32], last)?;
+                slot.n_past += 1;
+                if last {
+                    slot.i_batch = Some(batch.n_tokens() - 1);
+                }
+            }
+            self.prefix_cache.extend(seq_id as i32, &rest[..n]);
+            slot.n_prompt_queued += n;
+        }
+        Ok(())
+    }
+
+    // 解码之后为输出了 logits 的序列采样，结束的请求释放占用的序列
+    fn sample(
+        &mut self,
+        mut sample: impl FnMut(&mut Sampler, i32) -> Token,
+        is_eog: impl Fn(Token) -> bool,
+    ) {
+        let mut finished = Vec::new();
+        for (seq_id, slot) in self.slots.iter_mut().enumerate() {
+            let Some(slot) = slot else {
+                continue;
+            };
+            let Some(i_batch) = slot.i_batch.take() else {
+                continue;
+            };
+            let token = sample(&mut slot.sampler, i_batch);
+            if is_eog(token) {
+                finished.push((seq_id, FinishReason::Eog));
+                continue;
+            }
+            self.events.push(Event::Token { id: slot.id, token });
+            slot.n_generated += 1;
+            slot.next = Some(token);
+            // 下一个 token 没有位置可以放入序列时也需要结束
+            if slot.max_tokens.is_some_and(|max| slot.n_generated >= max)
+                || slot.n_past >= self.n_ctx_seq
+            {
+                finished.push((seq_id, FinishReason::Length));
+            }
+        }
+        for (seq_id, reason) in finished {
+            self.free(seq_id, reason);
+        }
+    }
+
+    // 结束一个请求，释放它占用的序列，序列中的 KV cache 保留给之后的请求复用
+    fn free(&mut self, seq_id: usize, reason: FinishReason) {
+        if let Some(slot) = self.slots[seq_id].take() {
+            self.events.push(slot.finished(reason));
+        }
+    }
+
+    fn take_events(&mut self) -> Vec<Event> {
+        std::mem::take(&mut self.events)
+    }
+}
+
+// 还没有开始处理就被取消的请求
+fn cancelled(id: RequestId) -> Event {
+    Event::Finished {
+        id,
+        reason: FinishReason::Cancelled,
+        n_prompt: 0,
+        n_cached: 0,
+        n_generated: 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EOG: i32 = 99;
+
+    fn request(prompt: &[i32], max_tokens: Option<u32>) -> Request {
+        Request {
+            prompt: prompt.iter().copied().map(Token::new).collect(),
+            sampler: Sampler::init_from_greedy(),
+            max_tokens,
+        }
+    }
+
+    // 批次中的 token、位置、序列以及是否输出 logits
+    fn entries(batch: &Batch) -> Vec<(i32, i32, i32, bool)> {
+        let raw = batch.raw();
+        (0..batch.n_tokens() as usize)
+            .map(|i| unsafe {
+                (
+                    *raw.token.add(i),
+                    *raw.pos.add(i),
+                    **raw.seq_id.add(i),
+                    *raw.logits.add(i) != 0,
+                )
+            })
+            .collect()
+    }
+
+    fn finished_ids(events: &[Event]) -> Vec<(RequestId, FinishReason)> {
+        events
+            .iter()
+            .filter_map(|event| match event {
+                Event::Finished { id, reason, .. } => Some((*id, *reason)),
+                Event::Token { .. } => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_assign_slots() {
+        let mut slots = Slots::new(2, 16, 64);
+        let first = slots.submit(request(&[1, 2, 3], None)).unwrap();
+        let second = slots.submit(request(&[4, 5], None)).unwrap();
+        let third = slots.submit(request(&[6], None)).unwrap();
+        assert!(slots.submit(request(&[], None)).is_err());
+        assert!(slots.submit(request(&[0; 64], None)).is_err());
+
+        // 请求按照提交的顺序占用空闲的序列，超过 n_seq_max 的请求在队列中等待
+        assert_eq!(slots.assign(), vec![(0, 0), (1, 0)]);
+        assert_eq!(slots.slots[0].as_ref().map(|slot| slot.id), Some(first));
+        assert_eq!(slots.slots[1].as_ref().map(|slot| slot.id), Some(second));
+        assert_eq!(slots.active(), 2);
+        assert_eq!(slots.pending.len(), 1);
+        assert_eq!(slots.assign(), vec![]);
+
+        // 取消等待中的请求，结束的事件在下一步返回
+        assert!(slots.cancel(third));
+        assert!(!slots.cancel(third));
+        assert_eq!(
+            finished_ids(&slots.take_events()),
+            vec![(third, FinishReason::Cancelled)]
+        );
+    }
+
+    #[test]
+    fn test_fill_batch() {
+        let mut slots = Slots::new(2, 8, 64);
+        let first = slots.submit(request(&[1, 2, 3, 4, 5], None)).unwrap();
+        let second = slots
+            .submit(request(&[11, 12, 13, 14, 15, 16], None))
+            .unwrap();
+        slots.assign();
+
+        // 第一个提示词完整放入批次，第二个提示词只放入剩余的空间，只有提示词的最后一个 token 输出 logits
+        let mut batch = Batch::new(8, 1);
+        slots.fill(&mut batch).unwrap();
+        assert_eq!(
+            entries(&batch),
+            vec![
+                (1, 0, 0, false),
+                (2, 1, 0, false),
+                (3, 2, 0, false),
+                (4, 3, 0, false),
+                (5, 4, 0, true),
+                (11, 0, 1, false),
+                (12, 1, 1, false),
+                (13, 2, 1, false),
+            ]
+        );
+        let mut sampled = Vec::new();
+        slots.sample(
+            |_, i_batch| {
+                sampled.push(i_batch);
+                Token::new(7)
+            },
+            |token| token.raw() == EOG,
+        );
+        assert_eq!(sampled, vec![4]);
+        assert_eq!(
+            slots.take_events(),
+            vec![Event::Token {
+                id: first,
+                token: Token::new(7)
+            }]
+        );
+
+        // 生成的 token 和剩余的提示词打包到同一个批次中
+        slots.fill(&mut batch).unwrap();
+        assert_eq!(
+            entries(&batch),
+            vec![
+                (7, 5, 0, true),
+                (14, 3, 1, false),
+                (15, 4, 1, false),
+                (16, 5, 1, true),
+            ]
+        );
+        slots.sample(
+            |_, i_batch| Token::new(20 + i_batch),
+            |token| token.raw() == EOG,
+        );
+        assert_eq!(
+            slots.take_events(),
+            vec![
+                Event::Token {
+                    id: first,
+                    token: Token::new(20)
+                },
+                Event::Token {
+                    id: second,
+                    token: Token::new(23)
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_free_slot() {
+        let mut slots = Slots::new(1, 16, 64);
+        let first = slots.submit(request(&[1, 2, 3], None)).unwrap();
+        let second = slots.submit(request(&[1, 2, 4], Some(1))).unwrap();
+        let third = slots.submit(request(&[5, 6], None)).unwrap();
+        let mut batch = Batch::new(16, 1);
+
+        // 生成了结束的 token 之后释放序列，等待中的请求在下一步占用这个序列
+        assert_eq!(slots.assign(), vec![(0, 0)]);
+        slots.fill(&mut batch).unwrap();
+        slots.sample(|_, _| Token::new(EOG), |token| token.raw() == EOG);
+        assert!(slots.slots[0].is_none());
+        assert_eq!(
+            slots.take_events(),
+            vec![Event::Finished {
+                id: first,
+                reason: FinishReason::Eog,
+                n_prompt: 3,
+                n_cached: 0,
+                n_generated: 0,
+            }]
+        );
+
+        // 序列中保留的 KV cache 和提示词有公共前缀时只需要解码剩余的部分
+        assert_eq!(slots.assign(), vec![(0, 2)]);
+        slots.fill(&mut batch).unwrap();
+        assert_eq!(entries(&batch), vec![(4, 2, 0, true)]);
+        slots.sample(|_, _| Token::new(8), |token| token.raw() == EOG);
+        assert_eq!(
+            finished_ids(&slots.take_events()),
+            vec![(second, FinishReason::Length)]
+        );
+
+        // 取消正在处理的请求同样会释放序列
+        assert_eq!(slots.assign(), vec![(0, 0)]);
+        slots.fill(&mut batch).unwrap();
+        assert!(slots.cancel(third));
+        assert!(slots.slots[0].is_none());
+        assert!(!slots.cancel(third));
+        assert_eq!(
+            finished_ids(&slots.take_events()),
+            vec![(third, FinishReason::Cancelled)]
+        );
+        assert!(slots.is_idle());
+    }
+}
@@ -1,6 +1,6 @@
 use super::Token;
 use crate::sampler::Sampler;
-use std::ptr;
+use std::{ptr, slice};
 
 /// `llama_token_data` 的包装
 #[derive(Clone, Copy, Debug)]
@@ -74,6 +74,85 @@ impl TokenDataVec {
         self.raw.get(self.selected?).map(TokenData::id)
     }
 
+    #[must_use]
+    pub fn data(&self) -> &[TokenData] {
+        &self.raw
+    }
+
+    pub fn data_mut(&mut self) -> &mut [TokenData] {
+        &mut self.raw
+    }
+
+    /// 只保留前 len 个候选 token
+    pub fn truncate(&mut self, len: usize) {
+        self.raw.truncate(len);
+        self.selected = self.selected.filter(|&s| s < len);
+    }
+
+    #[must_use]
+    pub fn selected(&self) -> Option<usize> {
+        self.selected
+    }
+
+    pub fn set_selected(&mut self, selected: Option<usize>) {
+        self.selected = selected.filter(|&s| s < self.raw.len());
+    }
+
+    #[must_use]
+    pub fn is_sorted(&self) -> bool {
+        self.sorted
+    }
+
+    pub fn set_sorted(&mut self, sorted: bool) {
+        self.sorted = sorted;
+    }
+
+    /// 复制 llama.cpp 传入的候选 token
+    ///
+    /// # Safety
+    ///
+    /// `data_array.data` 需要指向 `data_array.size` 个有效的 `llama_token_data`
+    pub(crate) unsafe fn from_llama_token_data_array(
+        data_array: &llama_cpp_sys::llama_token_data_array,
+    ) -> Self {
+        let data = if data_array.size == 0 {
+            &[]
+        } else {
+            unsafe { slice::from_raw_parts(data_array.data.cast::<TokenData>(), data_array.size) }
+        };
+        let mut vec = Self::new(data.to_vec(), data_array.sorted);
+        vec.set_selected(data_array.selected.try_into().ok());
+        vec
+    }
+
+    /// 将候选 token 写回 llama.cpp 的数组，数组的缓冲区不能扩大，超出原来数量的部分会被丢弃
+    ///
+    /// # Safety
+    ///
+    /// `data_array.data` 需要指向 `data_array.size` 个可写的 `llama_token_data`
+    pub(crate) unsafe fn write_to_llama_token_data_array(
+        &self,
+        data_array: &mut llama_cpp_sys::llama_token_data_array,
+    ) {
+        let size = self.raw.len().min(data_array.size);
+        if size > 0 {
+            unsafe {
+                ptr::copy_nonoverlapping(
+                    self.raw.as_ptr().cast::<llama_cpp_sys::llama_token_data>(),
+                    data_array.data,
+                    size,
+                );
+            }
+        }
+        data_array.size = size;
+        data_array.sorted = self.sorted;
+        data_array.selected = self
+            .selected
+            .filter(|&s| s < size)
+            .and_then(|s| s.try_into().ok())
+            .unwrap_or(-1);
+    }
+
     pub(crate) unsafe fn modify_by_llama_token_data_array<T>(
         &mut self,
         modify_fn: impl FnOnce(&mut llama_cpp_sys::llama_token_data_array) -> T,
@@ -97,7 +176,10 @@ impl TokenDataVec {
             data_array.size <= self.raw.capacity(),
             "Size of the returned array exceeds the data buffer's capacity!"
         );
-        if !ptr::eq(data_array.data, data) {
+        // 采样器可能直接在原来的缓冲区中减少候选 token 的数量
+        if ptr::eq(data_array.data, data) {
+            self.raw.truncate(data_array.size);
+        } else {
             unsafe {
                 ptr::copy(data_array.data, data, data_array.size);
                 self.raw.set_len(data_array.size);
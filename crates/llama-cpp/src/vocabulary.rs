@@ -65,6 +65,12 @@ impl Vocabulary {
         unsafe { llama_cpp_sys::llama_token_eos(self.raw_mut()) }.into()
     }
 
+    /// 分词时是否会在开头添加 BOS
+    #[must_use]
+    pub fn add_bos(&self) -> bool {
+        unsafe { llama_cpp_sys::llama_vocab_get_add_bos(self.raw_mut()) }
+    }
+
     #[must_use]
     pub fn token_nl(&self) -> Token {
         unsafe { llama_cpp_sys::llama_token_nl(self.raw_mut()) }.into()
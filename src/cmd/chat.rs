@@ -0,0 +1,264 @@
+//! 管理 simple-run 中保存的对话
+
+use crate::{
+    cmd::{
+        search::{fts5_query, highlight_marks},
+        simple_run::{RunOptions, chat_with_model},
+    },
+    config::{Config as LLamaBuddyConfig, Data},
+    db,
+    db::chat::{ChatMessage, ChatSearchHit, ChatSummary},
+    utils::format::format_table,
+};
+use clap::{Args, Subcommand, ValueEnum};
+use serde::Serialize;
+use serde_json::Value;
+use std::{fs, path::PathBuf};
+use tracing::{error, info};
+
+pub async fn manage_chats(args: ChatArgs) {
+    // 获取配置
+    let (
+        LLamaBuddyConfig {
+            data: Data { path: data_path },
+            ..
+        },
+        _,
+    ) = LLamaBuddyConfig::try_config_path().expect("Couldn't get the config");
+    let sqlite_dir = data_path.join("sqlite");
+    let conn = db::open_llama_buddy_db(sqlite_dir).expect("Couldn't open sqlite file");
+    match args.command {
+        ChatCommand::List(ChatListArgs { limit, json }) => {
+            let chats = db::chat::query_chat_list(&conn, limit).expect("Couldn't query chat list");
+            if json {
+                let json = serde_json::to_string_pretty(&chats).expect("Couldn't serialize chats");
+                println!("{json}");
+            } else {
+                print_chats(&chats);
+            }
+        }
+        ChatCommand::Resume(ChatResumeArgs { id, options }) => {
+            // 检查一下有没有完成初始化，没有完成初始化，不能运行模型
+            if !db::check_llama_buddy_init_completed(&conn)
+                .expect("Couldn't check init whatever completed")
+            {
+                error!("Initialization should be ensured to be completed");
+                return;
+            }
+            let Some(chat) = db::chat::query_chat(&conn, id).expect("Couldn't query the chat")
+            else {
+                error!("There is no chat with id {id}");
+                return;
+            };
+            let model_name = chat.model_name.clone();
+            chat_with_model(&conn, &data_path, &model_name, options, Some(chat));
+        }
+        ChatCommand::Search(ChatSearchArgs { query, limit, json }) => {
+            let (open, close) = highlight_marks(json);
+            let hits = db::chat::search_chat_message(&conn, fts5_query(&query), open, close, limit)
+                .expect("Couldn't search chat messages");
+            if json {
+                let json = serde_json::to_string_pretty(&hits).expect("Couldn't serialize hits");
+                println!("{json}");
+            } else {
+                print_hits(&hits);
+            }
+        }
+        ChatCommand::Export(ChatExportArgs { id, format, output }) => {
+            let Some(chat) = db::chat::query_chat(&conn, id).expect("Couldn't query the chat")
+            else {
+                error!("There is no chat with id {id}");
+                return;
+            };
+            let messages =
+                db::chat::query_chat_messages(&conn, id).expect("Couldn't query chat messages");
+            let exported = match format {
+                ExportFormat::Markdown => to_markdown(&chat, &messages),
+                ExportFormat::Json => {
+                    let exported = ChatExport::new(chat, messages);
+                    serde_json::to_string_pretty(&exported).expect("Couldn't serialize the chat")
+                }
+            };
+            match output {
+                Some(path) => {
+                    fs::write(&path, exported).expect("Couldn't write the exported chat");
+                    info!("The chat {id} was exported to {}", path.display());
+                }
+                None => println!("{exported}"),
+            }
+        }
+    }
+}
+
+fn print_chats(chats: &[ChatSummary]) {
+    let header = ["ID", "TITLE", "MODEL", "MESSAGES", "TOKENS", "UPDATED"];
+    let rows = chats
+        .iter()
+        .map(|chat| {
+            [
+                chat.id.to_string(),
+                chat.title.clone(),
+                chat.model_name.clone(),
+                chat.n_messages.to_string(),
+                chat.n_tokens.to_string(),
+                chat.updated_time.clone(),
+            ]
+        })
+        .collect::<Vec<_>>();
+    print!("{}", format_table(header, &rows));
+}
+
+fn print_hits(hits: &[ChatSearchHit]) {
+    if hits.is_empty() {
+        println!("No messages matched");
+        return;
+    }
+    for (index, hit) in hits.iter().enumerate() {
+        if index > 0 {
+            println!();
+        }
+        println!(
+            "[{}] {} ({}, {})",
+            hit.chat_id, hit.title, hit.model_name, hit.created_time
+        );
+        let snippet = hit.snippet.split_whitespace().collect::<Vec<_>>().join(" ");
+        println!("  {}: {snippet}", hit.role);
+    }
+}
+
+fn to_markdown(chat: &ChatSummary, messages: &[ChatMessage]) -> String {
+    let mut markdown = format!(
+        "# {}\n\n- Model: {}\n- Created: {}\n- Updated: {}\n- Sampler: `{}`\n",
+        chat.title, chat.model_name, chat.created_time, chat.updated_time, chat.sampler
+    );
+    for message in messages {
+        let role = match message.role.as_str() {
+            "system" => "System",
+            "user" => "User",
+            "assistant" => "Assistant",
+            role => role,
+        };
+        markdown.push_str(&format!("\n## {role}\n\n{}\n", message.content.trim_end()));
+    }
+    markdown
+}
+
+// 导出为 JSON 的对话
+#[derive(Debug, Serialize)]
+struct ChatExport {
+    id: i64,
+    title: String,
+    model_name: String,
+    sampler: Value,
+    created_time: String,
+    updated_time: String,
+    messages: Vec<ChatMessage>,
+}
+
+impl ChatExport {
+    fn new(chat: ChatSummary, messages: Vec<ChatMessage>) -> Self {
+        let ChatSummary {
+            id,
+            title,
+            model_name,
+            sampler,
+            created_time,
+            updated_time,
+            ..
+        } = chat;
+        Self {
+            id,
+            title,
+            model_name,
+            sampler: serde_json::from_str(&sampler).unwrap_or(Value::String(sampler)),
+            created_time,
+            updated_time,
+            messages,
+        }
+    }
+}
+
+#[derive(Args)]
+pub struct ChatArgs {
+    #[command(subcommand)]
+    pub command: ChatCommand,
+}
+
+#[derive(Subcommand)]
+pub enum ChatCommand {
+    #[command(about = "List the saved chats, the most recently updated first")]
+    List(ChatListArgs),
+    #[command(about = "Continue a saved chat with the model it was created with")]
+    Resume(ChatResumeArgs),
+    #[command(about = "Search the messages of the saved chats")]
+    Search(ChatSearchArgs),
+    #[command(about = "Export a saved chat")]
+    Export(ChatExportArgs),
+}
+
+#[derive(Args)]
+pub struct ChatListArgs {
+    #[arg(
+        short = 'l',
+        long = "limit",
+        default_value_t = 20,
+        help = "The maximum number of chats to show"
+    )]
+    pub limit: usize,
+    #[arg(long = "json", help = "Output the chats in JSON format")]
+    pub json: bool,
+}
+
+#[derive(Args)]
+pub struct ChatResumeArgs {
+    #[arg(help = "The id of the chat")]
+    pub id: i64,
+    #[command(flatten)]
+    pub options: RunOptions,
+}
+
+#[derive(Args)]
+pub struct ChatSearchArgs {
+    #[arg(help = "The keywords to search, both Chinese and English are supported")]
+    pub query: String,
+    #[arg(
+        short = 'l',
+        long = "limit",
+        default_value_t = 10,
+        help = "The maximum number of messages to show"
+    )]
+    pub limit: usize,
+    #[arg(long = "json", help = "Output the results in JSON format")]
+    pub json: bool,
+}
+
+#[derive(Args)]
+pub struct ChatExportArgs {
+    #[arg(help = "The id of the chat")]
+    pub id: i64,
+    #[arg(
+        value_enum,
+        short = 'f',
+        long = "format",
+        default_value = "markdown",
+        help = "The format of the exported chat"
+    )]
+    pub format: ExportFormat,
+    #[arg(
+        short = 'o',
+        long = "output",
+        help = "Write the exported chat to the file instead of stdout"
+    )]
+    pub output: Option<PathBuf>,
+}
+
+/// 导出对话的格式
+#[derive(Clone, Copy, Debug, Eq, PartialEq, ValueEnum)]
+pub enum ExportFormat {
+    /// Markdown 文档
+    #[value(help = "A Markdown document, one section per message")]
+    Markdown,
+    /// JSON 对象
+    #[value(help = "A JSON object with the messages and their token counts")]
+    Json,
+}
@@ -4,6 +4,7 @@ use crate::{
     config::{Config as LLamaBuddyConfig, Data, HttpClient as HttpClientConfig, Registry},
     db::CompletedStatus,
     service,
+    utils::progress::{ProgressDisplay, ProgressUnit},
 };
 use clap::Args;
 use std::{fs, path::PathBuf, sync::Arc};
@@ -25,6 +26,7 @@ pub async fn init_local_registry(args: InitArgs) {
             registry:
                 Registry {
                     remote,
+                    auth,
                     client: client_config,
                 },
             model,
@@ -56,7 +58,11 @@ pub async fn init_local_registry(args: InitArgs) {
     {
         info!("Initialization completed");
     }
-    match service::model::try_save_model_info(Arc::clone(&conn), client, remote.clone()).await {
+    // 展示获取模型详情的进度
+    let progress = ProgressDisplay::new().add("registry", ProgressUnit::Items);
+    match service::model::try_save_model_info(Arc::clone(&conn), client, remote.clone(), progress)
+        .await
+    {
         Ok(_) => {
             // 如果成功，那么将初始化状态设置成完成，后续的流程应该以这个状态为准
             service::init::completed_init(Arc::clone(&conn), CompletedStatus::Completed)
@@ -77,6 +83,7 @@ pub async fn init_local_registry(args: InitArgs) {
             data: Data { path: data_path },
             registry: Registry {
                 client: client_config,
+                auth,
                 remote,
             },
             model,
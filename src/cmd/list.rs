@@ -0,0 +1,117 @@
+//! 列出本地注册表中的模型
+
+use crate::{
+    config::{Config as LLamaBuddyConfig, Data},
+    db,
+    db::model::ModelSummary,
+    utils::format::{format_table, human_readable_size},
+};
+use clap::{Args, ValueEnum};
+use tracing::info;
+
+pub async fn list_local_models(args: ListArgs) {
+    let ListArgs {
+        all,
+        sort,
+        reverse,
+        json,
+        ..
+    } = args;
+    // 获取配置
+    let (
+        LLamaBuddyConfig {
+            data: Data { path: data_path },
+            ..
+        },
+        _,
+    ) = LLamaBuddyConfig::try_config_path().expect("Couldn't get the config");
+    let sqlite_dir = data_path.join("sqlite");
+    let conn = db::open_llama_buddy_db(sqlite_dir).expect("Couldn't open sqlite file");
+    // 检查一下有没有完成初始化，没有完成初始化，注册表中没有模型
+    if !db::check_llama_buddy_init_completed(&conn).expect("Couldn't check init whatever completed")
+    {
+        info!("Initialization should be ensured to be completed");
+        return;
+    }
+    let mut models = db::model::query_model_list(&conn, all).expect("Couldn't query model list");
+    sort_models(&mut models, sort, reverse);
+    if json {
+        let json = serde_json::to_string_pretty(&models).expect("Couldn't serialize model list");
+        println!("{json}");
+    } else {
+        print_table(&models);
+    }
+}
+
+fn sort_models(models: &mut [ModelSummary], sort: SortBy, reverse: bool) {
+    match sort {
+        SortBy::Name => models.sort_by(|a, b| (&a.name, &a.category).cmp(&(&b.name, &b.category))),
+        SortBy::Size => models.sort_by_key(|model| model.size),
+        SortBy::Updated => models.sort_by_key(|model| model.updated_at),
+    }
+    if reverse {
+        models.reverse();
+    }
+}
+
+fn print_table(models: &[ModelSummary]) {
+    let header = [
+        "NAME", "CATEGORY", "STATUS", "SIZE", "CONTEXT", "INPUT", "UPDATED",
+    ];
+    let rows = models
+        .iter()
+        .map(|model| {
+            [
+                model.name.clone(),
+                model.category.clone(),
+                model.pull_status.clone(),
+                human_readable_size(model.size),
+                model.context.clone().unwrap_or_default(),
+                model.input.clone().unwrap_or_default(),
+                model.updated_time.clone(),
+            ]
+        })
+        .collect::<Vec<_>>();
+    print!("{}", format_table(header, &rows));
+}
+
+#[derive(Args)]
+pub struct ListArgs {
+    #[arg(
+        long = "pulled",
+        conflicts_with = "all",
+        help = "Only list the models that have been pulled to local, this is the default behavior"
+    )]
+    pub pulled: bool,
+    #[arg(
+        short = 'a',
+        long = "all",
+        help = "List all the models in the local registry, including the models that have not been pulled"
+    )]
+    pub all: bool,
+    #[arg(
+        value_enum,
+        long = "sort",
+        default_value = "name",
+        help = "Sort the models by the field"
+    )]
+    pub sort: SortBy,
+    #[arg(short = 'r', long = "reverse", help = "Reverse the order of the sort")]
+    pub reverse: bool,
+    #[arg(long = "json", help = "Output the models in JSON format")]
+    pub json: bool,
+}
+
+/// 模型列表的排序字段
+#[derive(Clone, Copy, Debug, Eq, PartialEq, ValueEnum)]
+pub enum SortBy {
+    /// 按照模型名字和规格排序
+    #[value(help = "Sort by the name and category of the model")]
+    Name,
+    /// 按照本地文件的大小排序
+    #[value(help = "Sort by the size of the local files")]
+    Size,
+    /// 按照最后更新的时间排序
+    #[value(help = "Sort by the last updated time")]
+    Updated,
+}
@@ -1,5 +1,14 @@
+pub mod chat;
 pub mod config;
 pub mod init;
+pub mod list;
+pub mod prune;
+pub mod ps;
 pub mod pull;
+pub mod rm;
+pub mod search;
+pub mod serve;
+pub mod show;
 pub mod simple_run;
 pub mod update;
+pub mod verify;
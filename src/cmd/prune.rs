@@ -0,0 +1,198 @@
+//! 清理模型目录中残留的文件
+
+use crate::{
+    config::{Config as LLamaBuddyConfig, Data},
+    db,
+    utils::{blob::BlobStore, file_lock::FileLock, format::human_readable_size},
+};
+use clap::Args;
+use rusqlite::Connection;
+use std::{
+    collections::HashSet,
+    fs::{TryLockError, canonicalize, read_dir, remove_dir, remove_file},
+    path::{Path, PathBuf},
+};
+use tracing::{error, info, warn};
+
+pub async fn prune_local_files(args: PruneArgs) {
+    let PruneArgs { dry_run } = args;
+    // 获取配置
+    let (
+        LLamaBuddyConfig {
+            data: Data { path: data_path },
+            ..
+        },
+        _,
+    ) = LLamaBuddyConfig::try_config_path().expect("Couldn't get the config");
+    let sqlite_dir = data_path.join("sqlite");
+    let conn = db::open_llama_buddy_db(sqlite_dir).expect("Couldn't open sqlite file");
+    // 检查一下有没有完成初始化，没有完成初始化，注册表中没有模型
+    if !db::check_llama_buddy_init_completed(&conn).expect("Couldn't check init whatever completed")
+    {
+        info!("Initialization should be ensured to be completed");
+        return;
+    }
+    let blob_store = BlobStore::new(&data_path).expect("Couldn't open the blob store");
+    let model_dir = data_path.join("model");
+    // 获取 blobs 目录的锁，避免清理正在下载的文件，预演时不修改任何文件，不需要获取锁
+    let file_lock = if dry_run {
+        match FileLock::is_held(blob_store.dir()) {
+            Ok(false) => (),
+            Ok(true) => info!("{} is in use", blob_store.dir().display()),
+            Err(e) => error!(
+                "Couldn't check the lock in {}, {e}",
+                blob_store.dir().display()
+            ),
+        }
+        None
+    } else {
+        let mut file_lock = FileLock::new(blob_store.dir());
+        file_lock.lock_or_panic();
+        blob_store
+            .migrate(&conn, &model_dir)
+            .expect("Couldn't migrate the model files to the blob store");
+        Some(file_lock)
+    };
+    // 注册表中引用的文件，统一转换为绝对路径再比较，避免同一个文件的路径写法不同导致误删
+    let referenced = db::model::query_all_model_file_paths(&conn)
+        .expect("Couldn't query all model file paths")
+        .into_iter()
+        .map(|path| normalize(&path))
+        .collect::<HashSet<_>>();
+    let mut reclaimed = prune_dir(&conn, blob_store.dir(), &referenced, dry_run);
+    drop(file_lock);
+    // 旧版本按照规格保存模型文件的目录
+    if model_dir.exists() {
+        for entry in read_dir(&model_dir).expect("Couldn't read the model directory") {
+            let dir = entry.expect("Couldn't read the directory entry").path();
+            if dir.is_dir() {
+                reclaimed += prune_legacy_dir(&conn, &dir, &referenced, dry_run);
+            }
+        }
+    }
+    if dry_run {
+        println!("Would reclaim {}", human_readable_size(reclaimed));
+    } else {
+        println!("Reclaimed {}", human_readable_size(reclaimed));
+    }
+}
+
+// 清理一个旧版本的模型目录，返回清理的文件大小
+fn prune_legacy_dir(
+    conn: &Connection,
+    dir: &Path,
+    referenced: &HashSet<PathBuf>,
+    dry_run: bool,
+) -> u64 {
+    // 锁被持有，说明有进程正在下载，不能清理
+    match FileLock::is_held(dir) {
+        Ok(false) => (),
+        Ok(true) => {
+            info!("{} is in use, skip it", dir.display());
+            return 0;
+        }
+        Err(e) => {
+            error!("Couldn't check the lock in {}, {e}", dir.display());
+            return 0;
+        }
+    }
+    // 获取锁，释放锁的时候会删除锁文件，预演时不修改任何文件，不需要获取锁
+    let file_lock = if dry_run {
+        None
+    } else {
+        let mut file_lock = FileLock::new(dir);
+        match file_lock.try_lock() {
+            Ok(_) => Some(file_lock),
+            Err(TryLockError::WouldBlock) => {
+                info!("{} is in use, skip it", dir.display());
+                return 0;
+            }
+            Err(TryLockError::Error(e)) => {
+                error!("Couldn't lock {}, {e}", dir.display());
+                return 0;
+            }
+        }
+    };
+    let reclaimed = prune_dir(conn, dir, referenced, dry_run);
+    // 释放锁之后，锁文件会被删除，模型目录为空时一并删除
+    drop(file_lock);
+    if !dry_run
+        && read_dir(dir).is_ok_and(|mut entries| entries.next().is_none())
+        && let Err(e) = remove_dir(dir)
+    {
+        warn!(
+            "Couldn't remove the empty directory({}), {e}",
+            dir.display()
+        );
+    }
+    reclaimed
+}
+
+// 清理目录中残留的文件，返回清理的文件大小，调用之前需要先获取目录的锁
+fn prune_dir(conn: &Connection, dir: &Path, referenced: &HashSet<PathBuf>, dry_run: bool) -> u64 {
+    let lock_path = dir.join(FileLock::file_name());
+    // 预演时没有进程持有锁，锁文件是之前的进程异常退出后残留的，获取锁之后，锁文件会在释放锁时被删除
+    if dry_run && lock_path.exists() && FileLock::is_held(dir).is_ok_and(|held| !held) {
+        println!("Would remove stale lock {}", lock_path.display());
+    }
+    let mut reclaimed = 0;
+    for entry in read_dir(dir).expect("Couldn't read the directory") {
+        let path = entry.expect("Couldn't read the directory entry").path();
+        let Some(file_name) = path.file_name().and_then(|name| name.to_str()) else {
+            continue;
+        };
+        if file_name == FileLock::file_name() || !path.is_file() {
+            continue;
+        }
+        let reason = if file_name.contains(".part") {
+            // 下载中断后残留的临时文件和分片进度文件
+            "orphaned part"
+        } else if !referenced.contains(&normalize(&path)) {
+            // 没有被任何模型引用的文件
+            "unreferenced blob"
+        } else {
+            continue;
+        };
+        let size = path.metadata().map(|m| m.len()).unwrap_or_default();
+        if dry_run {
+            println!(
+                "Would remove {reason} {} ({})",
+                path.display(),
+                human_readable_size(size)
+            );
+            reclaimed += size;
+            continue;
+        }
+        match remove_file(&path) {
+            Ok(_) => {
+                if let Err(e) = db::blob::delete_verified_blob_by_path(conn, &path) {
+                    warn!(
+                        "Couldn't delete the verified blob of {}, {e}",
+                        path.display()
+                    );
+                }
+                println!(
+                    "Removed {reason} {} ({})",
+                    path.display(),
+                    human_readable_size(size)
+                );
+                reclaimed += size;
+            }
+            Err(e) => error!("Couldn't remove the file({}), {e}", path.display()),
+        }
+    }
+    reclaimed
+}
+
+fn normalize(path: &Path) -> PathBuf {
+    canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+#[derive(Args)]
+pub struct PruneArgs {
+    #[arg(
+        long = "dry-run",
+        help = "Only show the files that would be removed, without removing them"
+    )]
+    pub dry_run: bool,
+}
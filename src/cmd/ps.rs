@@ -0,0 +1,104 @@
+//! 列出 HTTP 服务中常驻的模型
+
+use crate::utils::format::{format_table, human_readable_duration, human_readable_size};
+use chrono::{DateTime, Utc};
+use clap::Args;
+use reqwest::Client;
+use serde::Deserialize;
+use serde_json::Value;
+
+pub async fn list_running_models(args: PsArgs) {
+    let PsArgs { host, port, json } = args;
+    let url = format!("http://{host}:{port}/api/ps");
+    // 服务在本地，不需要经过代理
+    let client = Client::builder()
+        .no_proxy()
+        .build()
+        .expect("Couldn't build the reqwest client");
+    let response =
+        client.get(&url).send().await.expect(
+            "Couldn't connect to the server, make sure that `llama-buddy serve` is running",
+        );
+    let response = response
+        .error_for_status()
+        .expect("Couldn't get the running models")
+        .text()
+        .await
+        .expect("Couldn't read the running models");
+    if json {
+        let value = serde_json::from_str::<Value>(&response).expect("Invalid response");
+        let json = serde_json::to_string_pretty(&value).expect("Couldn't serialize the models");
+        println!("{json}");
+        return;
+    }
+    let RunningModels { models } =
+        serde_json::from_str(&response).expect("Couldn't parse the running models");
+    print_table(&models);
+}
+
+#[derive(Deserialize)]
+struct RunningModels {
+    models: Vec<RunningModel>,
+}
+
+#[derive(Deserialize)]
+struct RunningModel {
+    name: String,
+    size: u64,
+    #[serde(default)]
+    digest: String,
+    expires_at: Option<String>,
+    #[serde(default)]
+    in_flight: usize,
+}
+
+impl RunningModel {
+    // 正在处理请求、一直保留，或者还有多久卸载
+    fn until(&self) -> String {
+        if self.in_flight > 0 {
+            return format!("In use by {} requests", self.in_flight);
+        }
+        let Some(expires_at) = self
+            .expires_at
+            .as_deref()
+            .and_then(|at| DateTime::parse_from_rfc3339(at).ok())
+        else {
+            return "Forever".to_owned();
+        };
+        let remaining = (expires_at.with_timezone(&Utc) - Utc::now())
+            .to_std()
+            .unwrap_or_default();
+        format!("{} from now", human_readable_duration(remaining))
+    }
+}
+
+fn print_table(models: &[RunningModel]) {
+    let header = ["NAME", "ID", "SIZE", "UNTIL"];
+    let rows = models
+        .iter()
+        .map(|model| {
+            [
+                model.name.clone(),
+                model.digest.chars().take(12).collect(),
+                human_readable_size(model.size),
+                model.until(),
+            ]
+        })
+        .collect::<Vec<_>>();
+    print!("{}", format_table(header, &rows));
+}
+
+#[derive(Args)]
+pub struct PsArgs {
+    #[arg(long, default_value = "127.0.0.1", help = "The address of the server")]
+    pub host: String,
+    #[arg(
+        short = 'p',
+        long,
+        default_value = "11434",
+        help = "The port of the server"
+    )]
+    pub port: u16,
+    #[arg(long = "json", help = "Output the running models in JSON format")]
+    pub json: bool,
+}
@@ -1,54 +1,13 @@
 //！从远程仓库中拉取模型
 
 use crate::{
-    config::{
-        Config as LLamaBuddyConfig, Data, HttpClient as HttpClientConfig, HttpClient, Model,
-        Registry,
-    },
+    config::{Config as LLamaBuddyConfig, Data, HttpClient as HttpClientConfig, Model, Registry},
     db,
-    db::CompletedStatus,
-    service,
+    service::pull::Puller,
+    utils::{progress::ProgressDisplay, reference::ModelReference},
 };
 use clap::Args;
-use http_extra::{download, download::DownloadParam, retry, sha256::checksum};
-use reqwest::Client;
-use rusqlite::Connection;
-use serde::Deserialize;
-use std::{
-    fs::{File, OpenOptions, TryLockError, create_dir_all, remove_file},
-    path::{Path, PathBuf},
-};
-use tracing::{debug, info};
-use url::Url;
-
-struct FileLock {
-    path: PathBuf,
-    lock: File,
-}
-
-impl FileLock {
-    pub fn new(path: impl AsRef<Path>) -> Self {
-        let path = path.as_ref().join(".lock");
-        let lock = OpenOptions::new()
-            .truncate(true)
-            .create(true)
-            .write(true)
-            .open(&path)
-            .expect("Couldn't open the lock file");
-        Self { path, lock }
-    }
-
-    pub fn try_lock(&self) -> Result<(), TryLockError> {
-        self.lock.try_lock()
-    }
-}
-
-impl Drop for FileLock {
-    fn drop(&mut self) {
-        self.lock.unlock().unwrap();
-        remove_file(&self.path).unwrap();
-    }
-}
+use tracing::info;
 
 pub async fn pull_model_from_registry(args: PullArgs) {
     let PullArgs {
@@ -65,12 +24,13 @@ pub async fn pull_model_from_registry(args: PullArgs) {
             registry:
                 Registry {
                     remote,
+                    auth,
                     client: registry_http_client_config,
                 },
             model:
                 Model {
+                    category: default_category,
                     client: model_http_client_config,
-                    ..
                 },
         },
         config_path,
@@ -83,102 +43,37 @@ pub async fn pull_model_from_registry(args: PullArgs) {
         info!("Initialization should be ensured to be completed");
         return;
     }
-    let (model_name, category) = service::model::final_name_and_category(&conn, &name, category)
-        .expect("Couldn't get model name and category");
-    // 获取模型所在目录
-    let dir = data_path.join("model").join(&model_name);
-    if !dir.exists() {
-        create_dir_all(&dir).expect("Couldn't create the model directory");
-    }
-    // 获取锁，只允许一个进程进行下载，避免多进程下载导致文件写入失败
-    let file_lock = FileLock::new(&dir);
-    match file_lock.try_lock() {
-        Ok(_) => (),
-        Err(TryLockError::WouldBlock) => {
-            panic!(
-                "The file is locked, uultiple processes are not allowed to pull the model file simultaneously,please wait"
-            )
-        }
-        Err(TryLockError::Error(e)) => {
-            panic!("Couldn't lock the file, {e}");
-        }
-    }
+    // 解析模型的引用，例如 qwen3:8b、someuser/model:tag、host/namespace/model@sha256:...
+    let reference = ModelReference::parse(&name)
+        .expect("Invalid model reference")
+        .relative_to(&remote)
+        .or_tag(category);
     // 获取下载 Model 时 HTTP client 的配置
     let client_config = if let Some(new) = http_client_config {
         model_http_client_config.merge(new)
     } else {
         model_http_client_config
     };
-    let client = client_config
-        .build_client()
-        .expect("Couldn't build the reqwest client");
-    let manifest_url = format!("/v2/library/{name}/manifests/{category}");
-    let manifest_url = remote.join(manifest_url.as_str()).unwrap();
-    let response = client.get(manifest_url).send().await.unwrap();
-    let response_text = response.text().await.unwrap();
-    let manifest: Manifest = serde_json::from_str(&response_text).unwrap();
-    // 判断当前的 Manifest 的 schema_version 和 media_type 是不是和注册表中的一致，如果不一致，那么需要退出，并且重新适配
-    if !db::config::check_manifest_schema_version_and_media_type(
-        &conn,
-        manifest.schema_version,
-        &manifest.media_type,
-    )
-    .expect("Failed to check manifest schema version and media type")
-    {
-        panic!(
-            "The manifest schema_version or media_type does not match. Please re-adapt the remote registry."
-        );
-    }
-    // 获取重试时超时设置
-    let chunk_timeout = client_config.build_chunk_timeout();
-    for layer in manifest.layers {
-        let Layer {
-            media_type,
-            digest,
-            size,
-        } = layer;
-        save_res_to_local(
-            &conn,
-            &client_config,
-            chunk_timeout,
-            &remote,
-            client.clone(),
-            &name,
-            &model_name,
-            media_type,
-            digest,
-            size,
-            &dir,
-        )
-        .await;
-    }
-    let Config {
-        media_type,
-        digest,
-        size,
-    } = manifest.config;
-    save_res_to_local(
-        &conn,
-        &client_config,
-        chunk_timeout,
-        &remote,
-        client.clone(),
-        &name,
-        &model_name,
-        media_type,
-        digest,
-        size,
-        &dir,
-    )
-    .await;
-    // 保存一个拉取状态，完成拉取，用来标识全部的资源都已经拉取完成
-    db::model::set_model_pull_status(&conn, &model_name, CompletedStatus::Completed)
-        .expect("Couldn't to set model pull status");
+    let puller = Puller {
+        conn: &conn,
+        data_path: &data_path,
+        remote: &remote,
+        auth: &auth,
+        client_config: &client_config,
+        default_category: &default_category,
+    };
+    // 每一层使用一个进度条展示下载进度
+    let display = ProgressDisplay::new();
+    let (_model_name, category) = puller
+        .pull(reference, &display)
+        .await
+        .expect("Couldn't pull the model");
     if saved {
         let config = LLamaBuddyConfig {
             data: Data { path: data_path },
             registry: Registry {
                 remote,
+                auth,
                 client: registry_http_client_config,
             },
             model: Model {
@@ -193,78 +88,13 @@ pub async fn pull_model_from_registry(args: PullArgs) {
     info!("Pull completed");
 }
 
-async fn save_res_to_local(
-    conn: &Connection,
-    client_config: &HttpClient,
-    chunk_timeout: Option<u64>,
-    remote: &Url,
-    client: Client,
-    name: &String,
-    model_name: &String,
-    media_type: String,
-    digest: String,
-    size: usize,
-    dir: &PathBuf,
-) {
-    let Some((filename, media_type)) = file_name(conn, &media_type, digest.replace("sha256:", ""))
-    else {
-        return;
-    };
-    let filepath = dir.join(&filename);
-    // 判断文件是否需要重新下载
-    if need_retry_download(&filepath, &digest) {
-        // 获取重试策略
-        let backoff = client_config.build_back_off();
-        let blob_url = format!("/v2/library/{name}/blobs/{}", digest.replace(":", "-"));
-        let blob_url = remote.join(blob_url.as_str()).unwrap();
-        let param = DownloadParam::try_new(blob_url, filename, dir.as_path())
-            .expect("Couldn't build a download param.")
-            .with_chunk_timeout(chunk_timeout);
-        let summary = retry::spawn(backoff, async || {
-            download::spawn(client.clone(), param.clone()).await
-        })
-        .await
-        .expect("Couldn't download the resources");
-        debug!("{summary:?}");
-        let checksum = checksum(&filepath, digest.replace("sha256:", ""))
-            .expect("There is no way to obtain the digest of the file");
-        if !checksum {
-            panic!("{digest}: checksum failed");
-        }
-    }
-    // 将这个目录保存在注册表中
-    db::model::save_model_file_path(conn, model_name, &filepath, size, &media_type)
-        .expect("Couldn't save model file path and size");
-}
-
-fn need_retry_download(filepath: &PathBuf, digest: &str) -> bool {
-    // 文件不存在，重新下载
-    if !filepath.exists() {
-        return true;
-    }
-    // 文件存在，判断一下文件的摘要，摘要不一样，重新下载
-    let Ok(checksum) = checksum(filepath, digest.replace("sha256:", "")) else {
-        // 没有办法校验摘要，那么重新下载
-        return true;
-    };
-    !checksum
-}
-
-fn file_name(
-    conn: &Connection,
-    media_type: impl AsRef<str>,
-    digest: impl AsRef<str>,
-) -> Option<(String, String)> {
-    let digest = digest.as_ref();
-    let media_type = media_type.as_ref();
-    let (media, file_type) =
-        db::config::get_media_type(conn, media_type).expect("No media type")?;
-    Some((format!("{media}-{digest}.{file_type}"), media))
-}
-
 #[derive(Args)]
 pub struct PullArgs {
-    #[arg(short = 'n', long = "name", help = "The name of mode")]
+    #[arg(
+        short = 'n',
+        long = "name",
+        help = "The name of mode, format is [host/][namespace/]name[:category][@digest]"
+    )]
     pub name: String,
     #[arg(
         short = 'c',
@@ -281,29 +111,3 @@ pub struct PullArgs {
     #[command(flatten)]
     pub client: Option<HttpClientConfig>,
 }
-
-#[derive(Debug, Deserialize)]
-struct Layer {
-    #[serde(rename(deserialize = "mediaType"))]
-    media_type: String,
-    digest: String,
-    size: usize,
-}
-
-#[derive(Debug, Deserialize)]
-struct Config {
-    #[serde(rename(deserialize = "mediaType"))]
-    media_type: String,
-    digest: String,
-    size: usize,
-}
-
-#[derive(Debug, Deserialize)]
-struct Manifest {
-    #[serde(rename(deserialize = "schemaVersion"))]
-    schema_version: u32,
-    #[serde(rename(deserialize = "mediaType"))]
-    media_type: String,
-    config: Config,
-    layers: Vec<Layer>,
-}
@@ -0,0 +1,103 @@
+//! 删除已经拉取到本地的模型
+
+use crate::{
+    config::{Config as LLamaBuddyConfig, Data, Registry},
+    db, service,
+    service::model::RemovedFile,
+    utils::{
+        blob::BlobStore, file_lock::FileLock, format::human_readable_size,
+        reference::ModelReference,
+    },
+};
+use clap::Args;
+use tracing::{error, info};
+
+pub async fn remove_local_model(args: RmArgs) {
+    let RmArgs { model, dry_run } = args;
+    // 获取配置
+    let (
+        LLamaBuddyConfig {
+            data: Data { path: data_path },
+            registry: Registry { remote, .. },
+            ..
+        },
+        _,
+    ) = LLamaBuddyConfig::try_config_path().expect("Couldn't get the config");
+    let sqlite_dir = data_path.join("sqlite");
+    let conn = db::open_llama_buddy_db(sqlite_dir).expect("Couldn't open sqlite file");
+    // 检查一下有没有完成初始化，没有完成初始化，注册表中没有模型
+    if !db::check_llama_buddy_init_completed(&conn).expect("Couldn't check init whatever completed")
+    {
+        info!("Initialization should be ensured to be completed");
+        return;
+    }
+    let reference = ModelReference::parse(&model)
+        .expect("Invalid model reference")
+        .relative_to(&remote);
+    let (model_name, _category) = service::model::local_name_and_category(&conn, &reference)
+        .expect("Couldn't get model name and category");
+    let blob_store = BlobStore::new(&data_path).expect("Couldn't open the blob store");
+    // 获取锁，避免删除正在下载的文件，预演时不修改任何文件，不需要获取锁
+    let file_lock = if dry_run {
+        None
+    } else {
+        let mut file_lock = FileLock::new(blob_store.dir());
+        file_lock.lock_or_panic();
+        blob_store
+            .migrate(&conn, data_path.join("model"))
+            .expect("Couldn't migrate the model files to the blob store");
+        Some(file_lock)
+    };
+    let Some(files) = service::model::remove_model_files(&conn, &model_name, dry_run)
+        .expect("Couldn't remove the model files")
+    else {
+        info!("Model {model_name} has not been pulled");
+        return;
+    };
+    let mut reclaimed = 0;
+    for file in files {
+        match file {
+            RemovedFile::Untagged(path) => println!("Untagged {}", path.display()),
+            RemovedFile::WouldRemove(path, size) => {
+                println!(
+                    "Would remove {} ({})",
+                    path.display(),
+                    human_readable_size(size)
+                );
+                reclaimed += size;
+            }
+            RemovedFile::Removed(path, size) => {
+                println!("Removed {} ({})", path.display(), human_readable_size(size));
+                reclaimed += size;
+            }
+            RemovedFile::Failed(path, e) => {
+                error!("Couldn't remove the file({}), {e}", path.display())
+            }
+        }
+    }
+    drop(file_lock);
+    if dry_run {
+        println!(
+            "Would reclaim {} from {model_name}",
+            human_readable_size(reclaimed)
+        );
+    } else {
+        println!(
+            "Removed {model_name}, reclaimed {}",
+            human_readable_size(reclaimed)
+        );
+    }
+}
+
+#[derive(Args)]
+pub struct RmArgs {
+    #[arg(
+        help = "The name of mode, format is [host/][namespace/]name[:category][@digest]. If the category is not provided, the default value is obtained from the local registry"
+    )]
+    pub model: String,
+    #[arg(
+        long = "dry-run",
+        help = "Only show the files that would be removed, without removing them"
+    )]
+    pub dry_run: bool,
+}
@@ -0,0 +1,214 @@
+//! 全文检索本地注册表中的模型
+
+use crate::{
+    config::{Config as LLamaBuddyConfig, Data},
+    db,
+    db::model::{ModelSearchHit, ModelSummary},
+    utils::format::{human_readable_size, parse_context_size},
+};
+use clap::{Args, ValueEnum};
+use serde::Serialize;
+use std::io::{IsTerminal, stdout};
+use tracing::info;
+
+pub async fn search_local_models(args: SearchArgs) {
+    let SearchArgs {
+        query,
+        input,
+        min_context,
+        pulled,
+        limit,
+        json,
+    } = args;
+    // 获取配置
+    let (
+        LLamaBuddyConfig {
+            data: Data { path: data_path },
+            ..
+        },
+        _,
+    ) = LLamaBuddyConfig::try_config_path().expect("Couldn't get the config");
+    let sqlite_dir = data_path.join("sqlite");
+    let conn = db::open_llama_buddy_db(sqlite_dir).expect("Couldn't open sqlite file");
+    // 检查一下有没有完成初始化，没有完成初始化，注册表中没有模型
+    if !db::check_llama_buddy_init_completed(&conn).expect("Couldn't check init whatever completed")
+    {
+        info!("Initialization should be ensured to be completed");
+        return;
+    }
+    let min_context = min_context.map(|context| {
+        parse_context_size(&context)
+            .unwrap_or_else(|| panic!("The context size({context}) is invalid, e.g. 32K"))
+    });
+    let (open, close) = highlight_marks(json);
+    let hits = db::model::search_model_info(&conn, fts5_query(&query), open, close)
+        .expect("Couldn't search model info");
+    let mut results = Vec::new();
+    for hit in hits {
+        let tags = db::model::query_model_list_by_model_id(&conn, &hit.model_id)
+            .expect("Couldn't query model tags")
+            .into_iter()
+            .filter(|tag| input.is_none_or(|input| input.matches(tag)))
+            .filter(|tag| {
+                min_context.is_none_or(|min| {
+                    tag.context
+                        .as_ref()
+                        .and_then(parse_context_size)
+                        .is_some_and(|context| context >= min)
+                })
+            })
+            .filter(|tag| !pulled || tag.pulled())
+            .collect::<Vec<_>>();
+        // 存在过滤条件时，没有任何规格满足条件的模型不展示
+        let filtered = input.is_some() || min_context.is_some() || pulled;
+        if filtered && tags.is_empty() {
+            continue;
+        }
+        results.push(SearchResult::new(hit, tags));
+        if results.len() >= limit {
+            break;
+        }
+    }
+    if json {
+        let json = serde_json::to_string_pretty(&results).expect("Couldn't serialize results");
+        println!("{json}");
+    } else {
+        print_results(&results);
+    }
+}
+
+// 包围命中片段的标记，JSON 中使用 Markdown 的粗体，只有在终端中输出时才使用颜色高亮
+pub(crate) fn highlight_marks(json: bool) -> (&'static str, &'static str) {
+    if json {
+        ("**", "**")
+    } else if stdout().is_terminal() {
+        ("\x1b[1;31m", "\x1b[0m")
+    } else {
+        ("[", "]")
+    }
+}
+
+// 将用户输入的每个词都作为 FTS5 的字符串，避免 . - : 等字符被当作查询语法，多个词之间是 AND 关系
+pub(crate) fn fts5_query(query: &str) -> String {
+    query
+        .split_whitespace()
+        .map(|term| format!("\"{}\"", term.replace('"', "\"\"")))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn print_results(results: &[SearchResult]) {
+    if results.is_empty() {
+        println!("No models matched");
+        return;
+    }
+    for (index, result) in results.iter().enumerate() {
+        if index > 0 {
+            println!();
+        }
+        println!("{} ({:.2})", result.title, result.score);
+        for snippet in [&result.summary, &result.readme] {
+            let snippet = snippet.split_whitespace().collect::<Vec<_>>().join(" ");
+            if !snippet.is_empty() {
+                println!("  {snippet}");
+            }
+        }
+        for tag in &result.tags {
+            let status = if tag.pulled() {
+                format!("{}, {}", tag.pull_status, human_readable_size(tag.size))
+            } else {
+                tag.pull_status.clone()
+            };
+            println!(
+                "  - {}:{}  {}  {}  [{status}]",
+                tag.name,
+                tag.category,
+                tag.context.as_deref().unwrap_or("-"),
+                tag.input.as_deref().unwrap_or("-"),
+            );
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct SearchResult {
+    title: String,
+    // bm25 的得分取反，越大越相关
+    score: f64,
+    summary: String,
+    readme: String,
+    tags: Vec<ModelSummary>,
+}
+
+impl SearchResult {
+    fn new(hit: ModelSearchHit, tags: Vec<ModelSummary>) -> Self {
+        let ModelSearchHit {
+            title,
+            rank,
+            summary,
+            readme,
+            ..
+        } = hit;
+        Self {
+            title,
+            score: -rank,
+            summary,
+            readme,
+            tags,
+        }
+    }
+}
+
+#[derive(Args)]
+pub struct SearchArgs {
+    #[arg(help = "The keywords to search, both Chinese and English are supported")]
+    pub query: String,
+    #[arg(
+        value_enum,
+        long = "input",
+        help = "Only show the models which support the input type"
+    )]
+    pub input: Option<InputType>,
+    #[arg(
+        long = "min-context",
+        help = "Only show the models whose context size is not less than the value, e.g. 32K"
+    )]
+    pub min_context: Option<String>,
+    #[arg(
+        long = "pulled",
+        help = "Only show the models that have been pulled to local"
+    )]
+    pub pulled: bool,
+    #[arg(
+        short = 'l',
+        long = "limit",
+        default_value_t = 10,
+        help = "The maximum number of models to show"
+    )]
+    pub limit: usize,
+    #[arg(long = "json", help = "Output the results in JSON format")]
+    pub json: bool,
+}
+
+/// 模型支持的输入类型
+#[derive(Clone, Copy, Debug, Eq, PartialEq, ValueEnum)]
+pub enum InputType {
+    /// 文本
+    #[value(help = "The model supports text input")]
+    Text,
+    /// 图片
+    #[value(help = "The model supports image input")]
+    Image,
+}
+
+impl InputType {
+    fn matches(&self, tag: &ModelSummary) -> bool {
+        let keyword = match self {
+            InputType::Text => "text",
+            InputType::Image => "image",
+        };
+        tag.input
+            .as_ref()
+            .is_some_and(|input| input.to_lowercase().contains(keyword))
+    }
+}
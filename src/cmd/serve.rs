@@ -0,0 +1,109 @@
+//! 启动兼容 OpenAI 和 Ollama 接口的 HTTP 服务
+
+use crate::{
+    config::Config as LLamaBuddyConfig,
+    db,
+    server::{ServerState, residency::KeepAlive, router},
+    utils::format::parse_human_readable_size,
+};
+use clap::Args;
+use llama_cpp::runtime::Runtime;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tracing::{error, info};
+
+pub async fn serve_models(args: ServeArgs) {
+    let ServeArgs {
+        host,
+        port,
+        text,
+        parallel,
+        layer,
+        keep_alive,
+        max_memory,
+    } = args;
+    // 获取配置
+    let (config, _) = LLamaBuddyConfig::try_config_path().expect("Couldn't get the config");
+    let sqlite_dir = config.data.path.join("sqlite");
+    let conn = db::open_llama_buddy_db(sqlite_dir).expect("Couldn't open sqlite file");
+    // 检查一下有没有完成初始化，没有完成初始化，注册表中没有模型
+    if !db::check_llama_buddy_init_completed(&conn).expect("Couldn't check init whatever completed")
+    {
+        error!("Initialization should be ensured to be completed");
+        return;
+    }
+    drop(conn);
+    // 加载一个后端
+    let runtime = Runtime::load_all();
+    let state = Arc::new(ServerState::new(
+        runtime, config, text, parallel, layer, keep_alive, max_memory,
+    ));
+    let reaper = state.spawn_reaper();
+    let listener = TcpListener::bind((host.as_str(), port))
+        .await
+        .expect("Couldn't bind the address");
+    info!(
+        "Listening on http://{}",
+        listener
+            .local_addr()
+            .expect("Couldn't get the local address")
+    );
+    axum::serve(listener, router(state))
+        .with_graceful_shutdown(async {
+            tokio::signal::ctrl_c()
+                .await
+                .expect("Failed to listen for ctrl-c");
+        })
+        .await
+        .expect("Failed to serve");
+    reaper.abort();
+}
+
+#[derive(Args)]
+pub struct ServeArgs {
+    #[arg(long, default_value = "127.0.0.1", help = "The address to listen on")]
+    pub host: String,
+    #[arg(
+        short = 'p',
+        long,
+        default_value = "11434",
+        help = "The port to listen on"
+    )]
+    pub port: u16,
+    #[arg(
+        short = 't',
+        long,
+        default_value = "2048",
+        help = "The amount of text context of each request"
+    )]
+    text: u32,
+    #[arg(
+        long = "parallel",
+        default_value = "4",
+        help = "The number of requests each model generates at the same time, the other requests wait in a queue"
+    )]
+    parallel: u32,
+    #[arg(
+        long = "ngl",
+        default_value = "99",
+        help = "The number of layers to offload to the GPU"
+    )]
+    layer: i32,
+    #[arg(
+        long = "keep-alive",
+        default_value = "5m",
+        allow_hyphen_values = true,
+        help = "How long a model stays loaded after the last request, such as 30s, 5m, 1h, 0 unloads it immediately, a negative value keeps it forever"
+    )]
+    keep_alive: KeepAlive,
+    #[arg(
+        long = "max-memory",
+        value_parser = parse_memory_size,
+        help = "The memory budget of the loaded models, such as 8G, the least recently used idle models are unloaded when it is exceeded"
+    )]
+    max_memory: Option<u64>,
+}
+
+fn parse_memory_size(size: &str) -> Result<u64, String> {
+    parse_human_readable_size(size).ok_or_else(|| format!("Invalid memory size({size})"))
+}
@@ -0,0 +1,199 @@
+//! 展示模型的详细信息
+
+use crate::{
+    config::{Config as LLamaBuddyConfig, Data, Registry},
+    db,
+    db::model::{ModelDetail, ModelFile},
+    service,
+    utils::{format::human_readable_size, reference::ModelReference},
+};
+use clap::Args;
+use llama_cpp::{model::ModelParams, runtime::Runtime};
+use std::{fs, path::Path};
+use tracing::{error, info};
+
+pub async fn show_model_detail(args: ShowArgs) {
+    let ShowArgs { model, no_gguf, .. } = args;
+    // 获取配置
+    let (
+        LLamaBuddyConfig {
+            data: Data { path: data_path },
+            registry: Registry { remote, .. },
+            ..
+        },
+        _,
+    ) = LLamaBuddyConfig::try_config_path().expect("Couldn't get the config");
+    let sqlite_dir = data_path.join("sqlite");
+    let conn = db::open_llama_buddy_db(sqlite_dir).expect("Couldn't open sqlite file");
+    // 检查一下有没有完成初始化，没有完成初始化，注册表中没有模型
+    if !db::check_llama_buddy_init_completed(&conn).expect("Couldn't check init whatever completed")
+    {
+        info!("Initialization should be ensured to be completed");
+        return;
+    }
+    let reference = ModelReference::parse(&model)
+        .expect("Invalid model reference")
+        .relative_to(&remote);
+    let (model_name, _category) = service::model::local_name_and_category(&conn, &reference)
+        .expect("Couldn't get model name and category");
+    let detail =
+        db::model::query_model_detail(&conn, &model_name).expect("Couldn't get model detail");
+    let siblings = db::model::query_sibling_model_names(&conn, &model_name)
+        .expect("Couldn't get sibling model names");
+    print_model_info(&detail, &siblings);
+    print_model_files(&detail.files);
+    // 只有拉取到本地的 GGUF 文件才能够读取元数据
+    if no_gguf {
+        return;
+    }
+    let Some(ModelFile { path, .. }) = detail.files.iter().find(|file| file.media == "model")
+    else {
+        return;
+    };
+    if !Path::new(path).is_file() {
+        error!("The model file({path}) does not exist, please pull it again");
+        return;
+    }
+    print_gguf_metadata(path);
+}
+
+fn print_model_info(detail: &ModelDetail, siblings: &[String]) {
+    let ModelDetail {
+        name,
+        pull_status,
+        context,
+        input,
+        hash,
+        title,
+        introduction,
+        summary,
+        readme,
+        pull_count,
+        tag_count,
+        updated_time,
+        namespace,
+        registry,
+        manifest_digest,
+        ..
+    } = detail;
+    println!("Model");
+    print_field("name", Some(name));
+    print_field("title", title.as_ref());
+    print_field("namespace", Some(namespace));
+    print_field("registry", registry.as_ref());
+    print_field("pull status", Some(pull_status));
+    print_field("context", context.as_ref());
+    print_field("input", input.as_ref());
+    print_field("hash", hash.as_ref());
+    print_field("manifest", manifest_digest.as_ref());
+    print_field("pulls", pull_count.as_ref());
+    print_field("tags", tag_count.as_ref());
+    print_field("updated", updated_time.as_ref());
+    print_section("Introduction", introduction.as_deref());
+    print_section("Summary", summary.as_deref());
+    if !siblings.is_empty() {
+        println!();
+        println!("Tags");
+        for sibling in siblings {
+            // 当前展示的规格使用 * 标识
+            let mark = if sibling == name { "*" } else { " " };
+            println!("  {mark} {sibling}");
+        }
+    }
+    print_section("Readme", readme.as_deref());
+}
+
+fn print_model_files(files: &[ModelFile]) {
+    if files.is_empty() {
+        return;
+    }
+    println!();
+    println!("Files");
+    for ModelFile { media, path, size } in files {
+        println!("  {media:<10}{:>10}  {path}", human_readable_size(*size));
+    }
+    // model 文件和 config 文件不是文本，不需要输出内容
+    for ModelFile { media, path, .. } in files {
+        if !matches!(media.as_str(), "template" | "system" | "params" | "license") {
+            continue;
+        }
+        match fs::read_to_string(path) {
+            Ok(content) => print_section(&capitalize(media), Some(&content)),
+            Err(e) => error!("Couldn't read the {media} file({path}), {e}"),
+        }
+    }
+}
+
+fn print_gguf_metadata(path: &str) {
+    let mut runtime = Runtime::load_all();
+    runtime.void_logs();
+    // 只加载词汇表，不加载张量，能够快速读取到模型的超参数
+    let model_params = ModelParams::default().with_vocab_only(true);
+    let model = match runtime.load_model_from_file(path, &model_params) {
+        Ok(model) => model,
+        Err(e) => {
+            error!("Couldn't load the GGUF metadata from {path}, {e}");
+            return;
+        }
+    };
+    println!();
+    println!("GGUF");
+    // 只加载词汇表时，不会统计张量，参数量为 0
+    let n_params = model.n_params();
+    let n_params = if n_params == 0 {
+        None
+    } else {
+        Some(n_params.to_string())
+    };
+    print_field("n_params", n_params.as_ref());
+    print_field("n_layer", Some(&model.n_layer()));
+    print_field("n_head", Some(&model.n_head()));
+    print_field("n_embd", Some(&model.n_embd()));
+    print_field("n_ctx_train", Some(&model.n_ctx_train()));
+    let rope_type = model
+        .rope_type()
+        .map_or_else(|| "none".to_owned(), |rope_type| format!("{rope_type:?}"));
+    print_field("rope type", Some(&rope_type));
+    match model.chat_template(None) {
+        Ok(template) => print_section("Chat Template", template.to_str().ok()),
+        Err(e) => info!("The model has no embedded chat template, {e}"),
+    }
+}
+
+fn print_field(key: &str, value: Option<&impl std::fmt::Display>) {
+    if let Some(value) = value {
+        println!("  {key:<14}{value}");
+    }
+}
+
+fn print_section(title: &str, content: Option<&str>) {
+    let Some(content) = content.map(str::trim).filter(|content| !content.is_empty()) else {
+        return;
+    };
+    println!();
+    println!("{title}");
+    for line in content.lines() {
+        println!("  {line}");
+    }
+}
+
+fn capitalize(str: &str) -> String {
+    let mut chars = str.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+#[derive(Args)]
+pub struct ShowArgs {
+    #[arg(
+        help = "The name of mode, format is [host/][namespace/]name[:category][@digest]. If the category is not provided, the default value is obtained from the local registry"
+    )]
+    pub model: String,
+    #[arg(
+        long = "no-gguf",
+        help = "Do not load the GGUF file to read the metadata of the model"
+    )]
+    pub no_gguf: bool,
+}
@@ -1,23 +1,42 @@
 //！直接启动一个模型
 
 use crate::{
-    config::{Config as LLamaBuddyConfig, Data},
-    db, service,
-    utils::rustyline::{EditorExt, new_rustyline},
+    config::{Config as LLamaBuddyConfig, Data, Registry},
+    db,
+    db::chat::ChatSummary,
+    error::Whatever,
+    service::{
+        self,
+        chat::ChatRecorder,
+        modelfile::{ChatTemplate, Modelfile, Parameters},
+        session::SessionStore,
+        tool::{builtin_tools, call_builtin_tool, new_tool_call_id},
+    },
+    utils::{
+        blob::BlobStore,
+        reference::ModelReference,
+        rustyline::{EditorExt, new_rustyline},
+    },
 };
-use clap::Args;
+use clap::{Args, ValueEnum};
 use llama_cpp::{
-    batch::Batch,
-    context::ContextParams,
-    model::{Message, ModelParams},
+    context::{Context, ContextParams},
+    generator::{ContextShift, Generator, GeneratorError},
+    json_schema::{json_grammar, json_schema_to_grammar},
+    model::{AdapterLora, Message, Model, ModelParams, Special, ToolCall},
+    prefix_cache::PrefixCache,
     runtime::Runtime,
     sampler::Sampler,
+    vocabulary::Vocabulary,
 };
+use rusqlite::Connection;
 use rustyline::error::ReadlineError;
+use serde::{Deserialize, Serialize};
+use snafu::prelude::*;
 use std::{
     fs,
     io::{Write, stdout},
-    process::exit,
+    path::{Path, PathBuf},
 };
 use tracing::error;
 
@@ -25,14 +44,14 @@ pub async fn simple_run_a_model(
     SimpleRunArgs {
         name,
         category,
-        text,
-        layer,
+        options,
     }: SimpleRunArgs,
 ) {
     // 首先从配置文件中获取到本地注册表相关的信息
     let (
         LLamaBuddyConfig {
             data: Data { path: data_path },
+            registry: Registry { remote, .. },
             ..
         },
         ..,
@@ -46,24 +65,63 @@ pub async fn simple_run_a_model(
         error!("Initialization should be ensured to be completed");
         return;
     }
-    // 检验模型资源是否正常拉取
-    let (model_name, _category) = service::model::final_name_and_category(&conn, &name, category)
+    let reference = ModelReference::parse(&name)
+        .expect("Invalid model reference")
+        .relative_to(&remote)
+        .or_tag(category);
+    let (model_name, _category) = service::model::local_name_and_category(&conn, &reference)
         .expect("Couldn't get model name and category");
-    if !db::model::check_pull_completed(&conn, &model_name)
+    chat_with_model(&conn, &data_path, &model_name, options, None);
+}
+
+/// 在终端中和模型对话，resume 为需要继续的对话
+pub(crate) fn chat_with_model(
+    conn: &Connection,
+    data_path: &Path,
+    model_name: &str,
+    RunOptions {
+        text,
+        layer,
+        context_shift: strategy,
+        keep,
+        tools,
+        tool_grammar,
+        json_schema,
+        json,
+    }: RunOptions,
+    resume: Option<ChatSummary>,
+) {
+    let sqlite_dir = data_path.join("sqlite");
+    // 检验模型资源是否正常拉取
+    if !db::model::check_pull_completed(conn, model_name)
         .expect("Couldn't check model pull completed")
     {
         error!("Model {model_name} should be ensured to be pulled");
         return;
     }
-    // 通过模型名获取到模型和模板所在的位置
-    let (path, template) = db::model::get_model_params(&conn, &model_name)
+    // 通过模型名获取到模型所在的位置
+    let (path, _) = db::model::get_model_params(conn, model_name)
         .expect("Couldn't get model path template params");
     let Some(path) = path else {
         error!("Model's path is none, should be ensured have path");
         return;
     };
-    let template =
-        template.map(|path| fs::read_to_string(path).expect("Couldn't to read template"));
+    // 回复需要符合 JSON Schema 或者是任意的 JSON 对象，在加载模型之前检查 schema
+    let json_grammar = match load_json_grammar(json_schema.as_deref(), json) {
+        Ok(grammar) => grammar,
+        Err(error) => {
+            error!("{error}");
+            return;
+        }
+    };
+    // 从 ollama 拉取的模板、系统提示词和参数
+    let Modelfile {
+        template,
+        system,
+        parameters,
+    } = Modelfile::load(conn, model_name).expect("Couldn't load the modelfile");
+    // 保存的对话状态只能恢复到同一个模型中，旧版本拉取的模型文件名中没有摘要，使用模型的路径代替
+    let model_digest = BlobStore::digest_of(&path).unwrap_or_else(|| path.clone());
     // 构建一个编辑器
     let mut rustyline = new_rustyline(&sqlite_dir);
 
@@ -75,23 +133,92 @@ pub async fn simple_run_a_model(
     let model = runtime
         .load_model_from_file(path, &model_params)
         .expect("Couldn't load model");
+    // 使用中的 LoRA 适配器，需要比上下文活得更久
+    let mut loras = Vec::<(PathBuf, AdapterLora)>::new();
+    // 没有指定上下文的大小时使用模型参数中的 num_ctx
+    let text = text.or(parameters.num_ctx).unwrap_or(DEFAULT_TEXT);
     let context_params = ContextParams::default().with_n_ctx(text).with_n_batch(text);
     // 初始化上下文
     let mut context = runtime
         .new_context(&model, context_params)
         .expect("Failed to create a model context");
-    // 设置采样器
-    let min_p_sampler = Sampler::init_from_min_p(0.05_f32, 1);
-    let temp_sampler = Sampler::init_from_temp(0.8_f32);
-    let dist_sampler = Sampler::init_from_dist(u32::MAX);
-    let mut sampler = Sampler::from_chain([min_p_sampler, temp_sampler, dist_sampler], true);
-    let template = &model
-        .chat_template(None)
-        .expect("Failed to get a chat template from model");
+    // 设置采样器，继续之前的对话时使用之前的采样参数，否则使用模型参数中的默认值
+    let mut sampler_settings = resume
+        .as_ref()
+        .and_then(|chat| serde_json::from_str::<SamplerSettings>(&chat.sampler).ok())
+        .unwrap_or_else(|| SamplerSettings::from_parameters(&parameters));
+    // 开启工具时，模型可以调用内置的工具，按照模板对应的格式解析工具调用
+    let tools = if tools { builtin_tools() } else { Vec::new() };
+    // 没有从 ollama 拉取模板时使用 GGUF 中的对话模板
+    let template = &ChatTemplate::new(&model, template, system)
+        .expect("Failed to get a chat template from model")
+        .with_tools(tools.clone());
+    let tool_format = (!tools.is_empty()).then(|| template.tool_call_format());
+    // 工具调用的语法只在生成了工具调用的开头之后生效，JSON 的语法从头开始生效，都放在采样器链的最前面
+    let build_sampler = |settings: &SamplerSettings| {
+        let grammar_sampler = match (tool_format.filter(|_| tool_grammar), &json_grammar) {
+            (Some(format), _) => Some(format.lazy_grammar_sampler(&model, &tools)),
+            (None, Some(grammar)) => Some(Runtime::sampler_from_grammar(&model, grammar, "root")),
+            (None, None) => None,
+        };
+        match grammar_sampler {
+            Some(grammar_sampler) => Sampler::from_chain([grammar_sampler, settings.build()], true),
+            None => settings.build(),
+        }
+    };
+    let mut sampler = build_sampler(&sampler_settings);
+    // 模型参数中的停止字符串和最多生成的 token 数量
+    let max_tokens = parameters
+        .num_predict
+        .and_then(|num_predict| u32::try_from(num_predict).ok());
     // 获取模型的词汇表
     let vocab = model.vocab();
+    // 对话中的消息都会保存到数据库中
+    let mut recorder = ChatRecorder::new(
+        conn,
+        model_name,
+        serde_json::to_string(&sampler_settings).expect("Couldn't serialize the sampler settings"),
+    );
     let mut messages = Vec::<Message>::new();
-    loop {
+    if let Some(chat) = resume {
+        messages = service::chat::chat_messages(conn, chat.id).expect("Couldn't load the chat");
+        recorder = recorder.with_chat_id(chat.id);
+        println!("Resume the chat {}: {}", chat.id, chat.title);
+        for message in &messages {
+            print_message(message);
+        }
+    }
+    // 记录 KV cache 中的 token，每一轮只解码和上一轮不同的部分
+    let mut prefix_cache = PrefixCache::new();
+    // 上下文是否移动过
+    let mut shifted = false;
+    // 上一轮对话结束之后模板渲染出来的提示词
+    let mut prev_prompt = String::new();
+    let sessions = SessionStore {
+        conn,
+        dir: data_path.join("session"),
+        model_name,
+        model_digest: &model_digest,
+    };
+    rustyline.slash_commands(
+        COMMANDS
+            .iter()
+            .flat_map(|(usage, _)| {
+                let command = usage.split(' ').next().unwrap_or(usage);
+                if command == "/set" {
+                    SAMPLER_PARAMETERS
+                        .iter()
+                        .map(|parameter| format!("{command} {parameter}"))
+                        .collect()
+                } else {
+                    vec![command.to_owned()]
+                }
+            })
+            .collect(),
+    );
+    // 上下文移动时保留开头的系统提示词，系统提示词改变时重新计算
+    let mut context_shift = context_shift_for(strategy, &model, template, &messages, keep);
+    'repl: loop {
         rustyline.colored_prompt("\x1b[1;32mQ>> \x1b[0m");
         let readline = rustyline.readline("Q>> ");
         match readline {
@@ -99,47 +226,308 @@ pub async fn simple_run_a_model(
                 rustyline
                     .add_history_entry(line.as_str())
                     .expect("Failed to add history entry to line editor");
-                let message = Message::try_new("user", line).expect("Failed to create new message");
-                messages.push(message);
-                let prompt = model
-                    .apply_chat_template(&template, messages.as_slice(), true)
-                    .expect("Failed to apply chat template to model");
-                let n_ctx_used = context.kv_cache_seq_pos_max(0) + 1;
-                let is_first = n_ctx_used == 0;
-                let tokens = vocab
-                    .tokenize(prompt, is_first, true)
-                    .expect("Failed to get tokens from vocab");
-                let mut batch =
-                    Batch::get_one(&tokens).expect("Failed to create a new batch by tokens");
-                let mut response = String::new();
+                // 以 / 开头的是命令，只有 /retry 需要继续生成回答
+                if let Some(command) = line.strip_prefix('/') {
+                    let (command, arg) = command
+                        .split_once(' ')
+                        .map_or((command, ""), |(command, arg)| (command, arg.trim()));
+                    match command {
+                        "retry" => {
+                            if !messages
+                                .last()
+                                .is_some_and(|message| message.role.as_bytes() == b"assistant")
+                            {
+                                eprintln!("There is no answer to retry");
+                                continue;
+                            }
+                            messages.pop();
+                            recorder
+                                .remove_last(1)
+                                .expect("Failed to remove the message");
+                            // 上下文移动过之后 KV cache 中只剩下部分对话，只能重新处理
+                            if shifted {
+                                reset_context(&mut context, &mut prefix_cache, &mut shifted);
+                            }
+                        }
+                        "system" => {
+                            if messages
+                                .first()
+                                .is_some_and(|message| message.role.as_bytes() == b"system")
+                            {
+                                messages.remove(0);
+                            }
+                            // 没有提示词时只删除原来的系统提示词
+                            if !arg.is_empty() {
+                                let message = Message::try_new("system", arg)
+                                    .expect("Failed to create new message");
+                                messages.insert(0, message);
+                            }
+                            // 对话的开头变了，保存为一个新的对话
+                            record_as_new_chat(&mut recorder, &messages, &vocab);
+                            if shifted {
+                                reset_context(&mut context, &mut prefix_cache, &mut shifted);
+                            }
+                            prev_prompt = rendered(&model, template, &messages);
+                            context_shift =
+                                context_shift_for(strategy, &model, template, &messages, keep);
+                            println!("Set the system prompt");
+                        }
+                        "set" => {
+                            let (name, value) = arg
+                                .split_once(' ')
+                                .map_or((arg, ""), |(name, value)| (name, value.trim()));
+                            match sampler_settings.set(name, value) {
+                                Ok(()) => {
+                                    sampler = build_sampler(&sampler_settings);
+                                    recorder
+                                        .set_sampler(
+                                            serde_json::to_string(&sampler_settings)
+                                                .expect("Couldn't serialize the sampler settings"),
+                                        )
+                                        .expect("Failed to save the sampler settings");
+                                    println!("Set {name} to {value}");
+                                }
+                                Err(e) => eprintln!("{e}"),
+                            }
+                        }
+                        "clear" => {
+                            messages.clear();
+                            reset_context(&mut context, &mut prefix_cache, &mut shifted);
+                            prev_prompt.clear();
+                            context_shift =
+                                context_shift_for(strategy, &model, template, &messages, keep);
+                            recorder.restart();
+                            println!("Cleared the conversation");
+                        }
+                        "undo" => {
+                            // 丢弃最后的回答和对应的问题
+                            let mut n_removed = 0;
+                            for role in [b"assistant".as_slice(), b"user"] {
+                                if messages
+                                    .last()
+                                    .is_some_and(|message| message.role.as_bytes() == role)
+                                {
+                                    messages.pop();
+                                    n_removed += 1;
+                                }
+                            }
+                            if n_removed == 0 {
+                                eprintln!("There is nothing to undo");
+                                continue;
+                            }
+                            recorder
+                                .remove_last(n_removed)
+                                .expect("Failed to remove the message");
+                            if shifted {
+                                reset_context(&mut context, &mut prefix_cache, &mut shifted);
+                            }
+                            prev_prompt = rendered(&model, template, &messages);
+                            println!("Removed the last {n_removed} messages");
+                        }
+                        "stats" => print!("{}", context.timings()),
+                        "tokens" => {
+                            let n_ctx = context.n_ctx() as usize;
+                            let n_used = prefix_cache.tokens(0).len();
+                            println!(
+                                "{n_used} / {n_ctx} tokens of the context are used ({:.1}%), {} messages",
+                                n_used as f64 * 100.0 / n_ctx as f64,
+                                messages.len()
+                            );
+                        }
+                        "lora" => match apply_lora(&model, &mut context, &mut loras, arg) {
+                            Ok(()) => {
+                                // KV cache 是使用之前的权重计算的，需要重新处理对话
+                                reset_context(&mut context, &mut prefix_cache, &mut shifted);
+                                println!("Applied the LoRA adapter {arg}");
+                            }
+                            Err(e) => eprintln!("{e}"),
+                        },
+                        "save" => match sessions.save(arg, &context, &prefix_cache, &messages) {
+                            Ok(()) => println!("Saved the session {arg}"),
+                            Err(e) => eprintln!("{e}"),
+                        },
+                        "load" => match sessions.load(arg, &mut context, &mut prefix_cache) {
+                            Ok(loaded) => {
+                                messages = loaded;
+                                // 恢复的对话保存为一个新的对话
+                                record_as_new_chat(&mut recorder, &messages, &vocab);
+                                prev_prompt = rendered(&model, template, &messages);
+                                context_shift =
+                                    context_shift_for(strategy, &model, template, &messages, keep);
+                                let prompt = template
+                                    .apply(&model, &messages, false)
+                                    .expect("Failed to apply chat template");
+                                let tokens = vocab
+                                    .tokenize(&prompt, true, true)
+                                    .expect("Failed to get tokens from vocab");
+                                // 保存之前上下文移动过的话，KV cache 中只剩下部分对话
+                                shifted = prefix_cache.common_prefix_len(0, &tokens)
+                                    < prefix_cache.tokens(0).len();
+                                println!(
+                                    "Loaded the session {arg} with {} messages",
+                                    messages.len()
+                                );
+                            }
+                            Err(e) => eprintln!("{e}"),
+                        },
+                        "help" => print_help(),
+                        _ => {
+                            eprintln!("Unknown command /{command}, type /help to see the commands")
+                        }
+                    }
+                    if command != "retry" {
+                        continue;
+                    }
+                } else {
+                    let n_tokens = count_tokens(&vocab, &line);
+                    let message =
+                        Message::try_new("user", line).expect("Failed to create new message");
+                    recorder
+                        .record(&message, n_tokens)
+                        .expect("Failed to save the message");
+                    messages.push(message);
+                }
+                // 模型调用了工具时，将工具的结果加入对话之后继续生成回答
                 loop {
-                    let n_ctx = context.n_ctx();
-                    if n_ctx_used + batch.n_tokens() > n_ctx as i32 {
-                        eprintln!("context size exceeded!");
-                        exit(0);
+                    let prompt = template
+                        .apply(&model, &messages, true)
+                        .expect("Failed to apply chat template to model");
+                    // 重新处理完整的对话，由前缀缓存跳过已经解码的部分；上下文移动过之后 KV cache 中只剩下部分对话，
+                    // 只能在缓存的 token 之后追加新的部分。模板重新渲染了之前的对话时（例如去掉了思考的内容），
+                    // 新的提示词不再以上一轮的提示词开头，只能丢弃 KV cache 重新处理完整的对话
+                    let appended = prompt
+                        .strip_prefix(prev_prompt.as_str())
+                        .filter(|_| shifted);
+                    if shifted && appended.is_none() {
+                        reset_context(&mut context, &mut prefix_cache, &mut shifted);
+                    }
+                    let mut tokens = if let Some(appended) = appended {
+                        let mut tokens = prefix_cache.tokens(0).to_vec();
+                        tokens.extend(
+                            vocab
+                                .tokenize(appended, false, true)
+                                .expect("Failed to get tokens from vocab"),
+                        );
+                        tokens
+                    } else {
+                        vocab
+                            .tokenize(&prompt, true, true)
+                            .expect("Failed to get tokens from vocab")
+                    };
+                    let mut response = String::new();
+                    // 按照截断的策略丢弃历史之后，需要重新处理剩余的对话，然后继续生成
+                    loop {
+                        // 语法的状态停留在上一次生成的结尾，需要重置
+                        if tool_grammar || json_grammar.is_some() {
+                            sampler.reset();
+                        }
+                        let mut generator =
+                            Generator::new(&model, &mut context, &mut sampler, tokens)
+                                .expect("Failed to create a generator")
+                                .with_special(Special::Tokenize)
+                                .with_prefix_cache(&mut prefix_cache)
+                                .with_context_shift(context_shift)
+                                .with_stop_strings(parameters.stop.clone())
+                                .with_max_tokens(max_tokens);
+                        let mut context_full = false;
+                        for piece in generator.by_ref() {
+                            let piece = match piece {
+                                Err(llama_cpp::Error::Generator {
+                                    source: GeneratorError::ContextFull { .. },
+                                }) => {
+                                    context_full = true;
+                                    break;
+                                }
+                                piece => piece.expect("Failed to generate a piece"),
+                            };
+                            response += &piece;
+                            print!("{piece}");
+                            // print! 不会自动刷新缓冲区，要确保消息立即显示在控制台上，需要手动刷新
+                            stdout().flush().expect("Failed to flush to stdout");
+                        }
+                        if generator.n_discarded() > 0 {
+                            shifted = true;
+                            warn_history_dropped(&format!(
+                                "the earliest {} tokens",
+                                generator.n_discarded()
+                            ));
+                        }
+                        if !context_full {
+                            break;
+                        }
+                        if strategy != ContextStrategy::Truncate {
+                            eprintln!("\ncontext size exceeded!");
+                            break 'repl;
+                        }
+                        // 丢弃最早的对话，直到剩余的对话和已经生成的回答只占用一半的上下文
+                        let n_ctx = context.n_ctx() as usize;
+                        let mut n_dropped = 0;
+                        tokens = loop {
+                            if !drop_oldest_turn(&mut messages) {
+                                eprintln!("\ncontext size exceeded!");
+                                break 'repl;
+                            }
+                            n_dropped += 1;
+                            let mut prompt = template
+                                .apply(&model, &messages, true)
+                                .expect("Failed to apply chat template to model");
+                            prompt.push_str(&response);
+                            let tokens = vocab
+                                .tokenize(prompt, true, true)
+                                .expect("Failed to get tokens from vocab");
+                            if tokens.len() <= n_ctx / 2 {
+                                break tokens;
+                            }
+                        };
+                        warn_history_dropped(&format!("{n_dropped} earliest messages"));
                     }
-                    context.decode(&mut batch).expect("Failed to decode token");
-                    let new_token = sampler.sample(&context, -1);
-                    if vocab.is_eog_token(new_token) {
+                    println!();
+                    let n_tokens = count_tokens(&vocab, &response);
+                    // 保存的是模型的原始回答，其中包含工具调用
+                    let message = Message::try_new("assistant", &response)
+                        .expect("Failed to create new message");
+                    recorder
+                        .record(&message, n_tokens)
+                        .expect("Failed to save the message");
+                    let Some((format, parsed)) = tool_format
+                        .map(|format| (format, format.parse(&response)))
+                        .filter(|(_, parsed)| !parsed.tool_calls.is_empty())
+                    else {
+                        messages.push(message);
+                        prev_prompt = rendered(&model, template, &messages);
+                        stdout().flush().expect("Failed to flush to stdout");
                         break;
+                    };
+                    let tool_calls = parsed
+                        .tool_calls
+                        .into_iter()
+                        .map(|call| ToolCall {
+                            id: call.id.or_else(|| Some(new_tool_call_id(format))),
+                            ..call
+                        })
+                        .collect::<Vec<_>>();
+                    messages.push(
+                        Message::try_new("assistant", parsed.content)
+                            .expect("Failed to create new message")
+                            .with_tool_calls(tool_calls.clone()),
+                    );
+                    prev_prompt = rendered(&model, template, &messages);
+                    for call in tool_calls {
+                        let result = call_builtin_tool(&call);
+                        println!(
+                            "\x1b[2m[tool] {}({}) -> {result}\x1b[0m",
+                            call.name, call.arguments
+                        );
+                        let n_tokens = count_tokens(&vocab, &result);
+                        let message = Message::try_new("tool", result)
+                            .expect("Failed to create new message")
+                            .with_tool_call_id(call.id.unwrap_or_default());
+                        recorder
+                            .record(&message, n_tokens)
+                            .expect("Failed to save the message");
+                        messages.push(message);
                     }
-                    let piece = vocab
-                        .token_to_piece(&new_token, 0, true)
-                        .expect("Failed to get new piece from token");
-                    response += &piece;
-                    print!("{piece}");
-                    // print! 不会自动刷新缓冲区，要确保消息立即显示在控制台上，需要手动刷新
-                    stdout().flush().expect("Failed to flush to stdout");
-                    batch = Batch::get_one(&[new_token])
-                        .expect("Failed to create a new batch by new token");
                 }
-                let message =
-                    Message::try_new("assistant", response).expect("Failed to create new message");
-                messages.push(message);
-                model
-                    .apply_chat_template(&template, messages.as_slice(), false)
-                    .expect("Failed to apply chat template");
-                stdout().flush().expect("Failed to flush to stdout");
             }
             Err(ReadlineError::Interrupted) => {
                 println!("Interrupted");
@@ -155,11 +543,313 @@ pub async fn simple_run_a_model(
             }
         }
     }
+    if let Some(chat_id) = recorder.chat_id() {
+        println!("The chat was saved, continue it with `llama-buddy chat resume {chat_id}`");
+    }
+}
+
+// REPL 中的命令和说明
+const COMMANDS: &[(&str, &str)] = &[
+    (
+        "/system [prompt]",
+        "Set the system prompt, remove it without a prompt",
+    ),
+    ("/set <parameter> <value>", "Set a parameter of the sampler"),
+    ("/clear", "Clear the conversation and the context"),
+    ("/retry", "Generate the last answer again"),
+    ("/undo", "Remove the last question and its answer"),
+    ("/stats", "Show the performance of the context"),
+    ("/tokens", "Show how much of the context is used"),
+    (
+        "/lora <path> [scale]",
+        "Apply a LoRA adapter, the default scale is 1.0",
+    ),
+    ("/save <name>", "Save the state of the conversation"),
+    ("/load <name>", "Restore a saved conversation"),
+    ("/help", "Show the commands"),
+];
+
+// /set 可以设置的采样参数
+const SAMPLER_PARAMETERS: &[&str] = &[
+    "temperature",
+    "top_p",
+    "top_k",
+    "min_p",
+    "seed",
+    "repeat_penalty",
+    "repeat_last_n",
+];
+
+fn print_help() {
+    let width = COMMANDS
+        .iter()
+        .map(|(usage, _)| usage.len())
+        .max()
+        .unwrap_or_default();
+    for (usage, description) in COMMANDS {
+        println!("{usage:<width$}  {description}");
+    }
+    println!("Parameters of /set: {}", SAMPLER_PARAMETERS.join(", "));
+}
+
+// 模型参数中没有 num_ctx 时上下文的大小
+const DEFAULT_TEXT: u32 = 2048;
+
+// 丢弃 KV cache 中的对话，下一轮重新处理完整的对话
+fn reset_context(context: &mut Context, prefix_cache: &mut PrefixCache, shifted: &mut bool) {
+    context
+        .clear_kv_cache_seq(Some(0), None, None)
+        .expect("Failed to clear the KV cache");
+    prefix_cache.clear(None);
+    *shifted = false;
+}
+
+// 上下文移动时保留的部分：对话开头的系统提示词渲染之后的 token，再加上之后的 keep 个 token，至少保留 BOS
+fn context_shift_for(
+    strategy: ContextStrategy,
+    model: &Model,
+    template: &ChatTemplate,
+    messages: &[Message],
+    keep: u32,
+) -> ContextShift {
+    if strategy != ContextStrategy::Discard {
+        return ContextShift::Disabled;
+    }
+    let n_system = template
+        .apply_system(model, messages)
+        .expect("Failed to apply chat template")
+        .map_or(0, |system| {
+            model
+                .vocab()
+                .tokenize(system, true, true)
+                .expect("Failed to get tokens from vocab")
+                .len() as u32
+        });
+    ContextShift::Discard {
+        n_keep: n_system.max(1) + keep,
+    }
+}
+
+// 对话结束之后模板渲染出来的提示词
+fn rendered(model: &Model, template: &ChatTemplate, messages: &[Message]) -> String {
+    if messages.is_empty() {
+        return String::new();
+    }
+    template
+        .apply(model, messages, false)
+        .expect("Failed to apply chat template")
+}
+
+// 消息内容的 token 数量
+fn count_tokens(vocab: &Vocabulary, content: &str) -> usize {
+    vocab
+        .tokenize(content, false, false)
+        .map_or(0, |tokens| tokens.len())
+}
+
+// 将全部的消息保存为一个新的对话
+fn record_as_new_chat(recorder: &mut ChatRecorder, messages: &[Message], vocab: &Vocabulary) {
+    recorder.restart();
+    for message in messages {
+        let n_tokens = count_tokens(vocab, &message.content.to_string_lossy());
+        recorder
+            .record(message, n_tokens)
+            .expect("Failed to save the message");
+    }
+}
+
+// 使用 LoRA 适配器，参数为适配器的路径和可选的权重，已经加载的适配器只更新权重
+fn apply_lora(
+    model: &Model,
+    context: &mut Context,
+    loras: &mut Vec<(PathBuf, AdapterLora)>,
+    arg: &str,
+) -> Result<(), Whatever> {
+    let (path, scale) = arg
+        .rsplit_once(' ')
+        .and_then(|(path, scale)| Some((path.trim(), scale.parse::<f32>().ok()?)))
+        .unwrap_or((arg, 1.0));
+    ensure_whatever!(!path.is_empty(), "Usage: /lora <path> [scale]");
+    let path = PathBuf::from(path);
+    ensure_whatever!(
+        path.is_file(),
+        "The LoRA adapter({}) doesn't exist",
+        path.display()
+    );
+    let index = match loras.iter().position(|(loaded, _)| *loaded == path) {
+        Some(index) => index,
+        None => {
+            let adapter = model.lora_adapter_init(&path).with_whatever_context(|_| {
+                format!("Failed to load the LoRA adapter({})", path.display())
+            })?;
+            loras.push((path, adapter));
+            loras.len() - 1
+        }
+    };
+    context
+        .set_lora_adapter(&loras[index].1, scale)
+        .with_whatever_context(|_| "Failed to apply the LoRA adapter")
+}
+
+// 继续之前的对话时，展示之前的消息
+fn print_message(message: &Message) {
+    let content = message.content.to_string_lossy();
+    match message.role.to_bytes() {
+        b"user" => println!("\x1b[1;32mQ>> \x1b[0m{content}"),
+        b"assistant" => println!("{content}"),
+        _ => println!(
+            "\x1b[2m[{}] {content}\x1b[0m",
+            message.role.to_string_lossy()
+        ),
+    }
+}
+
+/// 采样参数，之前保存的对话中可能缺少后来增加的参数
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+struct SamplerSettings {
+    temperature: f32,
+    // 为 1.0 时不生效
+    top_p: f32,
+    // 为 0 时不生效
+    top_k: i32,
+    min_p: f32,
+    // 为 u32::MAX 时使用随机的种子
+    seed: u32,
+    // 为 1.0 时不生效
+    repeat_penalty: f32,
+    // 惩罚重复时考虑的最近的 token 数量
+    repeat_last_n: i32,
+}
+
+impl Default for SamplerSettings {
+    fn default() -> Self {
+        Self {
+            temperature: 0.8,
+            top_p: 1.0,
+            top_k: 0,
+            min_p: 0.05,
+            seed: u32::MAX,
+            repeat_penalty: 1.0,
+            repeat_last_n: 64,
+        }
+    }
+}
+
+impl SamplerSettings {
+    // 使用模型参数中的采样参数，没有的参数使用默认值
+    fn from_parameters(parameters: &Parameters) -> Self {
+        let default = Self::default();
+        Self {
+            temperature: parameters.temperature.unwrap_or(default.temperature),
+            top_p: parameters.top_p.unwrap_or(default.top_p),
+            top_k: parameters.top_k.unwrap_or(default.top_k),
+            min_p: parameters.min_p.unwrap_or(default.min_p),
+            // 小于 0 的种子表示随机
+            seed: parameters
+                .seed
+                .and_then(|seed| u32::try_from(seed).ok())
+                .unwrap_or(default.seed),
+            repeat_penalty: parameters.repeat_penalty.unwrap_or(default.repeat_penalty),
+            repeat_last_n: parameters.repeat_last_n.unwrap_or(default.repeat_last_n),
+        }
+    }
+
+    fn build(&self) -> Sampler {
+        let penalties_sampler =
+            Sampler::init_from_penalties(self.repeat_last_n, self.repeat_penalty, 0.0, 0.0);
+        let top_k_sampler = Sampler::init_from_top_k(self.top_k);
+        let top_p_sampler = Sampler::init_from_top_p(self.top_p, 1);
+        let min_p_sampler = Sampler::init_from_min_p(self.min_p, 1);
+        let temp_sampler = Sampler::init_from_temp(self.temperature);
+        let dist_sampler = Sampler::init_from_dist(self.seed);
+        Sampler::from_chain(
+            [
+                penalties_sampler,
+                top_k_sampler,
+                top_p_sampler,
+                min_p_sampler,
+                temp_sampler,
+                dist_sampler,
+            ],
+            true,
+        )
+    }
+
+    // 设置一个参数，参数名和 SAMPLER_PARAMETERS 一致
+    fn set(&mut self, name: &str, value: &str) -> Result<(), Whatever> {
+        let invalid = || format!("Invalid value({value}) of {name}");
+        match name {
+            "temperature" => {
+                self.temperature = value.parse().ok().with_whatever_context(invalid)?
+            }
+            "top_p" => self.top_p = value.parse().ok().with_whatever_context(invalid)?,
+            "top_k" => self.top_k = value.parse().ok().with_whatever_context(invalid)?,
+            "min_p" => self.min_p = value.parse().ok().with_whatever_context(invalid)?,
+            "seed" => self.seed = value.parse().ok().with_whatever_context(invalid)?,
+            "repeat_penalty" => {
+                self.repeat_penalty = value.parse().ok().with_whatever_context(invalid)?
+            }
+            "repeat_last_n" => {
+                self.repeat_last_n = value.parse().ok().with_whatever_context(invalid)?
+            }
+            _ => whatever!(
+                "Unknown parameter({name}), the parameters are {}",
+                SAMPLER_PARAMETERS.join(", ")
+            ),
+        }
+        Ok(())
+    }
+}
+
+// 读取 JSON Schema 文件并转换成语法，只指定 --json 时使用任意 JSON 对象的语法
+fn load_json_grammar(schema_path: Option<&Path>, json: bool) -> Result<Option<String>, Whatever> {
+    let Some(schema_path) = schema_path else {
+        return Ok(json.then(json_grammar));
+    };
+    let schema = fs::read_to_string(schema_path).with_whatever_context(|_| {
+        format!("Couldn't read the JSON schema {}", schema_path.display())
+    })?;
+    let schema = serde_json::from_str(&schema).with_whatever_context(|_| {
+        format!("The JSON schema {} isn't valid JSON", schema_path.display())
+    })?;
+    let grammar = json_schema_to_grammar(&schema).with_whatever_context(|_| {
+        format!("Couldn't convert the JSON schema {}", schema_path.display())
+    })?;
+    Ok(Some(grammar))
+}
+
+// 提示用户较早的对话已经不在上下文中
+fn warn_history_dropped(dropped: &str) {
+    eprintln!("\n\x1b[1;33mThe context is full, {dropped} of the conversation were dropped\x1b[0m");
+}
+
+// 丢弃最早的一轮对话，系统提示词和当前的问题会被保留，没有可以丢弃的对话时返回 false
+fn drop_oldest_turn(messages: &mut Vec<Message>) -> bool {
+    let Some(index) = messages
+        .iter()
+        .position(|message| message.role.as_bytes() != b"system")
+    else {
+        return false;
+    };
+    if index + 1 >= messages.len() {
+        return false;
+    }
+    messages.remove(index);
+    // 问题和对应的回答一起丢弃
+    if index + 1 < messages.len() && messages[index].role.as_bytes() == b"assistant" {
+        messages.remove(index);
+    }
+    true
 }
 
 #[derive(Args)]
 pub struct SimpleRunArgs {
-    #[arg(short = 'n', long = "name", help = "The name of mode")]
+    #[arg(
+        short = 'n',
+        long = "name",
+        help = "The name of mode, format is [host/][namespace/]name[:category][@digest]"
+    )]
     pub name: String,
     #[arg(
         short = 'c',
@@ -167,17 +857,73 @@ pub struct SimpleRunArgs {
         help = "The category of mode, If the version of the mode is not provided, the default value is obtained from the local registry"
     )]
     pub category: Option<String>,
+    #[command(flatten)]
+    pub options: RunOptions,
+}
+
+/// 运行模型的参数
+#[derive(Args)]
+pub struct RunOptions {
     #[arg(
         short = 't',
         long,
-        default_value = "2048",
-        help = "The amount of text context"
+        help = "The amount of text context, defaults to num_ctx of the model or 2048"
     )]
-    text: u32,
+    text: Option<u32>,
     #[arg(
         long = "ngl",
         default_value = "99",
         help = "The number of layers to offload to the GPU"
     )]
     layer: i32,
+    #[arg(
+        value_enum,
+        long = "context-shift",
+        default_value = "discard",
+        help = "How to make room when the context is full"
+    )]
+    context_shift: ContextStrategy,
+    #[arg(
+        long = "keep",
+        default_value = "0",
+        help = "The number of tokens after the system prompt to keep when discarding"
+    )]
+    keep: u32,
+    #[arg(
+        long,
+        help = "Let the model call the built-in echo and current_time tools"
+    )]
+    tools: bool,
+    #[arg(
+        long = "tool-grammar",
+        requires = "tools",
+        help = "Constrain the tool calls with a grammar once the model starts a call"
+    )]
+    tool_grammar: bool,
+    #[arg(
+        long = "json-schema",
+        conflicts_with_all = ["json", "tools"],
+        help = "Constrain the replies to the JSON schema in the file"
+    )]
+    json_schema: Option<PathBuf>,
+    #[arg(
+        long,
+        conflicts_with = "tools",
+        help = "Constrain the replies to a JSON object"
+    )]
+    json: bool,
+}
+
+/// 上下文用完时的处理方式
+#[derive(Clone, Copy, Debug, Eq, PartialEq, ValueEnum)]
+pub enum ContextStrategy {
+    /// 丢弃 KV cache 中较早的一半，之后的 token 向前移动
+    #[value(help = "Discard the older half of the context and shift the rest")]
+    Discard,
+    /// 丢弃最早的对话，重新处理剩余的对话
+    #[value(help = "Drop the earliest messages and process the rest again")]
+    Truncate,
+    /// 不做处理，结束对话
+    #[value(help = "End the conversation")]
+    None,
 }
@@ -3,6 +3,7 @@
 use crate::{
     config::{Config as LLamaBuddyConfig, Data, HttpClient as HttpClientConfig, Registry},
     service,
+    utils::progress::{ProgressDisplay, ProgressUnit},
 };
 use clap::Args;
 use std::sync::Arc;
@@ -22,6 +23,7 @@ pub async fn update_local_registry(args: UpdateArgs) {
             registry:
                 Registry {
                     remote,
+                    auth,
                     client: client_config,
                 },
             model,
@@ -47,8 +49,10 @@ pub async fn update_local_registry(args: UpdateArgs) {
     {
         info!("Initialization should be ensured to be completed");
     } else {
+        // 展示获取模型详情的进度
+        let progress = ProgressDisplay::new().add("registry", ProgressUnit::Items);
         // 更新注册表
-        service::model::try_update_model_info(Arc::clone(&conn), client, remote.clone())
+        service::model::try_update_model_info(Arc::clone(&conn), client, remote.clone(), progress)
             .await
             .expect("Couldn't update model info");
     }
@@ -58,6 +62,7 @@ pub async fn update_local_registry(args: UpdateArgs) {
             data: Data { path: data_path },
             registry: Registry {
                 client: client_config,
+                auth,
                 remote,
             },
             model,
@@ -0,0 +1,97 @@
+//! 重新校验本地模型文件的摘要
+
+use crate::{
+    config::{Config as LLamaBuddyConfig, Data, Registry},
+    db, service,
+    utils::{blob::BlobStore, file_lock::FileLock, reference::ModelReference},
+};
+use clap::Args;
+use std::{collections::BTreeSet, path::PathBuf, process::exit};
+use tracing::{error, info, warn};
+
+pub async fn verify_local_models(args: VerifyArgs) {
+    let VerifyArgs { model } = args;
+    // 获取配置
+    let (
+        LLamaBuddyConfig {
+            data: Data { path: data_path },
+            registry: Registry { remote, .. },
+            ..
+        },
+        _,
+    ) = LLamaBuddyConfig::try_config_path().expect("Couldn't get the config");
+    let sqlite_dir = data_path.join("sqlite");
+    let conn = db::open_llama_buddy_db(sqlite_dir).expect("Couldn't open sqlite file");
+    // 检查一下有没有完成初始化，没有完成初始化，注册表中没有模型
+    if !db::check_llama_buddy_init_completed(&conn).expect("Couldn't check init whatever completed")
+    {
+        info!("Initialization should be ensured to be completed");
+        return;
+    }
+    let blob_store = BlobStore::new(&data_path).expect("Couldn't open the blob store");
+    // 获取锁，避免校验正在下载的文件
+    let mut file_lock = FileLock::new(blob_store.dir());
+    file_lock.lock_or_panic();
+    blob_store
+        .migrate(&conn, data_path.join("model"))
+        .expect("Couldn't migrate the model files to the blob store");
+    // 没有提供模型时，校验全部被引用的文件
+    let paths = match model {
+        Some(model) => {
+            let reference = ModelReference::parse(&model)
+                .expect("Invalid model reference")
+                .relative_to(&remote);
+            let (model_name, _category) =
+                service::model::local_name_and_category(&conn, &reference)
+                    .expect("Couldn't get model name and category");
+            db::model::query_model_detail(&conn, &model_name)
+                .expect("Couldn't get model detail")
+                .files
+                .into_iter()
+                .map(|file| PathBuf::from(file.path))
+                .collect::<BTreeSet<_>>()
+        }
+        None => db::model::query_all_model_file_paths(&conn)
+            .expect("Couldn't query all model file paths")
+            .into_iter()
+            .collect::<BTreeSet<_>>(),
+    };
+    let mut failed = 0;
+    for path in paths {
+        let Some(digest) = BlobStore::digest_of(&path) else {
+            warn!("{} is not in the blob store, skip it", path.display());
+            continue;
+        };
+        if !path.is_file() {
+            println!("Missing {}", path.display());
+            failed += 1;
+            continue;
+        }
+        // 不使用之前的校验记录，重新计算摘要
+        match blob_store.verify(&conn, &digest) {
+            Ok(true) => println!("Verified {digest}"),
+            Ok(false) => {
+                println!("Corrupted {}", path.display());
+                failed += 1;
+            }
+            Err(e) => {
+                error!("Couldn't verify {}, {e}", path.display());
+                failed += 1;
+            }
+        }
+    }
+    drop(file_lock);
+    if failed > 0 {
+        println!("{failed} file(s) failed to verify, please pull the model again");
+        exit(1);
+    }
+    println!("All files verified");
+}
+
+#[derive(Args)]
+pub struct VerifyArgs {
+    #[arg(
+        help = "The name of mode, format is [host/][namespace/]name[:category][@digest]. If it is not provided, all the pulled files will be verified"
+    )]
+    pub model: Option<String>,
+}
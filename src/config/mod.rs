@@ -1,6 +1,9 @@
 use crate::config::ConfigError::NotInterpret;
 use clap::{Args, ValueEnum};
-use http_extra::retry::strategy::{ExponentialBackoff, FibonacciBackoff, FixedInterval};
+use http_extra::{
+    registry::Credential,
+    retry::strategy::{ExponentialBackoff, FibonacciBackoff, FixedInterval},
+};
 use llama_buddy_macro::IndexByField;
 use reqwest::{Client as ReqwestClient, Proxy};
 use serde::{Deserialize, Serialize};
@@ -16,7 +19,7 @@ use std::{
     time::Duration,
 };
 use sys_extra::dir::BaseDirs;
-use toml_edit::{DocumentMut, Table, value};
+use toml_edit::{DocumentMut, Item, Table, value};
 use url::Url;
 
 const LLAMA_BUDDY_CONFIG: &str = include_str!("llama-buddy.toml");
@@ -53,6 +56,11 @@ pub enum ConfigError {
     },
     #[snafu(display("Couldn't build reqwest client"))]
     ReqwestBuildClient { source: reqwest::Error },
+    #[snafu(display("Couldn't read the credential from {}", path.display()))]
+    ReadCredential {
+        path: PathBuf,
+        source: http_extra::Error,
+    },
 }
 
 impl Default for Config {
@@ -84,6 +92,7 @@ impl Config {
             registry:
                 Registry {
                     remote,
+                    auth,
                     client: registry_client,
                 },
             model:
@@ -99,6 +108,7 @@ impl Config {
         doc["data"]["path"] = value(path.to_str().unwrap_or(""));
         doc["registry"]["remote"] = value(remote.to_string());
         doc["model"]["category"] = value(category);
+        Self::auth_table(&mut doc, auth);
         if let Some(table) = doc["registry"]["client"].as_table_mut() {
             Self::client_table(table, registry_client);
             Self::sort_client_table(table);
@@ -111,6 +121,43 @@ impl Config {
         Ok(doc.to_string())
     }
 
+    // 默认配置中没有认证的配置，提供了用户名、密码或者凭据文件时才添加 [registry.auth]
+    fn auth_table(doc: &mut DocumentMut, auth: &RegistryAuth) {
+        let RegistryAuth {
+            username,
+            password,
+            credential_file,
+        } = auth;
+        let items = [
+            ("username", "# 访问注册表的用户名", username.clone()),
+            ("password", "# 访问注册表的密码", password.clone()),
+            (
+                "credential_file",
+                "# Docker 的凭据文件，例如 ~/.docker/config.json",
+                credential_file
+                    .as_ref()
+                    .map(|path| path.display().to_string()),
+            ),
+        ];
+        if items.iter().all(|(_, _, item)| item.is_none()) {
+            return;
+        }
+        let mut table = Table::new();
+        for (key, decor, item) in items {
+            if let Some(item) = item {
+                let _ = table.insert(key, value(item));
+                let mut key = table
+                    .key_mut(key)
+                    .expect("The auth table doesn't have the inserted key");
+                key.leaf_decor_mut().set_prefix(format!("{decor}\n"));
+            }
+        }
+        table.decor_mut().set_prefix("\n");
+        if let Some(registry) = doc["registry"].as_table_mut() {
+            let _ = registry.insert("auth", Item::Table(table));
+        }
+    }
+
     fn client_table(table: &mut Table, client: &HttpClient) {
         let HttpClient {
             proxy,
@@ -119,7 +166,23 @@ impl Config {
             retry,
             back_off_strategy,
             back_off_time,
+            concurrency,
+            segment_size,
         } = client;
+        if let Some(segment_size) = segment_size {
+            let item = table
+                .get_mut("segment_size")
+                .expect("Invalid config, no segment_size item");
+            *item = value(*segment_size as i64);
+        }
+
+        if let Some(concurrency) = concurrency {
+            let item = table
+                .get_mut("concurrency")
+                .expect("Invalid config, no concurrency item");
+            *item = value(*concurrency as i64);
+        }
+
         if let Some(time) = back_off_time {
             let item = table
                 .get_mut("back_off_time")
@@ -337,10 +400,43 @@ pub struct Data {
 pub struct Registry {
     /// 远程注册表路径
     pub remote: Url,
+    /// 访问注册表的认证信息
+    #[serde(default)]
+    pub auth: RegistryAuth,
     /// 客户端配置
     pub client: HttpClient,
 }
 
+/// 注册表的认证配置，注册表要求认证时使用，都没有提供时匿名获取令牌
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct RegistryAuth {
+    /// 用户名
+    pub username: Option<String>,
+    /// 密码
+    pub password: Option<String>,
+    /// Docker 的凭据文件，没有提供用户名和密码时，从这个文件中读取注册表对应的凭据
+    pub credential_file: Option<PathBuf>,
+}
+
+impl RegistryAuth {
+    pub fn build_credential(&self, remote: &Url) -> Result<Option<Credential>, ConfigError> {
+        match self {
+            RegistryAuth {
+                username: Some(username),
+                password: Some(password),
+                ..
+            } => Ok(Some(Credential::new(username, password))),
+            RegistryAuth {
+                credential_file: Some(path),
+                ..
+            } => Credential::from_docker_config(path, remote).context(ReadCredentialSnafu {
+                path: path.to_owned(),
+            }),
+            _ => Ok(None),
+        }
+    }
+}
+
 /// 模型配置
 #[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
 pub struct Model {
@@ -389,6 +485,20 @@ pub struct HttpClient {
         required = false
     )]
     pub back_off_time: Option<u64>,
+    /// 下载时同时建立的连接数
+    #[arg(
+        long = "concurrency",
+        help = "The number of connections used to download a file in parallel",
+        required = false
+    )]
+    pub concurrency: Option<usize>,
+    /// 并行下载时每个分片的大小，单位为 MiB
+    #[arg(
+        long = "segment-size",
+        help = "Segment size of parallel downloads, specified in MiB",
+        required = false
+    )]
+    pub segment_size: Option<u64>,
 }
 
 /// 重试回退策略
@@ -429,6 +539,8 @@ impl HttpClient {
             retry,
             back_off_strategy,
             back_off_time,
+            concurrency,
+            segment_size,
         }: HttpClient,
     ) -> Self {
         if proxy.is_some() {
@@ -449,6 +561,12 @@ impl HttpClient {
         if back_off_time.is_some() {
             self.back_off_time = back_off_time;
         }
+        if concurrency.is_some() {
+            self.concurrency = concurrency;
+        }
+        if segment_size.is_some() {
+            self.segment_size = segment_size;
+        }
         self
     }
 
@@ -486,6 +604,15 @@ impl HttpClient {
     pub fn build_chunk_timeout(&self) -> Option<u64> {
         self.chunk_timeout
     }
+
+    pub fn build_concurrency(&self) -> usize {
+        self.concurrency.unwrap_or(4).max(1)
+    }
+
+    /// 分片大小，单位为字节
+    pub fn build_segment_size(&self) -> u64 {
+        self.segment_size.unwrap_or(64).max(1) * 1024 * 1024
+    }
 }
 
 #[cfg(test)]
@@ -504,7 +631,9 @@ mod tests {
             config.registry.client.back_off_strategy,
             Some(BackOffStrategy::Fibonacci)
         );
-        assert_eq!(config.model.client.back_off_time, Some(10000))
+        assert_eq!(config.model.client.back_off_time, Some(10000));
+        assert_eq!(config.model.client.build_concurrency(), 4);
+        assert_eq!(config.model.client.build_segment_size(), 64 * 1024 * 1024);
     }
 
     #[test]
@@ -550,6 +679,10 @@ retry = 3
 back_off_strategy = "Fibonacci"
 # 重试第一次的时间间隔，后续每次重试的时间间隔由上面的策略生成
 back_off_time = 10000
+# 下载时同时建立的连接数，服务器不支持断点续传时只使用一个连接
+concurrency = 4
+# 并行下载时每个分片的大小，单位为 MiB
+segment_size = 64
 
 [model]
 # 模型默认提供的版本
@@ -566,6 +699,10 @@ retry = 5
 back_off_strategy = "Exponential"
 # 重试第一次的时间间隔，后续每次重试的时间间隔由上面的策略生成
 back_off_time = 10000
+# 下载时同时建立的连接数，服务器不支持断点续传时只使用一个连接
+concurrency = 4
+# 并行下载时每个分片的大小，单位为 MiB
+segment_size = 64
 "#;
         assert_eq!(config_str, config.display().unwrap());
     }
@@ -594,6 +731,10 @@ retry = 3
 back_off_strategy = "Fibonacci"
 # 重试第一次的时间间隔，后续每次重试的时间间隔由上面的策略生成
 back_off_time = 10000
+# 下载时同时建立的连接数，服务器不支持断点续传时只使用一个连接
+concurrency = 4
+# 并行下载时每个分片的大小，单位为 MiB
+segment_size = 64
 
 [model]
 # 模型默认提供的版本
@@ -610,6 +751,10 @@ retry = 5
 back_off_strategy = "Exponential"
 # 重试第一次的时间间隔，后续每次重试的时间间隔由上面的策略生成
 back_off_time = 10000
+# 下载时同时建立的连接数，服务器不支持断点续传时只使用一个连接
+concurrency = 4
+# 并行下载时每个分片的大小，单位为 MiB
+segment_size = 64
 "#;
         assert_eq!(config_str, config.display().unwrap());
     }
@@ -638,6 +783,10 @@ retry = 3
 back_off_strategy = "Fibonacci"
 # 重试第一次的时间间隔，后续每次重试的时间间隔由上面的策略生成
 back_off_time = 10000
+# 下载时同时建立的连接数，服务器不支持断点续传时只使用一个连接
+concurrency = 4
+# 并行下载时每个分片的大小，单位为 MiB
+segment_size = 64
 
 [model]
 # 模型默认提供的版本
@@ -654,6 +803,10 @@ retry = 5
 back_off_strategy = "Exponential"
 # 重试第一次的时间间隔，后续每次重试的时间间隔由上面的策略生成
 back_off_time = 10000
+# 下载时同时建立的连接数，服务器不支持断点续传时只使用一个连接
+concurrency = 4
+# 并行下载时每个分片的大小，单位为 MiB
+segment_size = 64
 "#;
         assert_eq!(config_str, config.display().unwrap());
     }
@@ -689,6 +842,10 @@ retry = 3
 back_off_strategy = "Fibonacci"
 # 重试第一次的时间间隔，后续每次重试的时间间隔由上面的策略生成
 back_off_time = 10000
+# 下载时同时建立的连接数，服务器不支持断点续传时只使用一个连接
+concurrency = 4
+# 并行下载时每个分片的大小，单位为 MiB
+segment_size = 64
 
 [model]
 # 模型默认提供的版本
@@ -706,7 +863,31 @@ retry = 5
 back_off_strategy = "Exponential"
 # 重试第一次的时间间隔，后续每次重试的时间间隔由上面的策略生成
 back_off_time = 10000
+# 下载时同时建立的连接数，服务器不支持断点续传时只使用一个连接
+concurrency = 4
+# 并行下载时每个分片的大小，单位为 MiB
+segment_size = 64
 "#;
         assert_eq!(config_str, config.display().unwrap());
     }
+
+    #[test]
+    fn display_config_add_registry_auth() {
+        let mut config = Config::default();
+        config.registry.auth.username = Some("mirror".to_owned());
+        config.registry.auth.password = Some("secret".to_owned());
+        let config_str = config.display().unwrap();
+        assert!(config_str.contains(
+            r#"
+[registry.auth]
+# 访问注册表的用户名
+username = "mirror"
+# 访问注册表的密码
+password = "secret"
+"#
+        ));
+        let config = toml_edit::de::from_str::<Config>(&config_str).unwrap();
+        assert_eq!(config.registry.auth.username.as_deref(), Some("mirror"));
+        assert_eq!(config.registry.auth.credential_file, None);
+    }
 }
@@ -0,0 +1,75 @@
+use crate::error::Whatever;
+use rusqlite::{Connection, OptionalExtension};
+use snafu::prelude::*;
+use std::path::Path;
+
+const SAVE_VERIFIED_BLOB: &str = r#"insert into blob (digest, path, size, modified_at)
+values (?1, ?2, ?3, ?4)
+on conflict (digest) do update set path        = excluded.path,
+                                   size        = excluded.size,
+                                   modified_at = excluded.modified_at,
+                                   verified_at = strftime('%s', 'now')"#;
+
+const QUERY_VERIFIED_BLOB: &str = "select path, size, modified_at from blob where digest = ?1";
+
+const DELETE_VERIFIED_BLOB: &str = "delete from blob where digest = ?1";
+
+const DELETE_VERIFIED_BLOB_BY_PATH: &str = "delete from blob where path = ?1";
+
+/// 已经校验过摘要的文件，modified_at 为文件的修改时间，单位为纳秒
+#[derive(Debug, Eq, PartialEq)]
+pub struct VerifiedBlob {
+    pub path: String,
+    pub size: u64,
+    pub modified_at: i64,
+}
+
+/// 记录校验通过的文件
+pub fn save_verified_blob(
+    conn: &Connection,
+    digest: impl AsRef<str>,
+    path: impl AsRef<Path>,
+    size: u64,
+    modified_at: i64,
+) -> Result<(), Whatever> {
+    let digest = digest.as_ref();
+    let path = path.as_ref().display().to_string();
+    conn.execute(SAVE_VERIFIED_BLOB, (digest, &path, size, modified_at))
+        .with_whatever_context(|_| format!("Failed to save the verified blob({digest})"))?;
+    Ok(())
+}
+
+pub fn query_verified_blob(
+    conn: &Connection,
+    digest: impl AsRef<str>,
+) -> Result<Option<VerifiedBlob>, Whatever> {
+    let digest = digest.as_ref();
+    conn.query_row(QUERY_VERIFIED_BLOB, [digest], |r| {
+        Ok(VerifiedBlob {
+            path: r.get(0)?,
+            size: r.get(1)?,
+            modified_at: r.get(2)?,
+        })
+    })
+    .optional()
+    .with_whatever_context(|_| format!("Failed to query the verified blob({digest})"))
+}
+
+/// 文件校验失败时，删除之前的校验记录
+pub fn delete_verified_blob(conn: &Connection, digest: impl AsRef<str>) -> Result<(), Whatever> {
+    let digest = digest.as_ref();
+    conn.execute(DELETE_VERIFIED_BLOB, [digest])
+        .with_whatever_context(|_| format!("Failed to delete the verified blob({digest})"))?;
+    Ok(())
+}
+
+/// 文件被删除时，删除这个文件的校验记录
+pub fn delete_verified_blob_by_path(
+    conn: &Connection,
+    path: impl AsRef<Path>,
+) -> Result<(), Whatever> {
+    let path = path.as_ref().display().to_string();
+    conn.execute(DELETE_VERIFIED_BLOB_BY_PATH, [&path])
+        .with_whatever_context(|_| format!("Failed to delete the verified blob of {path}"))?;
+    Ok(())
+}
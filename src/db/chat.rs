@@ -0,0 +1,266 @@
+use crate::error::Whatever;
+use rusqlite::{Connection, OptionalExtension};
+use serde::Serialize;
+use snafu::prelude::*;
+
+const INSERT_CHAT: &str =
+    r#"insert into chat (title, model_name, sampler) values (?1, ?2, ?3) returning id;"#;
+
+const INSERT_CHAT_MESSAGE: &str =
+    r#"insert into chat_message (chat_id, role, content, n_tokens) values (?1, ?2, ?3, ?4);"#;
+
+// 按照添加的顺序删除最后的几条消息
+const DELETE_LAST_CHAT_MESSAGES: &str = r#"
+delete
+from chat_message
+where id in (select id from chat_message where chat_id = ?1 order by id desc limit ?2);
+"#;
+
+const UPDATE_CHAT_SAMPLER: &str =
+    r#"update chat set sampler = ?1, updated_at = strftime('%s', 'now') where id = ?2;"#;
+
+const TOUCH_CHAT: &str = r#"update chat set updated_at = strftime('%s', 'now') where id = ?1;"#;
+
+const QUERY_CHAT_LIST: &str = r#"
+select c.id,
+       c.title,
+       c.model_name,
+       c.sampler,
+       count(cm.id),
+       coalesce(sum(cm.n_tokens), 0),
+       datetime(c.created_at, 'unixepoch', 'localtime'),
+       datetime(c.updated_at, 'unixepoch', 'localtime')
+from chat c left join chat_message cm on cm.chat_id = c.id
+group by c.id
+order by c.updated_at desc, c.id desc
+limit ?1;
+"#;
+
+const QUERY_CHAT: &str = r#"
+select c.id,
+       c.title,
+       c.model_name,
+       c.sampler,
+       count(cm.id),
+       coalesce(sum(cm.n_tokens), 0),
+       datetime(c.created_at, 'unixepoch', 'localtime'),
+       datetime(c.updated_at, 'unixepoch', 'localtime')
+from chat c left join chat_message cm on cm.chat_id = c.id
+where c.id = ?1
+group by c.id;
+"#;
+
+const QUERY_CHAT_MESSAGES: &str = r#"
+select role, content, n_tokens, datetime(created_at, 'unixepoch', 'localtime')
+from chat_message
+where chat_id = ?1
+order by id;
+"#;
+
+// 使用 bm25 进行排序，同一个对话中可能有多条消息命中
+const SEARCH_CHAT_MESSAGE: &str = r#"
+select c.id,
+       c.title,
+       c.model_name,
+       cm.role,
+       bm25(chat_message_fts) as rank,
+       snippet(chat_message_fts, 0, ?2, ?3, '...', 16),
+       datetime(cm.created_at, 'unixepoch', 'localtime')
+from chat_message_fts
+         join chat_message cm on cm.id = chat_message_fts.rowid
+         join chat c on c.id = cm.chat_id
+where chat_message_fts match ?1
+order by rank
+limit ?4;
+"#;
+
+// 对话的概要
+#[derive(Eq, PartialEq, Clone, Default, Debug, Serialize)]
+pub(crate) struct ChatSummary {
+    pub(crate) id: i64,
+    // 对话的标题
+    pub(crate) title: String,
+    // 对话使用的模型
+    pub(crate) model_name: String,
+    // 采样参数，JSON 对象
+    pub(crate) sampler: String,
+    // 消息的数量
+    pub(crate) n_messages: usize,
+    // 全部消息的 token 数量
+    pub(crate) n_tokens: usize,
+    // 创建的本地时间
+    pub(crate) created_time: String,
+    // 最后更新的本地时间
+    pub(crate) updated_time: String,
+}
+
+// 对话中的一条消息
+#[derive(Eq, PartialEq, Clone, Default, Debug, Serialize)]
+pub(crate) struct ChatMessage {
+    pub(crate) role: String,
+    pub(crate) content: String,
+    pub(crate) n_tokens: usize,
+    // 创建的本地时间
+    pub(crate) created_time: String,
+}
+
+// 全文检索命中的消息
+#[derive(PartialEq, Clone, Default, Debug, Serialize)]
+pub(crate) struct ChatSearchHit {
+    pub(crate) chat_id: i64,
+    pub(crate) title: String,
+    pub(crate) model_name: String,
+    pub(crate) role: String,
+    // bm25 的得分，越小越相关
+    pub(crate) rank: f64,
+    // 消息中命中的片段
+    pub(crate) snippet: String,
+    // 消息创建的本地时间
+    pub(crate) created_time: String,
+}
+
+/// 创建一个对话，返回对话的 id
+pub fn insert_chat(
+    conn: &Connection,
+    title: impl AsRef<str>,
+    model_name: impl AsRef<str>,
+    sampler: impl AsRef<str>,
+) -> Result<i64, Whatever> {
+    let model_name = model_name.as_ref();
+    conn.query_one(
+        INSERT_CHAT,
+        (title.as_ref(), model_name, sampler.as_ref()),
+        |r| r.get(0),
+    )
+    .with_whatever_context(|_| format!("Failed to create a chat with {model_name}"))
+}
+
+/// 在对话的末尾添加一条消息
+pub fn insert_chat_message(
+    conn: &Connection,
+    chat_id: i64,
+    role: impl AsRef<str>,
+    content: impl AsRef<str>,
+    n_tokens: usize,
+) -> Result<(), Whatever> {
+    conn.execute(
+        INSERT_CHAT_MESSAGE,
+        (chat_id, role.as_ref(), content.as_ref(), n_tokens),
+    )
+    .with_whatever_context(|_| format!("Failed to save the message of chat({chat_id})"))?;
+    conn.execute(TOUCH_CHAT, [chat_id])
+        .with_whatever_context(|_| format!("Failed to update the chat({chat_id})"))?;
+    Ok(())
+}
+
+/// 删除对话中最后的 n 条消息
+pub fn delete_last_chat_messages(
+    conn: &Connection,
+    chat_id: i64,
+    n: usize,
+) -> Result<(), Whatever> {
+    conn.execute(DELETE_LAST_CHAT_MESSAGES, (chat_id, n))
+        .with_whatever_context(|_| format!("Failed to delete the messages of chat({chat_id})"))?;
+    conn.execute(TOUCH_CHAT, [chat_id])
+        .with_whatever_context(|_| format!("Failed to update the chat({chat_id})"))?;
+    Ok(())
+}
+
+/// 更新对话的采样参数
+pub fn update_chat_sampler(
+    conn: &Connection,
+    chat_id: i64,
+    sampler: impl AsRef<str>,
+) -> Result<(), Whatever> {
+    conn.execute(UPDATE_CHAT_SAMPLER, (sampler.as_ref(), chat_id))
+        .with_whatever_context(|_| format!("Failed to update the sampler of chat({chat_id})"))?;
+    Ok(())
+}
+
+fn chat_summary(r: &rusqlite::Row) -> rusqlite::Result<ChatSummary> {
+    Ok(ChatSummary {
+        id: r.get(0)?,
+        title: r.get(1)?,
+        model_name: r.get(2)?,
+        sampler: r.get(3)?,
+        n_messages: r.get(4)?,
+        n_tokens: r.get(5)?,
+        created_time: r.get(6)?,
+        updated_time: r.get(7)?,
+    })
+}
+
+/// 按照最后更新的时间列出最近的对话
+pub fn query_chat_list(conn: &Connection, limit: usize) -> Result<Vec<ChatSummary>, Whatever> {
+    let mut statement = conn
+        .prepare(QUERY_CHAT_LIST)
+        .with_whatever_context(|_| "Failed to prepare query chat list")?;
+    let rows = statement
+        .query_map([limit], chat_summary)
+        .with_whatever_context(|_| "Failed to query chat list")?;
+    let mut chats = Vec::new();
+    for row in rows {
+        chats.push(row.with_whatever_context(|_| "Failed to get row")?);
+    }
+    Ok(chats)
+}
+
+pub fn query_chat(conn: &Connection, chat_id: i64) -> Result<Option<ChatSummary>, Whatever> {
+    conn.query_row(QUERY_CHAT, [chat_id], chat_summary)
+        .optional()
+        .with_whatever_context(|_| format!("Failed to query the chat({chat_id})"))
+}
+
+/// 按照添加的顺序查询对话中的消息
+pub fn query_chat_messages(conn: &Connection, chat_id: i64) -> Result<Vec<ChatMessage>, Whatever> {
+    let mut statement = conn
+        .prepare(QUERY_CHAT_MESSAGES)
+        .with_whatever_context(|_| "Failed to prepare query chat messages")?;
+    let rows = statement
+        .query_map([chat_id], |r| {
+            Ok(ChatMessage {
+                role: r.get(0)?,
+                content: r.get(1)?,
+                n_tokens: r.get(2)?,
+                created_time: r.get(3)?,
+            })
+        })
+        .with_whatever_context(|_| format!("Failed to query the messages of chat({chat_id})"))?;
+    let mut messages = Vec::new();
+    for row in rows {
+        messages.push(row.with_whatever_context(|_| "Failed to get row")?);
+    }
+    Ok(messages)
+}
+
+/// 使用 chat_message_fts 倒排索引全文检索对话中的消息，命中的片段使用 open 和 close 包裹
+pub fn search_chat_message(
+    conn: &Connection,
+    query: impl AsRef<str>,
+    open: impl AsRef<str>,
+    close: impl AsRef<str>,
+    limit: usize,
+) -> Result<Vec<ChatSearchHit>, Whatever> {
+    let query = query.as_ref();
+    let mut statement = conn
+        .prepare(SEARCH_CHAT_MESSAGE)
+        .with_whatever_context(|_| "Failed to prepare search chat message")?;
+    let rows = statement
+        .query_map((query, open.as_ref(), close.as_ref(), limit), |r| {
+            Ok(ChatSearchHit {
+                chat_id: r.get(0)?,
+                title: r.get(1)?,
+                model_name: r.get(2)?,
+                role: r.get(3)?,
+                rank: r.get(4)?,
+                snippet: r.get(5)?,
+                created_time: r.get(6)?,
+            })
+        })
+        .with_whatever_context(|_| format!("Failed to search chat message by {query}"))?;
+    let mut hits = Vec::new();
+    for row in rows {
+        hits.push(row.with_whatever_context(|_| "Failed to get row")?);
+    }
+    Ok(hits)
+}
@@ -11,6 +11,9 @@ const QUERY_INSERT_MODEL_INFO_COMPLETED: &str =
 
 const SET_INSERT_MODEL_INFO_COMPLETED: &str = "update config set value = cast(?1 as blob), updated_at = (?2) where name = 'insert_model_info_completed'";
 
+const QUERY_BLOB_STORE_MIGRATED: &str =
+    "select value from config where name = 'blob_store_migrated'";
+
 const INSERT_CONFIG_ITEM: &str = r#"insert into config (name, value) values (?1, ?2) on conflict (name) do update set value = excluded.value, updated_at = strftime('%s', 'now')"#;
 
 const QUERY_MANIFEST_SCHEMA_VERSION: &str =
@@ -78,6 +81,26 @@ pub fn completed_update_model_info(
     Ok(())
 }
 
+/// 检查按照规格保存的旧模型目录是否已经迁移到 blobs 目录
+pub fn check_blob_store_migrated(conn: &Connection) -> Result<bool, Whatever> {
+    let status = conn
+        .query_row(QUERY_BLOB_STORE_MIGRATED, [], |r| r.get::<_, Vec<u8>>(0))
+        .with_whatever_context(|_| "Failed to get blob store migrated status")?;
+    let status = String::from_utf8(status)
+        .with_whatever_context(|_| "Couldn't convert blob_store_migrated to string")?;
+    Ok(status == CompletedStatus::Completed.as_ref())
+}
+
+pub fn completed_blob_store_migrated(
+    conn: &Connection,
+    completed_status: CompletedStatus,
+) -> Result<(), Whatever> {
+    let status = completed_status.as_ref();
+    insert_config(conn, "blob_store_migrated", status.as_bytes().to_vec())
+        .with_whatever_context(|_| "Failed to set blob store migrated status")?;
+    Ok(())
+}
+
 /// 插入一个新的配置项，如果配置项已经存在，那么则更新这个配置项
 pub fn insert_config(
     conn: &Connection,
@@ -5,6 +5,18 @@ use std::{fs::create_dir_all, path::Path};
 
 const INIT_LLAMA_BUDDY_DB_SQL: &str = include_str!("llama_buddy_schema.sql");
 
+// 数据库结构升级脚本，按照 user_version 从小到大排列
+const MIGRATE_LLAMA_BUDDY_DB_SQL: &[(i32, &str)] = &[
+    (2, include_str!("llama_buddy_schema_v2.sql")),
+    (3, include_str!("llama_buddy_schema_v3.sql")),
+    (4, include_str!("llama_buddy_schema_v4.sql")),
+    (5, include_str!("llama_buddy_schema_v5.sql")),
+    (6, include_str!("llama_buddy_schema_v6.sql")),
+    (7, include_str!("llama_buddy_schema_v7.sql")),
+    (8, include_str!("llama_buddy_schema_v8.sql")),
+    (9, include_str!("llama_buddy_schema_v9.sql")),
+];
+
 /// 获取数据库连接
 pub fn open_llama_buddy_db(path: impl AsRef<Path>) -> Result<Connection, Whatever> {
     let path = path.as_ref();
@@ -39,6 +51,13 @@ fn check_llama_buddy_schema(conn: &Connection) -> Result<(), Whatever> {
         conn.execute_batch(INIT_LLAMA_BUDDY_DB_SQL)
             .with_whatever_context(|_| "Couldn't init db")?;
     }
+    // 依次执行还没有执行过的升级脚本
+    for (version, sql) in MIGRATE_LLAMA_BUDDY_DB_SQL {
+        if user_version < *version {
+            conn.execute_batch(sql)
+                .with_whatever_context(|_| format!("Couldn't migrate db to version {version}"))?;
+        }
+    }
     Ok(())
 }
 
@@ -0,0 +1,17 @@
+-- 开启一个排他事务
+begin exclusive;
+
+-- 模型表补充拉取状态，用来标识某个规格的模型是否拉取完成
+alter table model add column pull_status text default ('Not Started');
+
+-- 之前已经把全部文件保存到本地的模型，视为已经拉取完成
+update model
+set pull_status = 'Completed'
+where path is not null
+  and template is not null
+  and params is not null
+  and config is not null;
+
+-- 设置数据库的用户版本号为 2
+pragma user_version = 2;
+commit;
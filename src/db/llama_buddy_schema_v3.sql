@@ -0,0 +1,41 @@
+-- 开启一个排他事务
+begin exclusive;
+
+-- model_info_fts 使用 model_info 作为外部内容表，倒排索引的 rowid 必须和 model_info 的 rowid 保持一致，
+-- 否则查询时无法关联到正确的模型信息，删除旧的触发器并且重新创建
+drop trigger if exists model_info_after_insert;
+drop trigger if exists model_info_after_delete;
+drop trigger if exists model_info_after_update;
+
+create trigger if not exists model_info_after_insert
+    after insert
+    on model_info
+begin
+    insert into model_info_fts(rowid, title, introduction, summary, readme)
+    values (new.rowid, new.title, new.introduction, new.summary, new.readme);
+end;
+
+create trigger if not exists model_info_after_delete
+    after delete
+    on model_info
+begin
+    insert into model_info_fts(model_info_fts, rowid, title, introduction, summary, readme)
+    values ('delete', old.rowid, old.title, old.introduction, old.summary, old.readme);
+end;
+
+create trigger if not exists model_info_after_update
+    after update
+    on model_info
+begin
+    insert into model_info_fts(model_info_fts, rowid, title, introduction, summary, readme)
+    values ('delete', old.rowid, old.title, old.introduction, old.summary, old.readme);
+    insert into model_info_fts(rowid, title, introduction, summary, readme)
+    values (new.rowid, new.title, new.introduction, new.summary, new.readme);
+end;
+
+-- 根据 model_info 重建倒排索引
+insert into model_info_fts(model_info_fts) values ('rebuild');
+
+-- 设置数据库的用户版本号为 3
+pragma user_version = 3;
+commit;
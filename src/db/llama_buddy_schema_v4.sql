@@ -0,0 +1,11 @@
+-- 开启一个排他事务
+begin exclusive;
+
+-- 模型文件统一保存到 blobs 目录中，记录按照规格保存的旧目录是否已经迁移
+insert into config(name, value)
+values ('blob_store_migrated', cast('Not Started' as blob))
+on conflict (name) do nothing;
+
+-- 设置数据库的用户版本号为 4
+pragma user_version = 4;
+commit;
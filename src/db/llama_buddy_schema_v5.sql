@@ -0,0 +1,18 @@
+-- 开启一个排他事务
+begin exclusive;
+
+-- 已经校验过摘要的文件，文件的大小和修改时间没有变化时，不需要重新计算摘要
+create table if not exists blob
+(
+    digest      text primary key,
+    path        text    not null,
+    size        integer not null,
+    modified_at integer not null,
+    verified_at integer default (strftime('%s', 'now'))
+) strict;
+
+create index if not exists blob_path on blob (path);
+
+-- 设置数据库的用户版本号为 5
+pragma user_version = 5;
+commit;
@@ -0,0 +1,13 @@
+-- 开启一个排他事务
+begin exclusive;
+
+-- 模型所在的命名空间，从 ollama.com 获取的官方模型都在 library 中
+alter table model add column namespace text not null default 'library';
+-- 模型来源的注册表，为空时表示配置中的远程注册表
+alter table model add column registry text;
+-- 最近一次拉取的清单的摘要
+alter table model add column manifest_digest text;
+
+-- 设置数据库的用户版本号为 6
+pragma user_version = 6;
+commit;
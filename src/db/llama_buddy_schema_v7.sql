@@ -0,0 +1,25 @@
+-- 开启一个排他事务
+begin exclusive;
+
+-- 保存的对话状态，状态文件中保存了 KV cache 和 token，只能恢复到摘要相同的模型中
+create table if not exists session
+(
+    -- 会话的名字
+    name         text primary key,
+    -- 保存时使用的模型
+    model_name   text    not null,
+    -- 模型文件的摘要
+    model_digest text    not null,
+    -- 状态文件的路径
+    path         text    not null,
+    -- 状态文件中的 token 数量
+    n_tokens     integer not null default 0,
+    -- 对话中的消息，JSON 数组
+    messages     text    not null default '[]',
+    created_at   integer not null default (strftime('%s', 'now')),
+    updated_at   integer not null default (strftime('%s', 'now'))
+);
+
+-- 设置数据库的用户版本号为 7
+pragma user_version = 7;
+commit;
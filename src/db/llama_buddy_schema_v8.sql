@@ -0,0 +1,70 @@
+-- 开启一个排他事务
+begin exclusive;
+
+-- 对话，simple-run 中的每一次对话都会保存下来
+create table if not exists chat
+(
+    id         integer primary key autoincrement,
+    -- 对话的标题，取第一个问题的开头
+    title      text    not null default '',
+    -- 对话使用的模型
+    model_name text    not null,
+    -- 采样参数，JSON 对象
+    sampler    text    not null default '{}',
+    created_at integer not null default (strftime('%s', 'now')),
+    updated_at integer not null default (strftime('%s', 'now'))
+);
+
+-- 对话中的消息
+create table if not exists chat_message
+(
+    id         integer primary key autoincrement,
+    chat_id    integer not null references chat (id),
+    -- system、user 或者 assistant
+    role       text    not null,
+    content    text    not null,
+    -- 消息内容的 token 数量
+    n_tokens   integer not null default 0,
+    created_at integer not null default (strftime('%s', 'now'))
+);
+
+create index if not exists chat_message_chat_id on chat_message (chat_id);
+
+-- 对消息的内容创建倒排索引，chat_message 作为外部内容表，倒排索引的 rowid 和 chat_message 的 id 保持一致
+create virtual table if not exists chat_message_fts using fts5
+(
+    content,
+    content = 'chat_message',
+    content_rowid = 'id',
+    tokenize = 'jieba'
+);
+
+create trigger if not exists chat_message_after_insert
+    after insert
+    on chat_message
+begin
+    insert into chat_message_fts(rowid, content)
+    values (new.id, new.content);
+end;
+
+create trigger if not exists chat_message_after_delete
+    after delete
+    on chat_message
+begin
+    insert into chat_message_fts(chat_message_fts, rowid, content)
+    values ('delete', old.id, old.content);
+end;
+
+create trigger if not exists chat_message_after_update
+    after update
+    on chat_message
+begin
+    insert into chat_message_fts(chat_message_fts, rowid, content)
+    values ('delete', old.id, old.content);
+    insert into chat_message_fts(rowid, content)
+    values (new.id, new.content);
+end;
+
+-- 设置数据库的用户版本号为 8
+pragma user_version = 8;
+commit;
@@ -0,0 +1,16 @@
+-- 开启一个排他事务
+begin exclusive;
+
+-- 拉取模型时同时拉取默认的系统提示词
+insert into config(name, value)
+values ('system_media_type', cast('application/vnd.ollama.image.system' as blob)),
+       ('system', cast('txt' as blob))
+on conflict (name) do update set value      = excluded.value,
+                                 updated_at = strftime('%s', 'now');
+
+alter table model add column system text;
+alter table model add column system_size integer;
+
+-- 设置数据库的用户版本号为 9
+pragma user_version = 9;
+commit;
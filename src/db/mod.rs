@@ -1,13 +1,15 @@
+pub(crate) mod blob;
+pub(crate) mod chat;
 pub(crate) mod config;
 mod llama_buddy;
 pub(crate) mod model;
 mod rustyline_history;
+pub(crate) mod session;
 
 pub(crate) use llama_buddy::*;
 pub(crate) use rustyline_history::*;
 
 pub enum CompletedStatus {
-    #[allow(unused)]
     NotStarted,
     Completed,
     #[allow(unused)]
@@ -1,10 +1,11 @@
 use crate::{db::CompletedStatus, error::Whatever};
 use http_extra::sha256::digest;
-use rusqlite::{Connection, Transaction};
+use rusqlite::{Connection, Row, Transaction};
+use serde::Serialize;
 use snafu::prelude::*;
 use std::{
-    collections::HashMap,
-    path::Path,
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
     time::{SystemTime, UNIX_EPOCH},
 };
 use tracing::{error, info};
@@ -46,14 +47,25 @@ const QUERY_MODEL_TITLE_AND_RAW_DIGEST: &str = r#"select title, raw_digest from
 
 const QUERY_MODEL_NAME: &str = r#"select name from model where name = ?1;"#;
 
+// 其它命名空间或者其它注册表的模型没有模型信息，使用名字的前缀匹配
 const QUERY_FIRST_MODEL_NAME: &str = r#"
 select m.name
 from model m left join model_info mi on m.model_id = mi.id
-where mi.title = ?1
+where mi.title = ?1 or substr(m.name, 1, length(?1) + 1) = ?1 || ':'
 order by m.created_at
 limit 1;
 "#;
 
+const INSERT_INTO_REFERENCED_MODEL: &str = r#"
+insert into model (id, name, href, namespace, registry)
+values (?1, ?2, ?3, ?4, ?5)
+on conflict (name) do update set namespace  = excluded.namespace,
+                                 registry   = excluded.registry,
+                                 updated_at = strftime('%s', 'now');"#;
+
+const UPDATE_MANIFEST_DIGEST: &str =
+    r#"update model set manifest_digest = ?1, updated_at = strftime('%s', 'now') where name = ?2;"#;
+
 const UPDATE_MODEL_PATH_AND_SIZE: &str =
     r#"update model set path = ?1, size = ?2, updated_at = strftime('%s', 'now') where name = ?3;"#;
 
@@ -65,6 +77,8 @@ const UPDATE_PARAMS_PATH_AND_SIZE: &str = r#"update model set params = ?1, param
 
 const UPDATE_CONFIG_PATH_AND_SIZE: &str = r#"update model set config = ?1, config_size = ?2, updated_at = strftime('%s', 'now') where name = ?3;"#;
 
+const UPDATE_SYSTEM_PATH_AND_SIZE: &str = r#"update model set system = ?1, system_size = ?2, updated_at = strftime('%s', 'now') where name = ?3;"#;
+
 const SET_PULL_STATUS: &str =
     r#"update model set pull_status = ?1, updated_at = strftime('%s', 'now') where name = ?2;"#;
 
@@ -72,6 +86,22 @@ const QUERY_PULL_STATUS: &str = r#"select pull_status from model where name = ?1
 
 const QUERY_MODEL_PATH_TEMPLATE: &str = r#"select path, template from model where name = ?1;"#;
 
+const QUERY_MODELFILE_PATHS: &str =
+    r#"select template, system, params from model where name = ?1;"#;
+
+const QUERY_MODEL_LIST: &str = r#"
+select m.name,
+       coalesce(m.pull_status, 'Not Started'),
+       coalesce(m.size, 0) + coalesce(m.template_size, 0) + coalesce(m.license_size, 0) +
+       coalesce(m.params_size, 0) + coalesce(m.config_size, 0) + coalesce(m.system_size, 0) as total_size,
+       m.context,
+       m.input,
+       m.updated_at,
+       datetime(m.updated_at, 'unixepoch', 'localtime')
+from model m
+where ?1 or m.pull_status != 'Not Started' or total_size > 0;
+"#;
+
 // 插入 model 信息
 #[derive(Eq, PartialEq, Clone, Default, Debug)]
 pub(crate) struct ModelInfo {
@@ -119,6 +149,213 @@ pub(crate) struct Model {
     pub(crate) hash: String,
 }
 
+const QUERY_MODEL_LIST_BY_MODEL_ID: &str = r#"
+select m.name,
+       coalesce(m.pull_status, 'Not Started'),
+       coalesce(m.size, 0) + coalesce(m.template_size, 0) + coalesce(m.license_size, 0) +
+       coalesce(m.params_size, 0) + coalesce(m.config_size, 0) + coalesce(m.system_size, 0) as total_size,
+       m.context,
+       m.input,
+       m.updated_at,
+       datetime(m.updated_at, 'unixepoch', 'localtime')
+from model m
+where m.model_id = ?1
+order by m.created_at;
+"#;
+
+// 使用 bm25 进行排序，title 的权重最高，其次是 introduction、summary、readme
+const SEARCH_MODEL_INFO: &str = r#"
+select mi.id,
+       mi.title,
+       bm25(model_info_fts, 10.0, 5.0, 2.0, 1.0) as rank,
+       snippet(model_info_fts, 2, ?2, ?3, '...', 16),
+       snippet(model_info_fts, 3, ?2, ?3, '...', 16)
+from model_info_fts join model_info mi on mi.rowid = model_info_fts.rowid
+where model_info_fts match ?1
+order by rank;
+"#;
+
+const CLEAR_MODEL_FILE_PATH: &str = r#"
+update model
+set path          = null,
+    size          = null,
+    template      = null,
+    template_size = null,
+    license       = null,
+    license_size  = null,
+    params        = null,
+    params_size   = null,
+    config        = null,
+    config_size   = null,
+    system        = null,
+    system_size   = null,
+    pull_status   = 'Not Started',
+    updated_at    = strftime('%s', 'now')
+where name = ?1;
+"#;
+
+const QUERY_ALL_MODEL_FILE_PATHS: &str = r#"
+select path from model where path is not null
+union
+select template from model where template is not null
+union
+select license from model where license is not null
+union
+select params from model where params is not null
+union
+select config from model where config is not null
+union
+select system from model where system is not null;
+"#;
+
+const REPLACE_MODEL_FILE_PATH: &str = r#"
+update model
+set path       = iif(path = ?1, ?2, path),
+    template   = iif(template = ?1, ?2, template),
+    license    = iif(license = ?1, ?2, license),
+    params     = iif(params = ?1, ?2, params),
+    config     = iif(config = ?1, ?2, config),
+    system     = iif(system = ?1, ?2, system),
+    updated_at = strftime('%s', 'now')
+where ?1 in (path, template, license, params, config, system);
+"#;
+
+const COUNT_MODEL_FILE_REFERENCES: &str = r#"
+select count(*)
+from model
+where ?1 in (path, template, license, params, config, system);
+"#;
+
+const QUERY_MODEL_DETAIL: &str = r#"
+select m.name,
+       coalesce(m.pull_status, 'Not Started'),
+       m.context,
+       m.input,
+       m.hash,
+       m.path,
+       m.size,
+       m.template,
+       m.template_size,
+       m.license,
+       m.license_size,
+       m.params,
+       m.params_size,
+       m.config,
+       m.config_size,
+       mi.title,
+       mi.introduction,
+       mi.summary,
+       mi.readme,
+       mi.pull_count,
+       mi.tag_count,
+       mi.updated_time,
+       m.namespace,
+       m.registry,
+       m.manifest_digest,
+       m.system,
+       m.system_size
+from model m left join model_info mi on m.model_id = mi.id
+where m.name = ?1;
+"#;
+
+const QUERY_SIBLING_MODEL_NAMES: &str = r#"
+select name
+from model
+where model_id = (select model_id from model where name = ?1)
+order by created_at;
+"#;
+
+// 本地注册表中的模型概要，用于列出模型
+#[derive(Eq, PartialEq, Clone, Default, Debug, Serialize)]
+pub(crate) struct ModelSummary {
+    // 模型名字
+    pub(crate) name: String,
+    // 模型的规格
+    pub(crate) category: String,
+    // 拉取状态
+    pub(crate) pull_status: String,
+    // 已经保存在本地的文件总大小，单位为字节
+    pub(crate) size: u64,
+    // 上下文大小
+    pub(crate) context: Option<String>,
+    // 输入类型
+    pub(crate) input: Option<String>,
+    // 最后更新的时间戳，单位为秒
+    pub(crate) updated_at: i64,
+    // 最后更新的本地时间
+    pub(crate) updated_time: String,
+}
+
+impl ModelSummary {
+    /// 是否已经拉取（包括拉取了一部分）到本地
+    pub(crate) fn pulled(&self) -> bool {
+        self.pull_status != CompletedStatus::NotStarted.as_ref() || self.size > 0
+    }
+}
+
+// 某个规格的模型保存在本地注册表中的全部信息
+#[derive(Eq, PartialEq, Clone, Default, Debug)]
+pub(crate) struct ModelDetail {
+    // 模型名字，包含规格
+    pub(crate) name: String,
+    // 拉取状态
+    pub(crate) pull_status: String,
+    // 上下文大小
+    pub(crate) context: Option<String>,
+    // 输入类型
+    pub(crate) input: Option<String>,
+    // 模型 hash
+    pub(crate) hash: Option<String>,
+    // 已经拉取到本地的文件，依次为 model、template、license、params、config、system
+    pub(crate) files: Vec<ModelFile>,
+    // 模型名字
+    pub(crate) title: Option<String>,
+    // 模型简介
+    pub(crate) introduction: Option<String>,
+    // 模型介绍摘要
+    pub(crate) summary: Option<String>,
+    // 模型详细介绍
+    pub(crate) readme: Option<String>,
+    // 拉取的数量
+    pub(crate) pull_count: Option<String>,
+    // 规格的数量
+    pub(crate) tag_count: Option<String>,
+    // 更新时间
+    pub(crate) updated_time: Option<String>,
+    // 命名空间
+    pub(crate) namespace: String,
+    // 来源的注册表，为 None 时表示配置中的远程注册表
+    pub(crate) registry: Option<String>,
+    // 最近一次拉取的清单的摘要
+    pub(crate) manifest_digest: Option<String>,
+}
+
+// 拉取到本地的文件
+#[derive(Eq, PartialEq, Clone, Default, Debug)]
+pub(crate) struct ModelFile {
+    // 文件的类型，model、template、license、params、config、system
+    pub(crate) media: String,
+    // 文件路径
+    pub(crate) path: String,
+    // 文件大小，单位为字节
+    pub(crate) size: u64,
+}
+
+// 全文检索命中的模型信息
+#[derive(PartialEq, Clone, Default, Debug)]
+pub(crate) struct ModelSearchHit {
+    // 模型信息的 id
+    pub(crate) model_id: Uuid,
+    // 模型名字
+    pub(crate) title: String,
+    // bm25 的得分，越小越相关
+    pub(crate) rank: f64,
+    // 模型介绍摘要中命中的片段
+    pub(crate) summary: String,
+    // 模型详细介绍中命中的片段
+    pub(crate) readme: String,
+}
+
 pub fn save_library_to_library_raw_data(conn: &Connection, html: String) -> Result<bool, Whatever> {
     let now = SystemTime::now()
         .duration_since(UNIX_EPOCH)
@@ -252,6 +489,40 @@ pub fn get_first_model_name(conn: &Connection, name: impl AsRef<str>) -> Result<
         .with_whatever_context(|_| "Failed to get first model name")
 }
 
+/// 保存没有模型信息的模型，例如其它命名空间或者其它注册表中的模型，拉取之前需要先保存到本地注册表中
+pub fn save_referenced_model(
+    conn: &Connection,
+    name: impl AsRef<str>,
+    href: impl AsRef<str>,
+    namespace: impl AsRef<str>,
+    registry: Option<&str>,
+) -> Result<(), Whatever> {
+    let name = name.as_ref();
+    conn.execute(
+        INSERT_INTO_REFERENCED_MODEL,
+        (
+            Uuid::now_v7(),
+            name,
+            href.as_ref(),
+            namespace.as_ref(),
+            registry,
+        ),
+    )
+    .with_whatever_context(|_| format!("Failed to save the referenced model({name})"))?;
+    Ok(())
+}
+
+pub fn set_model_manifest_digest(
+    conn: &Connection,
+    name: impl AsRef<str>,
+    digest: impl AsRef<str>,
+) -> Result<(), Whatever> {
+    let name = name.as_ref();
+    conn.execute(UPDATE_MANIFEST_DIGEST, (digest.as_ref(), name))
+        .with_whatever_context(|_| format!("Failed to set manifest digest for {name}"))?;
+    Ok(())
+}
+
 pub fn get_model_params(
     conn: &Connection,
     name: impl AsRef<str>,
@@ -265,6 +536,21 @@ pub fn get_model_params(
     .with_whatever_context(|_| "Failed to get model params")
 }
 
+/// 模型的模板、系统提示词和参数文件所在的位置
+pub type ModelfilePaths = (Option<String>, Option<String>, Option<String>);
+
+/// 获取模型的模板、系统提示词和参数文件所在的位置，没有拉取的文件为 None
+pub fn get_modelfile_paths(
+    conn: &Connection,
+    name: impl AsRef<str>,
+) -> Result<ModelfilePaths, Whatever> {
+    let name = name.as_ref();
+    conn.query_one(QUERY_MODELFILE_PATHS, [name], |r| {
+        Ok((r.get(0)?, r.get(1)?, r.get(2)?))
+    })
+    .with_whatever_context(|_| format!("Failed to get the modelfile paths of {name}"))
+}
+
 pub fn save_model_file_path(
     conn: &Connection,
     name: impl AsRef<str>,
@@ -279,6 +565,7 @@ pub fn save_model_file_path(
         "license" => UPDATE_LICENSE_PATH_AND_SIZE,
         "params" => UPDATE_PARAMS_PATH_AND_SIZE,
         "config" => UPDATE_CONFIG_PATH_AND_SIZE,
+        "system" => UPDATE_SYSTEM_PATH_AND_SIZE,
         str => whatever!("This value({str}) cannot be processed."),
     };
     let path = path.as_ref().display().to_string();
@@ -310,6 +597,204 @@ pub fn check_pull_completed(conn: &Connection, name: impl AsRef<str>) -> Result<
     Ok(status == CompletedStatus::Completed.as_ref())
 }
 
+/// 查询本地注册表中的模型，all 为 false 时只返回已经拉取（包括拉取了一部分）的模型
+pub fn query_model_list(conn: &Connection, all: bool) -> Result<Vec<ModelSummary>, Whatever> {
+    let mut statement = conn
+        .prepare(QUERY_MODEL_LIST)
+        .with_whatever_context(|_| "Failed to prepare query model list")?;
+    let rows = statement
+        .query_map([all], model_summary_from_row)
+        .with_whatever_context(|_| "Failed to query model list")?;
+    let mut models = Vec::new();
+    for row in rows {
+        models.push(row.with_whatever_context(|_| "Failed to get row")?);
+    }
+    Ok(models)
+}
+
+/// 查询某个模型的全部规格
+pub fn query_model_list_by_model_id(
+    conn: &Connection,
+    model_id: &Uuid,
+) -> Result<Vec<ModelSummary>, Whatever> {
+    let mut statement = conn
+        .prepare(QUERY_MODEL_LIST_BY_MODEL_ID)
+        .with_whatever_context(|_| "Failed to prepare query model list by model id")?;
+    let rows = statement
+        .query_map([model_id], model_summary_from_row)
+        .with_whatever_context(|_| format!("Failed to query model list by {model_id}"))?;
+    let mut models = Vec::new();
+    for row in rows {
+        models.push(row.with_whatever_context(|_| "Failed to get row")?);
+    }
+    Ok(models)
+}
+
+fn model_summary_from_row(row: &Row) -> rusqlite::Result<ModelSummary> {
+    let model_name = row.get::<_, String>(0)?;
+    let (name, category) = match model_name.rsplit_once(':') {
+        Some((name, category)) => (name.to_owned(), category.to_owned()),
+        None => (model_name, String::new()),
+    };
+    Ok(ModelSummary {
+        name,
+        category,
+        pull_status: row.get(1)?,
+        size: row.get(2)?,
+        context: row.get(3)?,
+        input: row.get(4)?,
+        updated_at: row.get(5)?,
+        updated_time: row.get(6)?,
+    })
+}
+
+/// 使用 model_info_fts 倒排索引全文检索模型信息，命中的片段使用 open 和 close 包裹
+pub fn search_model_info(
+    conn: &Connection,
+    query: impl AsRef<str>,
+    open: impl AsRef<str>,
+    close: impl AsRef<str>,
+) -> Result<Vec<ModelSearchHit>, Whatever> {
+    let query = query.as_ref();
+    let mut statement = conn
+        .prepare(SEARCH_MODEL_INFO)
+        .with_whatever_context(|_| "Failed to prepare search model info")?;
+    let rows = statement
+        .query_map((query, open.as_ref(), close.as_ref()), |r| {
+            Ok(ModelSearchHit {
+                model_id: r.get(0)?,
+                title: r.get(1)?,
+                rank: r.get(2)?,
+                summary: r.get::<_, Option<String>>(3)?.unwrap_or_default(),
+                readme: r.get::<_, Option<String>>(4)?.unwrap_or_default(),
+            })
+        })
+        .with_whatever_context(|_| format!("Failed to search model info by {query}"))?;
+    let mut hits = Vec::new();
+    for row in rows {
+        hits.push(row.with_whatever_context(|_| "Failed to get row")?);
+    }
+    Ok(hits)
+}
+
+/// 查询某个规格的模型的详细信息
+pub fn query_model_detail(
+    conn: &Connection,
+    name: impl AsRef<str>,
+) -> Result<ModelDetail, Whatever> {
+    let name = name.as_ref();
+    conn.query_one(QUERY_MODEL_DETAIL, [name], |r| {
+        let mut files = Vec::new();
+        // system 是后来增加的，在查询结果的最后
+        let columns = [
+            ("model", 5),
+            ("template", 7),
+            ("license", 9),
+            ("params", 11),
+            ("config", 13),
+            ("system", 25),
+        ];
+        for (media, index) in columns {
+            let path = r.get::<_, Option<String>>(index)?;
+            let size = r.get::<_, Option<u64>>(index + 1)?;
+            if let Some(path) = path {
+                files.push(ModelFile {
+                    media: media.to_owned(),
+                    path,
+                    size: size.unwrap_or_default(),
+                });
+            }
+        }
+        Ok(ModelDetail {
+            name: r.get(0)?,
+            pull_status: r.get(1)?,
+            context: r.get(2)?,
+            input: r.get(3)?,
+            hash: r.get(4)?,
+            files,
+            title: r.get(15)?,
+            introduction: r.get(16)?,
+            summary: r.get(17)?,
+            readme: r.get(18)?,
+            pull_count: r.get(19)?,
+            tag_count: r.get(20)?,
+            updated_time: r.get(21)?,
+            namespace: r.get(22)?,
+            registry: r.get(23)?,
+            manifest_digest: r.get(24)?,
+        })
+    })
+    .with_whatever_context(|_| format!("Failed to get model detail for {name}"))
+}
+
+/// 查询同一个模型的全部规格
+pub fn query_sibling_model_names(
+    conn: &Connection,
+    name: impl AsRef<str>,
+) -> Result<Vec<String>, Whatever> {
+    let name = name.as_ref();
+    let mut statement = conn
+        .prepare(QUERY_SIBLING_MODEL_NAMES)
+        .with_whatever_context(|_| "Failed to prepare query sibling model names")?;
+    let rows = statement
+        .query_map([name], |r| r.get::<_, String>(0))
+        .with_whatever_context(|_| format!("Failed to query sibling model names for {name}"))?;
+    let mut names = Vec::new();
+    for row in rows {
+        names.push(row.with_whatever_context(|_| "Failed to get row")?);
+    }
+    Ok(names)
+}
+
+/// 清空模型已经拉取的文件路径和大小，并且重置拉取状态
+pub fn clear_model_file_path(conn: &Connection, name: impl AsRef<str>) -> Result<(), Whatever> {
+    let name = name.as_ref();
+    conn.execute(CLEAR_MODEL_FILE_PATH, [name])
+        .with_whatever_context(|_| format!("Failed to clear file path for {name}"))?;
+    Ok(())
+}
+
+/// 将全部模型中引用的文件路径从 old 替换为 new
+pub fn replace_model_file_path(
+    conn: &Connection,
+    old: impl AsRef<Path>,
+    new: impl AsRef<Path>,
+) -> Result<(), Whatever> {
+    let old = old.as_ref().display().to_string();
+    let new = new.as_ref().display().to_string();
+    conn.execute(REPLACE_MODEL_FILE_PATH, (&old, &new))
+        .with_whatever_context(|_| format!("Failed to replace file path {old} with {new}"))?;
+    Ok(())
+}
+
+/// 统计引用某个文件的模型数量
+pub fn count_model_file_references(
+    conn: &Connection,
+    path: impl AsRef<Path>,
+) -> Result<usize, Whatever> {
+    let path = path.as_ref().display().to_string();
+    conn.query_one(COUNT_MODEL_FILE_REFERENCES, [&path], |r| {
+        r.get::<_, usize>(0)
+    })
+    .with_whatever_context(|_| format!("Failed to count model file references for {path}"))
+}
+
+/// 查询全部被模型引用的文件路径
+pub fn query_all_model_file_paths(conn: &Connection) -> Result<HashSet<PathBuf>, Whatever> {
+    let mut statement = conn
+        .prepare(QUERY_ALL_MODEL_FILE_PATHS)
+        .with_whatever_context(|_| "Failed to prepare query all model file paths")?;
+    let rows = statement
+        .query_map([], |r| r.get::<_, String>(0))
+        .with_whatever_context(|_| "Failed to query all model file paths")?;
+    let mut paths = HashSet::new();
+    for row in rows {
+        let path = row.with_whatever_context(|_| "Failed to get row")?;
+        paths.insert(PathBuf::from(path));
+    }
+    Ok(paths)
+}
+
 fn rollback_and_return(tx: Transaction) -> Result<bool, Whatever> {
     tx.rollback().with_whatever_context(|_| "Rollback failed")?;
     Ok(false)
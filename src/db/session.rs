@@ -0,0 +1,65 @@
+use crate::error::Whatever;
+use rusqlite::{Connection, OptionalExtension};
+use snafu::prelude::*;
+
+const SAVE_SESSION: &str = r#"insert into session (name, model_name, model_digest, path, n_tokens, messages)
+values (?1, ?2, ?3, ?4, ?5, ?6)
+on conflict (name) do update set model_name   = excluded.model_name,
+                                 model_digest = excluded.model_digest,
+                                 path         = excluded.path,
+                                 n_tokens     = excluded.n_tokens,
+                                 messages     = excluded.messages,
+                                 updated_at   = strftime('%s', 'now')"#;
+
+const QUERY_SESSION: &str = r#"select name, model_name, model_digest, path, n_tokens, messages
+from session
+where name = ?1"#;
+
+/// 保存的对话状态
+#[derive(Debug, Eq, PartialEq)]
+pub struct Session {
+    pub name: String,
+    pub model_name: String,
+    pub model_digest: String,
+    pub path: String,
+    pub n_tokens: usize,
+    /// 对话中的消息，JSON 数组
+    pub messages: String,
+}
+
+/// 保存对话状态，名字相同时覆盖之前的记录
+pub fn save_session(conn: &Connection, session: &Session) -> Result<(), Whatever> {
+    let Session {
+        name,
+        model_name,
+        model_digest,
+        path,
+        n_tokens,
+        messages,
+    } = session;
+    conn.execute(
+        SAVE_SESSION,
+        (name, model_name, model_digest, path, n_tokens, messages),
+    )
+    .with_whatever_context(|_| format!("Failed to save the session({name})"))?;
+    Ok(())
+}
+
+pub fn query_session(
+    conn: &Connection,
+    name: impl AsRef<str>,
+) -> Result<Option<Session>, Whatever> {
+    let name = name.as_ref();
+    conn.query_row(QUERY_SESSION, [name], |r| {
+        Ok(Session {
+            name: r.get(0)?,
+            model_name: r.get(1)?,
+            model_digest: r.get(2)?,
+            path: r.get(3)?,
+            n_tokens: r.get(4)?,
+            messages: r.get(5)?,
+        })
+    })
+    .optional()
+    .with_whatever_context(|_| format!("Failed to query the session({name})"))
+}
@@ -2,15 +2,25 @@ mod cmd;
 mod config;
 mod db;
 mod error;
+mod server;
 mod service;
 mod utils;
 
 use crate::cmd::{
+    chat::{ChatArgs, manage_chats},
     config::output,
     init::{InitArgs, init_local_registry},
+    list::{ListArgs, list_local_models},
+    prune::{PruneArgs, prune_local_files},
+    ps::{PsArgs, list_running_models},
     pull::{PullArgs, pull_model_from_registry},
+    rm::{RmArgs, remove_local_model},
+    search::{SearchArgs, search_local_models},
+    serve::{ServeArgs, serve_models},
+    show::{ShowArgs, show_model_detail},
     simple_run::{SimpleRunArgs, simple_run_a_model},
     update::{UpdateArgs, update_local_registry},
+    verify::{VerifyArgs, verify_local_models},
 };
 use clap::{
     Parser, Subcommand,
@@ -44,9 +54,24 @@ enum Commands {
     Update(UpdateArgs),
     #[command(about = "Simple run a model")]
     SimpleRun(SimpleRunArgs),
-    // 列出可用的模型 list
-    // 展示模型详细信息 show
-    // 查找模型 search
+    #[command(about = "List, resume, search and export the saved chats")]
+    Chat(ChatArgs),
+    #[command(about = "List models in local registry")]
+    List(ListArgs),
+    #[command(about = "Show the details of a model")]
+    Show(ShowArgs),
+    #[command(about = "Search models in local registry")]
+    Search(SearchArgs),
+    #[command(about = "Remove a pulled model from local")]
+    Rm(RmArgs),
+    #[command(about = "Prune the files which are not referenced by any model")]
+    Prune(PruneArgs),
+    #[command(about = "Verify the digests of the pulled files")]
+    Verify(VerifyArgs),
+    #[command(about = "Serve the pulled models over OpenAI and Ollama compatible HTTP APIs")]
+    Serve(ServeArgs),
+    #[command(about = "List the models loaded by the server")]
+    Ps(PsArgs),
 }
 
 #[tokio::main]
@@ -60,5 +85,14 @@ async fn main() {
         Commands::Pull(args) => pull_model_from_registry(args).await,
         Commands::Update(args) => update_local_registry(args).await,
         Commands::SimpleRun(args) => simple_run_a_model(args).await,
+        Commands::Chat(args) => manage_chats(args).await,
+        Commands::List(args) => list_local_models(args).await,
+        Commands::Show(args) => show_model_detail(args).await,
+        Commands::Search(args) => search_local_models(args).await,
+        Commands::Rm(args) => remove_local_model(args).await,
+        Commands::Prune(args) => prune_local_files(args).await,
+        Commands::Verify(args) => verify_local_models(args).await,
+        Commands::Serve(args) => serve_models(args).await,
+        Commands::Ps(args) => list_running_models(args).await,
     }
 }
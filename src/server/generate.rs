@@ -0,0 +1,191 @@
+//! 在阻塞的线程中完成一次生成
+
+use crate::{
+    error::Whatever,
+    server::{
+        sampling::SamplingParams,
+        worker::{Update, Worker},
+    },
+};
+use llama_cpp::{
+    batch::Batch,
+    context::Context,
+    generator::{StopMatcher, Utf8Decoder},
+    model::{Model, Special},
+    runtime::Runtime,
+    scheduler,
+    token::Token,
+};
+use serde::Serialize;
+use snafu::{FromString, prelude::*};
+use std::time::{Duration, Instant};
+use tracing::debug;
+
+/// 停止生成的原因
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum FinishReason {
+    /// 生成了结束的 token 或者遇到了停止字符串
+    Stop,
+    /// 达到了 max_tokens 或者上下文用完了
+    Length,
+    /// 模型调用了工具
+    ToolCalls,
+}
+
+/// 一次生成消耗的 token 数量和时间
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Serialize)]
+pub(crate) struct Usage {
+    pub(crate) prompt_tokens: u32,
+    pub(crate) completion_tokens: u32,
+    pub(crate) total_tokens: u32,
+    // 处理提示词使用的时间，OpenAI 的接口中不返回
+    #[serde(skip)]
+    pub(crate) prompt_duration: Duration,
+    // 生成文本使用的时间，OpenAI 的接口中不返回
+    #[serde(skip)]
+    pub(crate) completion_duration: Duration,
+}
+
+impl Usage {
+    fn new(prompt_tokens: usize, completion_tokens: u32) -> Self {
+        let prompt_tokens = prompt_tokens as u32;
+        Self {
+            prompt_tokens,
+            completion_tokens,
+            total_tokens: prompt_tokens + completion_tokens,
+            ..Default::default()
+        }
+    }
+}
+
+/// 将提示词转换成 token
+pub(crate) fn tokenize(model: &Model, prompt: &str) -> Result<Vec<Token>, Whatever> {
+    model
+        .vocab()
+        .tokenize(prompt, true, true)
+        .with_whatever_context(|_| "Failed to tokenize the prompt")
+}
+
+/// 根据提示词生成文本，每生成一段文本调用一次 on_text，on_text 返回 false 时提前结束
+///
+/// 请求提交给模型的生成线程，和其它请求共享同一个上下文，会阻塞当前线程，需要在 spawn_blocking 中调用
+pub(crate) fn generate(
+    model: &Model,
+    worker: &Worker,
+    tokens: Vec<Token>,
+    params: &SamplingParams,
+    mut on_text: impl FnMut(&str) -> bool,
+) -> Result<(FinishReason, Usage), Whatever> {
+    let n_prompt = tokens.len();
+    let started_at = Instant::now();
+    // 提前结束时释放通道，生成线程会取消这个请求
+    let updates = worker.submit(tokens, params.sampler(model), params.max_tokens)?;
+    let mut decoder = Utf8Decoder::default();
+    let mut stop = StopMatcher::new(params.stop.clone());
+    let mut prompt_duration = None;
+    let mut n_generated = 0;
+    let finish_reason = loop {
+        let update = updates
+            .recv()
+            .ok()
+            .whatever_context("The generation thread has stopped")?;
+        match update {
+            Update::Token(token) => {
+                prompt_duration.get_or_insert_with(|| started_at.elapsed());
+                n_generated += 1;
+                // 无法转换的 token 不输出任何文本
+                let bytes = model
+                    .token_to_bytes(token, Special::Plaintext)
+                    .unwrap_or_default();
+                let (text, stopped) = stop.push(&decoder.push(&bytes));
+                if (!text.is_empty() && !on_text(&text)) || stopped.is_some() {
+                    break FinishReason::Stop;
+                }
+            }
+            Update::Finished { reason, n_cached } => {
+                debug!("Reused {n_cached} of {n_prompt} prompt tokens from the prefix cache");
+                // 结束之后输出保留的文本
+                let mut rest = decoder.finish();
+                rest.insert_str(0, &stop.finish());
+                if !rest.is_empty() {
+                    on_text(&rest);
+                }
+                break match reason {
+                    // 达到了 max_tokens 或者序列的上下文用完了
+                    scheduler::FinishReason::Length => FinishReason::Length,
+                    _ => FinishReason::Stop,
+                };
+            }
+            Update::Failed(message) => whatever!("Failed to generate, {message}"),
+        }
+    };
+    let prompt_duration = prompt_duration.unwrap_or_else(|| started_at.elapsed());
+    let usage = Usage {
+        prompt_duration,
+        completion_duration: started_at.elapsed().saturating_sub(prompt_duration),
+        ..Usage::new(n_prompt, n_generated)
+    };
+    Ok((finish_reason, usage))
+}
+
+/// 计算文本的嵌入向量，向量经过 L2 归一化，上下文需要开启嵌入向量
+///
+/// 会阻塞当前线程，需要在 spawn_blocking 中调用
+pub(crate) fn embed(
+    runtime: &Runtime,
+    model: &Model,
+    context: &mut Context,
+    inputs: &[String],
+) -> Result<(Vec<Vec<f32>>, Usage), Whatever> {
+    let vocab = model.vocab();
+    let n_ctx = context.n_ctx();
+    let mut embeddings = Vec::with_capacity(inputs.len());
+    let mut prompt_tokens = 0;
+    let started_at = Instant::now();
+    for input in inputs {
+        let tokens = vocab
+            .tokenize(input, true, true)
+            .with_whatever_context(|_| "Failed to tokenize the input")?;
+        ensure_whatever!(
+            !tokens.is_empty() && tokens.len() <= n_ctx as usize,
+            "The input has {} tokens, but the context size is {n_ctx}",
+            tokens.len()
+        );
+        prompt_tokens += tokens.len();
+        // 每个输入都使用干净的上下文
+        context.clear_kv_cache(true);
+        let mut batch = Batch::new(tokens.len() as i32, 1);
+        batch.add_sequence(&tokens, 0, false).map_err(batch_error)?;
+        context
+            .decode(&mut batch)
+            .with_whatever_context(|_| "Failed to decode the batch")?;
+        // 模型没有池化时，使用最后一个 token 的嵌入向量
+        let embedding = match runtime.embeddings_seq_ith(model, context, 0) {
+            Ok(embedding) => embedding.to_vec(),
+            Err(_) => runtime
+                .embeddings_ith(model, context, batch.n_tokens() - 1)
+                .with_whatever_context(|_| "Failed to get the embeddings")?
+                .to_vec(),
+        };
+        embeddings.push(normalize(embedding));
+    }
+    let usage = Usage {
+        prompt_duration: started_at.elapsed(),
+        ..Usage::new(prompt_tokens, 0)
+    };
+    Ok((embeddings, usage))
+}
+
+// llama-cpp 的错误不能在线程之间传递，只保留错误信息
+fn batch_error(error: llama_cpp::Error) -> Whatever {
+    Whatever::without_source(format!("Failed to build the batch, {error}"))
+}
+
+fn normalize(mut embedding: Vec<f32>) -> Vec<f32> {
+    let norm = embedding.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm > 0.0 {
+        embedding.iter_mut().for_each(|x| *x /= norm);
+    }
+    embedding
+}
@@ -0,0 +1,316 @@
+//! 提供模型推理的 HTTP 服务，同时兼容 OpenAI 和 Ollama 的接口
+//!
+//! 模型的名字和命令行中一样，通过本地注册表查找拉取到本地的模型文件，模型在第一次使用时加载
+
+pub mod generate;
+pub mod ollama;
+pub mod openai;
+pub mod residency;
+pub mod sampling;
+pub mod worker;
+
+use crate::{
+    config::Config as LLamaBuddyConfig,
+    db,
+    error::Whatever,
+    server::residency::{KeepAlive, ModelLease, Residency},
+    service,
+    service::modelfile::Modelfile,
+    utils::reference::ModelReference,
+};
+use axum::{
+    Json, Router,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::{delete, get, post},
+};
+use llama_cpp::{
+    context::{Context, ContextParams},
+    model::{Model, ModelParams},
+    runtime::Runtime,
+};
+use serde_json::json;
+use snafu::prelude::*;
+use std::{path::PathBuf, sync::Arc, time::Duration};
+use tokio::{sync::Mutex, task::JoinHandle};
+use tracing::info;
+
+// 检查模型是否超过保留时间的间隔
+const REAP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// 服务的共享状态
+pub struct ServerState {
+    runtime: Runtime,
+    config: LLamaBuddyConfig,
+    sqlite_dir: PathBuf,
+    // 每个请求使用的上下文大小
+    n_ctx: u32,
+    // 每个模型同时处理的生成请求数量
+    n_parallel: u32,
+    // 卸载到 GPU 的层数
+    n_gpu_layers: i32,
+    // 常驻的模型，键为本地注册表中的模型名字
+    residency: Residency,
+    // 加载模型时持有，避免同一个模型被同时加载多次，也避免同时加载的模型超过内存预算
+    loading: Mutex<()>,
+}
+
+impl ServerState {
+    pub fn new(
+        runtime: Runtime,
+        config: LLamaBuddyConfig,
+        n_ctx: u32,
+        n_parallel: u32,
+        n_gpu_layers: i32,
+        keep_alive: KeepAlive,
+        max_memory: Option<u64>,
+    ) -> Self {
+        let sqlite_dir = config.data.path.join("sqlite");
+        Self {
+            runtime,
+            config,
+            sqlite_dir,
+            n_ctx,
+            n_parallel: n_parallel.max(1),
+            n_gpu_layers,
+            residency: Residency::new(keep_alive, max_memory),
+            loading: Mutex::new(()),
+        }
+    }
+
+    pub(crate) fn runtime(&self) -> &Runtime {
+        &self.runtime
+    }
+
+    pub(crate) fn config(&self) -> &LLamaBuddyConfig {
+        &self.config
+    }
+
+    pub(crate) fn residency(&self) -> &Residency {
+        &self.residency
+    }
+
+    pub(crate) fn open_db(&self) -> Result<rusqlite::Connection, ApiError> {
+        db::open_llama_buddy_db(&self.sqlite_dir).map_err(ApiError::internal)
+    }
+
+    /// 创建上下文使用的参数，计算嵌入向量时一次处理整个输入
+    ///
+    /// 生成上下文由模型的全部生成请求共享，每个请求占用一个序列，每个序列可以使用 n_ctx 个 token
+    pub(crate) fn context_params(&self, embeddings: bool) -> ContextParams {
+        let params = ContextParams::default().with_n_batch(self.n_ctx);
+        if embeddings {
+            params
+                .with_n_ctx(self.n_ctx)
+                .with_n_ubatch(self.n_ctx)
+                .with_embeddings(true)
+        } else {
+            // 每个序列使用独立的 KV cache，结束的请求保留的 KV cache 不会占用其它序列的空间
+            params
+                .with_n_ctx(self.n_ctx.saturating_mul(self.n_parallel))
+                .with_n_seq_max(self.n_parallel)
+                .with_kv_unified(false)
+        }
+    }
+
+    pub(crate) fn new_context(&self, model: &Model, embeddings: bool) -> Result<Context, Whatever> {
+        self.runtime
+            .new_context(model, self.context_params(embeddings))
+            .with_whatever_context(|_| "Failed to create a model context")
+    }
+
+    /// 通过模型的引用找到本地注册表中的模型，返回正在使用的模型，同时读取拉取的模板、系统提示词和参数
+    ///
+    /// 模型没有加载时先加载模型，超过内存预算时按照最近最少使用的顺序卸载空闲的模型，keep_alive 为 None 时使用服务的默认值
+    pub(crate) async fn model(
+        self: &Arc<Self>,
+        reference: &str,
+        keep_alive: Option<KeepAlive>,
+    ) -> Result<ModelLease, ApiError> {
+        let (model_name, path, modelfile) = self.resolve(reference)?;
+        let lease = |model| {
+            ModelLease::new(
+                Arc::clone(self),
+                model_name.clone(),
+                model,
+                modelfile.clone(),
+                keep_alive,
+            )
+        };
+        if let Some(model) = self.residency.acquire(&model_name) {
+            return Ok(lease(model));
+        }
+        let _loading = self.loading.lock().await;
+        // 等待锁的时候其它请求可能已经加载了这个模型
+        if let Some(model) = self.residency.acquire(&model_name) {
+            return Ok(lease(model));
+        }
+        // 加载之前按照文件大小估算模型占用的内存
+        let file_size = path
+            .metadata()
+            .map(|metadata| metadata.len())
+            .unwrap_or_default();
+        self.residency.make_room(file_size)?;
+        info!("Loading {model_name} from {}", path.display());
+        let state = Arc::clone(self);
+        let model = tokio::task::spawn_blocking(move || {
+            let params = ModelParams::default().with_n_gpu_layers(state.n_gpu_layers);
+            state.runtime.load_model_from_file(path, &params)
+        })
+        .await
+        .map_err(ApiError::internal)?
+        .map_err(ApiError::internal)?;
+        let model = Arc::new(model);
+        // 加载之后使用模型的大小重新计算，上下文的 KV cache 在创建上下文时计算，仍然超过预算时不保留这个模型
+        self.residency
+            .insert(&model_name, Arc::clone(&model), model.size());
+        if let Err(e) = self.residency.make_room(0) {
+            self.residency.unload(&model_name);
+            return Err(e.into());
+        }
+        Ok(lease(model))
+    }
+
+    /// 卸载模型，正在使用这个模型的请求结束之后才会释放
+    pub(crate) fn unload_model(&self, model_name: &str) {
+        if self.residency.unload(model_name) {
+            info!("Unloaded {model_name}");
+        }
+    }
+
+    /// 定期卸载超过保留时间的模型
+    pub fn spawn_reaper(self: &Arc<Self>) -> JoinHandle<()> {
+        let state = Arc::clone(self);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(REAP_INTERVAL);
+            loop {
+                interval.tick().await;
+                for expired in state.residency.unload_expired() {
+                    info!("Unloaded {expired}, the keep alive has expired");
+                }
+            }
+        })
+    }
+
+    /// 通过模型的引用找到本地注册表中的模型名字
+    pub(crate) fn model_name(
+        &self,
+        conn: &rusqlite::Connection,
+        reference: &str,
+    ) -> Result<String, ApiError> {
+        let reference = ModelReference::parse(reference)
+            .map_err(ApiError::bad_request)?
+            .relative_to(&self.config.registry.remote);
+        let (model_name, _category) = service::model::local_name_and_category(conn, &reference)
+            .map_err(ApiError::not_found)?;
+        Ok(model_name)
+    }
+
+    // 模型的名字和模型文件的路径
+    fn resolve(&self, reference: &str) -> Result<(String, PathBuf, Modelfile), ApiError> {
+        let conn = self.open_db()?;
+        let model_name = self.model_name(&conn, reference)?;
+        let pulled =
+            db::model::check_pull_completed(&conn, &model_name).map_err(ApiError::internal)?;
+        ensure!(
+            pulled,
+            NotFoundSnafu {
+                message: format!("Model {model_name} should be ensured to be pulled"),
+            }
+        );
+        let (path, _) =
+            db::model::get_model_params(&conn, &model_name).map_err(ApiError::internal)?;
+        let path = path.context(NotFoundSnafu {
+            message: format!("Model {model_name} doesn't have a model file"),
+        })?;
+        let modelfile = Modelfile::load(&conn, &model_name).map_err(ApiError::internal)?;
+        Ok((model_name, PathBuf::from(path), modelfile))
+    }
+}
+
+/// 返回给客户端的错误，格式和 OpenAI 的错误保持一致
+#[derive(Debug, Snafu)]
+#[snafu(visibility(pub(crate)))]
+pub enum ApiError {
+    #[snafu(display("{message}"))]
+    BadRequest { message: String },
+    #[snafu(display("{message}"))]
+    NotFound { message: String },
+    #[snafu(display("{message}"))]
+    Internal { message: String },
+}
+
+impl ApiError {
+    pub(crate) fn bad_request(error: impl std::fmt::Display) -> Self {
+        Self::BadRequest {
+            message: error.to_string(),
+        }
+    }
+
+    pub(crate) fn not_found(error: impl std::fmt::Display) -> Self {
+        Self::NotFound {
+            message: error.to_string(),
+        }
+    }
+
+    pub(crate) fn internal(error: impl std::fmt::Display) -> Self {
+        Self::Internal {
+            message: error.to_string(),
+        }
+    }
+
+    pub(crate) fn status(&self) -> StatusCode {
+        match self {
+            ApiError::BadRequest { .. } => StatusCode::BAD_REQUEST,
+            ApiError::NotFound { .. } => StatusCode::NOT_FOUND,
+            ApiError::Internal { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// OpenAI 格式的错误内容
+    pub(crate) fn body(&self) -> serde_json::Value {
+        let error_type = match self {
+            ApiError::BadRequest { .. } => "invalid_request_error",
+            ApiError::NotFound { .. } => "not_found_error",
+            ApiError::Internal { .. } => "server_error",
+        };
+        json!({
+            "error": {
+                "message": self.to_string(),
+                "type": error_type,
+                "code": null,
+            }
+        })
+    }
+}
+
+impl From<Whatever> for ApiError {
+    fn from(error: Whatever) -> Self {
+        ApiError::internal(error)
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.status(), Json(self.body())).into_response()
+    }
+}
+
+pub fn router(state: Arc<ServerState>) -> Router {
+    Router::new()
+        .route("/v1/models", get(openai::list_models))
+        .route("/v1/chat/completions", post(openai::chat_completions))
+        .route("/v1/completions", post(openai::completions))
+        .route("/v1/embeddings", post(openai::embeddings))
+        .route("/", get(ollama::heartbeat))
+        .route("/api/version", get(ollama::version))
+        .route("/api/generate", post(ollama::generate))
+        .route("/api/chat", post(ollama::chat))
+        .route("/api/tags", get(ollama::tags))
+        .route("/api/show", post(ollama::show))
+        .route("/api/pull", post(ollama::pull))
+        .route("/api/delete", delete(ollama::delete))
+        .route("/api/embed", post(ollama::embed))
+        .route("/api/ps", get(ollama::ps))
+        .with_state(state)
+}
@@ -0,0 +1,814 @@
+//! 兼容 Ollama 的接口，包括 /api/generate、/api/chat、/api/tags、/api/show、/api/pull、/api/delete、/api/embed 和 /api/ps
+//!
+//! 流式响应使用 NDJSON，每一行是一个 JSON 对象，时间的单位为纳秒
+
+use crate::{
+    config::Config as LLamaBuddyConfig,
+    db,
+    db::{CompletedStatus, model::ModelDetail},
+    error::Whatever,
+    server::{
+        ApiError, ServerState,
+        generate::{FinishReason, Usage, embed as embed_inputs},
+        openai::{ChatMessage, MessageContent, OneOrMany, chat_prompt},
+        residency::{KeepAlive, ModelLease},
+        sampling::{SamplingParams, json_grammar},
+    },
+    service,
+    service::{
+        model::RemovedFile,
+        pull::{PullObserver, Puller, short_digest},
+    },
+    utils::{blob::BlobStore, file_lock::FileLock, reference::ModelReference},
+};
+use axum::{
+    Json,
+    body::Body,
+    extract::State,
+    http::header::CONTENT_TYPE,
+    response::{IntoResponse, Response},
+};
+use chrono::{DateTime, SecondsFormat, Utc};
+use http_extra::progress::{Progress, ProgressEvent};
+use serde::Deserialize;
+use serde_json::{Map, Value, json};
+use snafu::prelude::*;
+use std::{
+    convert::Infallible,
+    fs::{TryLockError, read_to_string},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::sync::mpsc;
+use tokio_stream::{
+    Stream, StreamExt,
+    wrappers::{ReceiverStream, UnboundedReceiverStream},
+};
+use tracing::{error, info};
+
+/// Ollama 请求中的 options，只支持采样相关的参数，其它参数会被忽略
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct Options {
+    pub temperature: Option<f32>,
+    pub top_k: Option<i32>,
+    pub top_p: Option<f32>,
+    pub min_p: Option<f32>,
+    // 小于 0 时使用随机的种子
+    pub seed: Option<i64>,
+    // 最多生成的 token 数量，小于 0 时不限制
+    pub num_predict: Option<i32>,
+    #[serde(default)]
+    pub stop: Vec<String>,
+    pub presence_penalty: Option<f32>,
+    pub frequency_penalty: Option<f32>,
+    pub repeat_penalty: Option<f32>,
+    pub repeat_last_n: Option<i32>,
+}
+
+impl From<Options> for SamplingParams {
+    fn from(options: Options) -> Self {
+        SamplingParams {
+            temperature: options.temperature,
+            top_k: options.top_k,
+            top_p: options.top_p,
+            min_p: options.min_p,
+            seed: options.seed.and_then(|seed| u32::try_from(seed).ok()),
+            presence_penalty: options.presence_penalty,
+            frequency_penalty: options.frequency_penalty,
+            repeat_penalty: options.repeat_penalty,
+            repeat_last_n: options.repeat_last_n,
+            logit_bias: Vec::new(),
+            stop: options.stop,
+            max_tokens: options.num_predict.and_then(|num| u32::try_from(num).ok()),
+            tool_grammar: None,
+            grammar: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct GenerateRequest {
+    #[serde(alias = "name")]
+    pub model: String,
+    #[serde(default)]
+    pub prompt: String,
+    pub system: Option<String>,
+    // 为 true 时不使用对话模板，直接使用提示词
+    #[serde(default)]
+    pub raw: bool,
+    // 没有提供时使用流式响应
+    pub stream: Option<bool>,
+    // 输出的格式，"json" 为任意的 JSON 对象，也可以是一个 JSON Schema
+    pub format: Option<Value>,
+    pub options: Option<Options>,
+    // 请求结束之后模型保留的时间，没有提供时使用服务的默认值
+    pub keep_alive: Option<KeepAlive>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Message {
+    pub role: String,
+    #[serde(default)]
+    pub content: String,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ChatRequest {
+    #[serde(alias = "name")]
+    pub model: String,
+    #[serde(default)]
+    pub messages: Vec<Message>,
+    pub stream: Option<bool>,
+    pub format: Option<Value>,
+    pub options: Option<Options>,
+    pub keep_alive: Option<KeepAlive>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ModelRequest {
+    #[serde(alias = "name")]
+    pub model: String,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct PullRequest {
+    #[serde(alias = "name")]
+    pub model: String,
+    pub stream: Option<bool>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct EmbedRequest {
+    #[serde(alias = "name")]
+    pub model: String,
+    pub input: OneOrMany,
+    pub keep_alive: Option<KeepAlive>,
+}
+
+/// 返回给客户端的错误，格式和 Ollama 的错误保持一致，内容为 {"error": "..."}
+#[derive(Debug)]
+pub struct OllamaError(ApiError);
+
+impl From<ApiError> for OllamaError {
+    fn from(error: ApiError) -> Self {
+        Self(error)
+    }
+}
+
+impl From<Whatever> for OllamaError {
+    fn from(error: Whatever) -> Self {
+        Self(ApiError::from(error))
+    }
+}
+
+impl IntoResponse for OllamaError {
+    fn into_response(self) -> Response {
+        (
+            self.0.status(),
+            Json(json!({ "error": self.0.to_string() })),
+        )
+            .into_response()
+    }
+}
+
+/// Ollama 的客户端通过这个接口判断服务是否启动
+pub async fn heartbeat() -> &'static str {
+    "Ollama is running"
+}
+
+pub async fn version() -> Json<Value> {
+    Json(json!({ "version": env!("CARGO_PKG_VERSION") }))
+}
+
+pub async fn generate(
+    State(state): State<Arc<ServerState>>,
+    Json(request): Json<GenerateRequest>,
+) -> Result<Response, OllamaError> {
+    let GenerateRequest {
+        model,
+        prompt,
+        system,
+        raw,
+        stream,
+        format,
+        options,
+        keep_alive,
+    } = request;
+    let mut params = SamplingParams::from(options.unwrap_or_default());
+    params.grammar = format_grammar(format)?;
+    let started_at = Instant::now();
+    let lease = state.model(&model, keep_alive).await?;
+    let generation = Generation {
+        model: lease.name().to_owned(),
+        endpoint: Endpoint::Generate,
+        started_at,
+        load_duration: started_at.elapsed(),
+    };
+    // 提示词为空时只加载或者卸载模型
+    if prompt.is_empty() {
+        return Ok(Json(generation.loaded(lease)).into_response());
+    }
+    // raw 为 true 或者既没有拉取的模板、GGUF 中也没有对话模板时直接使用提示词
+    let template = if raw {
+        None
+    } else {
+        lease.chat_template(Vec::new()).ok()
+    };
+    let prompt = if let Some(template) = template {
+        let messages = system
+            .map(|system| ("system".to_owned(), system))
+            .into_iter()
+            .chain([("user".to_owned(), prompt)])
+            .map(|(role, content)| ChatMessage {
+                role,
+                content: Some(MessageContent::Text(content)),
+                ..Default::default()
+            })
+            .collect();
+        chat_prompt(lease.model(), &template, messages)?
+    } else {
+        prompt
+    };
+    generation
+        .respond(lease, prompt, params, stream.unwrap_or(true))
+        .await
+}
+
+pub async fn chat(
+    State(state): State<Arc<ServerState>>,
+    Json(request): Json<ChatRequest>,
+) -> Result<Response, OllamaError> {
+    let ChatRequest {
+        model,
+        messages,
+        stream,
+        format,
+        options,
+        keep_alive,
+    } = request;
+    let mut params = SamplingParams::from(options.unwrap_or_default());
+    params.grammar = format_grammar(format)?;
+    let started_at = Instant::now();
+    let lease = state.model(&model, keep_alive).await?;
+    let generation = Generation {
+        model: lease.name().to_owned(),
+        endpoint: Endpoint::Chat,
+        started_at,
+        load_duration: started_at.elapsed(),
+    };
+    // 消息为空时只加载或者卸载模型
+    if messages.is_empty() {
+        return Ok(Json(generation.loaded(lease)).into_response());
+    }
+    let messages = messages
+        .into_iter()
+        .map(|message| ChatMessage {
+            role: message.role,
+            content: Some(MessageContent::Text(message.content)),
+            ..Default::default()
+        })
+        .collect();
+    let template = lease.chat_template(Vec::new())?;
+    let prompt = chat_prompt(lease.model(), &template, messages)?;
+    generation
+        .respond(lease, prompt, params, stream.unwrap_or(true))
+        .await
+}
+
+// format 为 "json" 时输出任意的 JSON 对象，为对象时作为 JSON Schema
+fn format_grammar(format: Option<Value>) -> Result<Option<String>, ApiError> {
+    match format {
+        None | Some(Value::Null) => Ok(None),
+        Some(Value::String(format)) if format.is_empty() => Ok(None),
+        Some(Value::String(format)) if format == "json" => json_grammar(None).map(Some),
+        Some(schema @ Value::Object(_)) => json_grammar(Some(&schema)).map(Some),
+        Some(format) => Err(ApiError::bad_request(format!(
+            "Unsupported format {format}"
+        ))),
+    }
+}
+
+/// 列出已经拉取完成的模型
+pub async fn tags(State(state): State<Arc<ServerState>>) -> Result<Response, OllamaError> {
+    let conn = state.open_db()?;
+    let models = db::model::query_model_list(&conn, false)?
+        .into_iter()
+        .filter(|model| model.pull_status == CompletedStatus::Completed.as_ref())
+        .map(|model| {
+            let name = format!("{}:{}", model.name, model.category);
+            let detail = db::model::query_model_detail(&conn, &name)?;
+            Ok(json!({
+                "name": name,
+                "model": name,
+                "modified_at": DateTime::from_timestamp(model.updated_at, 0).map(rfc3339),
+                "size": model.size,
+                "digest": manifest_digest(&detail),
+                "details": model_details(&detail),
+            }))
+        })
+        .collect::<Result<Vec<_>, Whatever>>()?;
+    Ok(Json(json!({ "models": models })).into_response())
+}
+
+/// 展示模型的许可证、模板、参数等信息
+pub async fn show(
+    State(state): State<Arc<ServerState>>,
+    Json(request): Json<ModelRequest>,
+) -> Result<Response, OllamaError> {
+    let conn = state.open_db()?;
+    let model_name = state.model_name(&conn, &request.model)?;
+    let detail = db::model::query_model_detail(&conn, &model_name)?;
+    if detail.pull_status != CompletedStatus::Completed.as_ref() {
+        return Err(ApiError::not_found(format!("model '{model_name}' not found")).into());
+    }
+    let template = read_model_file(&detail, "template").unwrap_or_default();
+    let system = read_model_file(&detail, "system").unwrap_or_default();
+    let parameters = read_model_file(&detail, "params")
+        .and_then(|params| serde_json::from_str::<Map<String, Value>>(&params).ok())
+        .map(|params| parameter_lines(&params))
+        .unwrap_or_default();
+    let mut modelfile = String::from("# Modelfile generated by llama-buddy\n");
+    if let Some(model) = detail.files.iter().find(|file| file.media == "model") {
+        modelfile.push_str(&format!("FROM {}\n", model.path));
+    }
+    if !template.is_empty() {
+        modelfile.push_str(&format!("TEMPLATE \"\"\"{template}\"\"\"\n"));
+    }
+    if !system.is_empty() {
+        modelfile.push_str(&format!("SYSTEM \"\"\"{system}\"\"\"\n"));
+    }
+    for (key, value) in &parameters {
+        modelfile.push_str(&format!("PARAMETER {key} {value}\n"));
+    }
+    let parameters = parameters
+        .iter()
+        .map(|(key, value)| format!("{key:<30} {value}"))
+        .collect::<Vec<_>>()
+        .join("\n");
+    Ok(Json(json!({
+        "license": read_model_file(&detail, "license").unwrap_or_default(),
+        "modelfile": modelfile,
+        "parameters": parameters,
+        "template": template,
+        "system": system,
+        "details": model_details(&detail),
+        "model_info": {},
+        "capabilities": ["completion"],
+    }))
+    .into_response())
+}
+
+/// 拉取模型，流式响应中依次返回每个阶段和每一层的下载进度，最后返回 success
+pub async fn pull(
+    State(state): State<Arc<ServerState>>,
+    Json(request): Json<PullRequest>,
+) -> Result<Response, OllamaError> {
+    let PullRequest { model, stream } = request;
+    let reference = ModelReference::parse(&model).map_err(ApiError::bad_request)?;
+    let config = state.config().clone();
+    let (sender, mut receiver) = mpsc::unbounded_channel::<Value>();
+    // 拉取时使用的数据库连接和重试策略不能在线程之间传递，在单独的线程中使用单线程的运行时拉取
+    std::thread::spawn(move || {
+        let observer = PullProgress(sender);
+        let result = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .with_whatever_context(|_| "Couldn't build the tokio runtime")
+            .and_then(|runtime| runtime.block_on(pull_model(config, reference, &observer)));
+        let message = match result {
+            Ok((model_name, _category)) => {
+                info!("Pulled {model_name} from the api");
+                json!({ "status": "success" })
+            }
+            Err(e) => {
+                error!("Failed to pull {model}, {e}");
+                json!({ "error": e.to_string() })
+            }
+        };
+        let _ = observer.0.send(message);
+    });
+    if stream.unwrap_or(true) {
+        return Ok(ndjson(UnboundedReceiverStream::new(receiver)));
+    }
+    // 不使用流式响应时，只返回最后的结果
+    let mut last = Value::Null;
+    while let Some(message) = receiver.recv().await {
+        last = message;
+    }
+    match last["error"].as_str() {
+        Some(message) => Err(ApiError::internal(message).into()),
+        None => Ok(Json(last).into_response()),
+    }
+}
+
+/// 删除模型，其它规格的模型还在引用的文件不会被删除
+pub async fn delete(
+    State(state): State<Arc<ServerState>>,
+    Json(request): Json<ModelRequest>,
+) -> Result<Response, OllamaError> {
+    // 文件锁、数据库和删除文件都会阻塞线程
+    tokio::task::spawn_blocking(move || delete_model(&state, &request.model))
+        .await
+        .map_err(ApiError::internal)??;
+    Ok(().into_response())
+}
+
+fn delete_model(state: &ServerState, reference: &str) -> Result<(), ApiError> {
+    let conn = state.open_db()?;
+    let model_name = state.model_name(&conn, reference)?;
+    state.unload_model(&model_name);
+    let data_path = &state.config().data.path;
+    let blob_store = BlobStore::new(data_path)?;
+    // 获取锁，避免删除正在下载的文件
+    let mut file_lock = FileLock::new(blob_store.dir());
+    match file_lock.try_lock() {
+        Ok(_) => (),
+        Err(TryLockError::WouldBlock) => {
+            return Err(ApiError::internal(
+                "The file is locked, multiple processes are not allowed to operate the model file simultaneously, please wait",
+            ));
+        }
+        Err(TryLockError::Error(e)) => {
+            return Err(ApiError::internal(format!("Couldn't lock the file, {e}")));
+        }
+    }
+    blob_store.migrate(&conn, data_path.join("model"))?;
+    let Some(files) = service::model::remove_model_files(&conn, &model_name, false)? else {
+        return Err(ApiError::not_found(format!(
+            "model '{model_name}' not found"
+        )));
+    };
+    for file in files {
+        match file {
+            RemovedFile::Removed(path, _) => info!("Removed {}", path.display()),
+            RemovedFile::Failed(path, e) => {
+                error!("Couldn't remove the file({}), {e}", path.display())
+            }
+            RemovedFile::Untagged(_) | RemovedFile::WouldRemove(..) => (),
+        }
+    }
+    drop(file_lock);
+    Ok(())
+}
+
+pub async fn embed(
+    State(state): State<Arc<ServerState>>,
+    Json(request): Json<EmbedRequest>,
+) -> Result<Response, OllamaError> {
+    let EmbedRequest {
+        model,
+        input,
+        keep_alive,
+    } = request;
+    let inputs = match input {
+        OneOrMany::One(one) => vec![one],
+        OneOrMany::Many(many) => many,
+    };
+    let started_at = Instant::now();
+    let lease = state.model(&model, keep_alive).await?;
+    let load_duration = started_at.elapsed();
+    let model_name = lease.name().to_owned();
+    let (embeddings, usage) = tokio::task::spawn_blocking(move || {
+        lease.with_embedding_context(|model, context| {
+            embed_inputs(state.runtime(), model, context, &inputs)
+        })
+    })
+    .await
+    .map_err(ApiError::internal)??;
+    Ok(Json(json!({
+        "model": model_name,
+        "embeddings": embeddings,
+        "total_duration": nanos(started_at.elapsed()),
+        "load_duration": nanos(load_duration),
+        "prompt_eval_count": usage.prompt_tokens,
+    }))
+    .into_response())
+}
+
+/// 列出已经加载的模型
+pub async fn ps(State(state): State<Arc<ServerState>>) -> Result<Response, OllamaError> {
+    let conn = state.open_db()?;
+    let models = state
+        .residency()
+        .resident()
+        .into_iter()
+        .map(|resident| {
+            let detail = db::model::query_model_detail(&conn, &resident.name)?;
+            // 正在处理请求或者一直保留的模型没有卸载的时间
+            let expires_at = resident
+                .expires_at
+                .map(|at| rfc3339(DateTime::<Utc>::from(at)));
+            Ok(json!({
+                "name": resident.name,
+                "model": resident.name,
+                "size": resident.size,
+                "digest": manifest_digest(&detail),
+                "details": model_details(&detail),
+                "expires_at": expires_at,
+                "in_flight": resident.in_flight,
+            }))
+        })
+        .collect::<Result<Vec<_>, Whatever>>()?;
+    Ok(Json(json!({ "models": models })).into_response())
+}
+
+async fn pull_model(
+    config: LLamaBuddyConfig,
+    reference: ModelReference,
+    observer: &PullProgress,
+) -> Result<(String, String), Whatever> {
+    let conn = db::open_llama_buddy_db(config.data.path.join("sqlite"))?;
+    let puller = Puller {
+        conn: &conn,
+        data_path: &config.data.path,
+        remote: &config.registry.remote,
+        auth: &config.registry.auth,
+        client_config: &config.model.client,
+        default_category: &config.model.category,
+    };
+    puller.pull(reference, observer).await
+}
+
+// 将拉取的进度转换成 Ollama 格式的消息
+struct PullProgress(mpsc::UnboundedSender<Value>);
+
+impl PullObserver for PullProgress {
+    fn status(&self, status: &str) {
+        let _ = self.0.send(json!({ "status": status }));
+    }
+
+    fn download(&self, digest: &str, _media: &str, size: u64) -> Progress {
+        let sender = self.0.clone();
+        let status = format!("pulling {}", short_digest(digest));
+        let digest = digest.to_owned();
+        Progress::new(move |event| {
+            let completed = match event {
+                ProgressEvent::Started(state)
+                | ProgressEvent::Advanced(state)
+                | ProgressEvent::Finished(state) => state.received,
+                ProgressEvent::Retrying { .. } => return,
+            };
+            let _ = sender.send(json!({
+                "status": status,
+                "digest": digest,
+                "total": size,
+                "completed": completed,
+            }));
+        })
+    }
+
+    fn skip(&self, digest: &str, _media: &str, size: u64) {
+        let _ = self.0.send(json!({
+            "status": format!("pulling {}", short_digest(digest)),
+            "digest": digest,
+            "total": size,
+            "completed": size,
+        }));
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Endpoint {
+    // /api/generate
+    Generate,
+    // /api/chat
+    Chat,
+}
+
+// 一次生成请求的响应
+struct Generation {
+    model: String,
+    endpoint: Endpoint,
+    started_at: Instant,
+    load_duration: Duration,
+}
+
+impl Generation {
+    async fn respond(
+        self,
+        lease: ModelLease,
+        prompt: String,
+        params: SamplingParams,
+        stream: bool,
+    ) -> Result<Response, OllamaError> {
+        if !stream {
+            let (text, finish_reason, usage) = tokio::task::spawn_blocking(move || {
+                let mut text = String::new();
+                let (finish_reason, usage) = lease.generate(&prompt, &params, |piece| {
+                    text.push_str(piece);
+                    true
+                })?;
+                Ok::<_, Whatever>((text, finish_reason, usage))
+            })
+            .await
+            .map_err(ApiError::internal)??;
+            return Ok(Json(self.done(&text, finish_reason, usage)).into_response());
+        }
+        let (sender, receiver) = mpsc::channel::<Value>(64);
+        tokio::task::spawn_blocking(move || {
+            let result = lease.generate(&prompt, &params, |piece| {
+                // 客户端断开连接时停止生成
+                sender.blocking_send(self.message(piece, false)).is_ok()
+            });
+            let message = match result {
+                Ok((finish_reason, usage)) => self.done("", finish_reason, usage),
+                Err(e) => {
+                    error!("Failed to generate with {}, {e}", self.model);
+                    json!({ "error": e.to_string() })
+                }
+            };
+            let _ = sender.blocking_send(message);
+        });
+        Ok(ndjson(ReceiverStream::new(receiver)))
+    }
+
+    // 生成的一段文本
+    fn message(&self, text: &str, done: bool) -> Value {
+        let mut message = json!({
+            "model": self.model,
+            "created_at": rfc3339(Utc::now()),
+            "done": done,
+        });
+        match self.endpoint {
+            Endpoint::Generate => message["response"] = json!(text),
+            Endpoint::Chat => message["message"] = json!({ "role": "assistant", "content": text }),
+        }
+        message
+    }
+
+    // 最后一条消息，包含停止的原因和统计信息
+    fn done(&self, text: &str, finish_reason: FinishReason, usage: Usage) -> Value {
+        let mut message = self.message(text, true);
+        message["done_reason"] = json!(finish_reason);
+        message["total_duration"] = json!(nanos(self.started_at.elapsed()));
+        message["load_duration"] = json!(nanos(self.load_duration));
+        message["prompt_eval_count"] = json!(usage.prompt_tokens);
+        message["prompt_eval_duration"] = json!(nanos(usage.prompt_duration));
+        message["eval_count"] = json!(usage.completion_tokens);
+        message["eval_duration"] = json!(nanos(usage.completion_duration));
+        message
+    }
+
+    // 没有提示词时，只返回加载完成的消息，keep_alive 为 0 时模型会在释放之后卸载
+    fn loaded(&self, lease: ModelLease) -> Value {
+        let mut message = self.message("", true);
+        message["done_reason"] = match lease.keep_alive() {
+            Some(KeepAlive::For(Duration::ZERO)) => json!("unload"),
+            _ => json!("load"),
+        };
+        message
+    }
+}
+
+fn ndjson(stream: impl Stream<Item = Value> + Send + 'static) -> Response {
+    let lines = stream.map(|value| Ok::<_, Infallible>(format!("{value}\n")));
+    (
+        [(CONTENT_TYPE, "application/x-ndjson")],
+        Body::from_stream(lines),
+    )
+        .into_response()
+}
+
+fn rfc3339(time: DateTime<Utc>) -> String {
+    time.to_rfc3339_opts(SecondsFormat::Nanos, true)
+}
+
+fn nanos(duration: Duration) -> u64 {
+    duration.as_nanos() as u64
+}
+
+// Ollama 中展示的摘要不包含 sha256: 前缀
+fn manifest_digest(detail: &ModelDetail) -> String {
+    detail
+        .manifest_digest
+        .as_deref()
+        .map(|digest| digest.trim_start_matches("sha256:").to_owned())
+        .unwrap_or_default()
+}
+
+fn read_model_file(detail: &ModelDetail, media: &str) -> Option<String> {
+    detail
+        .files
+        .iter()
+        .find(|file| file.media == media)
+        .and_then(|file| read_to_string(&file.path).ok())
+}
+
+// 模型的格式、家族、参数量和量化等级保存在 config 文件中
+fn model_details(detail: &ModelDetail) -> Value {
+    let config = read_model_file(detail, "config")
+        .and_then(|config| serde_json::from_str::<Value>(&config).ok())
+        .unwrap_or_default();
+    json!({
+        "parent_model": "",
+        "format": config["model_format"].as_str().unwrap_or("gguf"),
+        "family": config["model_family"].as_str().unwrap_or_default(),
+        "families": config["model_families"],
+        "parameter_size": config["model_type"].as_str().unwrap_or_default(),
+        "quantization_level": config["file_type"].as_str().unwrap_or_default(),
+    })
+}
+
+// params 文件中的参数，数组中的每个值单独一行
+fn parameter_lines(params: &Map<String, Value>) -> Vec<(String, String)> {
+    params
+        .iter()
+        .flat_map(|(key, value)| {
+            let values = match value {
+                Value::Array(values) => values.clone(),
+                value => vec![value.clone()],
+            };
+            values
+                .into_iter()
+                .map(move |value| (key.clone(), value.to_string()))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::service::modelfile::Parameters;
+
+    #[test]
+    fn test_deserialize_generate_request() {
+        let request: GenerateRequest = serde_json::from_value(json!({
+            "name": "qwen3:8b",
+            "prompt": "Why is the sky blue?",
+            "options": {
+                "temperature": 0.2,
+                "seed": -1,
+                "num_predict": 16,
+                "stop": ["\n\n"],
+                "num_ctx": 4096,
+            },
+        }))
+        .unwrap();
+        assert_eq!(request.model, "qwen3:8b");
+        assert!(request.stream.is_none());
+        let params = SamplingParams::from(request.options.unwrap());
+        assert_eq!(params.temperature, Some(0.2));
+        assert_eq!(params.seed, None);
+        assert_eq!(params.max_tokens, Some(16));
+        assert_eq!(params.stop, vec!["\n\n".to_owned()]);
+
+        let options = Options {
+            num_predict: Some(-1),
+            seed: Some(42),
+            ..Default::default()
+        };
+        let params = SamplingParams::from(options);
+        assert_eq!(params.max_tokens, None);
+        assert_eq!(params.seed, Some(42));
+
+        // 请求中没有提供的参数使用拉取的模型参数
+        let parameters: Parameters = serde_json::from_value(json!({
+            "stop": ["<|im_end|>"],
+            "temperature": 0.6,
+            "top_k": 20,
+            "repeat_penalty": 1.1,
+            "seed": -1,
+            "num_predict": 128,
+        }))
+        .unwrap();
+        let params = params.with_defaults(&parameters);
+        assert_eq!(params.seed, Some(42));
+        assert_eq!(params.temperature, Some(0.6));
+        assert_eq!(params.top_k, Some(20));
+        assert_eq!(params.repeat_penalty, Some(1.1));
+        assert_eq!(params.max_tokens, Some(128));
+        assert_eq!(params.stop, vec!["<|im_end|>".to_owned()]);
+        let params = SamplingParams {
+            stop: vec!["\n\n".to_owned()],
+            ..Default::default()
+        }
+        .with_defaults(&parameters);
+        assert_eq!(params.stop, vec!["\n\n".to_owned()]);
+        assert_eq!(params.seed, None);
+    }
+
+    #[test]
+    fn test_format_grammar() {
+        assert!(format_grammar(None).unwrap().is_none());
+        let grammar = format_grammar(Some(json!("json"))).unwrap().unwrap();
+        assert!(grammar.contains("root ::= object"));
+        let schema = json!({ "type": "array", "items": { "type": "integer" } });
+        let grammar = format_grammar(Some(schema)).unwrap().unwrap();
+        assert!(grammar.contains("root-item ::= integer"));
+        assert!(format_grammar(Some(json!("yaml"))).is_err());
+    }
+
+    #[test]
+    fn test_parameter_lines() {
+        let params = json!({ "stop": ["<|im_start|>", "<|im_end|>"], "temperature": 0.6 });
+        let lines = parameter_lines(params.as_object().unwrap());
+        assert_eq!(
+            lines,
+            vec![
+                ("stop".to_owned(), "\"<|im_start|>\"".to_owned()),
+                ("stop".to_owned(), "\"<|im_end|>\"".to_owned()),
+                ("temperature".to_owned(), "0.6".to_owned()),
+            ]
+        );
+    }
+}
@@ -0,0 +1,732 @@
+//! 兼容 OpenAI 的接口，包括 /v1/chat/completions、/v1/completions、/v1/embeddings 和 /v1/models
+
+use crate::{
+    db,
+    db::CompletedStatus,
+    server::{
+        ApiError, ServerState,
+        generate::{FinishReason, Usage, embed},
+        residency::ModelLease,
+        sampling::{SamplingParams, ToolGrammar, json_grammar},
+    },
+    service::{modelfile::ChatTemplate, tool::new_tool_call_id},
+};
+use axum::{
+    Json,
+    extract::State,
+    response::{
+        IntoResponse, Response,
+        sse::{Event, KeepAlive, Sse},
+    },
+};
+use llama_cpp::model::{Message, Model, ToolCall, ToolCallFormat};
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::mpsc;
+use tokio_stream::{StreamExt, wrappers::ReceiverStream};
+use tracing::error;
+use uuid::Uuid;
+
+/// 字符串或者字符串数组
+#[derive(Clone, Debug, Deserialize)]
+#[serde(untagged)]
+pub enum OneOrMany {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl OneOrMany {
+    fn into_vec(self) -> Vec<String> {
+        match self {
+            OneOrMany::One(one) => vec![one],
+            OneOrMany::Many(many) => many,
+        }
+    }
+}
+
+/// 请求中的采样参数，/v1/chat/completions 和 /v1/completions 共用
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct SamplingOptions {
+    pub temperature: Option<f32>,
+    pub top_p: Option<f32>,
+    pub top_k: Option<i32>,
+    pub min_p: Option<f32>,
+    pub seed: Option<u32>,
+    pub stop: Option<OneOrMany>,
+    pub max_tokens: Option<u32>,
+    // 新版本的接口使用 max_completion_tokens 代替 max_tokens
+    pub max_completion_tokens: Option<u32>,
+    // 键为 token id
+    #[serde(default)]
+    pub logit_bias: HashMap<String, f32>,
+    pub presence_penalty: Option<f32>,
+    pub frequency_penalty: Option<f32>,
+    pub response_format: Option<ResponseFormat>,
+}
+
+/// 输出的格式，json_object 为任意的 JSON 对象，json_schema 需要符合其中的 schema
+#[derive(Clone, Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ResponseFormat {
+    Text,
+    JsonObject,
+    JsonSchema { json_schema: JsonSchemaFormat },
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct JsonSchemaFormat {
+    // 没有提供时可以是任意的 JSON 对象
+    pub schema: Option<Value>,
+}
+
+impl TryFrom<SamplingOptions> for SamplingParams {
+    type Error = ApiError;
+
+    fn try_from(options: SamplingOptions) -> Result<Self, Self::Error> {
+        let logit_bias = options
+            .logit_bias
+            .into_iter()
+            .map(|(token, bias)| {
+                token
+                    .parse::<i32>()
+                    .map(|token| (token, bias))
+                    .map_err(|_| {
+                        ApiError::bad_request(format!("Invalid token id({token}) in logit_bias"))
+                    })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let grammar = match options.response_format {
+            Some(ResponseFormat::JsonObject) => Some(json_grammar(None)?),
+            Some(ResponseFormat::JsonSchema { json_schema }) => {
+                Some(json_grammar(json_schema.schema.as_ref())?)
+            }
+            Some(ResponseFormat::Text) | None => None,
+        };
+        Ok(SamplingParams {
+            temperature: options.temperature,
+            top_k: options.top_k,
+            top_p: options.top_p,
+            min_p: options.min_p,
+            seed: options.seed,
+            presence_penalty: options.presence_penalty,
+            frequency_penalty: options.frequency_penalty,
+            repeat_penalty: None,
+            repeat_last_n: None,
+            logit_bias,
+            stop: options.stop.map(OneOrMany::into_vec).unwrap_or_default(),
+            max_tokens: options.max_completion_tokens.or(options.max_tokens),
+            tool_grammar: None,
+            grammar,
+        })
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct StreamOptions {
+    #[serde(default)]
+    pub include_usage: bool,
+}
+
+/// 消息的内容，可以是字符串，也可以是多个内容片段，目前只支持文本片段
+#[derive(Clone, Debug, Deserialize)]
+#[serde(untagged)]
+pub enum MessageContent {
+    Text(String),
+    Parts(Vec<ContentPart>),
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ContentPart {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub text: Option<String>,
+}
+
+impl MessageContent {
+    fn into_text(self) -> String {
+        match self {
+            MessageContent::Text(text) => text,
+            MessageContent::Parts(parts) => parts
+                .into_iter()
+                .filter(|part| part.kind == "text")
+                .filter_map(|part| part.text)
+                .collect::<Vec<_>>()
+                .join("\n"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct ChatMessage {
+    pub role: String,
+    pub content: Option<MessageContent>,
+    // 模型的回答中调用的工具
+    pub tool_calls: Option<Vec<ChatToolCall>>,
+    // 角色为 tool 时，对应的工具调用的 id
+    pub tool_call_id: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ChatToolCall {
+    pub id: Option<String>,
+    pub function: FunctionCall,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct FunctionCall {
+    pub name: String,
+    // OpenAI 的参数是 JSON 字符串，也接受 JSON 对象
+    pub arguments: Value,
+}
+
+impl From<ChatToolCall> for ToolCall {
+    fn from(call: ChatToolCall) -> Self {
+        let arguments = match call.function.arguments {
+            Value::String(arguments) => arguments,
+            arguments => arguments.to_string(),
+        };
+        ToolCall {
+            id: call.id,
+            name: call.function.name,
+            arguments,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ChatCompletionRequest {
+    pub model: String,
+    pub messages: Vec<ChatMessage>,
+    #[serde(default)]
+    pub stream: bool,
+    pub stream_options: Option<StreamOptions>,
+    // 可以调用的工具，和 OpenAI 的格式一致
+    pub tools: Option<Vec<Value>>,
+    // 为 "none" 时不调用工具，为 "required" 时必须调用工具
+    pub tool_choice: Option<Value>,
+    #[serde(flatten)]
+    pub sampling: SamplingOptions,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct CompletionRequest {
+    pub model: String,
+    pub prompt: OneOrMany,
+    #[serde(default)]
+    pub stream: bool,
+    pub stream_options: Option<StreamOptions>,
+    #[serde(flatten)]
+    pub sampling: SamplingOptions,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct EmbeddingRequest {
+    pub model: String,
+    pub input: OneOrMany,
+    pub encoding_format: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ModelObject {
+    id: String,
+    object: &'static str,
+    created: i64,
+    owned_by: &'static str,
+}
+
+/// 列出已经拉取完成的模型
+pub async fn list_models(State(state): State<Arc<ServerState>>) -> Result<Response, ApiError> {
+    let conn = state.open_db()?;
+    let models = db::model::query_model_list(&conn, false)?
+        .into_iter()
+        .filter(|model| model.pull_status == CompletedStatus::Completed.as_ref())
+        .map(|model| ModelObject {
+            id: format!("{}:{}", model.name, model.category),
+            object: "model",
+            created: model.updated_at,
+            owned_by: "llama-buddy",
+        })
+        .collect::<Vec<_>>();
+    Ok(Json(json!({ "object": "list", "data": models })).into_response())
+}
+
+pub async fn chat_completions(
+    State(state): State<Arc<ServerState>>,
+    Json(request): Json<ChatCompletionRequest>,
+) -> Result<Response, ApiError> {
+    let ChatCompletionRequest {
+        model,
+        messages,
+        stream,
+        stream_options,
+        tools,
+        tool_choice,
+        sampling,
+    } = request;
+    let mut params = SamplingParams::try_from(sampling)?;
+    let tools = match tool_choice.as_ref().and_then(Value::as_str) {
+        Some("none") => Vec::new(),
+        _ => tools.unwrap_or_default(),
+    };
+    let lease = state.model(&model, None).await?;
+    let template = lease.chat_template(tools.clone())?;
+    let prompt = chat_prompt(lease.model(), &template, messages)?;
+    let tool_format = (!tools.is_empty()).then(|| template.tool_call_format());
+    if let Some(format) = tool_format {
+        let required = tool_choice.as_ref().and_then(Value::as_str) == Some("required");
+        // 严格模式的工具只在生成了工具调用的开头之后约束参数的格式
+        let strict = tools
+            .iter()
+            .any(|tool| tool["function"]["strict"].as_bool() == Some(true));
+        if required || strict {
+            // 两个语法不能同时约束输出
+            if params.grammar.is_some() {
+                return Err(ApiError::bad_request(
+                    "The response_format can't be used with required or strict tools",
+                ));
+            }
+            params.tool_grammar = Some(ToolGrammar {
+                format,
+                tools,
+                lazy: !required,
+            });
+        }
+    }
+    let completion = Completion {
+        id: format!("chatcmpl-{}", Uuid::now_v7().simple()),
+        created: now(),
+        model: lease.name().to_owned(),
+        kind: CompletionKind::Chat,
+        tool_format,
+    };
+    if stream {
+        let include_usage = stream_options.is_some_and(|options| options.include_usage);
+        Ok(completion.stream(lease, prompt, params, include_usage))
+    } else {
+        completion.complete(lease, prompt, params).await
+    }
+}
+
+pub async fn completions(
+    State(state): State<Arc<ServerState>>,
+    Json(request): Json<CompletionRequest>,
+) -> Result<Response, ApiError> {
+    let CompletionRequest {
+        model,
+        prompt,
+        stream,
+        stream_options,
+        sampling,
+    } = request;
+    let params = SamplingParams::try_from(sampling)?;
+    let [prompt] = <[String; 1]>::try_from(prompt.into_vec())
+        .map_err(|_| ApiError::bad_request("Only one prompt is supported"))?;
+    let lease = state.model(&model, None).await?;
+    let completion = Completion {
+        id: format!("cmpl-{}", Uuid::now_v7().simple()),
+        created: now(),
+        model: lease.name().to_owned(),
+        kind: CompletionKind::Text,
+        tool_format: None,
+    };
+    if stream {
+        let include_usage = stream_options.is_some_and(|options| options.include_usage);
+        Ok(completion.stream(lease, prompt, params, include_usage))
+    } else {
+        completion.complete(lease, prompt, params).await
+    }
+}
+
+pub async fn embeddings(
+    State(state): State<Arc<ServerState>>,
+    Json(request): Json<EmbeddingRequest>,
+) -> Result<Response, ApiError> {
+    let EmbeddingRequest {
+        model,
+        input,
+        encoding_format,
+    } = request;
+    if let Some(format) = encoding_format
+        && format != "float"
+    {
+        return Err(ApiError::bad_request(format!(
+            "The encoding format({format}) isn't supported"
+        )));
+    }
+    let inputs = input.into_vec();
+    let lease = state.model(&model, None).await?;
+    let model_name = lease.name().to_owned();
+    let (embeddings, usage) = tokio::task::spawn_blocking(move || {
+        lease.with_embedding_context(|model, context| {
+            embed(state.runtime(), model, context, &inputs)
+        })
+    })
+    .await
+    .map_err(ApiError::internal)??;
+    let data = embeddings
+        .into_iter()
+        .enumerate()
+        .map(|(index, embedding)| {
+            json!({
+                "object": "embedding",
+                "index": index,
+                "embedding": embedding,
+            })
+        })
+        .collect::<Vec<_>>();
+    Ok(Json(json!({
+        "object": "list",
+        "data": data,
+        "model": model_name,
+        "usage": {
+            "prompt_tokens": usage.prompt_tokens,
+            "total_tokens": usage.total_tokens,
+        },
+    }))
+    .into_response())
+}
+
+// 使用对话模板生成提示词，模板不支持工具时，按照 Hermes 的格式在系统提示词中描述工具
+pub(crate) fn chat_prompt(
+    model: &Model,
+    template: &ChatTemplate,
+    messages: Vec<ChatMessage>,
+) -> Result<String, ApiError> {
+    if messages.is_empty() {
+        return Err(ApiError::bad_request("The messages are empty"));
+    }
+    let messages = messages
+        .into_iter()
+        .map(|message| {
+            let content = message
+                .content
+                .map(MessageContent::into_text)
+                .unwrap_or_default();
+            let mut converted =
+                Message::try_new(message.role, content).map_err(ApiError::bad_request)?;
+            if let Some(tool_calls) = message.tool_calls {
+                converted =
+                    converted.with_tool_calls(tool_calls.into_iter().map(Into::into).collect());
+            }
+            if let Some(tool_call_id) = message.tool_call_id {
+                converted = converted.with_tool_call_id(tool_call_id);
+            }
+            Ok(converted)
+        })
+        .collect::<Result<Vec<_>, ApiError>>()?;
+    template
+        .apply(model, &messages, true)
+        .map_err(ApiError::internal)
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default()
+}
+
+#[derive(Clone, Copy)]
+enum CompletionKind {
+    // /v1/chat/completions
+    Chat,
+    // /v1/completions
+    Text,
+}
+
+// 一次补全请求的响应
+struct Completion {
+    id: String,
+    created: u64,
+    model: String,
+    kind: CompletionKind,
+    // 请求中有工具时，按照这个格式解析生成的文本
+    tool_format: Option<ToolCallFormat>,
+}
+
+impl Completion {
+    async fn complete(
+        self,
+        lease: ModelLease,
+        prompt: String,
+        params: SamplingParams,
+    ) -> Result<Response, ApiError> {
+        let (text, finish_reason, usage) = tokio::task::spawn_blocking(move || {
+            let mut text = String::new();
+            let (finish_reason, usage) = lease.generate(&prompt, &params, |piece| {
+                text.push_str(piece);
+                true
+            })?;
+            Ok::<_, ApiError>((text, finish_reason, usage))
+        })
+        .await
+        .map_err(ApiError::internal)??;
+        let choice = match (self.kind, self.tool_format) {
+            (CompletionKind::Chat, Some(format)) => {
+                let parsed = format.parse(&text);
+                let finish_reason = if parsed.tool_calls.is_empty() {
+                    finish_reason
+                } else {
+                    FinishReason::ToolCalls
+                };
+                let mut message = json!({
+                    "role": "assistant",
+                    "content": (!parsed.content.is_empty()).then_some(parsed.content),
+                });
+                if !parsed.tool_calls.is_empty() {
+                    message["tool_calls"] = tool_calls_json(format, parsed.tool_calls, false);
+                }
+                json!({
+                    "index": 0,
+                    "message": message,
+                    "finish_reason": finish_reason,
+                })
+            }
+            (CompletionKind::Chat, None) => json!({
+                "index": 0,
+                "message": { "role": "assistant", "content": text },
+                "finish_reason": finish_reason,
+            }),
+            (CompletionKind::Text, _) => json!({
+                "index": 0,
+                "text": text,
+                "logprobs": null,
+                "finish_reason": finish_reason,
+            }),
+        };
+        Ok(Json(self.body(false, vec![choice], Some(usage))).into_response())
+    }
+
+    /// 使用 SSE 返回生成的文本，最后返回 [DONE]
+    fn stream(
+        self,
+        lease: ModelLease,
+        prompt: String,
+        params: SamplingParams,
+        include_usage: bool,
+    ) -> Response {
+        let (sender, receiver) = mpsc::channel::<Value>(64);
+        tokio::task::spawn_blocking(move || {
+            // 第一个片段中声明角色
+            if let CompletionKind::Chat = self.kind {
+                let delta = json!({ "role": "assistant", "content": "" });
+                let _ = sender.blocking_send(self.chunk(delta, None));
+            }
+            // 有工具时，可能是工具调用的文本先不返回，生成结束之后再解析
+            let mut text = String::new();
+            let mut sent = 0;
+            let result = lease.generate(&prompt, &params, |piece| {
+                let piece = match self.tool_format {
+                    Some(format) => {
+                        text.push_str(piece);
+                        let end = format.streamable_len(&text).max(sent);
+                        let piece = &text[sent..end];
+                        sent = end;
+                        if piece.is_empty() {
+                            return true;
+                        }
+                        piece
+                    }
+                    None => piece,
+                };
+                let delta = match self.kind {
+                    CompletionKind::Chat => json!({ "content": piece }),
+                    CompletionKind::Text => json!(piece),
+                };
+                // 客户端断开连接时停止生成
+                sender.blocking_send(self.chunk(delta, None)).is_ok()
+            });
+            match result {
+                Ok((mut finish_reason, usage)) => {
+                    if let Some(format) = self.tool_format {
+                        let parsed = format.parse(&text);
+                        let delta = if parsed.tool_calls.is_empty() {
+                            json!({ "content": &text[sent..] })
+                        } else {
+                            finish_reason = FinishReason::ToolCalls;
+                            json!({ "tool_calls": tool_calls_json(format, parsed.tool_calls, true) })
+                        };
+                        let _ = sender.blocking_send(self.chunk(delta, None));
+                    }
+                    let delta = match self.kind {
+                        CompletionKind::Chat => json!({}),
+                        CompletionKind::Text => json!(""),
+                    };
+                    let _ = sender.blocking_send(self.chunk(delta, Some(finish_reason)));
+                    if include_usage {
+                        let _ = sender.blocking_send(self.body(true, Vec::new(), Some(usage)));
+                    }
+                }
+                Err(e) => {
+                    error!("Failed to generate {}, {e}", self.id);
+                    let _ = sender.blocking_send(ApiError::internal(e).body());
+                }
+            }
+        });
+        let events = ReceiverStream::new(receiver)
+            .map(|value| Event::default().data(value.to_string()))
+            .chain(tokio_stream::once(Event::default().data("[DONE]")))
+            .map(Ok::<_, Infallible>);
+        Sse::new(events)
+            .keep_alive(KeepAlive::default())
+            .into_response()
+    }
+
+    fn object(&self, chunk: bool) -> &'static str {
+        match (self.kind, chunk) {
+            (CompletionKind::Chat, false) => "chat.completion",
+            (CompletionKind::Chat, true) => "chat.completion.chunk",
+            (CompletionKind::Text, _) => "text_completion",
+        }
+    }
+
+    fn body(&self, chunk: bool, choices: Vec<Value>, usage: Option<Usage>) -> Value {
+        json!({
+            "id": self.id,
+            "object": self.object(chunk),
+            "created": self.created,
+            "model": self.model,
+            "choices": choices,
+            "usage": usage,
+        })
+    }
+
+    // 流式返回的一个片段
+    fn chunk(&self, delta: Value, finish_reason: Option<FinishReason>) -> Value {
+        let choice = match self.kind {
+            CompletionKind::Chat => json!({
+                "index": 0,
+                "delta": delta,
+                "finish_reason": finish_reason,
+            }),
+            CompletionKind::Text => json!({
+                "index": 0,
+                "text": delta,
+                "logprobs": null,
+                "finish_reason": finish_reason,
+            }),
+        };
+        self.body(true, vec![choice], None)
+    }
+}
+
+// OpenAI 格式的工具调用，流式返回时需要 index
+fn tool_calls_json(format: ToolCallFormat, tool_calls: Vec<ToolCall>, indexed: bool) -> Value {
+    tool_calls
+        .into_iter()
+        .enumerate()
+        .map(|(index, call)| {
+            let mut value = json!({
+                "id": call.id.unwrap_or_else(|| new_tool_call_id(format)),
+                "type": "function",
+                "function": { "name": call.name, "arguments": call.arguments },
+            });
+            if indexed {
+                value["index"] = json!(index);
+            }
+            value
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_deserialize_chat_completion_request() {
+        let request: ChatCompletionRequest = serde_json::from_value(json!({
+            "model": "qwen3:8b",
+            "messages": [
+                { "role": "system", "content": "You are a helpful assistant." },
+                { "role": "user", "content": [{ "type": "text", "text": "Hello" }] }
+            ],
+            "stream": true,
+            "temperature": 0.2,
+            "stop": "\n\n",
+            "max_tokens": 16,
+            "logit_bias": { "42": -100 },
+        }))
+        .unwrap();
+        assert!(request.stream);
+        assert_eq!(
+            request.messages[1].content.clone().unwrap().into_text(),
+            "Hello"
+        );
+        let params = SamplingParams::try_from(request.sampling).unwrap();
+        assert_eq!(params.temperature, Some(0.2));
+        assert_eq!(params.stop, vec!["\n\n".to_owned()]);
+        assert_eq!(params.max_tokens, Some(16));
+        assert_eq!(params.logit_bias, vec![(42, -100.0)]);
+
+        let options = SamplingOptions {
+            logit_bias: HashMap::from([("token".to_owned(), 1.0)]),
+            ..Default::default()
+        };
+        assert!(SamplingParams::try_from(options).is_err());
+    }
+
+    #[test]
+    fn test_deserialize_response_format() {
+        let options: SamplingOptions = serde_json::from_value(json!({
+            "response_format": {
+                "type": "json_schema",
+                "json_schema": {
+                    "name": "answer",
+                    "schema": {
+                        "type": "object",
+                        "properties": { "answer": { "type": "string" } },
+                        "required": ["answer"],
+                    },
+                    "strict": true,
+                },
+            },
+        }))
+        .unwrap();
+        let grammar = SamplingParams::try_from(options).unwrap().grammar.unwrap();
+        assert!(grammar.contains(r#"root ::= "{" space root-answer-kv "}" space"#));
+
+        let options: SamplingOptions =
+            serde_json::from_value(json!({ "response_format": { "type": "text" } })).unwrap();
+        assert!(SamplingParams::try_from(options).unwrap().grammar.is_none());
+        let options: SamplingOptions = serde_json::from_value(json!({
+            "response_format": { "type": "json_schema", "json_schema": { "schema": { "$ref": "#/missing" } } },
+        }))
+        .unwrap();
+        assert!(SamplingParams::try_from(options).is_err());
+    }
+
+    #[test]
+    fn test_deserialize_tool_messages() {
+        let request: ChatCompletionRequest = serde_json::from_value(json!({
+            "model": "qwen3:8b",
+            "messages": [
+                { "role": "user", "content": "Echo hi" },
+                {
+                    "role": "assistant",
+                    "content": null,
+                    "tool_calls": [{
+                        "id": "call_1",
+                        "type": "function",
+                        "function": { "name": "echo", "arguments": "{\"text\":\"hi\"}" },
+                    }],
+                },
+                { "role": "tool", "tool_call_id": "call_1", "content": "hi" },
+            ],
+            "tools": [{ "type": "function", "function": { "name": "echo" } }],
+            "tool_choice": "auto",
+        }))
+        .unwrap();
+        let call = ToolCall::from(request.messages[1].tool_calls.clone().unwrap().remove(0));
+        assert_eq!(call.id.as_deref(), Some("call_1"));
+        assert_eq!(call.arguments, r#"{"text":"hi"}"#);
+        assert_eq!(request.messages[2].tool_call_id.as_deref(), Some("call_1"));
+        assert_eq!(request.tools.unwrap().len(), 1);
+    }
+}
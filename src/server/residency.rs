@@ -0,0 +1,506 @@
+//! 管理服务中常驻的模型
+//!
+//! 模型在最后一个请求结束之后保留 keep_alive 的时间，超时之后卸载；加载新的模型超过内存预算时，按照最近最少使用的顺序卸载空闲的模型。
+//! 正在处理请求的模型有引用计数，不会被卸载
+//!
+//! 每个模型的生成请求共享同一个生成上下文，由模型的生成线程通过连续批处理同时处理，每个请求占用一个序列。
+//! 请求结束之后序列中的 KV cache 会被保留，新的请求优先使用和提示词公共前缀最长的序列，
+//! 相同的系统提示词和多轮对话中之前的内容不需要重新解码
+
+use crate::{
+    error::Whatever,
+    server::{
+        ServerState,
+        generate::{FinishReason, Usage, generate, tokenize},
+        sampling::SamplingParams,
+        worker::Worker,
+    },
+    service::modelfile::{ChatTemplate, Modelfile},
+};
+use llama_cpp::{context::Context, model::Model};
+use serde::{Deserialize, Deserializer};
+use serde_json::Value;
+use snafu::prelude::*;
+use std::{
+    collections::HashMap,
+    str::FromStr,
+    sync::{Arc, Condvar, Mutex, MutexGuard},
+    time::{Duration, Instant, SystemTime},
+};
+use tracing::{debug, info};
+
+/// 模型在最后一个请求结束之后保留的时间
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum KeepAlive {
+    /// 一直保留，直到超过内存预算或者被删除
+    Forever,
+    /// 保留一段时间，为 0 时请求结束之后立即卸载
+    For(Duration),
+}
+
+/// 解析 ollama 中 keep_alive 的写法，例如 5m、1h30m、300（秒）、0，负数表示一直保留
+impl FromStr for KeepAlive {
+    type Err = String;
+
+    fn from_str(keep_alive: &str) -> Result<Self, Self::Err> {
+        let keep_alive = keep_alive.trim();
+        if keep_alive.is_empty() {
+            return Err("The keep alive is empty".to_owned());
+        }
+        if keep_alive.starts_with('-') {
+            return Ok(KeepAlive::Forever);
+        }
+        // 没有单位时为秒
+        if let Ok(secs) = keep_alive.parse::<f64>() {
+            return duration_from_secs(secs, keep_alive).map(KeepAlive::For);
+        }
+        let is_number = |c: char| c.is_ascii_digit() || c == '.';
+        let mut total = Duration::ZERO;
+        let mut rest = keep_alive;
+        while !rest.is_empty() {
+            let number_len = rest.find(|c| !is_number(c)).unwrap_or(rest.len());
+            let (number, tail) = rest.split_at(number_len);
+            let unit_len = tail.find(is_number).unwrap_or(tail.len());
+            let (unit, tail) = tail.split_at(unit_len);
+            let number = number
+                .parse::<f64>()
+                .map_err(|_| format!("Invalid keep alive({keep_alive})"))?;
+            let secs = match unit {
+                "ms" => number / 1000.0,
+                "s" => number,
+                "m" => number * 60.0,
+                "h" => number * 3600.0,
+                _ => return Err(format!("Invalid unit({unit}) in keep alive({keep_alive})")),
+            };
+            total = total
+                .checked_add(duration_from_secs(secs, keep_alive)?)
+                .ok_or_else(|| format!("The keep alive({keep_alive}) is too long"))?;
+            rest = tail;
+        }
+        Ok(KeepAlive::For(total))
+    }
+}
+
+// 秒数转换成时间，NaN、无穷大或者超出范围时返回错误
+fn duration_from_secs(secs: f64, keep_alive: &str) -> Result<Duration, String> {
+    Duration::try_from_secs_f64(secs).map_err(|e| format!("Invalid keep alive({keep_alive}), {e}"))
+}
+
+/// 请求中的 keep_alive 可以是秒数，也可以是带有单位的字符串
+impl<'de> Deserialize<'de> for KeepAlive {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Raw {
+            Seconds(f64),
+            Text(String),
+        }
+        match Raw::deserialize(deserializer)? {
+            Raw::Seconds(secs) if secs < 0.0 => Ok(KeepAlive::Forever),
+            Raw::Seconds(secs) => Duration::try_from_secs_f64(secs)
+                .map(KeepAlive::For)
+                .map_err(serde::de::Error::custom),
+            Raw::Text(text) => text.parse().map_err(serde::de::Error::custom),
+        }
+    }
+}
+
+/// 常驻的模型的概要，用于 ps
+#[derive(Clone, Debug)]
+pub(crate) struct ResidentModel {
+    pub(crate) name: String,
+    // 估算的内存占用，包括模型和全部上下文的 KV cache
+    pub(crate) size: u64,
+    // 正在处理的请求数量
+    pub(crate) in_flight: usize,
+    // 空闲时卸载的时间，正在处理请求或者一直保留时为 None
+    pub(crate) expires_at: Option<SystemTime>,
+}
+
+// 一个常驻的模型
+struct Resident {
+    // 生成线程，第一次生成时启动，释放之后线程处理完剩余的请求就会结束
+    worker: Option<Worker>,
+    // 空闲的嵌入向量上下文，字段的顺序保证上下文在模型之前释放
+    contexts: Vec<Context>,
+    // 已经创建的嵌入向量上下文的数量，包括正在使用的，不超过同时处理的请求数量
+    n_contexts: usize,
+    model: Arc<Model>,
+    size: u64,
+    in_flight: usize,
+    last_used: Instant,
+    expires_at: Option<Instant>,
+}
+
+/// 常驻模型的注册表
+pub(crate) struct Residency {
+    keep_alive: KeepAlive,
+    // 内存预算，单位为字节，为 None 时不限制
+    budget: Option<u64>,
+    models: Mutex<HashMap<String, Resident>>,
+    // 归还嵌入向量上下文或者卸载模型时唤醒等待上下文的请求
+    context_returned: Condvar,
+}
+
+impl Residency {
+    pub(crate) fn new(keep_alive: KeepAlive, budget: Option<u64>) -> Self {
+        Self {
+            keep_alive,
+            budget,
+            models: Mutex::new(HashMap::new()),
+            context_returned: Condvar::new(),
+        }
+    }
+
+    fn models(&self) -> MutexGuard<'_, HashMap<String, Resident>> {
+        self.models.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// 模型已经加载时增加引用计数并返回模型
+    pub(crate) fn acquire(&self, name: &str) -> Option<Arc<Model>> {
+        let mut models = self.models();
+        let resident = models.get_mut(name)?;
+        resident.in_flight += 1;
+        resident.last_used = Instant::now();
+        resident.expires_at = None;
+        Some(Arc::clone(&resident.model))
+    }
+
+    /// 保存刚刚加载的模型，引用计数为 1，size 中不包括上下文，创建上下文时通过 grow 增加
+    pub(crate) fn insert(&self, name: &str, model: Arc<Model>, size: u64) {
+        let resident = Resident {
+            worker: None,
+            contexts: Vec::new(),
+            n_contexts: 0,
+            model,
+            size,
+            in_flight: 1,
+            last_used: Instant::now(),
+            expires_at: None,
+        };
+        self.models().insert(name.to_owned(), resident);
+    }
+
+    /// 请求结束，减少引用计数，没有请求时开始计算保留的时间
+    pub(crate) fn release(&self, name: &str, keep_alive: Option<KeepAlive>) {
+        let mut models = self.models();
+        let Some(resident) = models.get_mut(name) else {
+            return;
+        };
+        resident.in_flight = resident.in_flight.saturating_sub(1);
+        resident.last_used = Instant::now();
+        if resident.in_flight > 0 {
+            return;
+        }
+        match keep_alive.unwrap_or(self.keep_alive) {
+            KeepAlive::For(Duration::ZERO) => {
+                models.remove(name);
+                debug!("Unloaded {name}, the keep alive is 0");
+            }
+            // 时间太长超出 Instant 的范围时一直保留
+            KeepAlive::For(duration) => resident.expires_at = Instant::now().checked_add(duration),
+            KeepAlive::Forever => resident.expires_at = None,
+        }
+    }
+
+    /// 按照最近最少使用的顺序卸载空闲的模型，直到再加载 extra 字节之后不超过内存预算，返回卸载的模型
+    ///
+    /// 卸载全部空闲的模型之后仍然超过内存预算时返回错误，已经卸载的模型不会恢复
+    pub(crate) fn make_room(&self, extra: u64) -> Result<Vec<String>, Whatever> {
+        let Some(budget) = self.budget else {
+            return Ok(Vec::new());
+        };
+        let mut models = self.models();
+        let mut used = models.values().map(|resident| resident.size).sum::<u64>();
+        let mut idle = models
+            .iter()
+            .filter(|(_, resident)| resident.in_flight == 0)
+            .map(|(name, resident)| (resident.last_used, name.clone()))
+            .collect::<Vec<_>>();
+        idle.sort();
+        let mut evicted = Vec::new();
+        for (_, name) in idle {
+            if used + extra <= budget {
+                break;
+            }
+            if let Some(resident) = models.remove(&name) {
+                used -= resident.size;
+                evicted.push(name);
+            }
+        }
+        for name in &evicted {
+            info!("Unloaded {name} to stay within the memory budget");
+        }
+        ensure_whatever!(
+            used + extra <= budget,
+            "The models in use take {used} bytes, {extra} bytes more will exceed the memory budget({budget} bytes)"
+        );
+        Ok(evicted)
+    }
+
+    /// 卸载超过保留时间的空闲模型，返回卸载的模型
+    pub(crate) fn unload_expired(&self) -> Vec<String> {
+        let now = Instant::now();
+        let mut models = self.models();
+        let expired = models
+            .iter()
+            .filter(|(_, resident)| {
+                resident.in_flight == 0 && resident.expires_at.is_some_and(|at| at <= now)
+            })
+            .map(|(name, _)| name.clone())
+            .collect::<Vec<_>>();
+        for name in &expired {
+            models.remove(name);
+        }
+        expired
+    }
+
+    /// 模型创建了新的上下文，增加估算的内存占用
+    pub(crate) fn grow(&self, name: &str, size: u64) {
+        if let Some(resident) = self.models().get_mut(name) {
+            resident.size += size;
+        }
+    }
+
+    /// 卸载模型，正在处理的请求结束之后模型才会被释放
+    pub(crate) fn unload(&self, name: &str) -> bool {
+        let unloaded = self.models().remove(name).is_some();
+        // 等待上下文的请求不会再等到归还的上下文
+        self.context_returned.notify_all();
+        unloaded
+    }
+
+    /// 常驻的模型，按照名字排序
+    pub(crate) fn resident(&self) -> Vec<ResidentModel> {
+        let now = Instant::now();
+        let mut resident = self
+            .models()
+            .iter()
+            .map(|(name, resident)| ResidentModel {
+                name: name.clone(),
+                size: resident.size,
+                in_flight: resident.in_flight,
+                expires_at: resident
+                    .expires_at
+                    .map(|at| SystemTime::now() + at.saturating_duration_since(now)),
+            })
+            .collect::<Vec<_>>();
+        resident.sort_by(|a, b| a.name.cmp(&b.name));
+        resident
+    }
+
+    // 取出一个空闲的嵌入向量上下文，没有空闲的上下文时返回 None，由调用者创建新的上下文
+    //
+    // 上下文的数量达到 limit 时等待其它请求归还，模型在等待的时候被卸载时返回错误
+    fn take_context(&self, name: &str, limit: usize) -> Result<Option<Context>, Whatever> {
+        let mut models = self.models();
+        loop {
+            let resident = models
+                .get_mut(name)
+                .with_whatever_context(|| format!("The model {name} has been unloaded"))?;
+            if let Some(context) = resident.contexts.pop() {
+                return Ok(Some(context));
+            }
+            if resident.n_contexts < limit {
+                resident.n_contexts += 1;
+                return Ok(None);
+            }
+            models = self
+                .context_returned
+                .wait(models)
+                .unwrap_or_else(|e| e.into_inner());
+        }
+    }
+
+    // 归还上下文，模型已经被卸载时直接释放
+    fn put_context(&self, name: &str, context: Context) {
+        if let Some(resident) = self.models().get_mut(name) {
+            resident.contexts.push(context);
+        }
+        self.context_returned.notify_one();
+    }
+
+    // 没能创建上下文时让出 take_context 中占用的数量
+    fn forget_context(&self, name: &str) {
+        if let Some(resident) = self.models().get_mut(name) {
+            resident.n_contexts = resident.n_contexts.saturating_sub(1);
+        }
+        self.context_returned.notify_one();
+    }
+
+    // 模型的生成线程
+    fn worker(&self, name: &str) -> Option<Worker> {
+        self.models().get(name)?.worker.clone()
+    }
+
+    // 保存新启动的生成线程，size 为生成上下文的 KV cache 占用的内存
+    //
+    // 其它请求已经启动了生成线程时使用已有的线程，新的线程随着句柄释放而结束
+    fn set_worker(&self, name: &str, worker: Worker, size: u64) -> Worker {
+        let mut models = self.models();
+        let Some(resident) = models.get_mut(name) else {
+            return worker;
+        };
+        if let Some(worker) = &resident.worker {
+            return worker.clone();
+        }
+        resident.size += size;
+        resident.worker.insert(worker).clone()
+    }
+}
+
+/// 正在使用的模型，释放时减少模型的引用计数
+pub(crate) struct ModelLease {
+    state: Arc<ServerState>,
+    name: String,
+    model: Arc<Model>,
+    // 拉取的模板、系统提示词和参数
+    modelfile: Modelfile,
+    keep_alive: Option<KeepAlive>,
+}
+
+impl ModelLease {
+    pub(crate) fn new(
+        state: Arc<ServerState>,
+        name: String,
+        model: Arc<Model>,
+        modelfile: Modelfile,
+        keep_alive: Option<KeepAlive>,
+    ) -> Self {
+        Self {
+            state,
+            name,
+            model,
+            modelfile,
+            keep_alive,
+        }
+    }
+
+    pub(crate) fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub(crate) fn model(&self) -> &Model {
+        &self.model
+    }
+
+    pub(crate) fn keep_alive(&self) -> Option<KeepAlive> {
+        self.keep_alive
+    }
+
+    /// 对话模板，优先使用拉取的模板，没有拉取时使用 GGUF 中的模板，对话中没有系统提示词时使用拉取的系统提示词
+    pub(crate) fn chat_template(&self, tools: Vec<Value>) -> Result<ChatTemplate, Whatever> {
+        Ok(ChatTemplate::new(
+            &self.model,
+            self.modelfile.template.clone(),
+            self.modelfile.system.clone(),
+        )?
+        .with_tools(tools))
+    }
+
+    /// 使用一个计算嵌入向量的上下文，优先使用空闲的上下文，没有时创建新的上下文，使用完之后归还
+    ///
+    /// 每个模型的上下文数量不超过同时处理的请求数量，超出时等待其它请求归还上下文。
+    /// 会阻塞当前线程，需要在 spawn_blocking 中调用
+    pub(crate) fn with_embedding_context<T>(
+        &self,
+        f: impl FnOnce(&Model, &mut Context) -> Result<T, Whatever>,
+    ) -> Result<T, Whatever> {
+        let mut context = self.take_context()?;
+        let result = f(&self.model, &mut context);
+        self.state.residency().put_context(&self.name, context);
+        result
+    }
+
+    /// 根据提示词生成文本，请求提交给模型的生成线程，和其它请求共享同一个生成上下文
+    ///
+    /// 请求中没有提供的采样参数使用拉取的参数。和之前的请求相同的前缀不会重新解码，会阻塞当前线程，需要在 spawn_blocking 中调用
+    pub(crate) fn generate(
+        &self,
+        prompt: &str,
+        params: &SamplingParams,
+        on_text: impl FnMut(&str) -> bool,
+    ) -> Result<(FinishReason, Usage), Whatever> {
+        let tokens = tokenize(&self.model, prompt)?;
+        let params = params.clone().with_defaults(&self.modelfile.parameters);
+        let worker = self.worker()?;
+        generate(&self.model, &worker, tokens, &params, on_text)
+    }
+
+    // 取出一个空闲的嵌入向量上下文，没有时创建新的上下文，新的上下文需要额外的 KV cache
+    fn take_context(&self) -> Result<Context, Whatever> {
+        let residency = self.state.residency();
+        if let Some(context) = residency.take_context(&self.name, self.state.n_parallel as usize)? {
+            return Ok(context);
+        }
+        let size = self.model.kv_cache_size(&self.state.context_params(true));
+        let context = residency
+            .make_room(size)
+            .and_then(|_| self.state.new_context(&self.model, true));
+        match context {
+            Ok(context) => {
+                residency.grow(&self.name, size);
+                Ok(context)
+            }
+            Err(e) => {
+                residency.forget_context(&self.name);
+                Err(e)
+            }
+        }
+    }
+
+    // 模型的生成线程，第一次生成时创建生成上下文并启动线程
+    fn worker(&self) -> Result<Worker, Whatever> {
+        let residency = self.state.residency();
+        if let Some(worker) = residency.worker(&self.name) {
+            return Ok(worker);
+        }
+        let size = self.model.kv_cache_size(&self.state.context_params(false));
+        residency.make_room(size)?;
+        let context = self.state.new_context(&self.model, false)?;
+        let worker = Worker::spawn(&self.name, Arc::clone(&self.model), context)?;
+        Ok(residency.set_worker(&self.name, worker, size))
+    }
+}
+
+impl Drop for ModelLease {
+    fn drop(&mut self) {
+        self.state.residency().release(&self.name, self.keep_alive);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_keep_alive() {
+        let minutes = |m: u64| KeepAlive::For(Duration::from_secs(m * 60));
+        assert_eq!("5m".parse(), Ok(minutes(5)));
+        assert_eq!("1h30m".parse(), Ok(minutes(90)));
+        assert_eq!("300".parse(), Ok(minutes(5)));
+        assert_eq!("0".parse(), Ok(KeepAlive::For(Duration::ZERO)));
+        assert_eq!(
+            "1.5s".parse(),
+            Ok(KeepAlive::For(Duration::from_millis(1500)))
+        );
+        assert_eq!("-1".parse(), Ok(KeepAlive::Forever));
+        assert_eq!("-1m".parse(), Ok(KeepAlive::Forever));
+        assert!("".parse::<KeepAlive>().is_err());
+        assert!("5d".parse::<KeepAlive>().is_err());
+        assert!("m".parse::<KeepAlive>().is_err());
+        assert!("nan".parse::<KeepAlive>().is_err());
+        assert!("inf".parse::<KeepAlive>().is_err());
+        assert!("1e30".parse::<KeepAlive>().is_err());
+        assert!("99999999999999999999h".parse::<KeepAlive>().is_err());
+
+        let keep_alive: KeepAlive = serde_json::from_str("-1").unwrap();
+        assert_eq!(keep_alive, KeepAlive::Forever);
+        let keep_alive: KeepAlive = serde_json::from_str("60").unwrap();
+        assert_eq!(keep_alive, minutes(1));
+        let keep_alive: KeepAlive = serde_json::from_str("\"10m\"").unwrap();
+        assert_eq!(keep_alive, minutes(10));
+        assert!(serde_json::from_str::<KeepAlive>("1e300").is_err());
+        assert!(serde_json::from_str::<KeepAlive>("\"1e30\"").is_err());
+    }
+}
@@ -0,0 +1,151 @@
+//! 按照请求中的参数构建采样器
+
+use crate::{server::ApiError, service::modelfile::Parameters};
+use llama_cpp::{
+    json_schema::{self, json_schema_to_grammar},
+    model::{Model, ToolCallFormat},
+    runtime::Runtime,
+    sampler::Sampler,
+    token::{LogitBias, Token},
+};
+use serde_json::Value;
+
+// 没有提供采样参数时，和 llama.cpp 的默认值保持一致
+const DEFAULT_TEMPERATURE: f32 = 0.8;
+const DEFAULT_TOP_K: i32 = 40;
+const DEFAULT_TOP_P: f32 = 0.95;
+const DEFAULT_MIN_P: f32 = 0.05;
+// 计算重复惩罚时回看的 token 数量
+const PENALTY_LAST_N: i32 = 64;
+// llama.cpp 中的 LLAMA_DEFAULT_SEED，表示使用随机的种子
+const DEFAULT_SEED: u32 = u32::MAX;
+
+/// 一次生成使用的采样参数，没有提供的参数使用模型参数中的值，都没有时使用默认值
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct SamplingParams {
+    pub(crate) temperature: Option<f32>,
+    pub(crate) top_k: Option<i32>,
+    pub(crate) top_p: Option<f32>,
+    pub(crate) min_p: Option<f32>,
+    pub(crate) seed: Option<u32>,
+    pub(crate) presence_penalty: Option<f32>,
+    pub(crate) frequency_penalty: Option<f32>,
+    pub(crate) repeat_penalty: Option<f32>,
+    // 计算重复惩罚时回看的 token 数量
+    pub(crate) repeat_last_n: Option<i32>,
+    // token id 和对应的偏置
+    pub(crate) logit_bias: Vec<(i32, f32)>,
+    // 生成的文本中出现其中任意一个字符串时停止生成，停止字符串不会返回
+    pub(crate) stop: Vec<String>,
+    // 最多生成的 token 数量，为 None 时生成到上下文用完为止
+    pub(crate) max_tokens: Option<u32>,
+    // 约束工具调用的语法
+    pub(crate) tool_grammar: Option<ToolGrammar>,
+    // 约束输出格式的 GBNF 语法，根规则为 root，例如 JSON Schema 转换的语法
+    pub(crate) grammar: Option<String>,
+}
+
+/// 使用语法约束工具调用的格式和参数
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct ToolGrammar {
+    pub(crate) format: ToolCallFormat,
+    pub(crate) tools: Vec<Value>,
+    // 为 true 时只在生成了工具调用的开头之后才约束，否则必须调用工具
+    pub(crate) lazy: bool,
+}
+
+impl SamplingParams {
+    /// 没有提供的参数使用拉取的模型参数，停止字符串为空时使用模型参数中的停止字符串
+    pub(crate) fn with_defaults(mut self, parameters: &Parameters) -> Self {
+        self.temperature = self.temperature.or(parameters.temperature);
+        self.top_k = self.top_k.or(parameters.top_k);
+        self.top_p = self.top_p.or(parameters.top_p);
+        self.min_p = self.min_p.or(parameters.min_p);
+        // 模型参数中小于 0 的种子表示随机的种子
+        self.seed = self
+            .seed
+            .or_else(|| parameters.seed.and_then(|seed| u32::try_from(seed).ok()));
+        self.repeat_penalty = self.repeat_penalty.or(parameters.repeat_penalty);
+        self.repeat_last_n = self.repeat_last_n.or(parameters.repeat_last_n);
+        if self.stop.is_empty() {
+            self.stop = parameters.stop.clone();
+        }
+        self.max_tokens = self.max_tokens.or_else(|| {
+            parameters
+                .num_predict
+                .and_then(|num_predict| u32::try_from(num_predict).ok())
+        });
+        self
+    }
+
+    /// 构建采样器链，依次为工具调用或输出格式的语法、logit bias、重复惩罚、top-k、top-p、min-p、温度和随机选择
+    ///
+    /// 温度不大于 0 时使用贪心采样
+    pub(crate) fn sampler(&self, model: &Model) -> Sampler {
+        let mut samplers = Vec::new();
+        if let Some(ToolGrammar {
+            format,
+            tools,
+            lazy,
+        }) = &self.tool_grammar
+        {
+            samplers.push(if *lazy {
+                format.lazy_grammar_sampler(model, tools)
+            } else {
+                Runtime::sampler_from_grammar(model, &format.grammar(tools), "root")
+            });
+        }
+        if let Some(grammar) = &self.grammar {
+            samplers.push(Runtime::sampler_from_grammar(model, grammar, "root"));
+        }
+        let n_vocab = model.vocab().token_quantity();
+        let biases = self
+            .logit_bias
+            .iter()
+            .filter(|(token, _)| (0..n_vocab).contains(token))
+            .map(|&(token, bias)| LogitBias::new(Token::new(token), bias))
+            .collect::<Vec<_>>();
+        if !biases.is_empty() {
+            samplers.push(Sampler::init_from_logit_bias(n_vocab, &biases));
+        }
+        let presence_penalty = self.presence_penalty.unwrap_or_default();
+        let frequency_penalty = self.frequency_penalty.unwrap_or_default();
+        let repeat_penalty = self.repeat_penalty.unwrap_or(1.0);
+        if presence_penalty != 0.0 || frequency_penalty != 0.0 || repeat_penalty != 1.0 {
+            samplers.push(Sampler::init_from_penalties(
+                self.repeat_last_n.unwrap_or(PENALTY_LAST_N),
+                repeat_penalty,
+                frequency_penalty,
+                presence_penalty,
+            ));
+        }
+        let temperature = self.temperature.unwrap_or(DEFAULT_TEMPERATURE);
+        if temperature <= 0.0 {
+            samplers.push(Sampler::init_from_greedy());
+        } else {
+            samplers.push(Sampler::init_from_top_k(
+                self.top_k.unwrap_or(DEFAULT_TOP_K),
+            ));
+            samplers.push(Sampler::init_from_top_p(
+                self.top_p.unwrap_or(DEFAULT_TOP_P),
+                1,
+            ));
+            samplers.push(Sampler::init_from_min_p(
+                self.min_p.unwrap_or(DEFAULT_MIN_P),
+                1,
+            ));
+            samplers.push(Sampler::init_from_temp(temperature));
+            samplers.push(Sampler::init_from_dist(self.seed.unwrap_or(DEFAULT_SEED)));
+        }
+        Sampler::from_chain(samplers, true)
+    }
+}
+
+/// 约束输出为 JSON 的语法，没有 schema 时可以是任意的 JSON 对象
+pub(crate) fn json_grammar(schema: Option<&Value>) -> Result<String, ApiError> {
+    match schema {
+        Some(schema) => json_schema_to_grammar(schema)
+            .map_err(|error| ApiError::bad_request(format!("Invalid JSON schema, {error}"))),
+        None => Ok(json_schema::json_grammar()),
+    }
+}
@@ -0,0 +1,154 @@
+//! 每个常驻模型的生成线程
+//!
+//! 线程持有模型唯一的生成上下文，通过连续批处理的调度器同时处理多个请求，每个请求占用上下文中的一个序列，
+//! 超过序列数量的请求排队等待。请求通过通道提交，生成的 token 通过请求自己的通道返回，
+//! 请求一方释放通道之后请求会被取消
+
+use crate::error::Whatever;
+use llama_cpp::{
+    context::Context,
+    model::Model,
+    sampler::Sampler,
+    scheduler::{Event, FinishReason, Request, RequestId, Scheduler},
+    token::Token,
+};
+use snafu::prelude::*;
+use std::{
+    collections::HashMap,
+    sync::{Arc, mpsc},
+    thread,
+};
+use tracing::error;
+
+/// 生成线程返回给请求的消息
+pub(crate) enum Update {
+    /// 生成了一个 token
+    Token(Token),
+    /// 生成结束，n_cached 为提示词中复用 KV cache 的 token 数量
+    Finished {
+        reason: FinishReason,
+        n_cached: usize,
+    },
+    /// 提交或者解码失败，llama-cpp 的错误不能在线程之间传递，只保留错误信息
+    Failed(String),
+}
+
+// 提交给生成线程的请求，以及返回生成结果的通道
+struct Job {
+    request: Request,
+    updates: mpsc::Sender<Update>,
+}
+
+/// 生成线程的句柄，全部的句柄释放之后，线程处理完剩余的请求就会结束
+#[derive(Clone)]
+pub(crate) struct Worker {
+    jobs: mpsc::Sender<Job>,
+}
+
+impl Worker {
+    /// 启动生成线程，上下文的 n_seq_max 决定了同时处理的请求数量
+    pub(crate) fn spawn(name: &str, model: Arc<Model>, context: Context) -> Result<Self, Whatever> {
+        let (jobs, receiver) = mpsc::channel();
+        let scheduler = Scheduler::new(context);
+        thread::Builder::new()
+            .name(format!("generate-{name}"))
+            .spawn(move || run(model, scheduler, receiver))
+            .with_whatever_context(|_| "Failed to spawn the generation thread")?;
+        Ok(Self { jobs })
+    }
+
+    /// 提交一个请求，返回接收生成结果的通道，释放通道之后请求会被取消
+    pub(crate) fn submit(
+        &self,
+        prompt: Vec<Token>,
+        sampler: Sampler,
+        max_tokens: Option<u32>,
+    ) -> Result<mpsc::Receiver<Update>, Whatever> {
+        let (updates, receiver) = mpsc::channel();
+        let request = Request {
+            prompt,
+            sampler,
+            max_tokens,
+        };
+        self.jobs
+            .send(Job { request, updates })
+            .ok()
+            .whatever_context("The generation thread has stopped")?;
+        Ok(receiver)
+    }
+}
+
+// 没有请求时阻塞等待，有请求时不断解码批次，每一步之间接收新的请求
+fn run(model: Arc<Model>, mut scheduler: Scheduler, jobs: mpsc::Receiver<Job>) {
+    let mut requests = HashMap::new();
+    loop {
+        if scheduler.is_idle() {
+            let Ok(job) = jobs.recv() else {
+                break;
+            };
+            submit(&mut scheduler, &mut requests, job);
+        }
+        while let Ok(job) = jobs.try_recv() {
+            submit(&mut scheduler, &mut requests, job);
+        }
+        match scheduler.step(&model) {
+            Ok(events) => dispatch(&mut scheduler, &mut requests, events),
+            Err(e) => {
+                let message = format!("Failed to decode the batch, {e}");
+                error!("{message}");
+                for (_, updates) in requests.drain() {
+                    let _ = updates.send(Update::Failed(message.clone()));
+                }
+                scheduler.cancel_all();
+            }
+        }
+    }
+    // 上下文需要在模型之前释放
+    drop(scheduler);
+    drop(model);
+}
+
+fn submit(
+    scheduler: &mut Scheduler,
+    requests: &mut HashMap<RequestId, mpsc::Sender<Update>>,
+    job: Job,
+) {
+    match scheduler.submit(job.request) {
+        Ok(id) => {
+            requests.insert(id, job.updates);
+        }
+        Err(e) => {
+            let _ = job.updates.send(Update::Failed(e.to_string()));
+        }
+    }
+}
+
+// 将事件发送给对应的请求，请求一方不再接收时取消请求
+fn dispatch(
+    scheduler: &mut Scheduler,
+    requests: &mut HashMap<RequestId, mpsc::Sender<Update>>,
+    events: Vec<Event>,
+) {
+    for event in events {
+        match event {
+            Event::Token { id, token } => {
+                let sent = requests
+                    .get(&id)
+                    .is_some_and(|updates| updates.send(Update::Token(token)).is_ok());
+                if !sent && requests.remove(&id).is_some() {
+                    scheduler.cancel(id);
+                }
+            }
+            Event::Finished {
+                id,
+                reason,
+                n_cached,
+                ..
+            } => {
+                if let Some(updates) = requests.remove(&id) {
+                    let _ = updates.send(Update::Finished { reason, n_cached });
+                }
+            }
+        }
+    }
+}
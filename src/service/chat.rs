@@ -0,0 +1,114 @@
+//! 保存 simple-run 中的对话，之后可以继续对话、检索和导出
+
+use crate::{db, error::Whatever};
+use llama_cpp::model::Message;
+use rusqlite::Connection;
+use snafu::prelude::*;
+
+// 对话标题的最大字符数
+const TITLE_MAX_CHARS: usize = 48;
+
+/// 将对话中的消息保存到数据库中
+pub(crate) struct ChatRecorder<'a> {
+    conn: &'a Connection,
+    model_name: &'a str,
+    // 采样参数，JSON 对象
+    sampler: String,
+    // 保存第一条消息时才创建对话，避免留下空的对话
+    chat_id: Option<i64>,
+}
+
+impl<'a> ChatRecorder<'a> {
+    pub(crate) fn new(conn: &'a Connection, model_name: &'a str, sampler: String) -> Self {
+        Self {
+            conn,
+            model_name,
+            sampler,
+            chat_id: None,
+        }
+    }
+
+    /// 之后的消息追加到已有的对话中
+    pub(crate) fn with_chat_id(mut self, chat_id: i64) -> Self {
+        self.chat_id = Some(chat_id);
+        self
+    }
+
+    pub(crate) fn chat_id(&self) -> Option<i64> {
+        self.chat_id
+    }
+
+    /// 之后的消息保存到一个新的对话中
+    pub(crate) fn restart(&mut self) {
+        self.chat_id = None;
+    }
+
+    /// 删除最后的 n 条消息
+    pub(crate) fn remove_last(&mut self, n: usize) -> Result<(), Whatever> {
+        match self.chat_id {
+            Some(chat_id) => db::chat::delete_last_chat_messages(self.conn, chat_id, n),
+            None => Ok(()),
+        }
+    }
+
+    /// 采样参数变化之后，更新对话的采样参数
+    pub(crate) fn set_sampler(&mut self, sampler: String) -> Result<(), Whatever> {
+        if let Some(chat_id) = self.chat_id {
+            db::chat::update_chat_sampler(self.conn, chat_id, &sampler)?;
+        }
+        self.sampler = sampler;
+        Ok(())
+    }
+
+    /// 保存一条消息，n_tokens 是消息内容的 token 数量
+    pub(crate) fn record(&mut self, message: &Message, n_tokens: usize) -> Result<(), Whatever> {
+        let role = message.role.to_string_lossy();
+        let content = message.content.to_string_lossy();
+        let chat_id = match self.chat_id {
+            Some(chat_id) => chat_id,
+            None => {
+                let chat_id = db::chat::insert_chat(
+                    self.conn,
+                    title(&content),
+                    self.model_name,
+                    &self.sampler,
+                )?;
+                *self.chat_id.insert(chat_id)
+            }
+        };
+        db::chat::insert_chat_message(self.conn, chat_id, role, content, n_tokens)
+    }
+}
+
+/// 读取对话中的消息
+pub(crate) fn chat_messages(conn: &Connection, chat_id: i64) -> Result<Vec<Message>, Whatever> {
+    db::chat::query_chat_messages(conn, chat_id)?
+        .into_iter()
+        .map(|message| {
+            Message::try_new(message.role, message.content)
+                .with_whatever_context(|_| format!("Invalid message in the chat({chat_id})"))
+        })
+        .collect()
+}
+
+// 取第一行的开头作为对话的标题
+fn title(content: &str) -> String {
+    let line = content.trim().lines().next().unwrap_or_default().trim();
+    match line.char_indices().nth(TITLE_MAX_CHARS) {
+        Some((index, _)) => format!("{}...", &line[..index]),
+        None => line.to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::title;
+
+    #[test]
+    fn title_is_the_beginning_of_the_first_line() {
+        assert_eq!(title("  hello\nworld"), "hello");
+        assert_eq!(title(""), "");
+        let long = "长".repeat(60);
+        assert_eq!(title(&long), format!("{}...", "长".repeat(48)));
+    }
+}
@@ -3,8 +3,13 @@ use rusqlite::Connection;
 use std::{path::Path, sync::Arc};
 use tokio::sync::Mutex;
 
+pub(crate) mod chat;
 pub(crate) mod init;
 pub(crate) mod model;
+pub(crate) mod modelfile;
+pub(crate) mod pull;
+pub(crate) mod session;
+pub(crate) mod tool;
 
 pub(crate) fn connection_llama_buddy_db(
     path: impl AsRef<Path>,
@@ -2,23 +2,34 @@ use crate::{
     db,
     db::{
         CompletedStatus,
-        model::{Model, ModelInfo},
+        model::{Model, ModelFile, ModelInfo},
     },
     error::Whatever,
+    utils::reference::ModelReference,
 };
-use http_extra::sha256::digest;
+use http_extra::{progress::Progress, sha256::digest};
 use reqwest::Client;
 use rusqlite::Connection;
 use scraper::{ElementRef, Html, Selector};
 use snafu::{FromString, prelude::*};
 use std::{
     collections::{HashMap, VecDeque},
+    fs::remove_file,
+    path::PathBuf,
     sync::Arc,
 };
 use tokio::sync::Mutex;
 use tracing::{debug, error};
 use url::Url;
 
+/// 将模型的引用转换为本地注册表中的名字和规格
+pub(crate) fn local_name_and_category(
+    conn: &Connection,
+    reference: &ModelReference,
+) -> Result<(String, String), Whatever> {
+    final_name_and_category(conn, reference.local_title(), reference.category())
+}
+
 pub(crate) fn final_name_and_category(
     conn: &Connection,
     name: impl AsRef<str> + std::fmt::Display,
@@ -45,12 +56,67 @@ pub(crate) fn final_name_and_category(
         }
     }
 }
+/// 删除模型时每个文件的处理结果
+#[derive(Debug)]
+pub(crate) enum RemovedFile {
+    /// 其它规格的模型还在引用这个文件，只解除引用
+    Untagged(PathBuf),
+    /// 预演时将要删除的文件和大小
+    WouldRemove(PathBuf, u64),
+    /// 已经删除的文件和大小
+    Removed(PathBuf, u64),
+    /// 删除失败的文件和原因
+    Failed(PathBuf, String),
+}
+
+/// 删除模型拉取到本地的文件，模型没有拉取过时返回 None
+///
+/// 调用方需要持有文件锁并且完成文件的迁移，预演时不修改注册表和文件
+pub(crate) fn remove_model_files(
+    conn: &Connection,
+    model_name: &str,
+    dry_run: bool,
+) -> Result<Option<Vec<RemovedFile>>, Whatever> {
+    let detail = db::model::query_model_detail(conn, model_name)?;
+    if detail.files.is_empty() && detail.pull_status == CompletedStatus::NotStarted.as_ref() {
+        return Ok(None);
+    }
+    // 先清空注册表中的文件路径，再根据引用计数决定文件是否可以删除
+    if !dry_run {
+        db::model::clear_model_file_path(conn, model_name)?;
+    }
+    // 预演时注册表没有修改，引用计数中包含当前模型自己
+    let own = usize::from(dry_run);
+    let mut removed = Vec::with_capacity(detail.files.len());
+    for ModelFile { path, size, .. } in detail.files {
+        let path = PathBuf::from(path);
+        let references = db::model::count_model_file_references(conn, &path)?;
+        if references > own || !path.exists() {
+            removed.push(RemovedFile::Untagged(path));
+            continue;
+        }
+        if dry_run {
+            removed.push(RemovedFile::WouldRemove(path, size));
+            continue;
+        }
+        match remove_file(&path) {
+            Ok(_) => {
+                db::blob::delete_verified_blob_by_path(conn, &path)?;
+                removed.push(RemovedFile::Removed(path, size));
+            }
+            Err(e) => removed.push(RemovedFile::Failed(path, e.to_string())),
+        }
+    }
+    Ok(Some(removed))
+}
+
 pub(crate) async fn try_update_model_info(
     conn: Arc<Mutex<Connection>>,
     client: Client,
     remote_registry: Url,
+    progress: Progress,
 ) -> Result<(), Whatever> {
-    match save_model_info(Arc::clone(&conn), client, remote_registry).await {
+    match save_model_info(Arc::clone(&conn), client, remote_registry, progress).await {
         Ok(_) => completed_update_model_info(Arc::clone(&conn), CompletedStatus::Completed).await,
         Err(error) => {
             error!("Failed to update model info, {error:?}");
@@ -71,11 +137,12 @@ pub(crate) async fn try_save_model_info(
     conn: Arc<Mutex<Connection>>,
     client: Client,
     remote_registry: Url,
+    progress: Progress,
 ) -> Result<(), Whatever> {
     if check_insert_model_info_completed(Arc::clone(&conn)).await? {
         return Ok(());
     }
-    match save_model_info(Arc::clone(&conn), client, remote_registry).await {
+    match save_model_info(Arc::clone(&conn), client, remote_registry, progress).await {
         Ok(_) => completed_insert_model_info(Arc::clone(&conn), CompletedStatus::Completed).await,
         Err(error) => {
             completed_insert_model_info(Arc::clone(&conn), CompletedStatus::Failed).await?;
@@ -99,10 +166,12 @@ pub(crate) async fn completed_insert_model_info(
     db::config::completed_insert_model_info(&conn, completed_status)
 }
 
+/// 保存远程注册表中的模型信息，progress 的进度为需要获取详情的模型个数
 pub(crate) async fn save_model_info(
     conn: Arc<Mutex<Connection>>,
     client: Client,
     remote_registry: Url,
+    progress: Progress,
 ) -> Result<(), Whatever> {
     let old_model_raw_digest_map = query_model_title_and_model_info(Arc::clone(&conn)).await?;
     let (library_html_sender, library_html_receiver) = tokio::sync::oneshot::channel::<String>();
@@ -114,6 +183,7 @@ pub(crate) async fn save_model_info(
         library_html_sender,
         model_info_sender,
         old_model_raw_digest_map,
+        progress,
     ));
     let receive_job_one = tokio::spawn(receive_one(Arc::clone(&conn), library_html_receiver));
     let receive_job_two = tokio::spawn(receive_two(Arc::clone(&conn), model_info_receiver));
@@ -151,6 +221,7 @@ async fn send(
     library_html_sender: tokio::sync::oneshot::Sender<String>,
     model_info_sender: tokio::sync::mpsc::Sender<ModelInfo>,
     old_model_raw_digest_map: HashMap<String, String>,
+    progress: Progress,
 ) -> Result<(), Whatever> {
     let library_html = fetch_library_html(client.clone(), remote_registry.clone()).await?;
     let library_html_str = library_html.as_str();
@@ -158,6 +229,16 @@ async fn send(
     library_html_sender
         .send(library_html)
         .with_whatever_context(|_| "send library html to channel failed!")?;
+    // 摘要没有变化的模型不需要重新获取详情
+    let pending = model_infos
+        .iter()
+        .filter(|model_info| {
+            old_model_raw_digest_map
+                .get(&model_info.title)
+                .is_none_or(|old_raw_digest| old_raw_digest != model_info.raw_digest.as_str())
+        })
+        .count();
+    progress.start(Some(pending as u64), 0);
     for model_info in model_infos.iter_mut() {
         if let Some(old_raw_digest) = old_model_raw_digest_map.get(&model_info.title) {
             if old_raw_digest == model_info.raw_digest.as_str() {
@@ -174,7 +255,9 @@ async fn send(
             .send(model_info.to_owned())
             .await
             .with_whatever_context(|_| "send model info to channel failed!")?;
+        progress.advance(1);
     }
+    progress.finish();
     Ok(())
 }
 
@@ -0,0 +1,376 @@
+//! 使用从 ollama 拉取的模板、系统提示词和参数，没有拉取模板时使用 GGUF 中的对话模板
+
+use crate::{db, error::Whatever, utils::go_template::GoTemplate};
+use llama_cpp::model::{
+    ChatTemplateInputs, JinjaTemplate, Message, Model, Template, ToolCall, ToolCallFormat,
+    describe_tools_in_system,
+};
+use rusqlite::Connection;
+use serde::Deserialize;
+use serde_json::{Value, json};
+use snafu::prelude::*;
+use std::{fs, slice};
+
+// 渲染旧格式的模板时，用来标记回答开始的位置
+const RESPONSE_SENTINEL: &str = "\u{0}llama-buddy-response\u{0}";
+
+/// ollama 模型中的参数，只支持生成相关的参数，其它参数会被忽略
+#[derive(Clone, Debug, Default, Deserialize)]
+pub(crate) struct Parameters {
+    #[serde(default)]
+    pub(crate) stop: Vec<String>,
+    pub(crate) temperature: Option<f32>,
+    pub(crate) top_k: Option<i32>,
+    pub(crate) top_p: Option<f32>,
+    pub(crate) min_p: Option<f32>,
+    pub(crate) repeat_penalty: Option<f32>,
+    pub(crate) repeat_last_n: Option<i32>,
+    // 小于 0 时使用随机的种子
+    pub(crate) seed: Option<i64>,
+    // 上下文的大小
+    pub(crate) num_ctx: Option<u32>,
+    // 最多生成的 token 数量，小于 0 时不限制
+    pub(crate) num_predict: Option<i32>,
+}
+
+/// 从 ollama 拉取的模型文件，旧版本拉取的模型中没有系统提示词
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Modelfile {
+    pub(crate) template: Option<GoTemplate>,
+    pub(crate) system: Option<String>,
+    pub(crate) parameters: Parameters,
+}
+
+impl Modelfile {
+    pub(crate) fn load(conn: &Connection, model_name: &str) -> Result<Self, Whatever> {
+        let (template, system, params) = db::model::get_modelfile_paths(conn, model_name)?;
+        let read = |path: String| {
+            fs::read_to_string(&path).with_whatever_context(|_| format!("Failed to read {path}"))
+        };
+        let template = match template {
+            Some(path) => Some(GoTemplate::parse(&read(path)?).with_whatever_context(|_| {
+                format!("Failed to parse the template of {model_name}")
+            })?),
+            None => None,
+        };
+        let system = system.map(read).transpose()?;
+        let parameters = match params {
+            Some(path) => serde_json::from_str(&read(path)?)
+                .with_whatever_context(|_| format!("Failed to parse the params of {model_name}"))?,
+            None => Parameters::default(),
+        };
+        Ok(Self {
+            template,
+            system: system.filter(|system| !system.is_empty()),
+            parameters,
+        })
+    }
+}
+
+enum TemplateSource {
+    Ollama(GoTemplate),
+    // GGUF 中的模板优先使用 Jinja 渲染，无法编译时交给 llama.cpp 处理
+    Gguf(Template, Option<Box<JinjaTemplate>>),
+}
+
+/// 对话模板，优先使用从 ollama 拉取的模板
+pub(crate) struct ChatTemplate {
+    source: TemplateSource,
+    // 对话中没有系统提示词时使用的系统提示词
+    system: Option<String>,
+    // 可以调用的工具，和 OpenAI 的 tools 参数格式一致
+    tools: Vec<Value>,
+}
+
+// ollama 模板中的一条消息
+#[derive(Clone, Debug, Default)]
+struct OllamaMessage {
+    role: String,
+    content: String,
+    tool_calls: Vec<ToolCall>,
+}
+
+impl ChatTemplate {
+    pub(crate) fn new(
+        model: &Model,
+        template: Option<GoTemplate>,
+        system: Option<String>,
+    ) -> Result<Self, Whatever> {
+        let source = match template {
+            Some(template) => TemplateSource::Ollama(template),
+            None => TemplateSource::Gguf(
+                model
+                    .chat_template(None)
+                    .with_whatever_context(|_| "Failed to get a chat template from model")?,
+                model.jinja_chat_template().ok().map(Box::new),
+            ),
+        };
+        Ok(Self {
+            source,
+            system,
+            tools: Vec::new(),
+        })
+    }
+
+    pub(crate) fn with_tools(mut self, tools: Vec<Value>) -> Self {
+        self.tools = tools;
+        self
+    }
+
+    /// 模型输出工具调用的格式，模板不支持工具时使用系统提示词中描述的 Hermes 格式
+    pub(crate) fn tool_call_format(&self) -> ToolCallFormat {
+        let source = match &self.source {
+            TemplateSource::Ollama(template) => Some(template.source()),
+            TemplateSource::Gguf(template, _) => template.to_str().ok(),
+        };
+        source
+            .filter(|_| self.supports_tools())
+            .and_then(ToolCallFormat::detect)
+            .unwrap_or(ToolCallFormat::Hermes)
+    }
+
+    // 模板中是否渲染了工具的定义
+    fn supports_tools(&self) -> bool {
+        match &self.source {
+            TemplateSource::Ollama(template) => template.uses_field("Tools"),
+            TemplateSource::Gguf(_, Some(jinja)) => jinja
+                .source()
+                .to_str()
+                .is_ok_and(|source| source.contains("tools")),
+            TemplateSource::Gguf(_, None) => false,
+        }
+    }
+
+    /// 只渲染对话开头的系统提示词，对话中没有时使用默认的系统提示词，都没有时返回 None
+    ///
+    /// 工具的描述放在系统提示词中时一起渲染，渲染结果是完整对话的提示词的开头
+    pub(crate) fn apply_system(
+        &self,
+        model: &Model,
+        messages: &[Message],
+    ) -> Result<Option<String>, Whatever> {
+        let system = messages
+            .first()
+            .filter(|message| message.role.as_bytes() == b"system");
+        let tools_in_system = !self.tools.is_empty() && !self.supports_tools();
+        if system.is_none() && self.system.is_none() && !tools_in_system {
+            return Ok(None);
+        }
+        let messages = system.map(slice::from_ref).unwrap_or_default();
+        self.apply(model, messages, false).map(Some)
+    }
+
+    /// 将对话渲染为提示词，add_ass 为 true 时在末尾添加回答的开头
+    pub(crate) fn apply(
+        &self,
+        model: &Model,
+        messages: &[Message],
+        add_ass: bool,
+    ) -> Result<String, Whatever> {
+        let has_system = messages
+            .iter()
+            .any(|message| message.role.as_bytes() == b"system");
+        let mut chats = Vec::with_capacity(messages.len() + 1);
+        if let Some(system) = &self.system
+            && !has_system
+        {
+            chats.push(
+                Message::try_new("system", system.as_str())
+                    .with_whatever_context(|_| "Invalid system prompt")?,
+            );
+        }
+        chats.extend_from_slice(messages);
+        let supports_tools = self.supports_tools();
+        if !self.tools.is_empty() && !supports_tools {
+            describe_tools_in_system(&mut chats, &self.tools)
+                .with_whatever_context(|_| "Invalid tool definitions")?;
+        }
+        let tools = (supports_tools && !self.tools.is_empty()).then(|| self.tools.clone());
+        match &self.source {
+            TemplateSource::Gguf(template, jinja) => match jinja {
+                Some(jinja) => {
+                    let mut inputs = ChatTemplateInputs::from_messages(&chats)
+                        .with_add_generation_prompt(add_ass);
+                    if let Some(tools) = tools {
+                        inputs = inputs.with_tools(tools);
+                    }
+                    model.apply_jinja_chat_template(jinja, inputs)
+                }
+                None => model.apply_chat_template(template, &chats, add_ass),
+            }
+            .with_whatever_context(|_| "Failed to apply chat template"),
+            TemplateSource::Ollama(template) => {
+                let chats = chats
+                    .into_iter()
+                    .map(|message| OllamaMessage {
+                        role: message.role.to_string_lossy().into_owned(),
+                        content: message.content.to_string_lossy().into_owned(),
+                        tool_calls: message.tool_calls,
+                    })
+                    .collect();
+                render_ollama(template, collate(chats), tools, add_ass)
+            }
+        }
+    }
+}
+
+// 合并连续的同一个角色的消息，和 ollama 保持一致，工具调用和工具的结果不合并
+fn collate(messages: Vec<OllamaMessage>) -> Vec<OllamaMessage> {
+    let mut collated = Vec::<OllamaMessage>::with_capacity(messages.len());
+    for message in messages {
+        match collated.last_mut() {
+            Some(last)
+                if last.role == message.role
+                    && message.role != "tool"
+                    && last.tool_calls.is_empty()
+                    && message.tool_calls.is_empty() =>
+            {
+                last.content.push_str("\n\n");
+                last.content.push_str(&message.content);
+            }
+            _ => collated.push(message),
+        }
+    }
+    collated
+}
+
+// 使用了 .Messages 的模板一次渲染全部的消息；旧格式的模板只有 .System、.Prompt 和 .Response，每一轮对话渲染一次，
+// 最后一轮渲染到回答开始的位置
+//
+// 工具调用使用小写字段的 JSON，模板中的 .Function.Name 会匹配到 function.name
+fn render_ollama(
+    template: &GoTemplate,
+    messages: Vec<OllamaMessage>,
+    tools: Option<Vec<Value>>,
+    add_ass: bool,
+) -> Result<String, Whatever> {
+    let system = messages
+        .iter()
+        .rev()
+        .find(|message| message.role == "system")
+        .map(|message| message.content.clone())
+        .unwrap_or_default();
+    if template.uses_field("Messages") {
+        let messages = messages
+            .iter()
+            .map(|message| {
+                let tool_calls = message
+                    .tool_calls
+                    .iter()
+                    .map(ToolCall::to_template_value)
+                    .collect::<Vec<_>>();
+                json!({
+                    "Role": message.role,
+                    "Content": message.content,
+                    "ToolCalls": (!tool_calls.is_empty()).then_some(tool_calls),
+                })
+            })
+            .collect::<Vec<_>>();
+        return template.render(&json!({
+            "System": system,
+            "Messages": messages,
+            "Tools": tools,
+            "Response": "",
+        }));
+    }
+    let mut rendered = String::new();
+    let (mut system, mut prompt, mut response) = (String::new(), String::new(), String::new());
+    let render = |system: &mut String, prompt: &mut String, response: &mut String| {
+        let data = json!({ "System": system, "Prompt": prompt, "Response": response });
+        system.clear();
+        prompt.clear();
+        response.clear();
+        template.render(&data)
+    };
+    for OllamaMessage { role, content, .. } in messages {
+        match role.as_str() {
+            "system" => {
+                if !prompt.is_empty() || !response.is_empty() {
+                    rendered += &render(&mut system, &mut prompt, &mut response)?;
+                }
+                system = content;
+            }
+            "user" => {
+                if !response.is_empty() {
+                    rendered += &render(&mut system, &mut prompt, &mut response)?;
+                }
+                prompt = content;
+            }
+            "assistant" => response = content,
+            _ => {}
+        }
+    }
+    if add_ass {
+        response = RESPONSE_SENTINEL.to_owned();
+        let last = render(&mut system, &mut prompt, &mut response)?;
+        match last.find(RESPONSE_SENTINEL) {
+            Some(index) => rendered += &last[..index],
+            None => rendered += &last,
+        }
+    } else if !system.is_empty() || !prompt.is_empty() || !response.is_empty() {
+        rendered += &render(&mut system, &mut prompt, &mut response)?;
+    }
+    Ok(rendered)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{GoTemplate, OllamaMessage, render_ollama};
+    use llama_cpp::model::ToolCall;
+    use serde_json::json;
+
+    fn messages(messages: &[(&str, &str)]) -> Vec<OllamaMessage> {
+        messages
+            .iter()
+            .map(|(role, content)| OllamaMessage {
+                role: role.to_string(),
+                content: content.to_string(),
+                ..Default::default()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn render_legacy_template_turn_by_turn() {
+        let template = GoTemplate::parse(
+            "{{ if .System }}[S]{{ .System }}{{ end }}[U]{{ .Prompt }}[A]{{ .Response }}[E]",
+        )
+        .unwrap();
+        let chats = messages(&[
+            ("system", "s"),
+            ("user", "a"),
+            ("assistant", "b"),
+            ("user", "c"),
+        ]);
+        assert_eq!(
+            render_ollama(&template, chats.clone(), None, true).unwrap(),
+            "[S]s[U]a[A]b[E][U]c[A]"
+        );
+        assert_eq!(
+            render_ollama(&template, chats[..3].to_vec(), None, false).unwrap(),
+            "[S]s[U]a[A]b[E]"
+        );
+    }
+
+    #[test]
+    fn render_messages_template_with_tools() {
+        let template = GoTemplate::parse(
+            r#"{{- if .Tools }}[T]{{ range .Tools }}{{ .Function.Name }};{{ end }}{{ end }}
+{{- range .Messages }}[{{ .Role }}]{{ .Content }}
+{{- range .ToolCalls }}<call>{{ .Function.Name }} {{ .Function.Arguments }}</call>{{ end }}
+{{- end }}"#,
+        )
+        .unwrap();
+        let mut chats = messages(&[("user", "a"), ("assistant", ""), ("tool", "b")]);
+        chats[1].tool_calls = vec![ToolCall {
+            id: Some("call_1".to_owned()),
+            name: "echo".to_owned(),
+            arguments: r#"{"text":"b"}"#.to_owned(),
+        }];
+        let tools = vec![json!({ "type": "function", "function": { "name": "echo" } })];
+        assert_eq!(
+            render_ollama(&template, chats, Some(tools), true).unwrap(),
+            r#"[T]echo;[user]a[assistant]<call>echo {"text":"b"}</call>[tool]b"#
+        );
+    }
+}
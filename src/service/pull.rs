@@ -0,0 +1,314 @@
+//! 从注册表中拉取模型，命令行和 HTTP 服务共用同一个流程，只是展示进度的方式不同
+
+use crate::{
+    config::{HttpClient, RegistryAuth},
+    db,
+    db::CompletedStatus,
+    error::Whatever,
+    service,
+    utils::{
+        blob::BlobStore,
+        file_lock::FileLock,
+        progress::{ProgressDisplay, ProgressUnit},
+        reference::ModelReference,
+    },
+};
+use http_extra::{
+    download, download::DownloadParam, progress::Progress, registry::RegistryClient, retry,
+    sha256::StreamingDigest,
+};
+use reqwest::header::AUTHORIZATION;
+use rusqlite::Connection;
+use serde::Deserialize;
+use snafu::prelude::*;
+use std::{
+    fs::{TryLockError, remove_file},
+    path::Path,
+};
+use tracing::{debug, info};
+use url::Url;
+
+/// 拉取过程中的进度
+pub(crate) trait PullObserver {
+    /// 进入了新的阶段，例如 pulling manifest、writing manifest
+    fn status(&self, status: &str);
+    /// 开始下载一个文件，返回的进度交给下载任务
+    fn download(&self, digest: &str, media: &str, size: u64) -> Progress;
+    /// 文件已经存在并且校验通过，不需要下载
+    fn skip(&self, digest: &str, media: &str, size: u64);
+}
+
+/// 命令行中每一层使用一个进度条
+impl PullObserver for ProgressDisplay {
+    fn status(&self, status: &str) {
+        debug!("{status}");
+    }
+
+    fn download(&self, digest: &str, media: &str, _size: u64) -> Progress {
+        self.add(label(digest, media), ProgressUnit::Bytes)
+    }
+
+    fn skip(&self, digest: &str, media: &str, _size: u64) {
+        ProgressDisplay::skip(self, label(digest, media), "already exists");
+    }
+}
+
+// 进度条的标签，例如 model 6a0746a1ec1a
+fn label(digest: &str, media: &str) -> String {
+    format!("{media} {}", short_digest(digest))
+}
+
+/// 摘要的前 12 位，和 ollama 中展示的一致
+pub(crate) fn short_digest(digest: &str) -> &str {
+    let hex = digest.trim_start_matches("sha256:");
+    hex.get(..12).unwrap_or(hex)
+}
+
+/// 拉取模型需要的配置
+pub(crate) struct Puller<'a> {
+    pub(crate) conn: &'a Connection,
+    pub(crate) data_path: &'a Path,
+    pub(crate) remote: &'a Url,
+    pub(crate) auth: &'a RegistryAuth,
+    pub(crate) client_config: &'a HttpClient,
+    // 引用中没有规格时使用的规格
+    pub(crate) default_category: &'a str,
+}
+
+impl Puller<'_> {
+    /// 拉取模型，返回本地注册表中的模型名字和规格
+    pub(crate) async fn pull(
+        &self,
+        reference: ModelReference,
+        observer: &dyn PullObserver,
+    ) -> Result<(String, String), Whatever> {
+        let conn = self.conn;
+        let reference = reference.relative_to(self.remote);
+        let (model_name, category) = if reference.is_library() && reference.digest.is_none() {
+            // 官方的模型需要已经保存在本地注册表中
+            service::model::local_name_and_category(conn, &reference)?
+        } else {
+            // 其它命名空间、其它注册表或者按照摘要拉取的模型，先保存到本地注册表中
+            let category = reference
+                .category()
+                .unwrap_or_else(|| self.default_category.to_owned());
+            let model_name = format!("{}:{category}", reference.local_title());
+            let href = format!("/{}:{category}", reference.path());
+            db::model::save_referenced_model(
+                conn,
+                &model_name,
+                href,
+                &reference.namespace,
+                reference.registry.as_deref(),
+            )?;
+            (model_name, category)
+        };
+        let reference = reference.or_tag(Some(category.clone()));
+        let repository = reference.path();
+        let registry_remote = reference.remote(self.remote)?;
+        // 模型文件保存在 blobs 目录中，不同规格的模型共享相同摘要的文件
+        let blob_store = BlobStore::new(self.data_path)?;
+        // 获取锁，只允许一个进程进行下载，避免多进程下载导致文件写入失败
+        let mut file_lock = FileLock::new(blob_store.dir());
+        match file_lock.try_lock() {
+            Ok(_) => (),
+            Err(TryLockError::WouldBlock) => whatever!(
+                "The file is locked, multiple processes are not allowed to operate the model file simultaneously, please wait"
+            ),
+            Err(TryLockError::Error(e)) => whatever!("Couldn't lock the file, {e}"),
+        }
+        // 将旧版本按照规格保存的模型文件迁移到 blobs 目录中
+        blob_store.migrate(conn, self.data_path.join("model"))?;
+        let client = self
+            .client_config
+            .build_client()
+            .with_whatever_context(|_| "Couldn't build the reqwest client")?;
+        // 注册表要求认证时，使用配置中的凭据获取令牌，没有凭据时匿名获取
+        let credential = if &registry_remote == self.remote {
+            self.auth.build_credential(self.remote)
+        } else {
+            // 其它注册表只从凭据文件中读取凭据，避免将配置中的密码发送给其它注册表
+            RegistryAuth {
+                credential_file: self.auth.credential_file.clone(),
+                ..Default::default()
+            }
+            .build_credential(&registry_remote)
+        }
+        .with_whatever_context(|_| "Couldn't get the credential of the registry")?;
+        let registry = RegistryClient::new(client).with_credential(credential);
+        observer.status("pulling manifest");
+        let manifest_reference = reference
+            .manifest_reference()
+            .whatever_context("The reference of the manifest is none")?;
+        let manifest_url = format!("/v2/{repository}/manifests/{manifest_reference}");
+        let manifest_url = registry_remote
+            .join(manifest_url.as_str())
+            .with_whatever_context(|_| format!("Invalid manifest url({manifest_url})"))?;
+        let response = registry
+            .get(manifest_url, &RegistryClient::pull_scope(&repository))
+            .await
+            .with_whatever_context(|_| format!("Couldn't fetch the manifest of {reference}"))?;
+        ensure_whatever!(
+            response.status().is_success(),
+            "Couldn't fetch the manifest of {reference}, the status is {}",
+            response.status()
+        );
+        let response_bytes = response
+            .bytes()
+            .await
+            .with_whatever_context(|_| format!("Couldn't read the manifest of {reference}"))?;
+        // 按照摘要拉取时，清单的摘要需要和引用中的摘要一致
+        let mut manifest_digest = StreamingDigest::new();
+        manifest_digest.update(&response_bytes);
+        let manifest_digest = manifest_digest.finalize();
+        if let Some(digest) = &reference.digest {
+            ensure_whatever!(
+                digest == &manifest_digest,
+                "The digest of the manifest is {manifest_digest}, but {digest} is expected"
+            );
+        }
+        let manifest: Manifest = serde_json::from_slice(&response_bytes)
+            .with_whatever_context(|_| format!("Couldn't parse the manifest of {reference}"))?;
+        // 判断当前的 Manifest 的 schema_version 和 media_type 是不是和注册表中的一致，如果不一致，那么需要退出，并且重新适配
+        ensure_whatever!(
+            db::config::check_manifest_schema_version_and_media_type(
+                conn,
+                manifest.schema_version,
+                &manifest.media_type,
+            )?,
+            "The manifest schema_version or media_type does not match. Please re-adapt the remote registry."
+        );
+        let layers = LayerFetcher {
+            conn,
+            client_config: self.client_config,
+            remote: &registry_remote,
+            registry: &registry,
+            repository: &repository,
+            model_name: &model_name,
+            blob_store: &blob_store,
+            observer,
+        };
+        for layer in manifest.layers {
+            layers.fetch(layer).await?;
+        }
+        layers.fetch(manifest.config).await?;
+        observer.status("writing manifest");
+        db::model::set_model_manifest_digest(conn, &model_name, &manifest_digest)?;
+        // 保存一个拉取状态，完成拉取，用来标识全部的资源都已经拉取完成
+        db::model::set_model_pull_status(conn, &model_name, CompletedStatus::Completed)?;
+        drop(file_lock);
+        info!("Pulled {model_name}");
+        Ok((model_name, category))
+    }
+}
+
+// 下载清单中的一个文件需要的信息
+struct LayerFetcher<'a> {
+    conn: &'a Connection,
+    client_config: &'a HttpClient,
+    remote: &'a Url,
+    registry: &'a RegistryClient,
+    repository: &'a str,
+    model_name: &'a str,
+    blob_store: &'a BlobStore,
+    observer: &'a dyn PullObserver,
+}
+
+impl LayerFetcher<'_> {
+    async fn fetch(&self, layer: Layer) -> Result<(), Whatever> {
+        let Layer {
+            media_type,
+            digest,
+            size,
+        } = layer;
+        let conn = self.conn;
+        let Some(media) = db::config::get_media_type(conn, &media_type)?.map(|(media, _)| media)
+        else {
+            return Ok(());
+        };
+        let filename = BlobStore::file_name(&digest);
+        let filepath = self.blob_store.path(&digest);
+        // 相同摘要的文件已经存在并且校验通过，不需要重新下载
+        if self.blob_store.verified(conn, &digest)? {
+            debug!("{digest} already exists, skip downloading");
+            self.observer.skip(&digest, &media, size as u64);
+        } else {
+            // 文件内容不完整或者已经损坏，需要删除之后重新下载，空文件是下载中断时留下的占位文件，需要保留用来断点续传
+            if filepath.metadata().is_ok_and(|metadata| metadata.len() > 0) {
+                remove_file(&filepath)
+                    .with_whatever_context(|_| "Couldn't remove the corrupted file")?;
+            }
+            // 获取重试策略
+            let backoff = self.client_config.build_back_off();
+            let blob_url = format!("/v2/{}/blobs/{digest}", self.repository);
+            let blob_url = self
+                .remote
+                .join(blob_url.as_str())
+                .with_whatever_context(|_| format!("Invalid blob url({blob_url})"))?;
+            let scope = RegistryClient::pull_scope(self.repository);
+            let progress = self.observer.download(&digest, &media, size as u64);
+            let param = DownloadParam::try_new(blob_url, filename, self.blob_store.dir())
+                .with_whatever_context(|_| "Couldn't build a download param.")?
+                .with_chunk_timeout(self.client_config.build_chunk_timeout())
+                .with_concurrency(self.client_config.build_concurrency())
+                .with_segment_size(self.client_config.build_segment_size())
+                .with_progress(progress.clone());
+            let registry = self.registry;
+            let summary = retry::spawn_notify(
+                backoff,
+                async || {
+                    // 每次重试都重新获取认证信息，令牌过期时会获取新的令牌
+                    let param = match registry.authorization(&scope).await? {
+                        Some(authorization) => {
+                            param.clone().with_header(AUTHORIZATION, authorization)
+                        }
+                        None => param.clone(),
+                    };
+                    download::spawn(registry.client().clone(), param).await
+                },
+                |attempt, delay, error| {
+                    // 注册表拒绝了缓存的令牌，重试时重新获取
+                    if let http_extra::Error::Unauthorized { .. } = error {
+                        registry.invalidate(&scope);
+                    }
+                    progress.retry(attempt, delay, error)
+                },
+            )
+            .await
+            .with_whatever_context(|_| format!("Couldn't download {digest}"))?;
+            debug!("{summary:?}");
+            self.observer.status("verifying sha256 digest");
+            // 下载时已经计算了摘要，不需要再读取一遍文件
+            match summary.digest() {
+                Some(downloaded) if downloaded == digest => {
+                    self.blob_store.save_verified(conn, &digest)?
+                }
+                Some(downloaded) => {
+                    whatever!("{digest}: checksum failed, the digest is {downloaded}")
+                }
+                None => whatever!("{digest}: download failed, {:?}", summary.status()),
+            }
+        }
+        // 将这个目录保存在注册表中
+        db::model::save_model_file_path(conn, self.model_name, &filepath, size, &media)?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct Layer {
+    #[serde(rename(deserialize = "mediaType"))]
+    media_type: String,
+    digest: String,
+    size: usize,
+}
+
+#[derive(Debug, Deserialize)]
+struct Manifest {
+    #[serde(rename(deserialize = "schemaVersion"))]
+    schema_version: u32,
+    #[serde(rename(deserialize = "mediaType"))]
+    media_type: String,
+    config: Layer,
+    layers: Vec<Layer>,
+}
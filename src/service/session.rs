@@ -0,0 +1,116 @@
+//! 保存和恢复对话的状态，恢复之后不需要重新处理之前的对话
+//!
+//! 状态文件中保存了序列 0 的 KV cache 和 token，对话中的消息保存在数据库中。KV cache 只对生成它的模型有效，
+//! 所以记录模型文件的摘要，摘要不同的模型不能恢复
+
+use crate::{db, db::session::Session, error::Whatever};
+use llama_cpp::{context::Context, model::Message, prefix_cache::PrefixCache};
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+use snafu::prelude::*;
+use std::{fs::create_dir_all, path::PathBuf};
+
+// 对话状态使用的序列
+const SEQ_ID: i32 = 0;
+
+#[derive(Serialize, Deserialize)]
+struct SavedMessage {
+    role: String,
+    content: String,
+}
+
+/// 当前模型的对话状态
+pub(crate) struct SessionStore<'a> {
+    pub(crate) conn: &'a Connection,
+    // 保存状态文件的目录
+    pub(crate) dir: PathBuf,
+    pub(crate) model_name: &'a str,
+    pub(crate) model_digest: &'a str,
+}
+
+impl SessionStore<'_> {
+    /// 保存对话的状态，名字相同时覆盖之前保存的状态
+    pub(crate) fn save(
+        &self,
+        name: &str,
+        context: &Context,
+        prefix_cache: &PrefixCache,
+        messages: &[Message],
+    ) -> Result<(), Whatever> {
+        check_name(name)?;
+        create_dir_all(&self.dir).with_whatever_context(|_| {
+            format!("Couldn't create dir in the path({})", self.dir.display())
+        })?;
+        let path = self.dir.join(format!("{name}.session"));
+        let tokens = prefix_cache.tokens(SEQ_ID);
+        context
+            .save_seq_state_file(&path, SEQ_ID, tokens)
+            .with_whatever_context(|_| format!("Failed to save the session({name})"))?;
+        let messages = messages
+            .iter()
+            .map(|message| SavedMessage {
+                role: message.role.to_string_lossy().into_owned(),
+                content: message.content.to_string_lossy().into_owned(),
+            })
+            .collect::<Vec<_>>();
+        let messages = serde_json::to_string(&messages)
+            .with_whatever_context(|_| "Failed to serialize the messages")?;
+        let session = Session {
+            name: name.to_owned(),
+            model_name: self.model_name.to_owned(),
+            model_digest: self.model_digest.to_owned(),
+            path: path.display().to_string(),
+            n_tokens: tokens.len(),
+            messages,
+        };
+        db::session::save_session(self.conn, &session)
+    }
+
+    /// 恢复对话的状态，返回对话中的消息，序列 0 中原有的 KV cache 会被替换
+    pub(crate) fn load(
+        &self,
+        name: &str,
+        context: &mut Context,
+        prefix_cache: &mut PrefixCache,
+    ) -> Result<Vec<Message>, Whatever> {
+        let session = db::session::query_session(self.conn, name)?
+            .with_whatever_context(|| format!("No session named {name}"))?;
+        ensure_whatever!(
+            session.model_digest == self.model_digest,
+            "The session {name} was saved with {}, it can't be loaded into {}",
+            session.model_name,
+            self.model_name
+        );
+        let messages = serde_json::from_str::<Vec<SavedMessage>>(&session.messages)
+            .with_whatever_context(|_| format!("Invalid messages in the session({name})"))?
+            .into_iter()
+            .map(|SavedMessage { role, content }| Message::try_new(role, content))
+            .collect::<Result<Vec<_>, _>>()
+            .with_whatever_context(|_| format!("Invalid messages in the session({name})"))?;
+        // 恢复失败时 KV cache 的内容不确定，需要清空
+        prefix_cache.clear(None);
+        let n_ctx = context.n_ctx() as usize;
+        let tokens = match context.load_seq_state_file(&session.path, SEQ_ID, n_ctx) {
+            Ok(tokens) => tokens,
+            Err(e) => {
+                let _ = context.clear_kv_cache_seq(Some(SEQ_ID as u32), None, None);
+                whatever!("Failed to load the session({name}), {e}");
+            }
+        };
+        prefix_cache.extend(SEQ_ID, &tokens);
+        Ok(messages)
+    }
+}
+
+// 会话的名字会用作文件名，只允许字母、数字、-、_ 和 .
+fn check_name(name: &str) -> Result<(), Whatever> {
+    ensure_whatever!(
+        !name.is_empty()
+            && !name.starts_with('.')
+            && name
+                .chars()
+                .all(|c| c.is_alphanumeric() || matches!(c, '-' | '_' | '.')),
+        "Invalid session name({name}), only letters, digits, '-', '_' and '.' are allowed"
+    );
+    Ok(())
+}
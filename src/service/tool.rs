@@ -0,0 +1,78 @@
+//! simple-run 中可以调用的内置工具，工具不会执行命令，也不会读写文件
+
+use chrono::Local;
+use llama_cpp::model::{ToolCall, ToolCallFormat};
+use serde_json::{Value, json};
+use uuid::Uuid;
+
+// Mistral 要求工具调用的 id 是 9 个字母或数字
+const MISTRAL_ID_LEN: usize = 9;
+
+/// 内置工具的定义，和 OpenAI 的 tools 参数格式一致
+pub(crate) fn builtin_tools() -> Vec<Value> {
+    vec![
+        json!({
+            "type": "function",
+            "function": {
+                "name": "echo",
+                "description": "Return the given text unchanged",
+                "parameters": {
+                    "type": "object",
+                    "properties": {
+                        "text": { "type": "string", "description": "The text to return" },
+                    },
+                    "required": ["text"],
+                },
+            },
+        }),
+        json!({
+            "type": "function",
+            "function": {
+                "name": "current_time",
+                "description": "Get the current local date and time",
+                "parameters": { "type": "object", "properties": {} },
+            },
+        }),
+    ]
+}
+
+/// 调用内置工具，返回给模型的结果，调用失败时返回错误信息
+pub(crate) fn call_builtin_tool(call: &ToolCall) -> String {
+    let arguments = serde_json::from_str::<Value>(&call.arguments).unwrap_or(Value::Null);
+    match call.name.as_str() {
+        "echo" => match arguments["text"].as_str() {
+            Some(text) => text.to_owned(),
+            None => "Error: the argument text is required".to_owned(),
+        },
+        "current_time" => Local::now().to_rfc3339(),
+        name => format!("Error: unknown tool {name}"),
+    }
+}
+
+/// 生成工具调用的 id，模型的输出中一般没有 id
+pub(crate) fn new_tool_call_id(format: ToolCallFormat) -> String {
+    let id = Uuid::now_v7().simple().to_string();
+    match format {
+        // v7 的前面是时间戳，取后面随机的部分
+        ToolCallFormat::Mistral => id[id.len() - MISTRAL_ID_LEN..].to_owned(),
+        _ => format!("call_{id}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_call_builtin_tool() {
+        let call = |name: &str, arguments: &str| ToolCall {
+            id: None,
+            name: name.to_owned(),
+            arguments: arguments.to_owned(),
+        };
+        assert_eq!(call_builtin_tool(&call("echo", r#"{"text":"hi"}"#)), "hi");
+        assert!(call_builtin_tool(&call("echo", "{}")).starts_with("Error"));
+        assert!(call_builtin_tool(&call("shell", "{}")).starts_with("Error"));
+        assert_eq!(new_tool_call_id(ToolCallFormat::Mistral).len(), 9);
+    }
+}
@@ -0,0 +1,226 @@
+use crate::{db, db::CompletedStatus, error::Whatever, utils::file_lock::FileLock};
+use http_extra::sha256::checksum;
+use rusqlite::Connection;
+use snafu::prelude::*;
+use std::{
+    fs::{create_dir_all, read_dir, remove_dir, remove_file, rename},
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+use tracing::{info, warn};
+
+/// 内容寻址的 blob 存储，文件名为 `sha256-<digest>`，不同规格的模型引用同一个摘要时共享同一个文件
+pub struct BlobStore {
+    dir: PathBuf,
+}
+
+impl BlobStore {
+    pub fn new(data_path: impl AsRef<Path>) -> Result<Self, Whatever> {
+        let dir = data_path.as_ref().join("blobs");
+        if !dir.exists() {
+            create_dir_all(&dir).with_whatever_context(|_| {
+                format!("Couldn't create the blobs directory({})", dir.display())
+            })?;
+        }
+        Ok(Self { dir })
+    }
+
+    pub fn dir(&self) -> &Path {
+        self.dir.as_path()
+    }
+
+    /// 摘要对应的文件名，`sha256:<hex>` 转换为 `sha256-<hex>`
+    pub fn file_name(digest: impl AsRef<str>) -> String {
+        digest.as_ref().replace(":", "-")
+    }
+
+    /// 摘要对应的文件路径
+    pub fn path(&self, digest: impl AsRef<str>) -> PathBuf {
+        self.dir.join(Self::file_name(digest))
+    }
+
+    /// 从文件名 `sha256-<hex>` 中获取摘要 `sha256:<hex>`
+    pub fn digest_of(path: impl AsRef<Path>) -> Option<String> {
+        let file_name = path.as_ref().file_name()?.to_str()?;
+        let hex = file_name.strip_prefix("sha256-")?;
+        (hex.len() == 64 && hex.chars().all(|c| c.is_ascii_hexdigit()))
+            .then(|| format!("sha256:{hex}"))
+    }
+
+    /// 摘要对应的文件存在，并且文件的摘要校验通过
+    ///
+    /// 文件的大小和修改时间与上一次校验时相同，直接使用上一次校验的结果，不会重新计算摘要
+    pub fn verified(&self, conn: &Connection, digest: impl AsRef<str>) -> Result<bool, Whatever> {
+        let digest = digest.as_ref();
+        let path = self.path(digest);
+        let Some((size, modified_at)) = file_stat(&path) else {
+            return Ok(false);
+        };
+        if let Some(blob) = db::blob::query_verified_blob(conn, digest)?
+            && blob.path == path.display().to_string()
+            && blob.size == size
+            && blob.modified_at == modified_at
+        {
+            return Ok(true);
+        }
+        self.verify(conn, digest)
+    }
+
+    /// 重新计算文件的摘要，并且更新校验记录
+    pub fn verify(&self, conn: &Connection, digest: impl AsRef<str>) -> Result<bool, Whatever> {
+        let digest = digest.as_ref();
+        let path = self.path(digest);
+        let verified = path.is_file()
+            && checksum(&path, digest.replace("sha256:", "")).is_ok_and(|checksum| checksum);
+        if verified {
+            self.save_verified(conn, digest)?;
+        } else {
+            db::blob::delete_verified_blob(conn, digest)?;
+        }
+        Ok(verified)
+    }
+
+    /// 记录校验通过的文件，下载时已经计算过摘要的文件直接记录，不需要再读取一遍
+    pub fn save_verified(
+        &self,
+        conn: &Connection,
+        digest: impl AsRef<str>,
+    ) -> Result<(), Whatever> {
+        let digest = digest.as_ref();
+        let path = self.path(digest);
+        let (size, modified_at) = file_stat(&path).with_whatever_context(|| {
+            format!("Couldn't read the metadata of {}", path.display())
+        })?;
+        db::blob::save_verified_blob(conn, digest, &path, size, modified_at)
+    }
+
+    /// 将按照规格保存在 `model/<name:category>` 目录中的文件迁移到 blobs 目录，只会执行一次
+    ///
+    /// 调用之前需要先获取 blobs 目录的文件锁
+    pub fn migrate(&self, conn: &Connection, model_dir: impl AsRef<Path>) -> Result<(), Whatever> {
+        if db::config::check_blob_store_migrated(conn)? {
+            return Ok(());
+        }
+        let model_dir = model_dir.as_ref();
+        let mut completed = true;
+        if model_dir.exists() {
+            let entries = read_dir(model_dir).with_whatever_context(|_| {
+                format!("Couldn't read the model directory({})", model_dir.display())
+            })?;
+            for entry in entries {
+                let dir = entry
+                    .with_whatever_context(|_| "Couldn't read the directory entry")?
+                    .path();
+                if !dir.is_dir() {
+                    continue;
+                }
+                // 旧版本的进程还在下载，等下一次再迁移
+                if FileLock::is_held(&dir).unwrap_or(true) {
+                    warn!("{} is in use, skip migrating it", dir.display());
+                    completed = false;
+                    continue;
+                }
+                self.migrate_dir(conn, &dir)?;
+            }
+        }
+        if completed {
+            db::config::completed_blob_store_migrated(conn, CompletedStatus::Completed)?;
+        }
+        Ok(())
+    }
+
+    fn migrate_dir(&self, conn: &Connection, dir: &Path) -> Result<(), Whatever> {
+        let entries = read_dir(dir).with_whatever_context(|_| {
+            format!("Couldn't read the model directory({})", dir.display())
+        })?;
+        for entry in entries {
+            let path = entry
+                .with_whatever_context(|_| "Couldn't read the directory entry")?
+                .path();
+            // 旧的文件名为 <media>-<digest>.<ext>，残留的 .part 和 .lock 文件交给 prune 清理
+            let Some(digest) = legacy_digest(&path) else {
+                continue;
+            };
+            // 空文件是下载中断时留下的占位文件，不需要迁移
+            if path.metadata().is_ok_and(|metadata| metadata.len() == 0) {
+                continue;
+            }
+            let digest = format!("sha256:{digest}");
+            let blob = self.path(&digest);
+            if self.verified(conn, &digest)? {
+                // blobs 中已经有相同的文件，删除重复的文件
+                remove_file(&path).with_whatever_context(|_| {
+                    format!("Couldn't remove the duplicate file({})", path.display())
+                })?;
+            } else {
+                rename(&path, &blob).with_whatever_context(|_| {
+                    format!(
+                        "Couldn't move the file({}) to {}",
+                        path.display(),
+                        blob.display()
+                    )
+                })?;
+            }
+            db::model::replace_model_file_path(conn, &path, &blob)?;
+            info!("Migrated {} to {}", path.display(), blob.display());
+        }
+        // 目录为空时一并删除
+        if read_dir(dir).is_ok_and(|mut entries| entries.next().is_none()) {
+            let _ = remove_dir(dir);
+        }
+        Ok(())
+    }
+}
+
+// 文件的大小和修改时间，修改时间的单位为纳秒
+fn file_stat(path: &Path) -> Option<(u64, i64)> {
+    let metadata = path.metadata().ok().filter(|metadata| metadata.is_file())?;
+    let modified_at = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+    Some((metadata.len(), modified_at.as_nanos() as i64))
+}
+
+// 从旧的文件名 <media>-<digest>.<ext> 中获取摘要
+fn legacy_digest(path: &Path) -> Option<&str> {
+    if !path.is_file() {
+        return None;
+    }
+    let stem = path.file_stem()?.to_str()?;
+    let extension = path.extension()?.to_str()?;
+    if extension == "part" {
+        return None;
+    }
+    let (_media, digest) = stem.rsplit_once('-')?;
+    (digest.len() == 64 && digest.chars().all(|c| c.is_ascii_hexdigit())).then_some(digest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_legacy_digest() {
+        let dir = tempfile::tempdir().unwrap();
+        let digest = "dffd6021bb2bd5b0af676290809ec3a53191dd81c7f70a4b28688a362182986f";
+        for name in [
+            format!("model-{digest}.gguf"),
+            format!("model-{digest}.gguf.part"),
+            "model-1234.gguf".to_owned(),
+        ] {
+            std::fs::write(dir.path().join(name), b"").unwrap();
+        }
+        let model = dir.path().join(format!("model-{digest}.gguf"));
+        assert_eq!(legacy_digest(&model), Some(digest));
+        let part = dir.path().join(format!("model-{digest}.gguf.part"));
+        assert_eq!(legacy_digest(&part), None);
+        assert_eq!(legacy_digest(&dir.path().join("model-1234.gguf")), None);
+        assert_eq!(
+            BlobStore::file_name(format!("sha256:{digest}")),
+            format!("sha256-{digest}")
+        );
+        assert_eq!(
+            BlobStore::digest_of(dir.path().join(format!("sha256-{digest}"))),
+            Some(format!("sha256:{digest}"))
+        );
+        assert_eq!(BlobStore::digest_of(&model), None);
+    }
+}
@@ -0,0 +1,85 @@
+use std::{
+    fs::{File, OpenOptions, TryLockError, remove_file},
+    io,
+    path::{Path, PathBuf},
+};
+
+const LOCK_FILE_NAME: &str = ".lock";
+
+/// 模型目录中的文件锁，同一时间只允许一个进程修改目录中的文件
+pub struct FileLock {
+    path: PathBuf,
+    lock: File,
+    locked: bool,
+}
+
+impl FileLock {
+    pub fn new(path: impl AsRef<Path>) -> Self {
+        let path = path.as_ref().join(LOCK_FILE_NAME);
+        let lock = OpenOptions::new()
+            .truncate(true)
+            .create(true)
+            .write(true)
+            .open(&path)
+            .expect("Couldn't open the lock file");
+        Self {
+            path,
+            lock,
+            locked: false,
+        }
+    }
+
+    pub fn try_lock(&mut self) -> Result<(), TryLockError> {
+        self.lock.try_lock()?;
+        self.locked = true;
+        Ok(())
+    }
+
+    /// 获取锁，锁被其他进程持有时直接退出
+    pub fn lock_or_panic(&mut self) {
+        match self.try_lock() {
+            Ok(_) => (),
+            Err(TryLockError::WouldBlock) => {
+                panic!(
+                    "The file is locked, multiple processes are not allowed to operate the model file simultaneously, please wait"
+                )
+            }
+            Err(TryLockError::Error(e)) => {
+                panic!("Couldn't lock the file, {e}");
+            }
+        }
+    }
+
+    /// 检查目录中的锁是否被其他进程持有，不会创建和删除锁文件
+    pub fn is_held(path: impl AsRef<Path>) -> io::Result<bool> {
+        let path = path.as_ref().join(LOCK_FILE_NAME);
+        if !path.exists() {
+            return Ok(false);
+        }
+        let lock = File::open(path)?;
+        match lock.try_lock() {
+            Ok(_) => {
+                lock.unlock()?;
+                Ok(false)
+            }
+            Err(TryLockError::WouldBlock) => Ok(true),
+            Err(TryLockError::Error(e)) => Err(e),
+        }
+    }
+
+    /// 锁文件的名字
+    pub fn file_name() -> &'static str {
+        LOCK_FILE_NAME
+    }
+}
+
+impl Drop for FileLock {
+    fn drop(&mut self) {
+        // 没有拿到锁，锁文件属于其他进程，不能删除
+        if !self.locked {
+            return;
+        }
+        self.lock.unlock().unwrap();
+        remove_file(&self.path).unwrap();
+    }
+}
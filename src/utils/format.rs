@@ -0,0 +1,142 @@
+use std::time::Duration;
+
+const UNITS: [&str; 5] = ["B", "KB", "MB", "GB", "TB"];
+
+/// 将字节数转换为便于阅读的大小，例如 4.7 GB
+pub fn human_readable_size(bytes: u64) -> String {
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{bytes} {}", UNITS[unit])
+    } else {
+        format!("{size:.1} {}", UNITS[unit])
+    }
+}
+
+/// 将时间转换为便于阅读的格式，例如 1h02m、3m12s、45s
+pub fn human_readable_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    let (hours, minutes, secs) = (secs / 3600, secs % 3600 / 60, secs % 60);
+    if hours > 0 {
+        format!("{hours}h{minutes:02}m")
+    } else if minutes > 0 {
+        format!("{minutes}m{secs:02}s")
+    } else {
+        format!("{secs}s")
+    }
+}
+
+/// 解析 ollama 中上下文大小的写法，例如 2048、32K、1M
+pub fn parse_context_size(context: impl AsRef<str>) -> Option<u64> {
+    let context = context.as_ref().trim();
+    let (number, unit) = match context.char_indices().last()? {
+        (index, 'k' | 'K') => (&context[..index], 1024),
+        (index, 'm' | 'M') => (&context[..index], 1024 * 1024),
+        _ => (context, 1),
+    };
+    number
+        .trim()
+        .parse::<u64>()
+        .ok()
+        .map(|number| number * unit)
+}
+
+/// 解析便于阅读的大小，例如 512M、16G、16GB、1.5GiB，没有单位时为字节
+pub fn parse_human_readable_size(size: impl AsRef<str>) -> Option<u64> {
+    let size = size.as_ref().trim();
+    let index = size
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(size.len());
+    let (number, unit) = size.split_at(index);
+    let number = number.parse::<f64>().ok()?;
+    let unit = unit.trim().to_ascii_uppercase();
+    let exponent = match unit.trim_end_matches('B').trim_end_matches('I') {
+        "" => 0,
+        "K" => 1,
+        "M" => 2,
+        "G" => 3,
+        "T" => 4,
+        _ => return None,
+    };
+    Some((number * 1024_f64.powi(exponent)) as u64)
+}
+
+/// 将表头和每一行对齐成表格，每一列的宽度取表头和内容中最长的那个，列之间用两个空格分隔
+pub fn format_table<const N: usize>(header: [&str; N], rows: &[[String; N]]) -> String {
+    let mut widths = header.map(str::len);
+    for row in rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    let format_row = |cells: [&str; N]| {
+        let line = cells
+            .iter()
+            .zip(widths)
+            .map(|(cell, width)| format!("{cell:<width$}"))
+            .collect::<Vec<_>>()
+            .join("  ");
+        format!("{}\n", line.trim_end())
+    };
+    let mut table = format_row(header);
+    for row in rows {
+        table += &format_row(row.each_ref().map(String::as_str));
+    }
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_human_readable_size() {
+        assert_eq!(human_readable_size(0), "0 B");
+        assert_eq!(human_readable_size(1023), "1023 B");
+        assert_eq!(human_readable_size(1536), "1.5 KB");
+        assert_eq!(human_readable_size(5 * 1024 * 1024 * 1024), "5.0 GB");
+    }
+
+    #[test]
+    fn test_human_readable_duration() {
+        assert_eq!(human_readable_duration(Duration::from_millis(900)), "0s");
+        assert_eq!(human_readable_duration(Duration::from_secs(45)), "45s");
+        assert_eq!(human_readable_duration(Duration::from_secs(192)), "3m12s");
+        assert_eq!(human_readable_duration(Duration::from_secs(3720)), "1h02m");
+    }
+
+    #[test]
+    fn test_format_table() {
+        let rows = [
+            ["qwen3:8b".to_owned(), "5.2 GB".to_owned(), String::new()],
+            ["bge-m3".to_owned(), "1.2 GB".to_owned(), "中文".to_owned()],
+        ];
+        assert_eq!(
+            format_table(["NAME", "SIZE", "NOTE"], &rows),
+            "NAME      SIZE    NOTE\nqwen3:8b  5.2 GB\nbge-m3    1.2 GB  中文\n"
+        );
+    }
+
+    #[test]
+    fn test_parse_context_size() {
+        assert_eq!(parse_context_size("2048"), Some(2048));
+        assert_eq!(parse_context_size("32K"), Some(32 * 1024));
+        assert_eq!(parse_context_size("1M"), Some(1024 * 1024));
+        assert_eq!(parse_context_size(""), None);
+        assert_eq!(parse_context_size("-"), None);
+    }
+
+    #[test]
+    fn test_parse_human_readable_size() {
+        assert_eq!(parse_human_readable_size("1024"), Some(1024));
+        assert_eq!(parse_human_readable_size("512M"), Some(512 * 1024 * 1024));
+        assert_eq!(parse_human_readable_size("16GB"), Some(16 << 30));
+        assert_eq!(parse_human_readable_size("1.5 GiB"), Some(3 << 29));
+        assert_eq!(parse_human_readable_size("GB"), None);
+        assert_eq!(parse_human_readable_size("16X"), None);
+    }
+}
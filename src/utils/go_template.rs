@@ -0,0 +1,1034 @@
+//! Go text/template 的一个子集，用来渲染 ollama 模型中的 TEMPLATE
+//!
+//! 支持 ollama 模板中常用的语法：`{{ .Field }}`、`{{-`/`-}}` 去除空白、注释、if/else if/else、range/else、
+//! with/else、break/continue、变量的声明和赋值、管道、括号，以及 eq、ne、lt、le、gt、ge、and、or、not、len、
+//! index、slice、json、print、currentDate 函数。模板的数据使用 JSON 表示，不存在的字段视为空值
+
+use crate::error::Whatever;
+use serde_json::{Number, Value};
+use snafu::prelude::*;
+
+/// 解析之后的模板
+#[derive(Clone, Debug)]
+pub struct GoTemplate {
+    source: String,
+    nodes: Vec<Node>,
+}
+
+#[derive(Clone, Debug)]
+enum Node {
+    Text(String),
+    // 输出管道的结果，声明变量时不输出
+    Action(Pipeline),
+    If {
+        branches: Vec<(Pipeline, Vec<Node>)>,
+        otherwise: Vec<Node>,
+    },
+    Range {
+        pipeline: Pipeline,
+        body: Vec<Node>,
+        otherwise: Vec<Node>,
+    },
+    With {
+        pipeline: Pipeline,
+        body: Vec<Node>,
+        otherwise: Vec<Node>,
+    },
+    Break,
+    Continue,
+}
+
+#[derive(Clone, Debug, Default)]
+struct Pipeline {
+    // 声明或者赋值的变量，例如 $i, $m := .Messages
+    vars: Vec<String>,
+    // := 声明新的变量，= 给已有的变量赋值
+    declare: bool,
+    commands: Vec<Vec<Arg>>,
+}
+
+#[derive(Clone, Debug)]
+enum Arg {
+    Dot,
+    Field(Vec<String>),
+    // 变量的名字包含 $，后面是字段
+    Var(String, Vec<String>),
+    Const(Value),
+    Func(String),
+    // 括号中的管道，后面是字段
+    Paren(Pipeline, Vec<String>),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Ident(String),
+    Field(Vec<String>),
+    Dot,
+    Var(String, Vec<String>),
+    Const(Value),
+    LParen,
+    // 右括号之后紧跟的字段
+    RParen(Vec<String>),
+    Pipe,
+    Comma,
+    Declare,
+    Assign,
+}
+
+// 模板中的文本或者 {{ }} 中的内容
+enum Item<'a> {
+    Text(String),
+    Action(&'a str),
+}
+
+// 一段节点的结束位置
+enum Terminator {
+    End,
+    // else 之后的内容，例如 else if 的条件
+    Else(Vec<Token>),
+    Eof,
+}
+
+// range 中的 break 和 continue
+enum Flow {
+    Normal,
+    Break,
+    Continue,
+}
+
+impl GoTemplate {
+    pub fn parse(source: &str) -> Result<Self, Whatever> {
+        let items = split(source)?;
+        let mut parser = Parser { items, pos: 0 };
+        let (nodes, terminator) = parser.parse_list()?;
+        match terminator {
+            Terminator::Eof => Ok(Self {
+                source: source.to_owned(),
+                nodes,
+            }),
+            Terminator::End => whatever!("Unexpected {{{{ end }}}} in the template"),
+            Terminator::Else(_) => whatever!("Unexpected {{{{ else }}}} in the template"),
+        }
+    }
+
+    /// 使用数据渲染模板，数据也是模板中的 $
+    pub fn render(&self, data: &Value) -> Result<String, Whatever> {
+        let mut exec = Exec {
+            vars: vec![("$".to_owned(), data.clone())],
+            out: String::new(),
+        };
+        exec.nodes(&self.nodes, data)?;
+        Ok(exec.out)
+    }
+
+    /// 模板中是否使用了某个字段，例如 Messages
+    pub fn uses_field(&self, name: &str) -> bool {
+        nodes_use_field(&self.nodes, name)
+    }
+
+    /// 模板的原文
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+}
+
+// 将模板拆分成文本和 {{ }}，同时处理 {{- 和 -}} 两侧的空白以及注释
+fn split(source: &str) -> Result<Vec<Item<'_>>, Whatever> {
+    let mut items = Vec::new();
+    let mut rest = source;
+    let mut trim_next = false;
+    while let Some(start) = rest.find("{{") {
+        let mut text = &rest[..start];
+        if trim_next {
+            text = text.trim_start();
+        }
+        let after = &rest[start + 2..];
+        let trim_left = after.starts_with('-') && after[1..].starts_with(char::is_whitespace);
+        if trim_left {
+            text = text.trim_end();
+        }
+        if !text.is_empty() {
+            items.push(Item::Text(text.to_owned()));
+        }
+        let end = find_action_end(after).whatever_context("Unclosed {{ in the template")?;
+        let mut inner = &after[usize::from(trim_left)..end];
+        trim_next = inner.ends_with('-') && inner[..inner.len() - 1].ends_with(char::is_whitespace);
+        if trim_next {
+            inner = &inner[..inner.len() - 1];
+        }
+        let inner = inner.trim();
+        if !(inner.starts_with("/*") && inner.ends_with("*/")) {
+            items.push(Item::Action(inner));
+        }
+        rest = &after[end + 2..];
+    }
+    let text = if trim_next { rest.trim_start() } else { rest };
+    if !text.is_empty() {
+        items.push(Item::Text(text.to_owned()));
+    }
+    Ok(items)
+}
+
+// 找到 }} 的位置，跳过字符串中的 }}
+fn find_action_end(action: &str) -> Option<usize> {
+    let bytes = action.as_bytes();
+    let mut quote = None;
+    let mut index = 0;
+    while index < bytes.len() {
+        let byte = bytes[index];
+        match quote {
+            Some(b'"') if byte == b'\\' => index += 1,
+            Some(q) if byte == q => quote = None,
+            Some(_) => {}
+            None if byte == b'"' || byte == b'`' => quote = Some(byte),
+            None if action[index..].starts_with("}}") => return Some(index),
+            None => {}
+        }
+        index += 1;
+    }
+    None
+}
+
+fn is_ident_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+// 读取一串 .A.B 形式的字段
+fn lex_fields(chars: &[char], pos: &mut usize) -> Vec<String> {
+    let mut fields = Vec::new();
+    while *pos + 1 < chars.len() && chars[*pos] == '.' && is_ident_char(chars[*pos + 1]) {
+        *pos += 1;
+        let start = *pos;
+        while *pos < chars.len() && is_ident_char(chars[*pos]) {
+            *pos += 1;
+        }
+        fields.push(chars[start..*pos].iter().collect());
+    }
+    fields
+}
+
+fn lex(action: &str) -> Result<Vec<Token>, Whatever> {
+    let chars = action.chars().collect::<Vec<_>>();
+    let mut tokens = Vec::new();
+    let mut pos = 0;
+    while pos < chars.len() {
+        let c = chars[pos];
+        match c {
+            c if c.is_whitespace() => pos += 1,
+            '(' => {
+                tokens.push(Token::LParen);
+                pos += 1;
+            }
+            ')' => {
+                pos += 1;
+                tokens.push(Token::RParen(lex_fields(&chars, &mut pos)));
+            }
+            '|' => {
+                tokens.push(Token::Pipe);
+                pos += 1;
+            }
+            ',' => {
+                tokens.push(Token::Comma);
+                pos += 1;
+            }
+            ':' if chars.get(pos + 1) == Some(&'=') => {
+                tokens.push(Token::Declare);
+                pos += 2;
+            }
+            '=' => {
+                tokens.push(Token::Assign);
+                pos += 1;
+            }
+            '.' => {
+                let fields = lex_fields(&chars, &mut pos);
+                if fields.is_empty() {
+                    tokens.push(Token::Dot);
+                    pos += 1;
+                } else {
+                    tokens.push(Token::Field(fields));
+                }
+            }
+            '$' => {
+                let start = pos;
+                pos += 1;
+                while pos < chars.len() && is_ident_char(chars[pos]) {
+                    pos += 1;
+                }
+                let name = chars[start..pos].iter().collect();
+                tokens.push(Token::Var(name, lex_fields(&chars, &mut pos)));
+            }
+            '"' => {
+                let mut value = String::new();
+                pos += 1;
+                loop {
+                    let c = *chars
+                        .get(pos)
+                        .whatever_context("Unterminated string in the template")?;
+                    pos += 1;
+                    match c {
+                        '"' => break,
+                        '\\' => {
+                            let escaped = *chars
+                                .get(pos)
+                                .whatever_context("Unterminated string in the template")?;
+                            pos += 1;
+                            value.push(match escaped {
+                                'n' => '\n',
+                                't' => '\t',
+                                'r' => '\r',
+                                c => c,
+                            });
+                        }
+                        c => value.push(c),
+                    }
+                }
+                tokens.push(Token::Const(Value::String(value)));
+            }
+            '`' => {
+                let start = pos + 1;
+                let end = chars[start..]
+                    .iter()
+                    .position(|c| *c == '`')
+                    .whatever_context("Unterminated raw string in the template")?;
+                tokens.push(Token::Const(Value::String(
+                    chars[start..start + end].iter().collect(),
+                )));
+                pos = start + end + 1;
+            }
+            c if c.is_ascii_digit()
+                || (c == '-' && chars.get(pos + 1).is_some_and(char::is_ascii_digit)) =>
+            {
+                let start = pos;
+                pos += 1;
+                while pos < chars.len() && (chars[pos].is_ascii_digit() || chars[pos] == '.') {
+                    pos += 1;
+                }
+                let literal = chars[start..pos].iter().collect::<String>();
+                let number = match literal.parse::<i64>() {
+                    Ok(number) => Number::from(number),
+                    Err(_) => literal
+                        .parse::<f64>()
+                        .ok()
+                        .and_then(Number::from_f64)
+                        .with_whatever_context(|| format!("Invalid number({literal})"))?,
+                };
+                tokens.push(Token::Const(Value::Number(number)));
+            }
+            c if is_ident_char(c) => {
+                let start = pos;
+                while pos < chars.len() && is_ident_char(chars[pos]) {
+                    pos += 1;
+                }
+                let ident = chars[start..pos].iter().collect::<String>();
+                tokens.push(match ident.as_str() {
+                    "true" => Token::Const(Value::Bool(true)),
+                    "false" => Token::Const(Value::Bool(false)),
+                    "nil" => Token::Const(Value::Null),
+                    _ => Token::Ident(ident),
+                });
+            }
+            c => whatever!("Unexpected character({c}) in {{{{ {action} }}}}"),
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser<'a> {
+    items: Vec<Item<'a>>,
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn parse_list(&mut self) -> Result<(Vec<Node>, Terminator), Whatever> {
+        let mut nodes = Vec::new();
+        while self.pos < self.items.len() {
+            let item = &self.items[self.pos];
+            self.pos += 1;
+            let action = match item {
+                Item::Text(text) => {
+                    nodes.push(Node::Text(text.clone()));
+                    continue;
+                }
+                Item::Action(action) => *action,
+            };
+            let tokens = lex(action)?;
+            let keyword = match tokens.first() {
+                Some(Token::Ident(ident)) => ident.as_str(),
+                _ => "",
+            };
+            let rest = tokens.get(1..).unwrap_or_default();
+            match keyword {
+                "end" => return Ok((nodes, Terminator::End)),
+                "else" => return Ok((nodes, Terminator::Else(rest.to_vec()))),
+                "if" => nodes.push(self.parse_if(rest)?),
+                "range" => {
+                    let pipeline = parse_pipeline(rest)?;
+                    let (body, otherwise) = self.parse_body("range")?;
+                    nodes.push(Node::Range {
+                        pipeline,
+                        body,
+                        otherwise,
+                    });
+                }
+                "with" => {
+                    let pipeline = parse_pipeline(rest)?;
+                    let (body, otherwise) = self.parse_body("with")?;
+                    nodes.push(Node::With {
+                        pipeline,
+                        body,
+                        otherwise,
+                    });
+                }
+                "break" => nodes.push(Node::Break),
+                "continue" => nodes.push(Node::Continue),
+                "define" | "template" | "block" => {
+                    whatever!("{{{{ {keyword} }}}} is not supported in the template")
+                }
+                _ => nodes.push(Node::Action(parse_pipeline(&tokens)?)),
+            }
+        }
+        Ok((nodes, Terminator::Eof))
+    }
+
+    // if 之后可以有多个 else if 和一个 else
+    fn parse_if(&mut self, condition: &[Token]) -> Result<Node, Whatever> {
+        let mut branches = vec![(parse_pipeline(condition)?, Vec::new())];
+        loop {
+            let (body, terminator) = self.parse_list()?;
+            branches.last_mut().expect("There is a branch at least").1 = body;
+            match terminator {
+                Terminator::End => {
+                    return Ok(Node::If {
+                        branches,
+                        otherwise: Vec::new(),
+                    });
+                }
+                Terminator::Else(tokens) if tokens.is_empty() => {
+                    let otherwise = self.parse_until_end("if")?;
+                    return Ok(Node::If {
+                        branches,
+                        otherwise,
+                    });
+                }
+                Terminator::Else(tokens) if tokens[0] == Token::Ident("if".to_owned()) => {
+                    branches.push((parse_pipeline(&tokens[1..])?, Vec::new()));
+                }
+                Terminator::Else(_) => whatever!("Invalid {{{{ else }}}} of {{{{ if }}}}"),
+                Terminator::Eof => whatever!("Missing {{{{ end }}}} of {{{{ if }}}}"),
+            }
+        }
+    }
+
+    // range 和 with 的内容，以及可选的 else
+    fn parse_body(&mut self, keyword: &str) -> Result<(Vec<Node>, Vec<Node>), Whatever> {
+        let (body, terminator) = self.parse_list()?;
+        match terminator {
+            Terminator::End => Ok((body, Vec::new())),
+            Terminator::Else(tokens) if tokens.is_empty() => {
+                Ok((body, self.parse_until_end(keyword)?))
+            }
+            Terminator::Else(_) => whatever!("Invalid {{{{ else }}}} of {{{{ {keyword} }}}}"),
+            Terminator::Eof => whatever!("Missing {{{{ end }}}} of {{{{ {keyword} }}}}"),
+        }
+    }
+
+    fn parse_until_end(&mut self, keyword: &str) -> Result<Vec<Node>, Whatever> {
+        match self.parse_list()? {
+            (nodes, Terminator::End) => Ok(nodes),
+            _ => whatever!("Missing {{{{ end }}}} of {{{{ {keyword} }}}}"),
+        }
+    }
+}
+
+fn parse_pipeline(tokens: &[Token]) -> Result<Pipeline, Whatever> {
+    let mut pipeline = Pipeline::default();
+    let mut tokens = tokens;
+    // 变量的声明，例如 $i, $m := 或者 $x =
+    let decl = tokens
+        .iter()
+        .position(|token| matches!(token, Token::Declare | Token::Assign));
+    if let Some(index) = decl
+        && tokens[..index].iter().all(|token| {
+            matches!(token, Token::Var(_, fields) if fields.is_empty()) || *token == Token::Comma
+        })
+    {
+        pipeline.vars = tokens[..index]
+            .iter()
+            .filter_map(|token| match token {
+                Token::Var(name, _) => Some(name.clone()),
+                _ => None,
+            })
+            .collect();
+        pipeline.declare = tokens[index] == Token::Declare;
+        tokens = &tokens[index + 1..];
+    }
+    let mut pos = 0;
+    let mut command = Vec::new();
+    while pos < tokens.len() {
+        if tokens[pos] == Token::Pipe {
+            ensure_whatever!(!command.is_empty(), "Missing command before |");
+            pipeline.commands.push(std::mem::take(&mut command));
+            pos += 1;
+            continue;
+        }
+        command.push(parse_arg(tokens, &mut pos)?);
+    }
+    ensure_whatever!(!command.is_empty(), "Missing command in the pipeline");
+    pipeline.commands.push(command);
+    Ok(pipeline)
+}
+
+fn parse_arg(tokens: &[Token], pos: &mut usize) -> Result<Arg, Whatever> {
+    let token = &tokens[*pos];
+    *pos += 1;
+    Ok(match token {
+        Token::Dot => Arg::Dot,
+        Token::Field(fields) => Arg::Field(fields.clone()),
+        Token::Var(name, fields) => Arg::Var(name.clone(), fields.clone()),
+        Token::Const(value) => Arg::Const(value.clone()),
+        Token::Ident(name) => Arg::Func(name.clone()),
+        Token::LParen => {
+            // 找到对应的右括号
+            let start = *pos;
+            let mut depth = 1;
+            while *pos < tokens.len() {
+                match tokens[*pos] {
+                    Token::LParen => depth += 1,
+                    Token::RParen(_) => {
+                        depth -= 1;
+                        if depth == 0 {
+                            break;
+                        }
+                    }
+                    _ => {}
+                }
+                *pos += 1;
+            }
+            let Some(Token::RParen(fields)) = tokens.get(*pos) else {
+                whatever!("Missing ) in the template")
+            };
+            let pipeline = parse_pipeline(&tokens[start..*pos])?;
+            *pos += 1;
+            Arg::Paren(pipeline, fields.clone())
+        }
+        token => whatever!("Unexpected {token:?} in the template"),
+    })
+}
+
+fn nodes_use_field(nodes: &[Node], name: &str) -> bool {
+    nodes.iter().any(|node| match node {
+        Node::Text(_) | Node::Break | Node::Continue => false,
+        Node::Action(pipeline) => pipeline_uses_field(pipeline, name),
+        Node::If {
+            branches,
+            otherwise,
+        } => {
+            branches.iter().any(|(pipeline, body)| {
+                pipeline_uses_field(pipeline, name) || nodes_use_field(body, name)
+            }) || nodes_use_field(otherwise, name)
+        }
+        Node::Range {
+            pipeline,
+            body,
+            otherwise,
+        }
+        | Node::With {
+            pipeline,
+            body,
+            otherwise,
+        } => {
+            pipeline_uses_field(pipeline, name)
+                || nodes_use_field(body, name)
+                || nodes_use_field(otherwise, name)
+        }
+    })
+}
+
+fn pipeline_uses_field(pipeline: &Pipeline, name: &str) -> bool {
+    pipeline.commands.iter().flatten().any(|arg| match arg {
+        Arg::Field(fields) | Arg::Var(_, fields) => fields.iter().any(|field| field == name),
+        Arg::Paren(pipeline, fields) => {
+            pipeline_uses_field(pipeline, name) || fields.iter().any(|field| field == name)
+        }
+        Arg::Dot | Arg::Const(_) | Arg::Func(_) => false,
+    })
+}
+
+struct Exec {
+    // 变量，从后向前查找，离开控制结构时丢弃其中声明的变量
+    vars: Vec<(String, Value)>,
+    out: String,
+}
+
+impl Exec {
+    fn nodes(&mut self, nodes: &[Node], dot: &Value) -> Result<Flow, Whatever> {
+        for node in nodes {
+            let flow = match node {
+                Node::Text(text) => {
+                    self.out.push_str(text);
+                    Flow::Normal
+                }
+                Node::Action(pipeline) => {
+                    let value = self.pipeline(pipeline, dot)?;
+                    if pipeline.vars.is_empty() {
+                        self.out.push_str(&to_text(&value));
+                    }
+                    Flow::Normal
+                }
+                Node::If {
+                    branches,
+                    otherwise,
+                } => {
+                    let scope = self.vars.len();
+                    let mut body = otherwise;
+                    for (pipeline, nodes) in branches {
+                        if truthy(&self.pipeline(pipeline, dot)?) {
+                            body = nodes;
+                            break;
+                        }
+                    }
+                    let flow = self.nodes(body, dot)?;
+                    self.vars.truncate(scope);
+                    flow
+                }
+                Node::With {
+                    pipeline,
+                    body,
+                    otherwise,
+                } => {
+                    let scope = self.vars.len();
+                    let value = self.pipeline(pipeline, dot)?;
+                    let flow = if truthy(&value) {
+                        self.nodes(body, &value)?
+                    } else {
+                        self.nodes(otherwise, dot)?
+                    };
+                    self.vars.truncate(scope);
+                    flow
+                }
+                Node::Range {
+                    pipeline,
+                    body,
+                    otherwise,
+                } => {
+                    let flow = self.range(pipeline, body, otherwise, dot)?;
+                    // range 中的 break 和 continue 不会影响外层
+                    if let Flow::Normal = flow {
+                        Flow::Normal
+                    } else {
+                        whatever!(
+                            "{{{{ break }}}} or {{{{ continue }}}} outside of {{{{ range }}}}"
+                        )
+                    }
+                }
+                Node::Break => Flow::Break,
+                Node::Continue => Flow::Continue,
+            };
+            if !matches!(flow, Flow::Normal) {
+                return Ok(flow);
+            }
+        }
+        Ok(Flow::Normal)
+    }
+
+    fn range(
+        &mut self,
+        pipeline: &Pipeline,
+        body: &[Node],
+        otherwise: &[Node],
+        dot: &Value,
+    ) -> Result<Flow, Whatever> {
+        let scope = self.vars.len();
+        // range 中声明的变量是每一项的下标和值，不是管道的结果
+        let value = self.commands(&pipeline.commands, dot)?;
+        let entries = match value {
+            Value::Array(values) => values
+                .into_iter()
+                .enumerate()
+                .map(|(index, value)| (Value::from(index), value))
+                .collect::<Vec<_>>(),
+            Value::Object(map) => map
+                .into_iter()
+                .map(|(key, value)| (Value::String(key), value))
+                .collect(),
+            Value::Number(n) if n.is_u64() => (0..n.as_u64().unwrap_or_default())
+                .map(|index| (Value::from(index), Value::from(index)))
+                .collect(),
+            Value::Null => Vec::new(),
+            value => whatever!("Can't range over {value}"),
+        };
+        if entries.is_empty() {
+            self.nodes(otherwise, dot)?;
+        }
+        for (key, value) in entries {
+            match pipeline.vars.as_slice() {
+                [] => {}
+                [var] => self.vars.push((var.clone(), value.clone())),
+                [key_var, value_var] => {
+                    self.vars.push((key_var.clone(), key));
+                    self.vars.push((value_var.clone(), value.clone()));
+                }
+                _ => whatever!("Too many variables in {{{{ range }}}}"),
+            }
+            let flow = self.nodes(body, &value)?;
+            self.vars.truncate(scope);
+            if let Flow::Break = flow {
+                break;
+            }
+        }
+        self.vars.truncate(scope);
+        Ok(Flow::Normal)
+    }
+
+    fn pipeline(&mut self, pipeline: &Pipeline, dot: &Value) -> Result<Value, Whatever> {
+        let value = self.commands(&pipeline.commands, dot)?;
+        for var in &pipeline.vars {
+            if pipeline.declare {
+                self.vars.push((var.clone(), value.clone()));
+            } else {
+                let (_, old) = self
+                    .vars
+                    .iter_mut()
+                    .rev()
+                    .find(|(name, _)| name == var)
+                    .with_whatever_context(|| format!("Undefined variable {var}"))?;
+                *old = value.clone();
+            }
+        }
+        Ok(value)
+    }
+
+    // 前一个命令的结果作为后一个命令的最后一个参数
+    fn commands(&mut self, commands: &[Vec<Arg>], dot: &Value) -> Result<Value, Whatever> {
+        let mut piped = None;
+        for command in commands {
+            piped = Some(self.command(command, dot, piped)?);
+        }
+        Ok(piped.unwrap_or_default())
+    }
+
+    fn command(
+        &mut self,
+        command: &[Arg],
+        dot: &Value,
+        piped: Option<Value>,
+    ) -> Result<Value, Whatever> {
+        match command {
+            [Arg::Func(name), args @ ..] => {
+                let mut values = args
+                    .iter()
+                    .map(|arg| self.arg(arg, dot))
+                    .collect::<Result<Vec<_>, _>>()?;
+                values.extend(piped);
+                call(name, values)
+            }
+            [arg] if piped.is_none() => self.arg(arg, dot),
+            _ => whatever!("Only functions can have arguments in the template"),
+        }
+    }
+
+    fn arg(&mut self, arg: &Arg, dot: &Value) -> Result<Value, Whatever> {
+        Ok(match arg {
+            Arg::Dot => dot.clone(),
+            Arg::Field(fields) => fields_of(dot, fields),
+            Arg::Var(name, fields) => {
+                let (_, value) = self
+                    .vars
+                    .iter()
+                    .rev()
+                    .find(|(var, _)| var == name)
+                    .with_whatever_context(|| format!("Undefined variable {name}"))?;
+                fields_of(value, fields)
+            }
+            Arg::Const(value) => value.clone(),
+            Arg::Func(name) => call(name, Vec::new())?,
+            Arg::Paren(pipeline, fields) => {
+                let value = self.pipeline(pipeline, dot)?;
+                fields_of(&value, fields)
+            }
+        })
+    }
+}
+
+// 字段不存在时使用首字母小写的字段，和 Go 中结构体的字段对应 JSON 中的小写字段一样，比如 .Function.Name
+fn fields_of(value: &Value, fields: &[String]) -> Value {
+    fields
+        .iter()
+        .fold(value, |value, field| {
+            value
+                .get(field)
+                .or_else(|| value.get(lower_first(field)))
+                .unwrap_or(&Value::Null)
+        })
+        .clone()
+}
+
+fn lower_first(field: &str) -> String {
+    let mut chars = field.chars();
+    match chars.next() {
+        Some(first) => first.to_lowercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+// false、0、空值以及空的字符串、数组和对象为假
+fn truthy(value: &Value) -> bool {
+    match value {
+        Value::Null => false,
+        Value::Bool(value) => *value,
+        Value::Number(number) => number.as_f64().is_some_and(|number| number != 0.0),
+        Value::String(value) => !value.is_empty(),
+        Value::Array(values) => !values.is_empty(),
+        Value::Object(map) => !map.is_empty(),
+    }
+}
+
+// 输出到模板中的文本，数组和对象输出为 JSON
+fn to_text(value: &Value) -> String {
+    match value {
+        Value::Null => String::new(),
+        Value::String(value) => value.clone(),
+        value => value.to_string(),
+    }
+}
+
+fn equal(a: &Value, b: &Value) -> bool {
+    match (a, b) {
+        (Value::Number(a), Value::Number(b)) => a.as_f64() == b.as_f64(),
+        (a, b) => a == b,
+    }
+}
+
+fn compare(name: &str, a: &Value, b: &Value) -> Result<std::cmp::Ordering, Whatever> {
+    match (a, b) {
+        (Value::Number(a), Value::Number(b)) => a
+            .as_f64()
+            .partial_cmp(&b.as_f64())
+            .with_whatever_context(|| format!("Can't compare {a} and {b} in {name}")),
+        (Value::String(a), Value::String(b)) => Ok(a.cmp(b)),
+        (a, b) => whatever!("Can't compare {a} and {b} in {name}"),
+    }
+}
+
+fn index_of(value: &Value, index: &Value) -> Result<usize, Whatever> {
+    index
+        .as_u64()
+        .map(|index| index as usize)
+        .with_whatever_context(|| format!("Invalid index({index}) of {value}"))
+}
+
+fn call(name: &str, args: Vec<Value>) -> Result<Value, Whatever> {
+    let arity = |n: usize| {
+        ensure_whatever!(
+            args.len() == n,
+            "{name} expects {n} arguments, but got {}",
+            args.len()
+        );
+        Ok(())
+    };
+    Ok(match name {
+        "eq" => {
+            ensure_whatever!(args.len() >= 2, "eq expects 2 arguments at least");
+            Value::Bool(args[1..].iter().any(|arg| equal(&args[0], arg)))
+        }
+        "ne" => {
+            arity(2)?;
+            Value::Bool(!equal(&args[0], &args[1]))
+        }
+        "lt" | "le" | "gt" | "ge" => {
+            arity(2)?;
+            let ordering = compare(name, &args[0], &args[1])?;
+            Value::Bool(match name {
+                "lt" => ordering.is_lt(),
+                "le" => ordering.is_le(),
+                "gt" => ordering.is_gt(),
+                _ => ordering.is_ge(),
+            })
+        }
+        // 和 Go 一样返回第一个为假（and）或者为真（or）的参数，都不是时返回最后一个参数
+        "and" => args
+            .iter()
+            .find(|arg| !truthy(arg))
+            .or(args.last())
+            .cloned()
+            .unwrap_or_default(),
+        "or" => args
+            .iter()
+            .find(|arg| truthy(arg))
+            .or(args.last())
+            .cloned()
+            .unwrap_or_default(),
+        "not" => {
+            arity(1)?;
+            Value::Bool(!truthy(&args[0]))
+        }
+        "len" => {
+            arity(1)?;
+            Value::from(match &args[0] {
+                Value::String(value) => value.len(),
+                Value::Array(values) => values.len(),
+                Value::Object(map) => map.len(),
+                Value::Null => 0,
+                value => whatever!("Can't get the length of {value}"),
+            })
+        }
+        "index" => {
+            ensure_whatever!(!args.is_empty(), "index expects 1 argument at least");
+            let mut value = args[0].clone();
+            for key in &args[1..] {
+                value = match (&value, key) {
+                    (Value::Array(values), index) => values
+                        .get(index_of(&value, index)?)
+                        .cloned()
+                        .with_whatever_context(|| format!("Index({index}) out of range"))?,
+                    (Value::Object(map), Value::String(key)) => {
+                        map.get(key).cloned().unwrap_or_default()
+                    }
+                    (Value::Null, _) => Value::Null,
+                    (value, key) => whatever!("Can't index {value} with {key}"),
+                };
+            }
+            value
+        }
+        "slice" => {
+            ensure_whatever!(
+                (1..=3).contains(&args.len()),
+                "slice expects 1 to 3 arguments"
+            );
+            let value = &args[0];
+            let len = match value {
+                Value::String(value) => value.len(),
+                Value::Array(values) => values.len(),
+                value => whatever!("Can't slice {value}"),
+            };
+            let start = args
+                .get(1)
+                .map(|i| index_of(value, i))
+                .transpose()?
+                .unwrap_or(0);
+            let end = args
+                .get(2)
+                .map(|i| index_of(value, i))
+                .transpose()?
+                .unwrap_or(len);
+            ensure_whatever!(
+                start <= end && end <= len,
+                "Slice [{start}:{end}] out of range of {len}"
+            );
+            match value {
+                Value::String(value) => Value::String(
+                    value
+                        .get(start..end)
+                        .whatever_context("Slice a string in the middle of a character")?
+                        .to_owned(),
+                ),
+                Value::Array(values) => Value::Array(values[start..end].to_vec()),
+                _ => unreachable!(),
+            }
+        }
+        "json" => {
+            arity(1)?;
+            Value::String(args[0].to_string())
+        }
+        // 和 fmt.Sprint 一样，两个参数都不是字符串时使用空格分隔
+        "print" => {
+            let mut text = String::new();
+            for (index, arg) in args.iter().enumerate() {
+                if index > 0 && !args[index - 1].is_string() && !arg.is_string() {
+                    text.push(' ');
+                }
+                text.push_str(&to_text(arg));
+            }
+            Value::String(text)
+        }
+        "currentDate" => Value::String(chrono::Local::now().format("%Y-%m-%d").to_string()),
+        name => whatever!("Unknown function({name}) in the template"),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::GoTemplate;
+    use serde_json::json;
+
+    fn render(template: &str, data: serde_json::Value) -> String {
+        GoTemplate::parse(template)
+            .expect("Failed to parse the template")
+            .render(&data)
+            .expect("Failed to render the template")
+    }
+
+    #[test]
+    fn render_legacy_template() {
+        let template = "{{ if .System }}<|system|>\n{{ .System }}</s>\n{{ end }}<|user|>\n{{ .Prompt }}</s>\n<|assistant|>\n{{ .Response }}";
+        assert_eq!(
+            render(
+                template,
+                json!({"System": "", "Prompt": "hi", "Response": ""})
+            ),
+            "<|user|>\nhi</s>\n<|assistant|>\n"
+        );
+        assert_eq!(
+            render(template, json!({"System": "be nice", "Prompt": "hi"})),
+            "<|system|>\nbe nice</s>\n<|user|>\nhi</s>\n<|assistant|>\n"
+        );
+    }
+
+    #[test]
+    fn render_messages_template() {
+        let template = r#"{{- range $i, $_ := .Messages }}
+{{- $last := eq (len (slice $.Messages $i)) 1 -}}
+<|{{ .Role }}|>{{ .Content }}
+{{- if and (eq .Role "user") $last }}<|assistant|>{{ else if ne .Role "user" }}<|end|>{{ end }}
+{{- end }}"#;
+        let data = json!({"Messages": [
+            {"Role": "user", "Content": "a"},
+            {"Role": "assistant", "Content": "b"},
+            {"Role": "user", "Content": "c"},
+        ]});
+        assert_eq!(
+            render(template, data),
+            "<|user|>a<|assistant|>b<|end|><|user|>c<|assistant|>"
+        );
+        assert!(GoTemplate::parse(template).unwrap().uses_field("Messages"));
+    }
+
+    #[test]
+    fn render_functions_and_pipelines() {
+        assert_eq!(
+            render(r#"{{ .A | json }}"#, json!({"A": {"b": 1}})),
+            r#"{"b":1}"#
+        );
+        assert_eq!(render(r#"{{ index .A 1 }}"#, json!({"A": ["x", "y"]})), "y");
+        assert_eq!(
+            render(r#"{{ (index .A 0).B }}"#, json!({"A": [{"B": "z"}]})),
+            "z"
+        );
+        assert_eq!(
+            render(
+                r#"{{ .Function.Name }}"#,
+                json!({"function": {"name": "echo"}})
+            ),
+            "echo"
+        );
+        assert_eq!(render("{{/* comment */}}a {{- ` b` }}", json!({})), "a b");
+        assert_eq!(
+            render(r#"{{ with .A }}{{ . }}{{ else }}none{{ end }}"#, json!({})),
+            "none"
+        );
+        assert_eq!(
+            render(
+                r#"{{ $n := 0 }}{{ range .A }}{{ if eq . 2 }}{{ break }}{{ end }}{{ $n = . }}{{ end }}{{ $n }}"#,
+                json!({"A": [1, 2, 3]})
+            ),
+            "1"
+        );
+    }
+
+    #[test]
+    fn reject_invalid_template() {
+        assert!(GoTemplate::parse("{{ if .A }}").is_err());
+        assert!(GoTemplate::parse("{{ end }}").is_err());
+        assert!(GoTemplate::parse("{{ .A ").is_err());
+    }
+}
@@ -1 +1,7 @@
+pub mod blob;
+pub mod file_lock;
+pub mod format;
+pub mod go_template;
+pub mod progress;
+pub mod reference;
 pub mod rustyline;
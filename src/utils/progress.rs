@@ -0,0 +1,164 @@
+//! 命令行中的进度展示
+//!
+//! 标准错误是终端时，每个传输使用一个进度条，多个进度条同时展示；否则定期输出一行日志，便于在 CI 中查看
+
+use crate::utils::format::{human_readable_duration, human_readable_size};
+use http_extra::progress::{Progress, ProgressEvent, ProgressState};
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use std::{
+    io::{IsTerminal, stderr},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use tracing::{info, warn};
+
+// 非终端时两次输出进度日志之间的间隔
+const LOG_INTERVAL: Duration = Duration::from_secs(10);
+
+/// 进度的单位
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ProgressUnit {
+    /// 字节
+    Bytes,
+    /// 个数
+    Items,
+}
+
+pub struct ProgressDisplay {
+    multi: Option<MultiProgress>,
+}
+
+impl ProgressDisplay {
+    pub fn new() -> Self {
+        Self {
+            multi: stderr().is_terminal().then(MultiProgress::new),
+        }
+    }
+
+    /// 添加一个进度，返回的进度可以交给下载或者其它任务订阅
+    pub fn add(&self, label: impl Into<String>, unit: ProgressUnit) -> Progress {
+        let label = label.into();
+        match &self.multi {
+            Some(multi) => {
+                let bar = multi.add(ProgressBar::no_length().with_style(bar_style()));
+                bar.set_prefix(label);
+                Progress::new(move |event| render_bar(&bar, unit, event))
+            }
+            None => {
+                let logged_at = Mutex::new(Instant::now());
+                Progress::new(move |event| render_log(&label, unit, &logged_at, event))
+            }
+        }
+    }
+
+    /// 不需要传输的任务，直接展示结果
+    pub fn skip(&self, label: impl Into<String>, message: impl Into<String>) {
+        let label = label.into();
+        let message = message.into();
+        match &self.multi {
+            Some(multi) => {
+                let bar = multi.add(ProgressBar::new(1).with_style(bar_style()));
+                bar.set_prefix(label);
+                bar.finish_with_message(message);
+            }
+            None => info!("{label}: {message}"),
+        }
+    }
+}
+
+impl Default for ProgressDisplay {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn bar_style() -> ProgressStyle {
+    ProgressStyle::with_template("{prefix:<20} [{bar:30}] {percent:>3}% {msg}")
+        .expect("Invalid progress bar template")
+        .progress_chars("=> ")
+}
+
+fn render_bar(bar: &ProgressBar, unit: ProgressUnit, event: ProgressEvent) {
+    match event {
+        ProgressEvent::Started(state) | ProgressEvent::Advanced(state) => {
+            if let Some(total) = state.total {
+                bar.set_length(total);
+            }
+            bar.set_position(state.received);
+            bar.set_message(describe(unit, &state));
+        }
+        ProgressEvent::Retrying {
+            attempt,
+            delay,
+            error,
+        } => bar.set_message(format!(
+            "retry #{attempt} in {}, {error}",
+            human_readable_duration(delay)
+        )),
+        ProgressEvent::Finished(state) => {
+            bar.set_length(state.total.unwrap_or(state.received).max(1));
+            bar.set_position(state.total.unwrap_or(state.received).max(1));
+            bar.finish_with_message(amount(unit, state.received));
+        }
+    }
+}
+
+fn render_log(label: &str, unit: ProgressUnit, logged_at: &Mutex<Instant>, event: ProgressEvent) {
+    match event {
+        ProgressEvent::Started(state) => {
+            if state.resumed > 0 {
+                info!(
+                    "{label}: started, resumed from {}",
+                    amount(unit, state.resumed)
+                );
+            } else {
+                info!("{label}: started");
+            }
+        }
+        ProgressEvent::Advanced(state) => {
+            let mut logged_at = logged_at.lock().unwrap_or_else(|e| e.into_inner());
+            if logged_at.elapsed() >= LOG_INTERVAL {
+                *logged_at = Instant::now();
+                info!("{label}: {}", describe(unit, &state));
+            }
+        }
+        ProgressEvent::Retrying {
+            attempt,
+            delay,
+            error,
+        } => warn!(
+            "{label}: retry #{attempt} in {}, {error}",
+            human_readable_duration(delay)
+        ),
+        ProgressEvent::Finished(state) => {
+            info!("{label}: finished, {}", amount(unit, state.received))
+        }
+    }
+}
+
+// 例如 1.2 GB / 4.0 GB, 12.3 MB/s, ETA 3m12s
+fn describe(unit: ProgressUnit, state: &ProgressState) -> String {
+    let mut description = match state.total {
+        Some(total) => format!("{} / {}", amount(unit, state.received), amount(unit, total)),
+        None => amount(unit, state.received),
+    };
+    let rate = match unit {
+        ProgressUnit::Bytes => format!("{}/s", human_readable_size(state.rate as u64)),
+        ProgressUnit::Items => format!("{:.1}/s", state.rate),
+    };
+    description.push_str(&format!(", {rate}"));
+    if let Some(eta) = state.eta {
+        description.push_str(&format!(", ETA {}", human_readable_duration(eta)));
+    }
+    if state.resumed > 0 {
+        description.push_str(&format!(", resumed from {}", amount(unit, state.resumed)));
+    }
+    description
+}
+
+fn amount(unit: ProgressUnit, value: u64) -> String {
+    match unit {
+        ProgressUnit::Bytes => human_readable_size(value),
+        ProgressUnit::Items => value.to_string(),
+    }
+}
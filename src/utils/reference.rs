@@ -0,0 +1,259 @@
+//! 模型的引用
+//!
+//! 引用的格式为 `[host[:port]/][namespace/]repository[:tag][@sha256:<digest>]`，例如
+//! `qwen3:8b`、`someuser/model:q4`、`registry.example.com:5000/team/model@sha256:...`，
+//! 没有提供命名空间时使用 library，和 ollama.com 的官方模型一致
+
+use crate::error::Whatever;
+use snafu::prelude::*;
+use std::fmt::{Display, Formatter};
+use url::Url;
+
+// 官方模型所在的命名空间
+pub const DEFAULT_NAMESPACE: &str = "library";
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ModelReference {
+    /// 注册表的 host[:port]，为 None 时使用配置中的远程注册表
+    pub registry: Option<String>,
+    /// 命名空间
+    pub namespace: String,
+    /// 仓库，也就是模型的名字
+    pub repository: String,
+    /// 规格
+    pub tag: Option<String>,
+    /// 清单的摘要，格式为 sha256:<hex>，提供摘要时固定拉取这个清单
+    pub digest: Option<String>,
+}
+
+impl ModelReference {
+    pub fn parse(reference: impl AsRef<str>) -> Result<Self, Whatever> {
+        let reference = reference.as_ref().trim();
+        ensure_whatever!(!reference.is_empty(), "The model reference is empty");
+        let (rest, digest) = match reference.split_once('@') {
+            Some((rest, digest)) => (rest, Some(parse_digest(digest)?)),
+            None => (reference, None),
+        };
+        // 最后一个 / 之后的冒号才是规格的分隔符，host:port 中的冒号不是
+        let (path, tag) = match rest.rsplit_once(':') {
+            Some((path, tag)) if !tag.contains('/') => (path, Some(tag)),
+            _ => (rest, None),
+        };
+        let tag = match tag {
+            Some(tag) if !tag.is_empty() => {
+                ensure_whatever!(
+                    tag.len() <= 128 && tag.chars().all(is_name_char),
+                    "The tag({tag}) of {reference} is invalid"
+                );
+                Some(tag.to_owned())
+            }
+            _ => None,
+        };
+        let mut components = path.split('/').collect::<Vec<_>>();
+        // 第一部分包含 . 或者 : 或者是 localhost 时，视为注册表的地址
+        let registry = if components.len() > 1 && is_host(components[0]) {
+            Some(components.remove(0).to_owned())
+        } else {
+            None
+        };
+        ensure_whatever!(
+            components
+                .iter()
+                .all(|c| !c.is_empty() && c.chars().all(is_name_char)),
+            "The name of {reference} is invalid"
+        );
+        let repository = components
+            .pop()
+            .whatever_context(format!("The name of {reference} is invalid"))?
+            .to_owned();
+        let namespace = if components.is_empty() {
+            DEFAULT_NAMESPACE.to_owned()
+        } else {
+            components.join("/")
+        };
+        Ok(Self {
+            registry,
+            namespace,
+            repository,
+            tag,
+            digest,
+        })
+    }
+
+    /// 引用中没有规格时，使用提供的规格
+    pub fn or_tag(mut self, tag: Option<String>) -> Self {
+        if self.tag.is_none() {
+            self.tag = tag;
+        }
+        self
+    }
+
+    /// 注册表和配置中的远程注册表相同时，视为没有提供注册表
+    pub fn relative_to(mut self, remote: &Url) -> Self {
+        if self.registry.as_deref() == Some(authority(remote).as_str()) {
+            self.registry = None;
+        }
+        self
+    }
+
+    /// 官方的模型，也就是从配置中的远程注册表获取的 library 中的模型
+    pub fn is_library(&self) -> bool {
+        self.registry.is_none() && self.namespace == DEFAULT_NAMESPACE
+    }
+
+    /// 注册表中的仓库路径，例如 library/qwen3
+    pub fn path(&self) -> String {
+        format!("{}/{}", self.namespace, self.repository)
+    }
+
+    /// 本地注册表中不包含规格的名字，官方的模型直接使用仓库的名字，和从 ollama.com 获取的模型信息一致
+    pub fn local_title(&self) -> String {
+        match (&self.registry, self.is_library()) {
+            (_, true) => self.repository.clone(),
+            (Some(registry), _) => format!("{registry}/{}", self.path()),
+            (None, _) => self.path(),
+        }
+    }
+
+    /// 本地注册表中的规格，只提供了摘要时，使用摘要的前 12 位
+    pub fn category(&self) -> Option<String> {
+        self.tag.clone().or_else(|| {
+            self.digest
+                .as_ref()
+                .map(|digest| format!("sha256-{}", &digest["sha256:".len()..][..12]))
+        })
+    }
+
+    /// 获取清单时使用的引用，摘要优先
+    pub fn manifest_reference(&self) -> Option<String> {
+        self.digest.clone().or_else(|| self.tag.clone())
+    }
+
+    /// 注册表的地址，协议和配置中的远程注册表保持一致
+    pub fn remote(&self, remote: &Url) -> Result<Url, Whatever> {
+        match &self.registry {
+            None => Ok(remote.clone()),
+            Some(registry) => Url::parse(&format!("{}://{registry}/", remote.scheme()))
+                .with_whatever_context(|_| format!("The registry({registry}) is invalid")),
+        }
+    }
+}
+
+impl Display for ModelReference {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if let Some(registry) = &self.registry {
+            write!(f, "{registry}/")?;
+        }
+        write!(f, "{}", self.path())?;
+        if let Some(tag) = &self.tag {
+            write!(f, ":{tag}")?;
+        }
+        if let Some(digest) = &self.digest {
+            write!(f, "@{digest}")?;
+        }
+        Ok(())
+    }
+}
+
+fn parse_digest(digest: &str) -> Result<String, Whatever> {
+    let Some(hex) = digest.strip_prefix("sha256:") else {
+        whatever!("Only sha256 digest is supported, but the digest is {digest}");
+    };
+    ensure_whatever!(
+        hex.len() == 64 && hex.chars().all(|c| c.is_ascii_hexdigit()),
+        "The digest({digest}) is invalid"
+    );
+    Ok(format!("sha256:{}", hex.to_ascii_lowercase()))
+}
+
+fn is_host(component: &str) -> bool {
+    component.contains('.') || component.contains(':') || component == "localhost"
+}
+
+// 命名空间、仓库和规格中允许使用的字符
+fn is_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-')
+}
+
+// 远程注册表地址中的 host[:port]
+fn authority(url: &Url) -> String {
+    let host = url.host_str().unwrap_or_default();
+    match url.port() {
+        Some(port) => format!("{host}:{port}"),
+        None => host.to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DIGEST: &str = "sha256:6a0746a1ec1aef3e7ec53868f220ff6e389f6f8ef87a01d77c96807de94ca2aa";
+
+    #[test]
+    fn test_parse_reference() {
+        let reference = ModelReference::parse("qwen3:8b").unwrap();
+        assert_eq!(reference.namespace, "library");
+        assert_eq!(reference.repository, "qwen3");
+        assert_eq!(reference.tag.as_deref(), Some("8b"));
+        assert!(reference.is_library());
+        assert_eq!(reference.local_title(), "qwen3");
+        assert_eq!(reference.to_string(), "library/qwen3:8b");
+
+        let reference = ModelReference::parse("someuser/model").unwrap();
+        assert_eq!(reference.registry, None);
+        assert_eq!(reference.path(), "someuser/model");
+        assert_eq!(reference.tag, None);
+        assert_eq!(reference.local_title(), "someuser/model");
+
+        let reference =
+            ModelReference::parse("registry.example.com:5000/team/model:q4_K_M").unwrap();
+        assert_eq!(
+            reference.registry.as_deref(),
+            Some("registry.example.com:5000")
+        );
+        assert_eq!(reference.path(), "team/model");
+        assert_eq!(reference.tag.as_deref(), Some("q4_K_M"));
+        assert_eq!(
+            reference.local_title(),
+            "registry.example.com:5000/team/model"
+        );
+        let remote = Url::parse("https://registry.ollama.com/").unwrap();
+        assert_eq!(
+            reference.remote(&remote).unwrap().as_str(),
+            "https://registry.example.com:5000/"
+        );
+
+        let reference = ModelReference::parse(format!("localhost/model@{DIGEST}")).unwrap();
+        assert_eq!(reference.registry.as_deref(), Some("localhost"));
+        assert_eq!(reference.path(), "library/model");
+        assert_eq!(reference.category().as_deref(), Some("sha256-6a0746a1ec1a"));
+        assert_eq!(reference.manifest_reference().as_deref(), Some(DIGEST));
+
+        let reference = ModelReference::parse(format!("qwen3:8b@{DIGEST}")).unwrap();
+        assert_eq!(reference.category().as_deref(), Some("8b"));
+        assert_eq!(reference.manifest_reference().as_deref(), Some(DIGEST));
+    }
+
+    #[test]
+    fn test_reference_relative_to_remote() {
+        let remote = Url::parse("https://registry.ollama.com/").unwrap();
+        let reference = ModelReference::parse("registry.ollama.com/library/qwen3:8b")
+            .unwrap()
+            .relative_to(&remote)
+            .or_tag(Some("latest".to_owned()));
+        assert!(reference.is_library());
+        assert_eq!(reference.local_title(), "qwen3");
+        assert_eq!(reference.tag.as_deref(), Some("8b"));
+    }
+
+    #[test]
+    fn test_parse_invalid_reference() {
+        assert!(ModelReference::parse("").is_err());
+        assert!(ModelReference::parse("qwen3@sha256:1234").is_err());
+        assert!(ModelReference::parse("qwen3@md5:1234").is_err());
+        assert!(ModelReference::parse("some user/model").is_err());
+        assert!(ModelReference::parse("someuser//model").is_err());
+        assert!(ModelReference::parse("model:bad tag").is_err());
+    }
+}
@@ -1,7 +1,7 @@
 use crate::db;
 use rustyline::{
-    Cmd, Completer, CompletionType, EditMode, Editor, Helper, Hinter, KeyEvent, Validator,
-    completion::FilenameCompleter,
+    Cmd, CompletionType, Context, EditMode, Editor, Helper, Hinter, KeyEvent, Validator,
+    completion::{Completer, FilenameCompleter, Pair},
     highlight::{CmdKind, Highlighter, MatchingBracketHighlighter},
     hint::HistoryHinter,
     sqlite_history::SQLiteHistory,
@@ -19,6 +19,9 @@ pub type ReadLine = Editor<RustyLineHelper, SQLiteHistory>;
 
 pub trait EditorExt {
     fn colored_prompt(&mut self, prompt: impl ToString);
+
+    /// 以 / 开头的命令，例如 `/set temperature`，补全时按照空格分隔的单词逐个补全
+    fn slash_commands(&mut self, commands: Vec<String>);
 }
 
 impl EditorExt for ReadLine {
@@ -27,6 +30,12 @@ impl EditorExt for ReadLine {
             .expect("Line editor no helper")
             .colored_prompt = prompt.to_string();
     }
+
+    fn slash_commands(&mut self, commands: Vec<String>) {
+        self.helper_mut()
+            .expect("Line editor no helper")
+            .slash_commands = commands;
+    }
 }
 
 pub fn new_rustyline(path: impl AsRef<Path>) -> Editor<RustyLineHelper, SQLiteHistory> {
@@ -45,6 +54,7 @@ pub fn new_rustyline(path: impl AsRef<Path>) -> Editor<RustyLineHelper, SQLiteHi
     // 设置 helper
     let helper = RustyLineHelper {
         completer: FilenameCompleter::new(),
+        slash_commands: Vec::new(),
         highlighter: MatchingBracketHighlighter::new(),
         hinter: HistoryHinter::new(),
         colored_prompt: "".to_owned(),
@@ -60,10 +70,10 @@ pub fn new_rustyline(path: impl AsRef<Path>) -> Editor<RustyLineHelper, SQLiteHi
     line_editor
 }
 
-#[derive(Helper, Completer, Validator, Hinter)]
+#[derive(Helper, Validator, Hinter)]
 pub struct RustyLineHelper {
-    #[rustyline(Completer)]
     completer: FilenameCompleter,
+    slash_commands: Vec<String>,
     highlighter: MatchingBracketHighlighter,
     #[rustyline(Validator)]
     validator: MatchingBracketValidator,
@@ -72,6 +82,50 @@ pub struct RustyLineHelper {
     colored_prompt: String,
 }
 
+impl RustyLineHelper {
+    // 补全到下一个单词的末尾，相同的结果只保留一个
+    fn complete_slash_command(&self, prefix: &str) -> Vec<Pair> {
+        let mut candidates = Vec::<Pair>::new();
+        for command in self.slash_commands.iter().filter(|c| c.starts_with(prefix)) {
+            let rest = &command[prefix.len()..];
+            let end = rest
+                .char_indices()
+                .skip(1)
+                .find(|(_, c)| *c == ' ')
+                .map_or(command.len(), |(index, _)| prefix.len() + index);
+            let candidate = &command[..end];
+            if !candidates.iter().any(|pair| pair.replacement == candidate) {
+                candidates.push(Pair {
+                    display: candidate.to_owned(),
+                    replacement: candidate.to_owned(),
+                });
+            }
+        }
+        candidates
+    }
+}
+
+impl Completer for RustyLineHelper {
+    type Candidate = Pair;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<Pair>)> {
+        // 以 / 开头时优先补全命令，没有匹配的命令时（例如 /lora 的路径参数）补全文件名
+        let prefix = &line[..pos];
+        if prefix.starts_with('/') {
+            let candidates = self.complete_slash_command(prefix);
+            if !candidates.is_empty() {
+                return Ok((0, candidates));
+            }
+        }
+        self.completer.complete(line, pos, ctx)
+    }
+}
+
 impl Highlighter for RustyLineHelper {
     fn highlight<'l>(&self, line: &'l str, pos: usize) -> Cow<'l, str> {
         self.highlighter.highlight(line, pos)
@@ -97,3 +151,33 @@ impl Highlighter for RustyLineHelper {
         self.highlighter.highlight_char(line, pos, kind)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn complete_slash_command_word_by_word() {
+        let helper = RustyLineHelper {
+            completer: FilenameCompleter::new(),
+            slash_commands: ["/save", "/set temperature", "/set top_p", "/stats"]
+                .map(str::to_owned)
+                .to_vec(),
+            highlighter: MatchingBracketHighlighter::new(),
+            hinter: HistoryHinter::new(),
+            colored_prompt: "".to_owned(),
+            validator: MatchingBracketValidator::new(),
+        };
+        let complete = |prefix| {
+            helper
+                .complete_slash_command(prefix)
+                .into_iter()
+                .map(|pair| pair.replacement)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(complete("/s"), ["/save", "/set", "/stats"]);
+        assert_eq!(complete("/set"), ["/set temperature", "/set top_p"]);
+        assert_eq!(complete("/set t"), ["/set temperature", "/set top_p"]);
+        assert!(complete("/lora ").is_empty());
+    }
+}